@@ -0,0 +1,415 @@
+// Encoding of the audio streams. Without compression, each packet contains interleaved i16
+// samples. With Opus, each packet contains a 10 ms frame, prefixed by its index. The index is used
+// by the receiver to detect lost packets and conceal them.
+
+use alvr_common::{
+    anyhow::{Result, bail},
+    info,
+};
+use alvr_sockets::StreamSender;
+use audiopus::{
+    Application, Bitrate, Channels, MutSignals, SampleRate,
+    coder::{Decoder, Encoder},
+    packet::Packet,
+};
+use cpal::Sample;
+
+const OPUS_SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+const OPUS_FRAME_MS: usize = 10;
+// Opus packets can contain up to 120 ms of audio
+const OPUS_MAX_FRAME_MS: usize = 120;
+// The maximum recommended size of an Opus packet
+const OPUS_MAX_PACKET_SIZE: usize = 1275;
+const PACKET_INDEX_SIZE: usize = 4;
+// Longer gaps are a disruption of the stream, they are handled by the receive loop with fades
+const MAX_CONCEALED_PACKETS: u32 = 10;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AudioCodec {
+    Pcm,
+    Opus { bitrate_bps: u32 },
+}
+
+impl AudioCodec {
+    // Both peers must make the same choice. Opus supports only some sample rates, the samples are
+    // sent uncompressed otherwise
+    pub fn select(opus_bitrate_kbps: Option<u32>, sample_rate: u32) -> Self {
+        match opus_bitrate_kbps {
+            Some(bitrate_kbps) if OPUS_SAMPLE_RATES.contains(&sample_rate) => Self::Opus {
+                bitrate_bps: bitrate_kbps * 1000,
+            },
+            Some(_) => {
+                info!("Opus does not support the sample rate of {sample_rate} Hz, using PCM");
+                Self::Pcm
+            }
+            None => Self::Pcm,
+        }
+    }
+}
+
+fn opus_sample_rate(sample_rate: u32) -> Result<SampleRate> {
+    Ok(SampleRate::try_from(sample_rate as i32)?)
+}
+
+fn opus_channels(channels_count: u16) -> Result<Channels> {
+    match channels_count {
+        1 => Ok(Channels::Mono),
+        2 => Ok(Channels::Stereo),
+        _ => bail!("Opus supports only mono and stereo audio"),
+    }
+}
+
+struct OpusEncoderState {
+    encoder: Encoder,
+    frame_samples_count: usize,
+    pending_samples: Vec<i16>,
+    packet_index: u32,
+    packet_buffer: Vec<u8>,
+}
+
+pub struct AudioEncoder {
+    opus: Option<OpusEncoderState>,
+}
+
+impl AudioEncoder {
+    pub fn new(codec: AudioCodec, sample_rate: u32, channels_count: u16) -> Result<Self> {
+        let opus = if let AudioCodec::Opus { bitrate_bps } = codec {
+            // Restricted low delay mode has the lowest algorithmic delay
+            let mut encoder = Encoder::new(
+                opus_sample_rate(sample_rate)?,
+                opus_channels(channels_count)?,
+                Application::LowDelay,
+            )?;
+            encoder.set_bitrate(Bitrate::BitsPerSecond(bitrate_bps as i32))?;
+
+            Some(OpusEncoderState {
+                encoder,
+                frame_samples_count: sample_rate as usize / 1000
+                    * channels_count as usize
+                    * OPUS_FRAME_MS,
+                pending_samples: vec![],
+                packet_index: 0,
+                packet_buffer: vec![0; OPUS_MAX_PACKET_SIZE],
+            })
+        } else {
+            None
+        };
+
+        Ok(Self { opus })
+    }
+
+    // The data contains interleaved i16 samples, in native endianness. With Opus, the samples are
+    // buffered until a whole frame can be encoded
+    pub fn send(&mut self, sender: &mut StreamSender<()>, data: &[u8]) -> Result<()> {
+        let Some(state) = &mut self.opus else {
+            let mut buffer = sender.get_buffer(&())?;
+            buffer.get_range_mut(0, data.len()).copy_from_slice(data);
+
+            return sender.send(buffer);
+        };
+
+        state.encode_frames(data, |packet_index, frame| {
+            let mut buffer = sender.get_buffer(&())?;
+            buffer
+                .get_range_mut(0, PACKET_INDEX_SIZE)
+                .copy_from_slice(&packet_index.to_le_bytes());
+            buffer
+                .get_range_mut(PACKET_INDEX_SIZE, frame.len())
+                .copy_from_slice(frame);
+
+            sender.send(buffer)
+        })
+    }
+}
+
+impl OpusEncoderState {
+    fn encode_frames(
+        &mut self,
+        data: &[u8],
+        mut send_frame: impl FnMut(u32, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        self.pending_samples.extend(
+            data.chunks_exact(2)
+                .map(|c| i16::from_ne_bytes([c[0], c[1]])),
+        );
+
+        while self.pending_samples.len() >= self.frame_samples_count {
+            let size = self.encoder.encode(
+                &self.pending_samples[..self.frame_samples_count],
+                &mut self.packet_buffer,
+            )?;
+            self.pending_samples.drain(..self.frame_samples_count);
+
+            send_frame(self.packet_index, &self.packet_buffer[..size])?;
+
+            self.packet_index = self.packet_index.wrapping_add(1);
+        }
+
+        Ok(())
+    }
+}
+
+pub struct DecodedPacket {
+    pub samples: Vec<f32>,
+    // Packets were lost before this one and the missing audio could not be concealed
+    pub discontinuity: bool,
+}
+
+struct OpusDecoderState {
+    decoder: Decoder,
+    channels_count: usize,
+    frame_samples_count: usize,
+    next_packet_index: Option<u32>,
+    frame_buffer: Vec<f32>,
+}
+
+pub struct AudioDecoder {
+    opus: Option<OpusDecoderState>,
+}
+
+impl AudioDecoder {
+    pub fn new(codec: AudioCodec, sample_rate: u32, channels_count: u16) -> Result<Self> {
+        let opus = if let AudioCodec::Opus { .. } = codec {
+            let samples_per_ms = sample_rate as usize / 1000 * channels_count as usize;
+
+            Some(OpusDecoderState {
+                decoder: Decoder::new(
+                    opus_sample_rate(sample_rate)?,
+                    opus_channels(channels_count)?,
+                )?,
+                channels_count: channels_count as usize,
+                frame_samples_count: samples_per_ms * OPUS_FRAME_MS,
+                next_packet_index: None,
+                frame_buffer: vec![0.0; samples_per_ms * OPUS_MAX_FRAME_MS],
+            })
+        } else {
+            None
+        };
+
+        Ok(Self { opus })
+    }
+
+    // had_packet_loss is reported by the socket. It is needed only without compression, Opus
+    // packets are numbered
+    pub fn decode(&mut self, packet: &[u8], had_packet_loss: bool) -> Result<DecodedPacket> {
+        let Some(state) = &mut self.opus else {
+            return Ok(DecodedPacket {
+                samples: packet
+                    .chunks_exact(2)
+                    .map(|c| i16::from_ne_bytes([c[0], c[1]]).to_sample::<f32>())
+                    .collect(),
+                discontinuity: had_packet_loss,
+            });
+        };
+
+        if packet.len() < PACKET_INDEX_SIZE {
+            bail!("Invalid audio packet");
+        }
+        let (index_bytes, frame) = packet.split_at(PACKET_INDEX_SIZE);
+        let packet_index = u32::from_le_bytes(index_bytes.try_into().unwrap());
+
+        let lost_packets = state
+            .next_packet_index
+            .map(|next_index| packet_index.wrapping_sub(next_index))
+            .unwrap_or(0);
+
+        // A late or duplicate packet. Its audio was already concealed, it is dropped without
+        // rewinding the index
+        if lost_packets > u32::MAX / 2 {
+            return Ok(DecodedPacket {
+                samples: vec![],
+                discontinuity: false,
+            });
+        }
+        state.next_packet_index = Some(packet_index.wrapping_add(1));
+
+        let mut samples = vec![];
+        let discontinuity = lost_packets > MAX_CONCEALED_PACKETS;
+        if !discontinuity {
+            // Without a packet, the decoder extrapolates the missing frame
+            for _ in 0..lost_packets {
+                let frame_buffer = &mut state.frame_buffer[..state.frame_samples_count];
+                state.decoder.decode_float(
+                    None,
+                    MutSignals::try_from(&mut *frame_buffer)?,
+                    false,
+                )?;
+                samples.extend_from_slice(frame_buffer);
+            }
+        }
+
+        let frames_count = state.decoder.decode_float(
+            Some(Packet::try_from(frame)?),
+            MutSignals::try_from(&mut state.frame_buffer)?,
+            false,
+        )?;
+        samples.extend_from_slice(&state.frame_buffer[..frames_count * state.channels_count]);
+
+        Ok(DecodedPacket {
+            samples,
+            discontinuity,
+        })
+    }
+
+    // Used to record the audio, which is stored uncompressed
+    pub fn decode_to_pcm(&mut self, packet: &[u8]) -> Result<Vec<u8>> {
+        if self.opus.is_none() {
+            return Ok(packet.to_vec());
+        }
+
+        Ok(self
+            .decode(packet, false)?
+            .samples
+            .into_iter()
+            .flat_map(|sample| sample.to_sample::<i16>().to_ne_bytes())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+    const CHANNELS_COUNT: u16 = 2;
+    const FRAME_SAMPLES_COUNT: usize =
+        SAMPLE_RATE as usize / 1000 * CHANNELS_COUNT as usize * OPUS_FRAME_MS;
+
+    fn opus_codec() -> AudioCodec {
+        AudioCodec::Opus {
+            bitrate_bps: 128_000,
+        }
+    }
+
+    // Interleaved stereo sine wave, as native endian i16 bytes
+    fn sine_pcm(frames_count: usize) -> Vec<u8> {
+        (0..frames_count)
+            .flat_map(|f| {
+                let phase = f as f32 * 440.0 * std::f32::consts::TAU / SAMPLE_RATE as f32;
+                let sample = (phase.sin() * 0.5).to_sample::<i16>();
+                [sample, sample]
+            })
+            .flat_map(i16::to_ne_bytes)
+            .collect()
+    }
+
+    // Packets as they are sent, with the index prefix
+    fn encode_packets(frames_count: usize) -> Vec<Vec<u8>> {
+        let encoder = AudioEncoder::new(opus_codec(), SAMPLE_RATE, CHANNELS_COUNT).unwrap();
+        let mut state = encoder.opus.unwrap();
+
+        let mut packets = vec![];
+        state
+            .encode_frames(&sine_pcm(frames_count), |packet_index, frame| {
+                packets.push([&packet_index.to_le_bytes(), frame].concat());
+                Ok(())
+            })
+            .unwrap();
+
+        packets
+    }
+
+    fn with_index(packet: &[u8], packet_index: u32) -> Vec<u8> {
+        [&packet_index.to_le_bytes(), &packet[PACKET_INDEX_SIZE..]].concat()
+    }
+
+    fn new_decoder() -> AudioDecoder {
+        AudioDecoder::new(opus_codec(), SAMPLE_RATE, CHANNELS_COUNT).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // The last incomplete frame stays buffered in the encoder
+        let packets = encode_packets(SAMPLE_RATE as usize / 10 + 100);
+        assert_eq!(packets.len(), 10);
+
+        let mut decoder = new_decoder();
+        let mut samples = vec![];
+        for (index, packet) in packets.iter().enumerate() {
+            assert_eq!(&packet[..PACKET_INDEX_SIZE], (index as u32).to_le_bytes());
+
+            let decoded = decoder.decode(packet, false).unwrap();
+            assert!(!decoded.discontinuity);
+            assert_eq!(decoded.samples.len(), FRAME_SAMPLES_COUNT);
+            samples.extend(decoded.samples);
+        }
+
+        // The encoder delay shifts the wave, only its amplitude is compared
+        let peak = samples[samples.len() / 2..]
+            .iter()
+            .fold(0.0_f32, |peak, sample| peak.max(sample.abs()));
+        assert!((0.4..0.6).contains(&peak), "peak: {peak}");
+    }
+
+    #[test]
+    fn test_concealment() {
+        let packets = encode_packets(SAMPLE_RATE as usize / 10);
+
+        for lost_packets in 1..=MAX_CONCEALED_PACKETS {
+            let mut decoder = new_decoder();
+            decoder.decode(&packets[0], false).unwrap();
+
+            let packet = with_index(&packets[1], 1 + lost_packets);
+            let decoded = decoder.decode(&packet, false).unwrap();
+            assert!(!decoded.discontinuity);
+            assert_eq!(
+                decoded.samples.len(),
+                (lost_packets as usize + 1) * FRAME_SAMPLES_COUNT
+            );
+        }
+    }
+
+    #[test]
+    fn test_discontinuity() {
+        let packets = encode_packets(SAMPLE_RATE as usize / 10);
+
+        let mut decoder = new_decoder();
+        decoder.decode(&packets[0], false).unwrap();
+
+        let packet = with_index(&packets[1], 2 + MAX_CONCEALED_PACKETS);
+        let decoded = decoder.decode(&packet, false).unwrap();
+        assert!(decoded.discontinuity);
+        assert_eq!(decoded.samples.len(), FRAME_SAMPLES_COUNT);
+    }
+
+    #[test]
+    fn test_index_rollover() {
+        let packets = encode_packets(SAMPLE_RATE as usize / 10);
+
+        let mut decoder = new_decoder();
+        decoder
+            .decode(&with_index(&packets[0], u32::MAX), false)
+            .unwrap();
+
+        let decoded = decoder.decode(&with_index(&packets[1], 0), false).unwrap();
+        assert!(!decoded.discontinuity);
+        assert_eq!(decoded.samples.len(), FRAME_SAMPLES_COUNT);
+
+        // One packet lost across the rollover
+        let decoded = decoder.decode(&with_index(&packets[2], 2), false).unwrap();
+        assert!(!decoded.discontinuity);
+        assert_eq!(decoded.samples.len(), 2 * FRAME_SAMPLES_COUNT);
+    }
+
+    #[test]
+    fn test_stale_packets() {
+        let packets = encode_packets(SAMPLE_RATE as usize / 10);
+
+        let mut decoder = new_decoder();
+        decoder.decode(&with_index(&packets[0], 5), false).unwrap();
+
+        // Duplicate and late packets
+        for packet_index in [5, 3, 6_u32.wrapping_sub(u32::MAX / 2)] {
+            let decoded = decoder
+                .decode(&with_index(&packets[1], packet_index), false)
+                .unwrap();
+            assert!(!decoded.discontinuity);
+            assert!(decoded.samples.is_empty());
+        }
+
+        // The expected index was not rewound
+        let decoded = decoder.decode(&with_index(&packets[2], 6), false).unwrap();
+        assert!(!decoded.discontinuity);
+        assert_eq!(decoded.samples.len(), FRAME_SAMPLES_COUNT);
+    }
+}
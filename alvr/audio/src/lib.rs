@@ -1,3 +1,5 @@
+mod codec;
+mod resampler;
 #[cfg(windows)]
 mod windows;
 
@@ -6,12 +8,15 @@ pub mod linux;
 
 #[cfg(windows)]
 pub use crate::windows::*;
+pub use codec::*;
+pub use resampler::*;
 
 use alvr_common::{
     ConnectionError, ToAny,
     anyhow::{self, Context, Result, bail},
     info,
     parking_lot::Mutex,
+    warn,
 };
 use alvr_session::{AudioBufferingConfig, CustomAudioDeviceConfig, MicrophoneDevicesConfig};
 use alvr_sockets::{StreamReceiver, StreamSender};
@@ -250,7 +255,9 @@ pub fn record_audio_blocking(
     mut sender: StreamSender<()>,
     device: &Device,
     channels_count: u16,
+    sample_rate: u32,
     mute: bool,
+    codec: AudioCodec,
 ) -> Result<()> {
     let config = device
         .default_input_config()
@@ -275,6 +282,13 @@ pub fn record_audio_blocking(
         buffer_size: BufferSize::Default,
     };
 
+    let mut encoder = AudioEncoder::new(codec, sample_rate, channels_count)?;
+
+    // The device can use any sample rate, the audio is converted to the rate of the stream
+    let mut maybe_resampler = (config.sample_rate().0 != sample_rate)
+        .then(|| Resampler::new(config.sample_rate().0, sample_rate, channels_count))
+        .transpose()?;
+
     let state = Arc::new(Mutex::new(AudioRecordState::Recording));
 
     let stream = device.build_input_stream_raw(
@@ -298,12 +312,20 @@ pub fn record_audio_blocking(
                     data.bytes().to_vec()
                 };
 
-                let data = downmix_audio(data, config.channels(), channels_count);
+                let mut data = downmix_audio(data, config.channels(), channels_count);
+
+                if let Some(resampler) = &mut maybe_resampler {
+                    match resampler.process_pcm(&data) {
+                        Ok(resampled_data) => data = resampled_data,
+                        Err(e) => {
+                            *state.lock() = AudioRecordState::Err(Some(e));
+                            return;
+                        }
+                    }
+                }
 
                 if is_running() {
-                    let mut buffer = sender.get_buffer(&()).unwrap();
-                    buffer.get_range_mut(0, data.len()).copy_from_slice(&data);
-                    sender.send(buffer).ok();
+                    encoder.send(&mut sender, &data).ok();
                 } else {
                     *state.lock() = AudioRecordState::ShouldStop;
                 }
@@ -378,6 +400,8 @@ pub fn get_next_frame_batch(
 pub fn receive_samples_loop(
     is_running: impl Fn() -> bool,
     receiver: &mut StreamReceiver<()>,
+    mut decoder: AudioDecoder,
+    mut maybe_resampler: Option<Resampler>,
     sample_buffer: Arc<Mutex<VecDeque<f32>>>,
     channels_count: usize,
     batch_frames_count: usize,
@@ -392,14 +416,25 @@ pub fn receive_samples_loop(
         };
         let (_, packet) = data.get()?;
 
-        let new_samples = packet
-            .chunks_exact(2)
-            .map(|c| i16::from_ne_bytes([c[0], c[1]]).to_sample::<f32>())
-            .collect::<Vec<_>>();
+        // With Opus, the lost packets are concealed by the decoder. The fades are used only if the
+        // gap is too long
+        let (mut new_samples, had_packet_loss) =
+            match decoder.decode(packet, data.had_packet_loss()) {
+                Ok(decoded) => (decoded.samples, decoded.discontinuity),
+                Err(e) => {
+                    warn!("Audio decode error: {e}");
+                    continue;
+                }
+            };
+
+        // The frame counts refer to the sample rate after the conversion
+        if let Some(resampler) = &mut maybe_resampler {
+            new_samples = resampler.process(&new_samples)?;
+        }
 
         let mut sample_buffer_ref = sample_buffer.lock();
 
-        if data.had_packet_loss() {
+        if had_packet_loss {
             info!("Audio packet loss!");
 
             if sample_buffer_ref.len() / channels_count < batch_frames_count {
@@ -416,7 +451,7 @@ pub fn receive_samples_loop(
             recovery_sample_buffer.extend(sample_buffer_ref.drain(..));
         }
 
-        if sample_buffer_ref.is_empty() || data.had_packet_loss() {
+        if sample_buffer_ref.is_empty() || had_packet_loss {
             recovery_sample_buffer.extend(&new_samples);
 
             if recovery_sample_buffer.len() / channels_count
@@ -430,8 +465,7 @@ pub fn receive_samples_loop(
                     }
                 }
 
-                if data.had_packet_loss()
-                    && sample_buffer_ref.len() / channels_count == batch_frames_count
+                if had_packet_loss && sample_buffer_ref.len() / channels_count == batch_frames_count
                 {
                     // Add a fade-out to make a cross-fade.
                     for f in 0..batch_frames_count {
@@ -532,13 +566,23 @@ pub fn play_audio_loop(
     sample_rate: u32,
     config: AudioBufferingConfig,
     receiver: &mut StreamReceiver<()>,
+    codec: AudioCodec,
 ) -> Result<()> {
+    let decoder = AudioDecoder::new(codec, sample_rate, channels_count)?;
+
+    // The stream is played at the rate of the device, instead of relying on the conversion of the
+    // mixer
+    let device_sample_rate = device.default_output_config()?.sample_rate().0;
+    let maybe_resampler = (device_sample_rate != sample_rate)
+        .then(|| Resampler::new(sample_rate, device_sample_rate, channels_count))
+        .transpose()?;
+
     // Size of a chunk of frames. It corresponds to the duration if a fade-in/out in frames.
-    let batch_frames_count = sample_rate as usize * config.batch_ms as usize / 1000;
+    let batch_frames_count = device_sample_rate as usize * config.batch_ms as usize / 1000;
 
     // Average buffer size in frames
     let average_buffer_frames_count =
-        sample_rate as usize * config.average_buffering_ms as usize / 1000;
+        device_sample_rate as usize * config.average_buffering_ms as usize / 1000;
 
     let sample_buffer = Arc::new(Mutex::new(VecDeque::new()));
 
@@ -549,13 +593,15 @@ pub fn play_audio_loop(
         current_batch: vec![],
         current_batch_cursor: 0,
         channels_count: channels_count as _,
-        sample_rate,
+        sample_rate: device_sample_rate,
         batch_frames_count,
     });
 
     receive_samples_loop(
         is_running,
         receiver,
+        decoder,
+        maybe_resampler,
         sample_buffer,
         channels_count as _,
         batch_frames_count,
@@ -1,3 +1,4 @@
+use crate::{AudioCodec, AudioDecoder, AudioEncoder, Resampler};
 use alvr_common::{ConnectionError, anyhow::Result, debug, error, parking_lot::Mutex};
 use alvr_session::AudioBufferingConfig;
 use alvr_sockets::{StreamReceiver, StreamSender};
@@ -23,7 +24,12 @@ use pipewire::{
     main_loop::MainLoop,
     properties,
     spa::{
-        param::audio::{AudioFormat, AudioInfoRaw},
+        param::{
+            ParamType,
+            audio::{AudioFormat, AudioInfoRaw},
+            format::{MediaSubtype, MediaType},
+            format_utils,
+        },
         pod::{self, Pod, Value, serialize::PodSerializer},
         utils::Direction,
     },
@@ -69,6 +75,7 @@ fn probe_pipewire() -> Result<(), pipewire::Error> {
 pub struct AudioInfo {
     pub sample_rate: u32,
     pub channel_count: u32,
+    pub codec: AudioCodec,
 }
 
 struct Terminate;
@@ -115,9 +122,23 @@ pub fn audio_loop(
             let batch_frames_count = rate * buffering.batch_ms as usize / 1000;
             let average_buffer_frames_count = rate * buffering.average_buffering_ms as usize / 1000;
 
+            let decoder = match AudioDecoder::new(
+                mic_info.codec,
+                mic_info.sample_rate,
+                mic_info.channel_count as u16,
+            ) {
+                Ok(decoder) => decoder,
+                Err(e) => {
+                    error!("Failed to create microphone decoder: {e}");
+                    break;
+                }
+            };
+
             if let Err(e) = crate::receive_samples_loop(
                 || is_running() && MIC_STREAMING.load(Ordering::Relaxed),
                 receiver,
+                decoder,
+                None,
                 sample_queue.clone(),
                 mic_info.channel_count as usize,
                 batch_frames_count,
@@ -171,12 +192,7 @@ fn pw_main_loop(
 
     let _speaker = if let Some(info) = speaker_info {
         debug!("Creating pw output audio stream");
-        Some(create_speaker_stream(
-            &pw_core,
-            audio_sender,
-            info.sample_rate,
-            info.channel_count,
-        )?)
+        Some(create_speaker_stream(&pw_core, audio_sender, info)?)
     } else {
         None
     };
@@ -213,12 +229,63 @@ fn audio_info_to_vec(audio_info: AudioInfoRaw) -> Vec<u8> {
     .into_inner()
 }
 
+struct SpeakerCapture {
+    sender: StreamSender<()>,
+    encoder: AudioEncoder,
+    info: AudioInfo,
+    maybe_resampler: Option<Resampler>,
+}
+
+impl SpeakerCapture {
+    // The rate of the sink is chosen by pipewire, the audio is converted to the rate of the stream
+    fn set_capture_sample_rate(&mut self, sample_rate: u32) {
+        debug!("Game audio is captured at {sample_rate} Hz");
+
+        self.maybe_resampler = if sample_rate != self.info.sample_rate {
+            Resampler::new(
+                sample_rate,
+                self.info.sample_rate,
+                self.info.channel_count as u16,
+            )
+            .inspect_err(|e| error!("Failed to create game audio resampler: {e}"))
+            .ok()
+        } else {
+            None
+        };
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        let resampled_data;
+        let data = if let Some(resampler) = &mut self.maybe_resampler {
+            match resampler.process_pcm(data) {
+                Ok(data) => {
+                    resampled_data = data;
+                    &resampled_data
+                }
+                Err(e) => {
+                    error!("Failed to resample game audio: {e}");
+                    return;
+                }
+            }
+        } else {
+            data
+        };
+
+        self.encoder.send(&mut self.sender, data).ok();
+    }
+}
+
 fn create_speaker_stream(
     pw_core: &Core,
-    mut sender: StreamSender<()>,
-    sample_rate: u32,
-    channel_count: u32,
-) -> Result<(Stream, StreamListener<i16>), pipewire::Error> {
+    sender: StreamSender<()>,
+    info: AudioInfo,
+) -> Result<(Stream, StreamListener<SpeakerCapture>), pipewire::Error> {
+    let encoder = AudioEncoder::new(info.codec, info.sample_rate, info.channel_count as u16)
+        .map_err(|e| {
+            error!("Failed to create game audio encoder: {e}");
+            pipewire::Error::CreationFailed
+        })?;
+
     let stream = Stream::new(
         pw_core,
         "alvr-audio",
@@ -232,9 +299,24 @@ fn create_speaker_stream(
         },
     )?;
 
-    let listener: StreamListener<i16> = stream
-        .add_local_listener()
-        .process(move |stream, _| {
+    let listener = stream
+        .add_local_listener_with_user_data(SpeakerCapture {
+            sender,
+            encoder,
+            info,
+            maybe_resampler: None,
+        })
+        .param_changed(|_, capture, id, param| {
+            let mut format = AudioInfoRaw::new();
+            if id == ParamType::Format.as_raw()
+                && let Some(param) = param
+                && let Ok((MediaType::Audio, MediaSubtype::Raw)) = format_utils::parse_format(param)
+                && format.parse(param).is_ok()
+            {
+                capture.set_capture_sample_rate(format.rate());
+            }
+        })
+        .process(|stream, capture| {
             if let Some(mut pw_buf) = stream.dequeue_buffer()
                 && let Some(pw_buf) = pw_buf.datas_mut().first_mut()
             {
@@ -242,21 +324,17 @@ fn create_speaker_stream(
 
                 if let Some(data) = pw_buf.data() {
                     // Data is given as s16le in the correct layout by pipewire already,
-                    // no need to do conversions
-                    let mut buffer = sender.get_buffer(&()).unwrap();
-                    buffer
-                        .get_range_mut(0, size)
-                        .copy_from_slice(&data[0..size]);
-                    sender.send(buffer).ok();
+                    // only the sample rate may need a conversion
+                    capture.send(&data[0..size]);
                 }
             }
         })
         .register()?;
 
+    // The rate is not set, so the sink runs at the rate of the graph
     let mut audio_info = AudioInfoRaw::new();
     audio_info.set_format(AudioFormat::S16LE);
-    audio_info.set_rate(sample_rate);
-    audio_info.set_channels(channel_count);
+    audio_info.set_channels(info.channel_count);
 
     stream.connect(
         Direction::Input,
@@ -0,0 +1,166 @@
+// Sample rate conversion between the audio devices and the streams. The conversion uses windowed
+// sinc interpolation. The input is processed in chunks of fixed size, the samples that do not fill
+// a whole chunk are kept for the next call.
+
+use alvr_common::anyhow::Result;
+use cpal::Sample;
+use rubato::{
+    Resampler as _, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
+};
+
+// The chunk size adds to the latency of the audio
+const CHUNK_MS: usize = 5;
+const SINC_LEN: usize = 128;
+
+pub struct Resampler {
+    inner: SincFixedIn<f32>,
+    channels_count: usize,
+    // Deinterleaved input samples waiting for a whole chunk
+    pending_samples: Vec<Vec<f32>>,
+}
+
+impl Resampler {
+    pub fn new(
+        input_sample_rate: u32,
+        output_sample_rate: u32,
+        channels_count: u16,
+    ) -> Result<Self> {
+        let window = WindowFunction::BlackmanHarris2;
+        let parameters = SincInterpolationParameters {
+            sinc_len: SINC_LEN,
+            f_cutoff: rubato::calculate_cutoff(SINC_LEN, window),
+            interpolation: SincInterpolationType::Cubic,
+            oversampling_factor: 256,
+            window,
+        };
+
+        let inner = SincFixedIn::new(
+            output_sample_rate as f64 / input_sample_rate as f64,
+            1.0,
+            parameters,
+            input_sample_rate as usize * CHUNK_MS / 1000,
+            channels_count as usize,
+        )?;
+
+        Ok(Self {
+            inner,
+            channels_count: channels_count as usize,
+            pending_samples: vec![vec![]; channels_count as usize],
+        })
+    }
+
+    // Input and output samples are interleaved
+    pub fn process(&mut self, samples: &[f32]) -> Result<Vec<f32>> {
+        for frame in samples.chunks_exact(self.channels_count) {
+            for (channel, sample) in self.pending_samples.iter_mut().zip(frame) {
+                channel.push(*sample);
+            }
+        }
+
+        let mut output = vec![];
+        while self.pending_samples[0].len() >= self.inner.input_frames_next() {
+            let chunk_frames_count = self.inner.input_frames_next();
+            let chunk = self
+                .pending_samples
+                .iter_mut()
+                .map(|channel| channel.drain(..chunk_frames_count).collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+
+            let resampled = self.inner.process(&chunk, None)?;
+            for f in 0..resampled[0].len() {
+                output.extend(resampled.iter().map(|channel| channel[f]));
+            }
+        }
+
+        Ok(output)
+    }
+
+    // Same as process(), for interleaved i16 samples in native endianness
+    pub fn process_pcm(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let samples = data
+            .chunks_exact(2)
+            .map(|c| i16::from_ne_bytes([c[0], c[1]]).to_sample::<f32>())
+            .collect::<Vec<_>>();
+
+        Ok(self
+            .process(&samples)?
+            .into_iter()
+            .flat_map(|sample| sample.to_sample::<i16>().to_ne_bytes())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Interleaved stereo, with a sine wave on the left channel and silence on the right one
+    fn stereo_sine(sample_rate: u32, start_frame: usize, frames_count: usize) -> Vec<f32> {
+        (start_frame..start_frame + frames_count)
+            .flat_map(|f| {
+                let phase = f as f32 * 440.0 * std::f32::consts::TAU / sample_rate as f32;
+                [phase.sin() * 0.5, 0.0]
+            })
+            .collect()
+    }
+
+    fn check_frames_ratio(input_sample_rate: u32) {
+        let mut resampler = Resampler::new(input_sample_rate, 48000, 2).unwrap();
+
+        // Calls with sizes that do not match the chunk size
+        let call_frames_count = input_sample_rate as usize / 100 + 7;
+        let mut input_frames_count = 0;
+        let mut output_frames_count = 0;
+        for _ in 0..100 {
+            let input = stereo_sine(input_sample_rate, input_frames_count, call_frames_count);
+            input_frames_count += call_frames_count;
+            output_frames_count += resampler.process(&input).unwrap().len() / 2;
+        }
+
+        // Up to a chunk of input is pending
+        let expected_frames_count = input_frames_count as f64 * 48000.0 / input_sample_rate as f64;
+        let chunk_frames_count = 48000.0 * CHUNK_MS as f64 / 1000.0;
+        let missing_frames_count = expected_frames_count - output_frames_count as f64;
+        assert!(
+            (0.0..=chunk_frames_count).contains(&missing_frames_count),
+            "expected: {expected_frames_count}, output: {output_frames_count}"
+        );
+    }
+
+    #[test]
+    fn test_upsampling_ratio() {
+        check_frames_ratio(44100);
+    }
+
+    #[test]
+    fn test_downsampling_ratio() {
+        check_frames_ratio(96000);
+    }
+
+    #[test]
+    fn test_pending_frames() {
+        let mut resampler = Resampler::new(44100, 48000, 2).unwrap();
+        let chunk_frames_count = 44100 * CHUNK_MS / 1000;
+
+        // Less than a chunk is kept for the next call
+        let input = stereo_sine(44100, 0, chunk_frames_count - 1);
+        assert!(resampler.process(&input).unwrap().is_empty());
+
+        let input = stereo_sine(44100, chunk_frames_count - 1, 1);
+        assert!(!resampler.process(&input).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_interleaving() {
+        let mut resampler = Resampler::new(44100, 48000, 2).unwrap();
+
+        let output = resampler.process(&stereo_sine(44100, 0, 44100)).unwrap();
+
+        // Skips the filter delay
+        let frames = output.chunks_exact(2).skip(SINC_LEN);
+        let left_peak = frames.clone().fold(0.0_f32, |peak, f| peak.max(f[0].abs()));
+        let right_peak = frames.fold(0.0_f32, |peak, f| peak.max(f[1].abs()));
+        assert!((0.45..0.55).contains(&left_peak), "left peak: {left_peak}");
+        assert!(right_peak < 1e-3, "right peak: {right_peak}");
+    }
+}
@@ -1,4 +1,4 @@
-use alvr_audio::Device;
+use alvr_audio::{AudioCodec, AudioDecoder, AudioEncoder, Device};
 use alvr_common::{
     anyhow::{Result, bail},
     parking_lot::Mutex,
@@ -26,6 +26,7 @@ pub fn record_audio_blocking(
     device: &Device,
     channels_count: u16,
     mute: bool,
+    codec: AudioCodec,
 ) -> Result<()> {
     assert_eq!(
         channels_count, 1,
@@ -34,6 +35,8 @@ pub fn record_audio_blocking(
 
     let sample_rate = alvr_audio::input_sample_rate(device)?;
 
+    let mut encoder = AudioEncoder::new(codec, sample_rate, channels_count)?;
+
     let error = Arc::new(Mutex::new(None::<AudioError>));
 
     let (samples_sender, samples_receiver) =
@@ -76,11 +79,7 @@ pub fn record_audio_blocking(
 
     while is_running() && error.lock().is_none() {
         while let Ok(sample_buffer) = samples_receiver.recv_timeout(INPUT_RECV_TIMEOUT) {
-            let mut buffer = sender.get_buffer(&()).unwrap();
-            buffer
-                .get_range_mut(0, sample_buffer.len())
-                .copy_from_slice(&sample_buffer);
-            sender.send(buffer).ok();
+            encoder.send(&mut sender, &sample_buffer).ok();
         }
     }
 
@@ -102,6 +101,7 @@ pub fn play_audio_loop(
     sample_rate: u32,
     config: AudioBufferingConfig,
     receiver: &mut StreamReceiver<()>,
+    codec: AudioCodec,
 ) -> Result<()> {
     assert_eq!(channels_count, 2, "This code only supports stereo output");
 
@@ -111,6 +111,8 @@ pub fn play_audio_loop(
         bail!("Invalid audio sample rate");
     }
 
+    let decoder = AudioDecoder::new(codec, sample_rate, channels_count)?;
+
     let batch_frames_count = sample_rate as usize * config.batch_ms as usize / 1000;
     let average_buffer_frames_count =
         sample_rate as usize * config.average_buffering_ms as usize / 1000;
@@ -166,6 +168,8 @@ pub fn play_audio_loop(
     alvr_audio::receive_samples_loop(
         || is_running() && error.lock().is_none(),
         receiver,
+        decoder,
+        None,
         sample_buffer,
         2,
         batch_frames_count,
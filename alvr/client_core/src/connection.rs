@@ -14,14 +14,17 @@ use alvr_common::{
     wait_rwlock, warn,
 };
 use alvr_packets::{
-    AUDIO, ClientConnectionResult, ClientControlPacket, ClientStatistics, HAPTICS, Haptics,
+    AUDIO, ClientConnectionResult, ClientControlPacket, ClientStatistics,
+    EncryptionHandshakePacket, FEATURE_OPUS_AUDIO, FEATURE_QUIC, FEATURE_SHARD_FEC,
+    FEATURE_SHARD_RETRANSMISSION, FEATURE_STREAM_ENCRYPTION, HAPTICS, Haptics,
+    MAX_AUDIO_PACKET_SIZE, MAX_HAPTICS_PACKET_SIZE, MAX_VIDEO_PACKET_SIZE, ProtocolCapabilities,
     STATISTICS, ServerControlPacket, StreamConfigPacket, TRACKING, TrackingData, VIDEO,
     VideoPacketHeader, VideoStreamingCapabilities, VideoStreamingCapabilitiesExt,
 };
 use alvr_session::{SocketProtocol, settings_schema::Switch};
 use alvr_sockets::{
     ControlSocketSender, KEEPALIVE_INTERVAL, KEEPALIVE_TIMEOUT, PeerType, ProtoControlSocket,
-    StreamSender, StreamSocketBuilder,
+    SessionKeys, StreamSender, StreamSocketBuilder,
 };
 use std::{
     collections::VecDeque,
@@ -45,6 +48,10 @@ const STREAM_STARTING_MESSAGE: &str = "The stream will begin soon\nPlease wait..
 const SERVER_RESTART_MESSAGE: &str = "The streamer is restarting\nPlease wait...";
 const SERVER_DISCONNECTED_MESSAGE: &str = "The streamer has disconnected.";
 const CONNECTION_TIMEOUT_MESSAGE: &str = "Connection timeout.";
+const NOT_PAIRED_MESSAGE: &str = concat!(
+    "The streamer is not paired with this headset.\n",
+    "Remove the device entry on the streamer and trust it again."
+);
 
 const SOCKET_INIT_RETRY_INTERVAL: Duration = Duration::from_millis(500);
 const CONNECTION_RETRY_INTERVAL: Duration = Duration::from_secs(1);
@@ -54,6 +61,7 @@ const STREAMING_RECV_TIMEOUT: Duration = Duration::from_millis(500);
 const MAX_UNREAD_PACKETS: usize = 10; // Applies per stream
 
 pub type DecoderCallback = dyn FnMut(Duration, &[u8]) -> bool + Send;
+pub type VideoPacketCallback = dyn FnMut(&VideoPacketHeader, &[u8]) + Send;
 
 #[derive(Default)]
 pub struct ConnectionContext {
@@ -64,6 +72,7 @@ pub struct ConnectionContext {
     pub statistics_sender: Mutex<Option<StreamSender<ClientStatistics>>>,
     pub statistics_manager: Mutex<Option<StatisticsManager>>,
     pub decoder_callback: Mutex<Option<Box<DecoderCallback>>>,
+    pub video_packet_callback: Mutex<Option<Box<VideoPacketCallback>>>,
     pub global_view_params_queue: Mutex<VecDeque<(Duration, [ViewParams; 2])>>,
     pub velocities_multiplier: RwLock<f32>,
     pub max_prediction: RwLock<Duration>,
@@ -129,8 +138,9 @@ fn connection_pipeline(
 ) -> ConResult {
     dbg_connection!("connection_pipeline: Begin");
 
+    let mut config = Config::load();
+
     let (mut proto_control_socket, server_ip) = {
-        let config = Config::load();
         let announcer_socket = AnnouncerSocket::new(&config.hostname).to_con()?;
         let listener_socket =
             alvr_sockets::get_server_listener(HANDSHAKE_ACTION_TIMEOUT).to_con()?;
@@ -161,6 +171,8 @@ fn connection_pipeline(
     let microphone_sample_rate =
         alvr_audio::input_sample_rate(&alvr_audio::new_input(None).to_con()?).to_con()?;
 
+    let client_handshake_nonce = alvr_sockets::generate_handshake_nonce();
+
     dbg_connection!("connection_pipeline: Send stream capabilities");
     proto_control_socket
         .send(&ClientConnectionResult::ConnectionAccepted {
@@ -181,14 +193,50 @@ fn connection_pipeline(
                     prefer_hdr: capabilities.prefer_hdr,
                     ext_str: String::new(),
                 }
-                .with_ext(VideoStreamingCapabilitiesExt {}),
+                .with_ext(VideoStreamingCapabilitiesExt {
+                    protocol: Some(ProtocolCapabilities::local()),
+                }),
             ),
+            public_key: alvr_sockets::public_key(&config.secret_key),
+            handshake_nonce: client_handshake_nonce,
         })
         .to_con()?;
+
+    let encryption_handshake =
+        proto_control_socket.recv::<EncryptionHandshakePacket>(HANDSHAKE_ACTION_TIMEOUT)?;
+    let session_keys = SessionKeys::derive(
+        &config.secret_key,
+        &encryption_handshake.public_key,
+        &encryption_handshake.handshake_nonce,
+        &client_handshake_nonce,
+        false,
+    )
+    .to_con()?;
+    proto_control_socket.enable_encryption(&session_keys.control);
+
     let config_packet =
         proto_control_socket.recv::<StreamConfigPacket>(HANDSHAKE_ACTION_TIMEOUT)?;
     dbg_connection!("connection_pipeline: stream config received");
 
+    // The streamer derived the same keys, so it owns the key pair it sent. Its key is stored when
+    // it pairs, which happens at the first connection after the client has been trusted
+    let pairing = config_packet.negotiated.ext().is_ok_and(|ext| ext.pairing);
+    if !config
+        .streamer_public_keys
+        .contains(&encryption_handshake.public_key)
+    {
+        if !pairing {
+            warn!("Rejected streamer with unknown key");
+            set_hud_message(&event_queue, NOT_PAIRED_MESSAGE);
+
+            return Ok(());
+        }
+
+        info!("Paired with the streamer");
+        config.add_streamer_public_key(encryption_handshake.public_key);
+        config.store();
+    }
+
     let stream_config = config_packet.to_stream_config().to_con()?;
 
     let streaming_start_event = ClientCoreEvent::StreamingStarted(Box::new(stream_config.clone()));
@@ -229,8 +277,28 @@ fn connection_pipeline(
         }
     }
 
+    let mut negotiated_ext = negotiated_config.ext().ok();
+    if negotiated_ext.as_ref().is_some_and(|ext| ext.spectator) {
+        info!("Connected as spectator");
+    }
+
+    let server_stream_port = negotiated_ext
+        .as_ref()
+        .and_then(|ext| ext.server_stream_port);
+    let server_certificate = negotiated_ext
+        .as_mut()
+        .and_then(|ext| ext.server_certificate.take());
+
+    // The server already picked the common features, these are applied in the same way
+    let negotiated_protocol = negotiated_ext.and_then(|ext| ext.protocol);
+    let supports = |feature| alvr_packets::protocol_supports(negotiated_protocol.as_ref(), feature);
+
     let stream_protocol = if negotiated_config.wired {
         SocketProtocol::Tcp
+    } else if matches!(settings.connection.stream_protocol, SocketProtocol::Quic)
+        && !supports(FEATURE_QUIC)
+    {
+        SocketProtocol::Udp
     } else {
         settings.connection.stream_protocol
     };
@@ -256,20 +324,58 @@ fn connection_pipeline(
     dbg_connection!("connection_pipeline: accept connection");
     let mut stream_socket = stream_socket_builder.accept_from_server(
         server_ip,
-        settings.connection.stream_port,
+        server_stream_port.unwrap_or(settings.connection.stream_port),
+        server_certificate,
         settings.connection.packet_size as _,
+        settings
+            .connection
+            .forward_error_correction
+            .clone()
+            .into_option()
+            .filter(|_| supports(FEATURE_SHARD_FEC)),
+        settings
+            .connection
+            .shard_retransmission
+            .as_option()
+            .filter(|_| supports(FEATURE_SHARD_RETRANSMISSION))
+            .map(|config| {
+                Duration::from_secs_f32(
+                    config.deadline_frames / negotiated_config.refresh_rate_hint,
+                )
+            }),
+        (settings.connection.stream_encryption && supports(FEATURE_STREAM_ENCRYPTION))
+            .then_some(session_keys.stream),
         HANDSHAKE_ACTION_TIMEOUT,
     )?;
+    stream_socket.carry_control(&mut control_sender, &mut control_receiver);
 
     info!("Connected to server");
 
-    let mut video_receiver =
-        stream_socket.subscribe_to_stream::<VideoPacketHeader>(VIDEO, MAX_UNREAD_PACKETS);
-    let mut game_audio_receiver = stream_socket.subscribe_to_stream(AUDIO, MAX_UNREAD_PACKETS);
+    let opus_config = settings
+        .audio
+        .opus
+        .as_option()
+        .filter(|_| supports(FEATURE_OPUS_AUDIO));
+    let game_audio_codec = alvr_audio::AudioCodec::select(
+        opus_config.map(|config| config.game_audio_bitrate_kbps),
+        negotiated_config.game_audio_sample_rate,
+    );
+    let microphone_codec = alvr_audio::AudioCodec::select(
+        opus_config.map(|config| config.microphone_bitrate_kbps),
+        microphone_sample_rate,
+    );
+
+    let mut video_receiver = stream_socket
+        .subscribe_to_stream::<VideoPacketHeader>(VIDEO, MAX_UNREAD_PACKETS, MAX_VIDEO_PACKET_SIZE)
+        .to_con()?;
+    let mut game_audio_receiver = stream_socket
+        .subscribe_to_stream(AUDIO, MAX_UNREAD_PACKETS, MAX_AUDIO_PACKET_SIZE)
+        .to_con()?;
     let tracking_sender = stream_socket.request_stream(TRACKING);
-    let mut haptics_receiver =
-        stream_socket.subscribe_to_stream::<Haptics>(HAPTICS, MAX_UNREAD_PACKETS);
-    let statistics_sender = stream_socket.request_stream(STATISTICS);
+    let mut haptics_receiver = stream_socket
+        .subscribe_to_stream::<Haptics>(HAPTICS, MAX_UNREAD_PACKETS, MAX_HAPTICS_PACKET_SIZE)
+        .to_con()?;
+    let statistics_sender = stream_socket.request_reliable_stream(STATISTICS);
 
     let video_receive_thread = thread::spawn({
         let ctx = Arc::clone(&ctx);
@@ -287,6 +393,11 @@ fn connection_pipeline(
 
                 if let Some(stats) = &mut *ctx.statistics_manager.lock() {
                     stats.report_video_packet_received(header.timestamp);
+                    stats.report_video_stream_statistics(video_receiver.statistics());
+                }
+
+                if let Some(callback) = &mut *ctx.video_packet_callback.lock() {
+                    callback(&header, nal);
                 }
 
                 if header.is_idr {
@@ -350,6 +461,7 @@ fn connection_pipeline(
                         negotiated_config.game_audio_sample_rate,
                         config.buffering.clone(),
                         &mut game_audio_receiver,
+                        game_audio_codec,
                     ));
                 }
             }
@@ -374,6 +486,7 @@ fn connection_pipeline(
                         &device,
                         1,
                         false,
+                        microphone_codec,
                     ) {
                         Ok(()) => break,
                         Err(e) => {
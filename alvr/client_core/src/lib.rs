@@ -27,7 +27,7 @@ use alvr_packets::{
     BatteryInfo, ButtonEntry, ClientControlPacket, RealTimeConfig, StreamConfig, TrackingData,
 };
 use alvr_session::CodecType;
-use connection::{ConnectionContext, DecoderCallback};
+use connection::{ConnectionContext, DecoderCallback, VideoPacketCallback};
 use std::{
     collections::{HashSet, VecDeque},
     sync::Arc,
@@ -83,10 +83,18 @@ impl ClientCoreContext {
     pub fn new(capabilities: ClientCapabilities) -> Self {
         dbg_client_core!("Create");
 
-        // Make sure to reset config in case of version compat mismatch.
-        if Config::load().protocol_id != alvr_common::protocol_id() {
+        // Make sure to reset config in case of version compat mismatch. Configs of compatible
+        // versions are kept, so the client stays paired with its streamers.
+        let mut config = Config::load();
+        if config.protocol_version < alvr_common::MIN_PROTOCOL_VERSION {
             // NB: Config::default() sets the current protocol ID
             Config::default().store();
+        } else if config.protocol_id != alvr_common::protocol_id()
+            || config.protocol_version != alvr_common::PROTOCOL_VERSION
+        {
+            config.protocol_id = alvr_common::protocol_id();
+            config.protocol_version = alvr_common::PROTOCOL_VERSION;
+            config.store();
         }
 
         #[cfg(target_os = "android")]
@@ -246,6 +254,13 @@ impl ClientCoreContext {
         }
     }
 
+    /// The callback receives every video packet, before any packet is dropped. Used for debugging
+    pub fn set_video_packet_callback(&self, callback: Option<Box<VideoPacketCallback>>) {
+        dbg_client_core!("set_video_packet_callback");
+
+        *self.connection_context.video_packet_callback.lock() = callback;
+    }
+
     pub fn report_frame_decoded(&self, timestamp: Duration) {
         dbg_client_core!("report_frame_decoded");
 
@@ -22,17 +22,28 @@ impl AnnouncerSocket {
             bail!("IP is unspecified");
         }
 
-        self.daemon.register(ServiceInfo::new(
-            alvr_sockets::MDNS_SERVICE_TYPE,
-            &format!("alvr{}", rand::random::<u16>()),
-            &self.hostname,
-            local_ip,
-            5353,
-            &[(
-                alvr_sockets::MDNS_PROTOCOL_KEY,
-                alvr_common::protocol_id().as_str(),
-            )][..],
-        )?)?;
+        // Also announce the addresses of the other interfaces and IP families. The server picks
+        // the most suitable one
+        self.daemon.register(
+            ServiceInfo::new(
+                alvr_sockets::MDNS_SERVICE_TYPE,
+                &format!("alvr{}", rand::random::<u16>()),
+                &self.hostname,
+                local_ip,
+                5353,
+                &[
+                    (
+                        alvr_sockets::MDNS_PROTOCOL_KEY,
+                        alvr_common::protocol_id().as_str(),
+                    ),
+                    (
+                        alvr_sockets::MDNS_PROTOCOL_VERSIONS_KEY,
+                        alvr_common::protocol_versions_string().as_str(),
+                    ),
+                ][..],
+            )?
+            .enable_addr_auto(),
+        )?;
 
         Ok(())
     }
@@ -1,5 +1,6 @@
 use alvr_common::SlidingWindowAverage;
 use alvr_packets::ClientStatistics;
+use alvr_sockets::StreamStatistics;
 use std::{
     collections::VecDeque,
     time::{Duration, Instant},
@@ -16,6 +17,7 @@ pub struct StatisticsManager {
     max_history_size: usize,
     prev_vsync: Instant,
     total_pipeline_latency_average: SlidingWindowAverage<Duration>,
+    video_stream_statistics: StreamStatistics,
 }
 
 impl StatisticsManager {
@@ -28,6 +30,7 @@ impl StatisticsManager {
                 Duration::ZERO,
                 max_history_size,
             ),
+            video_stream_statistics: StreamStatistics::default(),
         }
     }
 
@@ -63,6 +66,10 @@ impl StatisticsManager {
         }
     }
 
+    pub fn report_video_stream_statistics(&mut self, statistics: StreamStatistics) {
+        self.video_stream_statistics = statistics;
+    }
+
     pub fn report_frame_decoded(&mut self, target_timestamp: Duration) {
         if let Some(frame) = self
             .history_buffer
@@ -117,7 +124,18 @@ impl StatisticsManager {
         self.history_buffer
             .iter()
             .find(|frame| frame.client_stats.target_timestamp == target_timestamp)
-            .map(|frame| frame.client_stats.clone())
+            .map(|frame| {
+                let video = &self.video_stream_statistics;
+
+                ClientStatistics {
+                    video_shards_received: video.shards_received,
+                    video_shards_lost: video.shards_lost,
+                    video_packets_reconstructed: video.packets_reconstructed,
+                    video_packets_discarded: video.packets_discarded,
+                    video_jitter: video.jitter,
+                    ..frame.client_stats.clone()
+                }
+            })
     }
 
     // latency used for head prediction
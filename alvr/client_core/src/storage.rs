@@ -4,6 +4,8 @@ use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::{fs, path::PathBuf};
 
+const MAX_PAIRED_STREAMERS: usize = 16;
+
 fn config_path() -> PathBuf {
     app_dirs2::app_root(
         AppDataType::UserConfig,
@@ -20,6 +22,17 @@ fn config_path() -> PathBuf {
 pub struct Config {
     pub hostname: String,
     pub protocol_id: String,
+    // Zero for configs stored by versions without protocol negotiation
+    #[serde(default)]
+    pub protocol_version: u32,
+    // Identifies this client when connecting to a streamer it has been paired with. Configs stored
+    // by versions without encryption get a new key
+    #[serde(default = "alvr_sockets::generate_secret_key")]
+    pub secret_key: [u8; 32],
+    // Keys of the streamers this client has been paired with, the most recent last. Streamers with
+    // other keys are rejected unless they pair again
+    #[serde(default)]
+    pub streamer_public_keys: Vec<[u8; 32]>,
 }
 
 impl Default for Config {
@@ -35,6 +48,9 @@ impl Default for Config {
                 rng.random_range(0..10),
             ),
             protocol_id: alvr_common::protocol_id(),
+            protocol_version: alvr_common::PROTOCOL_VERSION,
+            secret_key: alvr_sockets::generate_secret_key(),
+            streamer_public_keys: vec![],
         }
     }
 }
@@ -44,7 +60,14 @@ impl Config {
         if let Ok(config_string) = fs::read_to_string(config_path()) {
             // Failure happens if the Config signature changed between versions.
             // todo: recover data from mismatched Config signature. low priority
-            if let Ok(config) = serde_json::from_str(&config_string) {
+            if let Ok(config_json) = serde_json::from_str::<serde_json::Value>(&config_string)
+                && let Ok(config) = serde_json::from_value::<Config>(config_json.clone())
+            {
+                // The generated key must be kept
+                if config_json.get("secret_key").is_none() {
+                    config.store();
+                }
+
                 return config;
             } else {
                 info!("Error parsing ALVR config. Using default");
@@ -59,6 +82,16 @@ impl Config {
         config
     }
 
+    // Pairing again with a streamer creates a new key. The old key is dropped only when the list is
+    // full, since there is no way to tell which streamer it belonged to
+    pub fn add_streamer_public_key(&mut self, key: [u8; 32]) {
+        self.streamer_public_keys.retain(|k| *k != key);
+        if self.streamer_public_keys.len() >= MAX_PAIRED_STREAMERS {
+            self.streamer_public_keys.remove(0);
+        }
+        self.streamer_public_keys.push(key);
+    }
+
     pub fn store(&self) {
         let config_string = serde_json::to_string(self).unwrap();
         if let Err(e) = fs::write(config_path(), config_string) {
@@ -1,5 +1,7 @@
 #[cfg(target_os = "android")]
 mod android;
+#[cfg(all(not(target_os = "android"), feature = "software-decoder"))]
+mod software;
 
 use alvr_common::anyhow::Result;
 use alvr_session::{CodecType, MediacodecProperty};
@@ -15,9 +17,20 @@ pub struct VideoDecoderConfig {
     pub config_buffer: Vec<u8>,
 }
 
+// Decoded frame in CPU memory
+#[derive(Clone)]
+pub struct CpuFrame {
+    pub width: u32,
+    pub height: u32,
+    // RGBA8 pixels, row by row without padding
+    pub data: Vec<u8>,
+}
+
 pub struct VideoDecoderSink {
     #[cfg(target_os = "android")]
     inner: android::VideoDecoderSink,
+    #[cfg(all(not(target_os = "android"), feature = "software-decoder"))]
+    inner: software::VideoDecoderSink,
 }
 
 impl VideoDecoderSink {
@@ -28,7 +41,11 @@ impl VideoDecoderSink {
         {
             alvr_common::show_err(self.inner.push_frame_nal(timestamp, nal)).unwrap_or(false)
         }
-        #[cfg(not(target_os = "android"))]
+        #[cfg(all(not(target_os = "android"), feature = "software-decoder"))]
+        {
+            self.inner.push_frame_nal(timestamp, nal)
+        }
+        #[cfg(all(not(target_os = "android"), not(feature = "software-decoder")))]
         false
     }
 }
@@ -36,6 +53,8 @@ impl VideoDecoderSink {
 pub struct VideoDecoderSource {
     #[cfg(target_os = "android")]
     inner: android::VideoDecoderSource,
+    #[cfg(all(not(target_os = "android"), feature = "software-decoder"))]
+    inner: software::VideoDecoderSource,
 }
 
 impl VideoDecoderSource {
@@ -48,6 +67,17 @@ impl VideoDecoderSource {
         #[cfg(not(target_os = "android"))]
         None
     }
+
+    /// If a frame is available, return the timestamp and the decoded frame. Only supported by the
+    /// software decoder.
+    pub fn get_cpu_frame(&mut self) -> Option<(Duration, &CpuFrame)> {
+        #[cfg(all(not(target_os = "android"), feature = "software-decoder"))]
+        {
+            self.inner.dequeue_frame()
+        }
+        #[cfg(not(all(not(target_os = "android"), feature = "software-decoder")))]
+        None
+    }
 }
 
 // report_frame_decoded: (target_timestamp: Duration) -> ()
@@ -70,6 +100,15 @@ pub fn create_decoder(
             VideoDecoderSource { inner: source },
         )
     }
-    #[cfg(not(target_os = "android"))]
+    #[cfg(all(not(target_os = "android"), feature = "software-decoder"))]
+    {
+        let (sink, source) = software::video_decoder_split(config, report_frame_decoded);
+
+        (
+            VideoDecoderSink { inner: sink },
+            VideoDecoderSource { inner: source },
+        )
+    }
+    #[cfg(all(not(target_os = "android"), not(feature = "software-decoder")))]
     (VideoDecoderSink {}, VideoDecoderSource {})
 }
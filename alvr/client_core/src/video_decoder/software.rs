@@ -0,0 +1,227 @@
+use super::{CpuFrame, VideoDecoderConfig};
+use alvr_common::{
+    RelaxedAtomic,
+    anyhow::{Context, Result},
+    parking_lot::Mutex,
+    warn,
+};
+use alvr_session::CodecType;
+use ffmpeg_next::{
+    self as ffmpeg, Packet, codec, decoder, format::Pixel, frame, software::scaling,
+};
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc,
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+const MAX_QUEUED_NALS: usize = 8;
+
+fn cpu_frame_from_rgba(rgba: &frame::Video) -> CpuFrame {
+    let width = rgba.width() as usize;
+    let height = rgba.height() as usize;
+    let stride = rgba.stride(0);
+
+    let mut data = Vec::with_capacity(width * height * 4);
+    for row in rgba.data(0).chunks(stride).take(height) {
+        data.extend_from_slice(&row[..width * 4]);
+    }
+
+    CpuFrame {
+        width: width as u32,
+        height: height as u32,
+        data,
+    }
+}
+
+// Decoding happens in a separate thread, to not block the thread receiving the video packets
+pub struct VideoDecoderSink {
+    nal_sender: SyncSender<(Duration, Vec<u8>)>,
+}
+
+impl VideoDecoderSink {
+    // Returns false if the decoder is saturated
+    pub fn push_frame_nal(&mut self, timestamp: Duration, data: &[u8]) -> bool {
+        self.nal_sender.try_send((timestamp, data.to_vec())).is_ok()
+    }
+}
+
+pub struct VideoDecoderSource {
+    running: Arc<RelaxedAtomic>,
+    decoder_thread: Option<JoinHandle<()>>,
+    frame_queue: Arc<Mutex<VecDeque<(Duration, CpuFrame)>>>,
+    current_frame: Option<(Duration, CpuFrame)>,
+    config: VideoDecoderConfig,
+    buffering_running_average: f32,
+}
+
+impl VideoDecoderSource {
+    // The returned frame is valid until this function is called again
+    pub fn dequeue_frame(&mut self) -> Option<(Duration, &CpuFrame)> {
+        let mut frame_queue_lock = self.frame_queue.lock();
+
+        // use running average to give more weight to recent samples
+        self.buffering_running_average = self.buffering_running_average
+            * self.config.buffering_history_weight
+            + frame_queue_lock.len() as f32 * (1. - self.config.buffering_history_weight);
+        if self.buffering_running_average > self.config.max_buffering_frames {
+            frame_queue_lock.pop_front();
+        }
+
+        self.current_frame = frame_queue_lock.pop_front();
+        drop(frame_queue_lock);
+
+        self.current_frame
+            .as_ref()
+            .map(|(timestamp, frame)| (*timestamp, frame))
+    }
+}
+
+impl Drop for VideoDecoderSource {
+    fn drop(&mut self) {
+        self.running.set(false);
+
+        self.decoder_thread.take().map(|t| t.join());
+    }
+}
+
+fn create_ffmpeg_decoder(codec_type: CodecType) -> Result<decoder::Video> {
+    ffmpeg::init()?;
+
+    let codec = match codec_type {
+        CodecType::H264 => decoder::find(codec::Id::H264),
+        CodecType::Hevc => decoder::find(codec::Id::HEVC),
+        CodecType::AV1 => decoder::find_by_name("libdav1d"),
+    }
+    .with_context(|| format!("FFmpeg was built without a {codec_type:?} decoder"))?;
+
+    let mut context = codec::Context::new_with_codec(codec);
+    // Unlike frame threading, slice threading does not add latency
+    context.set_threading(codec::threading::Config::kind(
+        codec::threading::Type::Slice,
+    ));
+
+    Ok(context.decoder().open_as(codec)?.video()?)
+}
+
+fn decoder_lifecycle(
+    config: VideoDecoderConfig,
+    nal_receiver: Receiver<(Duration, Vec<u8>)>,
+    frame_result_callback: &impl Fn(Result<Duration>),
+    running: Arc<RelaxedAtomic>,
+    frame_queue: Arc<Mutex<VecDeque<(Duration, CpuFrame)>>>,
+) -> Result<()> {
+    // 2x: keep the target buffering in the middle of the max amount of queuable frames
+    let available_buffering_frames = (2. * config.max_buffering_frames).ceil() as usize;
+
+    let mut decoder = create_ffmpeg_decoder(config.codec)?;
+    let mut scaler = None::<scaling::Context>;
+    let mut decoded_frame = frame::Video::empty();
+    let mut rgba_frame = frame::Video::empty();
+
+    // The parameter sets are passed in-band, together with the first frame
+    let mut config_buffer = Some(config.config_buffer);
+
+    while running.value() {
+        let (timestamp, nal) = match nal_receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(pair) => pair,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        let mut packet = if let Some(mut buffer) = config_buffer.take() {
+            buffer.extend_from_slice(&nal);
+            Packet::copy(&buffer)
+        } else {
+            Packet::copy(&nal)
+        };
+        // Nanoseconds are used to compare the timestamps with full precision
+        packet.set_pts(Some(timestamp.as_nanos() as i64));
+
+        if let Err(e) = decoder.send_packet(&packet) {
+            // Corrupted frames are expected when the network drops packets
+            warn!("Software decoder error: {e}");
+
+            continue;
+        }
+
+        while decoder.receive_frame(&mut decoded_frame).is_ok() {
+            let timestamp = Duration::from_nanos(decoded_frame.pts().unwrap_or_default() as u64);
+
+            // The scaler and the output frame must be recreated if the resolution changes
+            if scaler.as_ref().is_none_or(|scaler| {
+                scaler.input().format != decoded_frame.format()
+                    || scaler.input().width != decoded_frame.width()
+                    || scaler.input().height != decoded_frame.height()
+            }) {
+                scaler = Some(scaling::Context::get(
+                    decoded_frame.format(),
+                    decoded_frame.width(),
+                    decoded_frame.height(),
+                    Pixel::RGBA,
+                    decoded_frame.width(),
+                    decoded_frame.height(),
+                    scaling::Flags::BILINEAR,
+                )?);
+                rgba_frame = frame::Video::empty();
+            }
+            if let Some(scaler) = &mut scaler {
+                scaler.run(&decoded_frame, &mut rgba_frame)?;
+            }
+
+            frame_result_callback(Ok(timestamp));
+
+            let mut frame_queue_lock = frame_queue.lock();
+            if frame_queue_lock.len() > available_buffering_frames {
+                warn!("Video frame queue overflow!");
+                frame_queue_lock.pop_front();
+            }
+            frame_queue_lock.push_back((timestamp, cpu_frame_from_rgba(&rgba_frame)));
+        }
+    }
+
+    Ok(())
+}
+
+// Create a sink/source pair
+pub fn video_decoder_split(
+    config: VideoDecoderConfig,
+    frame_result_callback: impl Fn(Result<Duration>) + Send + Sync + 'static,
+) -> (VideoDecoderSink, VideoDecoderSource) {
+    let running = Arc::new(RelaxedAtomic::new(true));
+    let frame_queue = Arc::new(Mutex::new(VecDeque::new()));
+    let (nal_sender, nal_receiver) = mpsc::sync_channel(MAX_QUEUED_NALS);
+
+    let decoder_thread = thread::spawn({
+        let config = config.clone();
+        let running = Arc::clone(&running);
+        let frame_queue = Arc::clone(&frame_queue);
+        move || {
+            if let Err(e) = decoder_lifecycle(
+                config,
+                nal_receiver,
+                &frame_result_callback,
+                running,
+                frame_queue,
+            ) {
+                frame_result_callback(Err(e));
+            }
+        }
+    });
+
+    let sink = VideoDecoderSink { nal_sender };
+    let source = VideoDecoderSource {
+        running,
+        decoder_thread: Some(decoder_thread),
+        frame_queue,
+        current_frame: None,
+        config,
+        buffering_running_average: 0.0,
+    };
+
+    (sink, source)
+}
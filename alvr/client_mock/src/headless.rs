@@ -0,0 +1,287 @@
+// Headless mode: the client is driven by a scenario file instead of the window, and a report of
+// what the client observed is returned at the end. Decoding is emulated with a fixed delay.
+
+use crate::{
+    scenario::{self, Scenario, ScenarioEvent, Timings},
+    video_dump,
+};
+use alvr_client_core::{ClientCapabilities, ClientCoreContext, ClientCoreEvent};
+use alvr_common::{
+    CONTROLLER_PROFILE_INFO, DeviceMotion, HAND_LEFT_ID, HAND_RIGHT_ID, HEAD_ID, Pose,
+    RelaxedAtomic, ViewParams,
+    glam::{Quat, Vec3},
+    hash_string,
+    parking_lot::Mutex,
+};
+use alvr_packets::{FaceData, TrackingData};
+use alvr_session::CodecType;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+// Rate of the main loop while not streaming, to apply the scenario events on time
+const IDLE_FRAMERATE: f32 = 10.0;
+
+const DEFAULT_HEAD_POSE: Pose = Pose {
+    orientation: Quat::IDENTITY,
+    position: Vec3::new(0.0, 1.5, 0.0),
+};
+
+#[derive(Serialize, Default)]
+pub struct Report {
+    pub streaming_sessions: usize,
+    pub streaming_time_s: f32,
+    pub decoder_configs: usize,
+    pub codec: Option<CodecType>,
+    pub frames_decoded: usize,
+    // Frames shown for the first time by the emulated compositor
+    pub frames_presented: usize,
+    pub average_fps: f32,
+    pub haptics_events: usize,
+    pub last_hud_message: String,
+}
+
+fn device_motion(pose: Pose) -> DeviceMotion {
+    DeviceMotion {
+        pose,
+        linear_velocity: Vec3::ZERO,
+        angular_velocity: Vec3::ZERO,
+    }
+}
+
+fn tracking_thread(
+    context: Arc<ClientCoreContext>,
+    streaming: Arc<RelaxedAtomic>,
+    fps: f32,
+    scenario: Arc<Scenario>,
+    start_instant: Instant,
+) {
+    context.send_view_params([ViewParams::DUMMY; 2]);
+
+    let mut loop_deadline = Instant::now();
+    while streaming.value() {
+        let time_s = start_instant.elapsed().as_secs_f32();
+
+        let mut device_motions = vec![(
+            *HEAD_ID,
+            device_motion(
+                scenario::sample_pose(&scenario.head, time_s).unwrap_or(DEFAULT_HEAD_POSE),
+            ),
+        )];
+        if let Some(pose) = scenario::sample_pose(&scenario.left_controller, time_s) {
+            device_motions.push((*HAND_LEFT_ID, device_motion(pose)));
+        }
+        if let Some(pose) = scenario::sample_pose(&scenario.right_controller, time_s) {
+            device_motions.push((*HAND_RIGHT_ID, device_motion(pose)));
+        }
+
+        context.send_tracking(TrackingData {
+            poll_timestamp: start_instant.elapsed(),
+            device_motions,
+            hand_skeletons: [
+                scenario::sample_hand(&scenario.left_hand, time_s),
+                scenario::sample_hand(&scenario.right_hand, time_s),
+            ],
+            face: FaceData::default(),
+            body: None,
+        });
+
+        loop_deadline += Duration::from_secs_f32(1.0 / fps / 3.0);
+        thread::sleep(loop_deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+fn send_interaction_profiles(context: &ClientCoreContext, profile_path: &str) {
+    let profile_id = hash_string(profile_path);
+    let input_ids = CONTROLLER_PROFILE_INFO
+        .get(&profile_id)
+        .map(|info| info.button_set.clone())
+        .unwrap_or_default();
+
+    for device_id in [*HAND_LEFT_ID, *HAND_RIGHT_ID] {
+        context.send_active_interaction_profile(device_id, profile_id, input_ids.clone());
+    }
+}
+
+fn apply_event(context: &ClientCoreContext, event: ScenarioEvent, timings: &mut Timings) {
+    match event {
+        ScenarioEvent::Buttons { entries } => {
+            context.send_buttons(entries.iter().map(|button| button.to_entry()).collect());
+        }
+        ScenarioEvent::Battery {
+            device_path,
+            gauge_value,
+            is_plugged,
+        } => context.send_battery(hash_string(&device_path), gauge_value, is_plugged),
+        ScenarioEvent::Playspace { area } => context.send_playspace(area),
+        ScenarioEvent::Timings {
+            decode_ms,
+            compositor_ms,
+            vsync_ms,
+        } => {
+            timings.decode_ms = decode_ms.unwrap_or(timings.decode_ms);
+            timings.compositor_ms = compositor_ms.unwrap_or(timings.compositor_ms);
+            timings.vsync_ms = vsync_ms.unwrap_or(timings.vsync_ms);
+        }
+        ScenarioEvent::Disconnect => context.pause(),
+        ScenarioEvent::Reconnect => context.resume(),
+    }
+}
+
+pub fn run(
+    capabilities: ClientCapabilities,
+    scenario: Scenario,
+    maybe_dump_dir: Option<PathBuf>,
+) -> Report {
+    let context = Arc::new(ClientCoreContext::new(capabilities));
+    let scenario = Arc::new(scenario);
+    let mut timings = scenario.timings;
+    let mut pending_events = scenario.events.iter().cloned().collect::<VecDeque<_>>();
+
+    let streaming = Arc::new(RelaxedAtomic::new(false));
+    let mut maybe_tracking_thread = None;
+    let mut streaming_start = None;
+    let mut got_decoder_config = false;
+    let mut fps = IDLE_FRAMERATE;
+
+    // (receive instant, timestamp) of the frames waiting for the emulated decoder
+    let decoder_queue = Arc::new(Mutex::new(VecDeque::<(Instant, Duration)>::new()));
+    let mut current_frame_timestamp = Duration::ZERO;
+    let mut last_presented_timestamp = Duration::ZERO;
+
+    let mut report = Report::default();
+
+    context.resume();
+
+    let start_instant = Instant::now();
+    let mut deadline = start_instant;
+    while start_instant.elapsed() < scenario.duration() {
+        let time_s = start_instant.elapsed().as_secs_f32();
+        while let Some(timed_event) = pending_events.front()
+            && timed_event.time_s <= time_s
+        {
+            let timed_event = pending_events.pop_front().unwrap();
+            apply_event(&context, timed_event.event, &mut timings);
+        }
+
+        while let Some(event) = context.poll_event() {
+            match event {
+                ClientCoreEvent::UpdateHudMessage(message) => {
+                    report.last_hud_message = message;
+                }
+                ClientCoreEvent::StreamingStarted(config) => {
+                    fps = config.negotiated_config.refresh_rate_hint;
+                    report.streaming_sessions += 1;
+                    streaming_start = Some(Instant::now());
+
+                    send_interaction_profiles(&context, &scenario.interaction_profile);
+
+                    streaming.set(true);
+
+                    let context = Arc::clone(&context);
+                    let streaming = Arc::clone(&streaming);
+                    let scenario = Arc::clone(&scenario);
+                    maybe_tracking_thread = Some(thread::spawn(move || {
+                        tracking_thread(context, streaming, fps, scenario, start_instant)
+                    }));
+                }
+                ClientCoreEvent::StreamingStopped => {
+                    streaming.set(false);
+                    got_decoder_config = false;
+                    decoder_queue.lock().clear();
+                    context.set_video_packet_callback(None);
+
+                    if let Some(thread) = maybe_tracking_thread.take() {
+                        thread.join().ok();
+                    }
+                    if let Some(start) = streaming_start.take() {
+                        report.streaming_time_s += start.elapsed().as_secs_f32();
+                    }
+
+                    fps = IDLE_FRAMERATE;
+                }
+                ClientCoreEvent::DecoderConfig { codec, config_nal } => {
+                    if !got_decoder_config {
+                        got_decoder_config = true;
+                        report.decoder_configs += 1;
+                        report.codec = Some(codec);
+
+                        if let Some(dir) = &maybe_dump_dir {
+                            video_dump::start(
+                                &context,
+                                dir,
+                                report.streaming_sessions,
+                                codec,
+                                &config_nal,
+                            );
+                        }
+
+                        let decoder_queue = Arc::clone(&decoder_queue);
+                        context.set_decoder_input_callback(Box::new(move |timestamp, _| {
+                            decoder_queue.lock().push_back((Instant::now(), timestamp));
+
+                            true
+                        }));
+                    }
+                }
+                ClientCoreEvent::Haptics { .. } => report.haptics_events += 1,
+                ClientCoreEvent::RealTimeConfig(_) => (),
+            }
+        }
+
+        thread::sleep(Duration::from_millis(3));
+
+        {
+            let decode_delay = Duration::from_millis(timings.decode_ms);
+            let decoder_queue_lock = &mut *decoder_queue.lock();
+            while let Some((receive_instant, timestamp)) = decoder_queue_lock.front().copied()
+                && receive_instant.elapsed() >= decode_delay
+            {
+                decoder_queue_lock.pop_front();
+
+                context.report_frame_decoded(timestamp);
+                current_frame_timestamp = timestamp;
+                report.frames_decoded += 1;
+            }
+        }
+
+        context.report_compositor_start(current_frame_timestamp);
+
+        thread::sleep(Duration::from_millis(timings.compositor_ms));
+
+        context.report_submit(
+            current_frame_timestamp,
+            Duration::from_millis(timings.vsync_ms),
+        );
+
+        if current_frame_timestamp != last_presented_timestamp {
+            last_presented_timestamp = current_frame_timestamp;
+            report.frames_presented += 1;
+        }
+
+        deadline += Duration::from_secs_f32(1.0 / fps);
+        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+    }
+
+    streaming.set(false);
+    if let Some(thread) = maybe_tracking_thread {
+        thread.join().ok();
+    }
+    if let Some(start) = streaming_start {
+        report.streaming_time_s += start.elapsed().as_secs_f32();
+    }
+
+    if report.streaming_time_s > 0.0 {
+        report.average_fps = report.frames_presented as f32 / report.streaming_time_s;
+    }
+
+    context.pause();
+
+    // client_core_context destroy is called here on drop
+    report
+}
@@ -1,17 +1,34 @@
-use alvr_client_core::{ClientCapabilities, ClientCoreContext, ClientCoreEvent};
+mod headless;
+mod scenario;
+mod video_dump;
+
+use alvr_client_core::{
+    ClientCapabilities, ClientCoreContext, ClientCoreEvent,
+    video_decoder::{self, CpuFrame, VideoDecoderConfig},
+};
 use alvr_common::{
     DeviceMotion, HEAD_ID, Pose, RelaxedAtomic, ViewParams,
+    anyhow::Result,
+    error,
     glam::{Quat, UVec2, Vec3},
-    parking_lot::RwLock,
+    parking_lot::{Mutex, RwLock},
 };
 use alvr_packets::{FaceData, TrackingData};
 use alvr_session::CodecType;
 use eframe::{
     Frame, NativeOptions,
-    egui::{CentralPanel, Context, RichText, Slider, ViewportBuilder},
+    egui::{
+        Align2, CentralPanel, Color32, ColorImage, Context, FontId, Image, Rect, RichText, Slider,
+        TextureHandle, TextureOptions, ViewportBuilder, load::SizedTexture, pos2, vec2,
+    },
 };
+use pico_args::Arguments;
+use scenario::Scenario;
 use std::{
     f32::consts::{FRAC_PI_2, PI},
+    fs,
+    path::{Path, PathBuf},
+    process,
     sync::{
         Arc,
         mpsc::{self, TryRecvError},
@@ -20,6 +37,23 @@ use std::{
     time::{Duration, Instant},
 };
 
+const HELP_STR: &str = r#"
+alvr_client_mock
+Client without a headset, used to test the streamer.
+
+USAGE:
+    alvr_client_mock [FLAGS]
+
+FLAGS:
+    --help                  Print this text
+    --headless <SCENARIO>   Run without window, driven by a TOML or JSON scenario file. A JSON
+                            report is printed at the end. The exit code is 1 if the client never
+                            started streaming
+    --report <PATH>         Also write the report of the headless mode to this file
+    --dump-video <DIR>      Save the received video stream and the header of each packet to this
+                            directory, one stream file for each streaming session
+"#;
+
 #[derive(Clone, PartialEq)]
 struct WindowInput {
     height: f32,
@@ -68,23 +102,49 @@ impl Default for WindowOutput {
     }
 }
 
+// Frames are big, they are shared separately from WindowOutput
+struct WindowFrame {
+    frame: CpuFrame,
+    view_params: [ViewParams; 2],
+}
+
+fn view_params_text(params: &ViewParams) -> String {
+    format!(
+        "Position: {:.3}\nOrientation: {:.3}\nFov: {:.1} {:.1} {:.1} {:.1}",
+        params.pose.position,
+        params.pose.orientation,
+        params.fov.left.to_degrees(),
+        params.fov.right.to_degrees(),
+        params.fov.up.to_degrees(),
+        params.fov.down.to_degrees(),
+    )
+}
+
 pub struct Window {
     input: WindowInput,
     input_sender: mpsc::Sender<WindowInput>,
     output: WindowOutput,
     output_receiver: mpsc::Receiver<WindowOutput>,
+    // Filled by the client thread, emptied by the window
+    frame_slot: Arc<Mutex<Option<WindowFrame>>>,
+    video_texture: Option<TextureHandle>,
+    view_params: [ViewParams; 2],
 }
 
 impl Window {
     fn new(
         input_sender: mpsc::Sender<WindowInput>,
         output_receiver: mpsc::Receiver<WindowOutput>,
+        frame_slot: Arc<Mutex<Option<WindowFrame>>>,
     ) -> Self {
         Self {
             input: WindowInput::default(),
             input_sender,
             output: WindowOutput::default(),
             output_receiver,
+            frame_slot,
+            video_texture: None,
+            view_params: [ViewParams::DUMMY; 2],
         }
     }
 }
@@ -95,6 +155,20 @@ impl eframe::App for Window {
             self.output = output;
         }
 
+        if let Some(WindowFrame { frame, view_params }) = self.frame_slot.lock().take() {
+            let image = ColorImage::from_rgba_unmultiplied(
+                [frame.width as usize, frame.height as usize],
+                &frame.data,
+            );
+            if let Some(texture) = &mut self.video_texture {
+                texture.set(image, TextureOptions::LINEAR);
+            } else {
+                self.video_texture =
+                    Some(context.load_texture("video", image, TextureOptions::LINEAR));
+            }
+            self.view_params = view_params;
+        }
+
         let mut input = self.input.clone();
 
         CentralPanel::default().show(context, |ui| {
@@ -126,6 +200,35 @@ impl eframe::App for Window {
                 &mut input.use_random_orientation,
                 "Use randomized orientation offset",
             );
+            ui.add_space(10.0);
+            if let Some(texture) = &self.video_texture {
+                // The left and right views are side by side in the frame
+                let view_width = (ui.available_width() - ui.spacing().item_spacing.x) / 2.0;
+                let size = vec2(
+                    view_width,
+                    view_width * texture.size_vec2().y / (texture.size_vec2().x / 2.0),
+                );
+                ui.horizontal(|ui| {
+                    for (idx, params) in self.view_params.iter().enumerate() {
+                        let uv = Rect::from_min_max(
+                            pos2(idx as f32 / 2.0, 0.0),
+                            pos2((idx + 1) as f32 / 2.0, 1.0),
+                        );
+                        let rect = ui
+                            .add(Image::new(SizedTexture::new(texture.id(), size)).uv(uv))
+                            .rect;
+                        ui.painter().text(
+                            rect.left_top() + vec2(5.0, 5.0),
+                            Align2::LEFT_TOP,
+                            view_params_text(params),
+                            FontId::monospace(11.0),
+                            Color32::YELLOW,
+                        );
+                    }
+                });
+            } else {
+                ui.label("No decoded frames. Showing frames requires the software-decoder feature");
+            }
         });
 
         if input != self.input {
@@ -185,11 +288,8 @@ fn tracking_thread(
     }
 }
 
-fn client_thread(
-    output_sender: mpsc::Sender<WindowOutput>,
-    input_receiver: mpsc::Receiver<WindowInput>,
-) {
-    let capabilities = ClientCapabilities {
+fn capabilities() -> ClientCapabilities {
+    ClientCapabilities {
         default_view_resolution: UVec2::new(1920, 1832),
         refresh_rates: vec![60.0, 72.0, 80.0, 90.0, 120.0],
         foveated_encoding: false,
@@ -200,14 +300,25 @@ fn client_thread(
         prefer_full_range: true,
         preferred_encoding_gamma: 1.0,
         prefer_hdr: false,
-    };
-    let client_core_context = Arc::new(ClientCoreContext::new(capabilities));
+    }
+}
+
+fn client_thread(
+    output_sender: mpsc::Sender<WindowOutput>,
+    input_receiver: mpsc::Receiver<WindowInput>,
+    frame_slot: Arc<Mutex<Option<WindowFrame>>>,
+    maybe_dump_dir: Option<PathBuf>,
+) {
+    let client_core_context = Arc::new(ClientCoreContext::new(capabilities()));
 
     client_core_context.resume();
 
     let streaming = Arc::new(RelaxedAtomic::new(false));
     let got_decoder_config = Arc::new(RelaxedAtomic::new(false));
     let mut maybe_tracking_thread = None;
+    let mut maybe_video_config = None;
+    let mut maybe_decoder_source = None;
+    let mut streaming_sessions = 0;
 
     let mut window_output = WindowOutput::default();
     let window_input = Arc::new(RwLock::new(WindowInput::default()));
@@ -225,6 +336,8 @@ fn client_thread(
                     window_output.fps = config.negotiated_config.refresh_rate_hint;
                     window_output.connected = true;
                     window_output.resolution = config.negotiated_config.view_resolution;
+                    maybe_video_config = Some(config.settings.video.clone());
+                    streaming_sessions += 1;
 
                     streaming.set(true);
 
@@ -243,6 +356,8 @@ fn client_thread(
                 ClientCoreEvent::StreamingStopped => {
                     streaming.set(false);
                     got_decoder_config.set(false);
+                    maybe_decoder_source = None;
+                    client_core_context.set_video_packet_callback(None);
 
                     if let Some(thread) = maybe_tracking_thread.take() {
                         thread.join().ok();
@@ -253,10 +368,47 @@ fn client_thread(
                     window_output.resolution = UVec2::ZERO;
                     window_output.decoder_codec = None;
                 }
-                ClientCoreEvent::DecoderConfig { codec, .. } => {
+                ClientCoreEvent::DecoderConfig { codec, config_nal } => {
+                    if let Some(dir) = &maybe_dump_dir
+                        && !got_decoder_config.value()
+                    {
+                        video_dump::start(
+                            &client_core_context,
+                            dir,
+                            streaming_sessions,
+                            codec,
+                            &config_nal,
+                        );
+                    }
+
                     got_decoder_config.set(true);
 
                     window_output.decoder_codec = Some(codec);
+
+                    if let Some(video_config) = &maybe_video_config {
+                        let (mut sink, source) = video_decoder::create_decoder(
+                            VideoDecoderConfig {
+                                codec,
+                                force_software_decoder: true,
+                                max_buffering_frames: video_config.max_buffering_frames,
+                                buffering_history_weight: video_config.buffering_history_weight,
+                                options: vec![],
+                                config_buffer: config_nal,
+                            },
+                            {
+                                let context = Arc::clone(&client_core_context);
+                                move |maybe_timestamp: Result<Duration>| match maybe_timestamp {
+                                    Ok(timestamp) => context.report_frame_decoded(timestamp),
+                                    Err(e) => context.report_fatal_decoder_error(&e.to_string()),
+                                }
+                            },
+                        );
+                        maybe_decoder_source = Some(source);
+
+                        client_core_context.set_decoder_input_callback(Box::new(
+                            move |timestamp, buffer| sink.push_nal(timestamp, buffer),
+                        ));
+                    }
                 }
                 ClientCoreEvent::Haptics { .. } | ClientCoreEvent::RealTimeConfig(_) => (),
             }
@@ -266,7 +418,24 @@ fn client_thread(
 
         thread::sleep(Duration::from_millis(3));
 
-        client_core_context.report_compositor_start(window_output.current_frame_timestamp);
+        let mut maybe_new_frame = None;
+        if let Some(source) = &mut maybe_decoder_source
+            && let Some((timestamp, frame)) = source.get_cpu_frame()
+        {
+            window_output.current_frame_timestamp = timestamp;
+
+            // Copy the frame only when the window is ready to show it
+            if frame_slot.lock().is_none() {
+                maybe_new_frame = Some(frame.clone());
+            }
+        }
+
+        let view_params =
+            client_core_context.report_compositor_start(window_output.current_frame_timestamp);
+
+        if let Some(frame) = maybe_new_frame {
+            *frame_slot.lock() = Some(WindowFrame { frame, view_params });
+        }
 
         thread::sleep(Duration::from_millis(input_lock.emulated_compositor_ms));
 
@@ -297,23 +466,81 @@ fn client_thread(
     // client_core_context destroy is called here on drop
 }
 
+fn run_headless(
+    scenario_path: &Path,
+    report_path: Option<PathBuf>,
+    maybe_dump_dir: Option<PathBuf>,
+) {
+    let scenario = match Scenario::load(scenario_path) {
+        Ok(scenario) => scenario,
+        Err(e) => {
+            error!("Invalid scenario: {e:?}");
+            process::exit(1);
+        }
+    };
+
+    let report = headless::run(capabilities(), scenario, maybe_dump_dir);
+
+    let report_json = serde_json::to_string_pretty(&report).unwrap();
+    println!("{report_json}");
+    if let Some(path) = report_path
+        && let Err(e) = fs::write(&path, &report_json)
+    {
+        error!("Failed to write report to {}: {e}", path.display());
+    }
+
+    if report.streaming_sessions == 0 {
+        process::exit(1);
+    }
+}
+
 fn main() {
     env_logger::init();
 
+    let mut args = Arguments::from_env();
+
+    if args.contains(["-h", "--help"]) {
+        println!("{HELP_STR}");
+        return;
+    }
+
+    let scenario_path: Option<PathBuf> = args.opt_value_from_str("--headless").unwrap();
+    let report_path: Option<PathBuf> = args.opt_value_from_str("--report").unwrap();
+    let dump_dir: Option<PathBuf> = args.opt_value_from_str("--dump-video").unwrap();
+
+    if !args.finish().is_empty() {
+        eprintln!("Unrecognized arguments\n{HELP_STR}");
+        process::exit(1);
+    }
+
+    if let Some(path) = scenario_path {
+        run_headless(&path, report_path, dump_dir);
+        return;
+    }
+
     let (input_sender, input_receiver) = mpsc::channel::<WindowInput>();
     let (output_sender, output_receiver) = mpsc::channel::<WindowOutput>();
 
-    let client_thread = thread::spawn(|| {
-        client_thread(output_sender, input_receiver);
+    let frame_slot = Arc::new(Mutex::new(None));
+
+    let client_thread = thread::spawn({
+        let frame_slot = Arc::clone(&frame_slot);
+        move || client_thread(output_sender, input_receiver, frame_slot, dump_dir)
     });
 
     eframe::run_native(
         "Mock client",
         NativeOptions {
-            viewport: ViewportBuilder::default().with_inner_size((400.0, 400.0)),
+            viewport: ViewportBuilder::default().with_inner_size((800.0, 800.0)),
             ..Default::default()
         },
-        Box::new(|_| Ok(Box::new(Window::new(input_sender, output_receiver)))),
+        Box::new(|_| {
+            Ok(Box::new(Window::new(
+                input_sender,
+                output_receiver,
+                frame_slot,
+            )))
+        }),
     )
     .ok();
 
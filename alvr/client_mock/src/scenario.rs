@@ -0,0 +1,232 @@
+// Scenario file of the headless mode, in TOML or JSON format. Poses and hand skeletons are
+// interpolated between keyframes, the other events take effect at their time.
+
+use alvr_common::{
+    Pose, QUEST_CONTROLLER_PROFILE_PATH,
+    anyhow::{Context, Result},
+    glam::{Quat, Vec2, Vec3},
+    hash_string,
+};
+use alvr_packets::{ButtonEntry, ButtonValue};
+use serde::Deserialize;
+use std::{array, fs, path::Path, time::Duration};
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct Timings {
+    pub decode_ms: u64,
+    pub compositor_ms: u64,
+    pub vsync_ms: u64,
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Self {
+            decode_ms: 5,
+            compositor_ms: 1,
+            vsync_ms: 25,
+        }
+    }
+}
+
+// The orientation defaults to identity
+#[derive(Deserialize, Clone, Copy)]
+pub struct ScenarioPose {
+    #[serde(default)]
+    pub orientation: Quat,
+    pub position: Vec3,
+}
+
+impl From<ScenarioPose> for Pose {
+    fn from(pose: ScenarioPose) -> Self {
+        Pose {
+            orientation: pose.orientation,
+            position: pose.position,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PoseKeyframe {
+    pub time_s: f32,
+    #[serde(flatten)]
+    pub pose: ScenarioPose,
+}
+
+// Joints in the OpenXR order
+#[derive(Deserialize, Clone)]
+pub struct HandKeyframe {
+    pub time_s: f32,
+    pub joints: [ScenarioPose; 26],
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(untagged)]
+pub enum ScenarioButtonValue {
+    Binary(bool),
+    Scalar(f32),
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ScenarioButton {
+    // For example /user/hand/left/input/a/click
+    pub path: String,
+    pub value: ScenarioButtonValue,
+}
+
+impl ScenarioButton {
+    pub fn to_entry(&self) -> ButtonEntry {
+        ButtonEntry {
+            path_id: hash_string(&self.path),
+            value: match self.value {
+                ScenarioButtonValue::Binary(value) => ButtonValue::Binary(value),
+                ScenarioButtonValue::Scalar(value) => ButtonValue::Scalar(value),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum ScenarioEvent {
+    Buttons {
+        entries: Vec<ScenarioButton>,
+    },
+    Battery {
+        device_path: String,
+        gauge_value: f32,
+        is_plugged: bool,
+    },
+    Playspace {
+        area: Option<Vec2>,
+    },
+    // Only the specified timings are changed
+    Timings {
+        decode_ms: Option<u64>,
+        compositor_ms: Option<u64>,
+        vsync_ms: Option<u64>,
+    },
+    // The client disconnects and stays idle until a Reconnect event
+    Disconnect,
+    Reconnect,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TimedEvent {
+    pub time_s: f32,
+    #[serde(flatten)]
+    pub event: ScenarioEvent,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    pub duration_s: f32,
+    #[serde(default = "default_interaction_profile")]
+    pub interaction_profile: String,
+    #[serde(default)]
+    pub timings: Timings,
+    #[serde(default)]
+    pub head: Vec<PoseKeyframe>,
+    #[serde(default)]
+    pub left_controller: Vec<PoseKeyframe>,
+    #[serde(default)]
+    pub right_controller: Vec<PoseKeyframe>,
+    #[serde(default)]
+    pub left_hand: Vec<HandKeyframe>,
+    #[serde(default)]
+    pub right_hand: Vec<HandKeyframe>,
+    #[serde(default)]
+    pub events: Vec<TimedEvent>,
+}
+
+fn default_interaction_profile() -> String {
+    QUEST_CONTROLLER_PROFILE_PATH.into()
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read scenario {}", path.display()))?;
+
+        let mut scenario: Scenario = if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            serde_json::from_str(&text)?
+        } else {
+            toml::from_str(&text)?
+        };
+
+        // Keyframes and events can be written in any order
+        for keyframes in [
+            &mut scenario.head,
+            &mut scenario.left_controller,
+            &mut scenario.right_controller,
+        ] {
+            keyframes.sort_by(|a, b| a.time_s.total_cmp(&b.time_s));
+        }
+        for keyframes in [&mut scenario.left_hand, &mut scenario.right_hand] {
+            keyframes.sort_by(|a, b| a.time_s.total_cmp(&b.time_s));
+        }
+        scenario
+            .events
+            .sort_by(|a, b| a.time_s.total_cmp(&b.time_s));
+
+        Ok(scenario)
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f32(self.duration_s)
+    }
+}
+
+fn interpolate_pose(from: Pose, to: Pose, factor: f32) -> Pose {
+    Pose {
+        orientation: from.orientation.slerp(to.orientation, factor),
+        position: from.position.lerp(to.position, factor),
+    }
+}
+
+// Returns the two keyframes around the given time and the interpolation factor between them. The
+// first and last keyframes are held before and after the timeline.
+fn surrounding_keyframes<T>(
+    keyframes: &[T],
+    time_s: f32,
+    keyframe_time_s: impl Fn(&T) -> f32,
+) -> Option<(&T, &T, f32)> {
+    let next_idx = keyframes.partition_point(|keyframe| keyframe_time_s(keyframe) <= time_s);
+
+    if next_idx == 0 {
+        let first = keyframes.first()?;
+        Some((first, first, 0.0))
+    } else if next_idx == keyframes.len() {
+        let last = keyframes.last()?;
+        Some((last, last, 0.0))
+    } else {
+        let previous = &keyframes[next_idx - 1];
+        let next = &keyframes[next_idx];
+        let previous_time_s = keyframe_time_s(previous);
+        let factor = (time_s - previous_time_s) / (keyframe_time_s(next) - previous_time_s);
+
+        Some((previous, next, factor))
+    }
+}
+
+pub fn sample_pose(keyframes: &[PoseKeyframe], time_s: f32) -> Option<Pose> {
+    let (previous, next, factor) = surrounding_keyframes(keyframes, time_s, |k| k.time_s)?;
+
+    Some(interpolate_pose(
+        previous.pose.into(),
+        next.pose.into(),
+        factor,
+    ))
+}
+
+pub fn sample_hand(keyframes: &[HandKeyframe], time_s: f32) -> Option<[Pose; 26]> {
+    let (previous, next, factor) = surrounding_keyframes(keyframes, time_s, |k| k.time_s)?;
+
+    Some(array::from_fn(|idx| {
+        interpolate_pose(previous.joints[idx].into(), next.joints[idx].into(), factor)
+    }))
+}
@@ -0,0 +1,100 @@
+// Saves the received video stream to disk. The NALs are appended to a raw stream file that can be
+// played with FFmpeg, and the header of each packet is written as a line of a JSON Lines file,
+// together with the position of the packet in the stream file.
+
+use alvr_client_core::ClientCoreContext;
+use alvr_common::{anyhow::Result, error, warn};
+use alvr_packets::VideoPacketHeader;
+use alvr_session::CodecType;
+use serde::Serialize;
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+#[derive(Serialize)]
+struct PacketRecord<'a> {
+    #[serde(flatten)]
+    header: &'a VideoPacketHeader,
+    offset: u64,
+    size: usize,
+}
+
+struct VideoDump {
+    stream_file: BufWriter<File>,
+    headers_file: BufWriter<File>,
+    offset: u64,
+}
+
+impl VideoDump {
+    // A new pair of files is created for each streaming session
+    fn new(dir: &Path, session_idx: usize, codec: CodecType, config_nal: &[u8]) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let extension = match codec {
+            CodecType::H264 => "h264",
+            CodecType::Hevc => "h265",
+            CodecType::AV1 => "obu",
+        };
+        let mut stream_file = BufWriter::new(File::create(
+            dir.join(format!("video_{session_idx}.{extension}")),
+        )?);
+        let headers_file = BufWriter::new(File::create(
+            dir.join(format!("video_{session_idx}.jsonl")),
+        )?);
+
+        // The parameter sets are needed to decode the stream
+        stream_file.write_all(config_nal)?;
+
+        Ok(Self {
+            stream_file,
+            headers_file,
+            offset: config_nal.len() as u64,
+        })
+    }
+
+    fn write_packet(&mut self, header: &VideoPacketHeader, nal: &[u8]) -> Result<()> {
+        self.stream_file.write_all(nal)?;
+
+        serde_json::to_writer(
+            &mut self.headers_file,
+            &PacketRecord {
+                header,
+                offset: self.offset,
+                size: nal.len(),
+            },
+        )?;
+        self.headers_file.write_all(b"\n")?;
+
+        self.offset += nal.len() as u64;
+
+        Ok(())
+    }
+}
+
+// Records the packets received from now on, until the video packet callback is reset
+pub fn start(
+    context: &ClientCoreContext,
+    dir: &Path,
+    session_idx: usize,
+    codec: CodecType,
+    config_nal: &[u8],
+) {
+    let mut maybe_dump = match VideoDump::new(dir, session_idx, codec, config_nal) {
+        Ok(dump) => Some(dump),
+        Err(e) => {
+            error!("Failed to create video dump: {e}");
+            return;
+        }
+    };
+
+    context.set_video_packet_callback(Some(Box::new(move |header, nal| {
+        if let Some(dump) = &mut maybe_dump
+            && let Err(e) = dump.write_packet(header, nal)
+        {
+            warn!("Failed to write video dump, stopping: {e}");
+            maybe_dump = None;
+        }
+    })));
+}
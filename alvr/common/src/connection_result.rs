@@ -6,6 +6,7 @@ use std::{
     sync::mpsc::{RecvTimeoutError, TryRecvError},
 };
 
+#[derive(Debug)]
 pub enum ConnectionError {
     TryAgain(anyhow::Error),
     Other(anyhow::Error),
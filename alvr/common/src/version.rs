@@ -38,6 +38,31 @@ pub fn protocol_id_u64() -> u64 {
     hash_string(&protocol_id())
 }
 
+// Version of the streaming protocol, independent of the ALVR version. Increment it when a change
+// breaks the packets exchanged after the handshake, and keep the code paths of the older versions
+// down to MIN_PROTOCOL_VERSION. The handshake packets must stay compatible: new fields go in their
+// ext_str.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// Highest version supported by both peers, if any
+pub fn negotiate_protocol_version(peer_min_version: u32, peer_max_version: u32) -> Option<u32> {
+    let version = u32::min(PROTOCOL_VERSION, peer_max_version);
+
+    (version >= u32::max(MIN_PROTOCOL_VERSION, peer_min_version)).then_some(version)
+}
+
+// Supported versions formatted as "min-max", used where JSON is not available (mDNS)
+pub fn protocol_versions_string() -> String {
+    format!("{MIN_PROTOCOL_VERSION}-{PROTOCOL_VERSION}")
+}
+
+pub fn parse_protocol_versions(versions: &str) -> Option<(u32, u32)> {
+    let (min_version, max_version) = versions.split_once('-')?;
+
+    Some((min_version.parse().ok()?, max_version.parse().ok()?))
+}
+
 // deprecated
 pub fn is_version_compatible(other_version: &Version) -> bool {
     let protocol_string = if other_version.pre.is_empty() {
@@ -48,3 +73,52 @@ pub fn is_version_compatible(other_version: &Version) -> bool {
 
     protocol_id_u64() == hash_string(&protocol_string)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_protocol_version() {
+        // Same range
+        assert_eq!(
+            negotiate_protocol_version(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+            Some(PROTOCOL_VERSION)
+        );
+        // Newer peer
+        assert_eq!(
+            negotiate_protocol_version(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION + 5),
+            Some(PROTOCOL_VERSION)
+        );
+        // Peer that dropped the local versions
+        assert_eq!(
+            negotiate_protocol_version(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 5),
+            None
+        );
+        // Peer older than the oldest local version
+        assert_eq!(
+            negotiate_protocol_version(0, MIN_PROTOCOL_VERSION - 1),
+            None
+        );
+        // Invalid range
+        assert_eq!(
+            negotiate_protocol_version(PROTOCOL_VERSION, MIN_PROTOCOL_VERSION - 1),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_protocol_versions() {
+        assert_eq!(
+            parse_protocol_versions(&protocol_versions_string()),
+            Some((MIN_PROTOCOL_VERSION, PROTOCOL_VERSION))
+        );
+        assert_eq!(parse_protocol_versions("2-10"), Some((2, 10)));
+
+        assert_eq!(parse_protocol_versions(""), None);
+        assert_eq!(parse_protocol_versions("3"), None);
+        assert_eq!(parse_protocol_versions("1-"), None);
+        assert_eq!(parse_protocol_versions("-1-2"), None);
+        assert_eq!(parse_protocol_versions("a-2"), None);
+    }
+}
@@ -27,5 +27,33 @@ For that, use other means of recording, for example through headset or desktop V
         }
     });
 
+    ui.columns(4, |ui| {
+        if ui[0].button("Start tracking recording").clicked() {
+            request = Some(ServerRequest::StartTrackingRecording);
+        }
+
+        if ui[1].button("Stop tracking recording").clicked() {
+            request = Some(ServerRequest::StopTrackingRecording);
+        }
+
+        if ui[2].button("Replay last tracking").clicked() {
+            request = Some(ServerRequest::StartTrackingReplay(None));
+        }
+
+        if ui[3].button("Stop tracking replay").clicked() {
+            request = Some(ServerRequest::StopTrackingReplay);
+        }
+    });
+
+    ui.columns(4, |ui| {
+        if ui[0].button("Start statistics recording").clicked() {
+            request = Some(ServerRequest::StartStatisticsRecording);
+        }
+
+        if ui[1].button("Stop statistics recording").clicked() {
+            request = Some(ServerRequest::StopStatisticsRecording);
+        }
+    });
+
     request
 }
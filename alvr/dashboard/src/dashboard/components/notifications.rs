@@ -38,7 +38,7 @@ const NOTIFICATION_TIPS: &[&str] = &[
     r#"If you often experience image glitching, you can trade that with stutter frames using "Avoid video glitching"."#,
     r#"You can run custom commands/programs at headset connection/disconnection using the "On connect/disconnect script"."#,
     r#"In case you want to report a bug, to get a log file, enable "Extra"->"Logging"->"Log to disk". The log will be inside "session_log.txt"."#,
-    r#"For hacking purposes, you can enable "Log tracking", "Log button presses" and "Log haptics". You can get the data using a websocket at ws://localhost:8082/api/events"#,
+    r#"For hacking purposes, you can enable "Log tracking", "Log button presses" and "Log haptics". You can get the data using a websocket at ws://localhost:8082/api/events?token=<token>, with a token from api_tokens.json"#,
     r#"In case you want to report a bug and share your log, you should enable "Extra"->"Logging"->"Prefer backtrace"."#,
     r#"You can quickly cycle through tips like this one by toggling "Show notification tip""#,
     r#"If you want to use body trackers or other SteamVR drivers together with ALVR, set "Driver launch action" to "Unregister ALVR at shutdown""#,
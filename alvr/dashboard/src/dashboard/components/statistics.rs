@@ -464,6 +464,21 @@ impl StatisticsTab {
             ui[0].label("Decoder latency:");
             ui[1].label(format!("{:.2} ms", statistics.decode_latency_ms));
 
+            ui[0].label("Network jitter:");
+            ui[1].label(format!("{:.2} ms", statistics.video_jitter_ms));
+
+            ui[0].label("Lost shards:");
+            ui[1].label(format!(
+                "{} shards ({:.2}%)",
+                statistics.video_shards_lost_total, statistics.video_shards_loss_percent
+            ));
+
+            ui[0].label("Discarded packets:");
+            ui[1].label(format!(
+                "{} packets (client too slow)",
+                statistics.video_packets_discarded_total
+            ));
+
             ui[0].label("Client FPS:");
             ui[1].label(format!("{} FPS", statistics.client_fps));
 
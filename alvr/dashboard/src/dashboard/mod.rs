@@ -161,7 +161,11 @@ impl eframe::App for Dashboard {
                 EventType::DebugGroup { .. }
                 | EventType::Tracking(_)
                 | EventType::Buttons(_)
-                | EventType::Haptics(_) => (),
+                | EventType::Haptics(_)
+                | EventType::Battery(_)
+                | EventType::ClientDiscovered { .. }
+                | EventType::StreamingStarted { .. }
+                | EventType::StreamingStopped { .. } => (),
             }
         }
 
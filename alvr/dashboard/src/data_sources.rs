@@ -139,6 +139,16 @@ impl DataSources {
         let port = session_manager.settings().connection.web_server_port;
         let session_source = Arc::new(Mutex::new(SessionSource::Local(Box::new(session_manager))));
 
+        // The dashboard is launched first, so the tokens are usually created here
+        let authorization =
+            match alvr_server_io::load_or_create_api_tokens(&filesystem_layout.api_tokens()) {
+                Ok(api_tokens) => format!("Bearer {}", api_tokens.token),
+                Err(e) => {
+                    error!("Failed to load the API tokens: {e}");
+                    String::new()
+                }
+            };
+
         let version_check_thread = thread::spawn({
             let context = context.clone();
             let session_source = Arc::clone(&session_source);
@@ -208,6 +218,7 @@ impl DataSources {
             let context = context.clone();
             let session_source = Arc::clone(&session_source);
             let events_sender = events_sender.clone();
+            let authorization = authorization.clone();
             move || {
                 let uri = format!("http://127.0.0.1:{port}/api/dashboard-request");
                 let request_agent: ureq::Agent = ureq::Agent::config_builder()
@@ -292,7 +303,13 @@ impl DataSources {
                                 ServerRequest::CaptureFrame
                                 | ServerRequest::InsertIdr
                                 | ServerRequest::StartRecording
-                                | ServerRequest::StopRecording => {
+                                | ServerRequest::StopRecording
+                                | ServerRequest::StartTrackingRecording
+                                | ServerRequest::StopTrackingRecording
+                                | ServerRequest::StartTrackingReplay(_)
+                                | ServerRequest::StopTrackingReplay
+                                | ServerRequest::StartStatisticsRecording
+                                | ServerRequest::StopStatisticsRecording => {
                                     warn!(
                                         "Cannot perform action, streamer (SteamVR) is not connected."
                                     )
@@ -306,6 +323,7 @@ impl DataSources {
                             request_agent
                                 .post(&uri)
                                 .header("X-ALVR", "true")
+                                .header("Authorization", &authorization)
                                 .send_json(&request)
                                 .ok();
                         }
@@ -342,6 +360,9 @@ impl DataSources {
                     let mut req = uri.into_client_request().unwrap();
                     req.headers_mut()
                         .insert("X-ALVR", HeaderValue::from_str("true").unwrap());
+                    if let Ok(value) = HeaderValue::from_str(&authorization) {
+                        req.headers_mut().insert("Authorization", value);
+                    }
 
                     let Ok((mut ws, _)) = tungstenite::client(req, socket) else {
                         thread::sleep(Duration::from_millis(500));
@@ -4,6 +4,20 @@ use eframe::{egui, web_sys};
 use ewebsock::{WsEvent, WsMessage, WsReceiver};
 use gloo_net::http::Request;
 
+// The API token is passed in the URL of the page: http://<host>/?token=<token>
+fn api_token() -> String {
+    web_sys::window()
+        .and_then(|window| window.location().search().ok())
+        .and_then(|search| {
+            search
+                .trim_start_matches('?')
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("token="))
+                .map(String::from)
+        })
+        .unwrap_or_default()
+}
+
 pub struct DataSources {
     context: egui::Context,
     ws_receiver: Option<WsReceiver>,
@@ -22,6 +36,7 @@ impl DataSources {
         wasm_bindgen_futures::spawn_local(async move {
             Request::post("/api/dashboard-request")
                 .header("X-ALVR", "true")
+                .header("Authorization", &format!("Bearer {}", api_token()))
                 .body(serde_json::to_string(&request).unwrap())
                 .send()
                 .await
@@ -37,7 +52,8 @@ impl DataSources {
             // TODO: Set X-ALVR
             //let mut options = ewebsock::Options::default();
             //options.additional_headers = vec!(("X-ALVR", "true"));
-            let Ok((_, receiver)) = ewebsock::connect(format!("ws://{host}/api/events")) else {
+            let url = format!("ws://{host}/api/events?token={}", api_token());
+            let Ok((_, receiver)) = ewebsock::connect(url) else {
                 return None;
             };
             self.ws_receiver = Some(receiver);
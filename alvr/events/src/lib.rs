@@ -2,7 +2,7 @@ use alvr_common::{DeviceMotion, LogEntry, LogSeverity, Pose, info};
 use alvr_packets::{ButtonValue, FaceData};
 use alvr_session::SessionConfig;
 use serde::{Deserialize, Serialize};
-use std::{path::PathBuf, time::Duration};
+use std::{net::IpAddr, path::PathBuf, time::Duration};
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct StatisticsSummary {
@@ -18,6 +18,10 @@ pub struct StatisticsSummary {
     pub server_fps: u32,
     pub battery_hmd: u32,
     pub hmd_plugged: bool,
+    pub video_shards_lost_total: u64,
+    pub video_shards_loss_percent: f32,
+    pub video_packets_discarded_total: u64,
+    pub video_jitter_ms: f32,
 }
 
 // Bitrate statistics minus the empirical output value
@@ -48,6 +52,10 @@ pub struct GraphStatistics {
     pub bitrate_directives: BitrateDirectives,
     pub throughput_bps: f32,
     pub bitrate_bps: f32,
+    // Since the previous frame
+    pub video_shards_lost: u64,
+    pub video_packets_discarded: u64,
+    pub video_jitter_s: f32,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -71,6 +79,13 @@ pub struct HapticsEvent {
     pub amplitude: f32,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BatteryEvent {
+    pub device_path: String,
+    pub gauge_value: f32, // range [0, 1]
+    pub is_plugged: bool,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct AdbEvent {
     pub download_progress: f32,
@@ -87,6 +102,10 @@ pub enum EventType {
     Tracking(Box<TrackingEvent>),
     Buttons(Vec<ButtonEvent>),
     Haptics(HapticsEvent),
+    Battery(BatteryEvent),
+    ClientDiscovered { hostname: String, ip: IpAddr },
+    StreamingStarted { hostname: String },
+    StreamingStopped { hostname: String },
     DriversList(Vec<PathBuf>),
     ServerRequestsSelfRestart,
     Adb(AdbEvent),
@@ -115,6 +134,11 @@ impl Event {
             EventType::Tracking(_) => "TRACKING".to_string(),
             EventType::Buttons(_) => "BUTTONS".to_string(),
             EventType::Haptics(_) => "HAPTICS".to_string(),
+            EventType::Battery(_) => "BATTERY".to_string(),
+            EventType::ClientDiscovered { .. } => "DISCOVERY".to_string(),
+            EventType::StreamingStarted { .. } | EventType::StreamingStopped { .. } => {
+                "STREAMING".to_string()
+            }
             EventType::DriversList(_) => "DRV LIST".to_string(),
             EventType::ServerRequestsSelfRestart => "RESTART".to_string(),
             EventType::Adb(_) => "ADB".to_string(),
@@ -131,6 +155,10 @@ impl Event {
             EventType::Tracking(tracking) => serde_json::to_string(tracking).unwrap(),
             EventType::Buttons(buttons) => serde_json::to_string(buttons).unwrap(),
             EventType::Haptics(haptics) => serde_json::to_string(haptics).unwrap(),
+            EventType::Battery(battery) => serde_json::to_string(battery).unwrap(),
+            EventType::ClientDiscovered { hostname, ip } => format!("{hostname} ({ip})"),
+            EventType::StreamingStarted { hostname } => format!("Started with {hostname}"),
+            EventType::StreamingStopped { hostname } => format!("Stopped with {hostname}"),
             EventType::DriversList(drivers) => serde_json::to_string(drivers).unwrap(),
             EventType::ServerRequestsSelfRestart => "Request for server restart".into(),
             EventType::Adb(adb) => serde_json::to_string(adb).unwrap(),
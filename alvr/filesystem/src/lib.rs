@@ -199,6 +199,10 @@ impl Layout {
         self.config_dir.join("session.json")
     }
 
+    pub fn api_tokens(&self) -> PathBuf {
+        self.config_dir.join("api_tokens.json")
+    }
+
     pub fn session_log(&self) -> PathBuf {
         if cfg!(target_os = "linux") {
             self.log_dir.join("alvr_session_log.txt")
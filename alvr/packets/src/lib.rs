@@ -5,7 +5,8 @@ use alvr_common::{
     semver::Version,
 };
 use alvr_session::{
-    ClientsidePostProcessingConfig, CodecType, PassthroughMode, SessionConfig, Settings,
+    ClientsidePostProcessingConfig, CodecType, PairingKeys, PassthroughMode, SessionConfig,
+    Settings,
 };
 use serde::{Deserialize, Serialize};
 use serde_json as json;
@@ -23,9 +24,78 @@ pub const AUDIO: u16 = 2;
 pub const VIDEO: u16 = 3;
 pub const STATISTICS: u16 = 4;
 
+// Upper bounds for the size of the packets received on each stream, including the header. They
+// limit the memory that a peer can make the receiver allocate
+pub const MAX_TRACKING_PACKET_SIZE: usize = 64 * 1024;
+pub const MAX_HAPTICS_PACKET_SIZE: usize = 1024;
+pub const MAX_AUDIO_PACKET_SIZE: usize = 256 * 1024;
+pub const MAX_VIDEO_PACKET_SIZE: usize = 16 * 1024 * 1024;
+pub const MAX_STATISTICS_PACKET_SIZE: usize = 64 * 1024;
+
+// Optional features of the streaming protocol. A feature is used only if both peers support it
+pub const FEATURE_SHARD_FEC: &str = "shard_fec";
+pub const FEATURE_SHARD_RETRANSMISSION: &str = "shard_retransmission";
+pub const FEATURE_STREAM_ENCRYPTION: &str = "stream_encryption";
+pub const FEATURE_QUIC: &str = "quic";
+pub const FEATURE_OPUS_AUDIO: &str = "opus_audio";
+
+const SUPPORTED_FEATURES: &[&str] = &[
+    FEATURE_SHARD_FEC,
+    FEATURE_SHARD_RETRANSMISSION,
+    FEATURE_STREAM_ENCRYPTION,
+    FEATURE_QUIC,
+    FEATURE_OPUS_AUDIO,
+];
+
+// Advertised by the client. Unknown features are ignored, so new ones can be added without
+// bumping the protocol version
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProtocolCapabilities {
+    pub min_version: u32,
+    pub max_version: u32,
+    pub features: HashSet<String>,
+}
+
+impl ProtocolCapabilities {
+    pub fn local() -> Self {
+        Self {
+            min_version: alvr_common::MIN_PROTOCOL_VERSION,
+            max_version: alvr_common::PROTOCOL_VERSION,
+            features: SUPPORTED_FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    // Returns None if there is no common version
+    pub fn negotiate(&self) -> Option<NegotiatedProtocol> {
+        alvr_common::negotiate_protocol_version(self.min_version, self.max_version)?;
+
+        Some(NegotiatedProtocol {
+            features: self
+                .features
+                .iter()
+                .filter(|f| SUPPORTED_FEATURES.contains(&f.as_str()))
+                .cloned()
+                .collect(),
+        })
+    }
+}
+
+// Chosen by the server. All the supported versions exchange the same packets, so the version itself
+// is not sent. Once a version changes the packets, it must be added here, defaulting to 1 if missing
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NegotiatedProtocol {
+    pub features: HashSet<String>,
+}
+
+// Peers without protocol negotiation (None) are accepted only with a matching protocol ID, so
+// they support all the features
+pub fn protocol_supports(protocol: Option<&NegotiatedProtocol>, feature: &str) -> bool {
+    protocol.is_none_or(|protocol| protocol.features.contains(feature))
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct VideoStreamingCapabilitiesExt {
-    // Nothing for now
+    pub protocol: Option<ProtocolCapabilities>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -52,11 +122,13 @@ impl VideoStreamingCapabilities {
     }
 
     pub fn ext(&self) -> Result<VideoStreamingCapabilitiesExt> {
-        let _ext_json = json::from_str::<json::Value>(&self.ext_str)?;
+        let ext_json = json::from_str::<json::Value>(&self.ext_str)?;
 
-        // decode values here
-
-        Ok(VideoStreamingCapabilitiesExt {})
+        // Values are decoded one by one, so that missing or malformed values of other versions
+        // don't prevent decoding the rest
+        Ok(VideoStreamingCapabilitiesExt {
+            protocol: json::from_value(ext_json["protocol"].clone()).ok(),
+        })
     }
 }
 
@@ -67,13 +139,31 @@ pub enum ClientConnectionResult {
         display_name: String,
         server_ip: IpAddr,
         streaming_capabilities: Option<VideoStreamingCapabilities>,
+        public_key: [u8; 32],
+        handshake_nonce: [u8; 32],
     },
     ClientStandby,
 }
 
+// Sent by the server in plaintext in response to ClientConnectionResult::ConnectionAccepted. All
+// the following control packets are encrypted.
+#[derive(Serialize, Deserialize)]
+pub struct EncryptionHandshakePacket {
+    pub public_key: [u8; 32],
+    pub handshake_nonce: [u8; 32],
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct NegotiatedStreamingConfigExt {
-    // Nothing for now
+    pub protocol: Option<NegotiatedProtocol>,
+    // The client watches the stream of another client. Its tracking and inputs are ignored
+    pub spectator: bool,
+    // Port of the stream socket of the server, if it is not the configured stream port
+    pub server_stream_port: Option<u16>,
+    // The server key has been generated for this connection, after the client has been trusted
+    pub pairing: bool,
+    // DER certificate of the QUIC endpoint of the server, pinned by the client
+    pub server_certificate: Option<Vec<u8>>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -97,11 +187,17 @@ impl NegotiatedStreamingConfig {
     }
 
     pub fn ext(&self) -> Result<NegotiatedStreamingConfigExt> {
-        let _ext_json = json::from_str::<json::Value>(&self.ext_str)?;
-
-        // decode values here
-
-        Ok(NegotiatedStreamingConfigExt {})
+        let ext_json = json::from_str::<json::Value>(&self.ext_str)?;
+
+        Ok(NegotiatedStreamingConfigExt {
+            protocol: json::from_value(ext_json["protocol"].clone()).ok(),
+            spectator: json::from_value(ext_json["spectator"].clone()).unwrap_or(false),
+            server_stream_port: json::from_value(ext_json["server_stream_port"].clone())
+                .unwrap_or(None),
+            pairing: json::from_value(ext_json["pairing"].clone()).unwrap_or(false),
+            server_certificate: json::from_value(ext_json["server_certificate"].clone())
+                .unwrap_or(None),
+        })
     }
 }
 
@@ -120,8 +216,14 @@ pub struct StreamConfig {
 
 impl StreamConfigPacket {
     pub fn new(session: &SessionConfig, negotiated: NegotiatedStreamingConfig) -> Result<Self> {
+        // Never share the pairing keys with the client
+        let mut session = session.clone();
+        for client_config in session.client_connections.values_mut() {
+            client_config.pairing_keys = None;
+        }
+
         Ok(Self {
-            session: json::to_string(session)?,
+            session: json::to_string(&session)?,
             negotiated,
         })
     }
@@ -292,6 +394,7 @@ pub enum ClientListAction {
     RemoveEntry,
     UpdateCurrentIp(Option<IpAddr>),
     SetConnectionState(ConnectionState),
+    SetPairingKeys(Option<PairingKeys>),
 }
 
 #[derive(Serialize, Deserialize, Default, Clone)]
@@ -303,6 +406,12 @@ pub struct ClientStatistics {
     pub rendering: Duration,
     pub vsync_queue: Duration,
     pub total_pipeline_latency: Duration,
+    // Network statistics of the video stream, cumulative since the start of the stream
+    pub video_shards_received: u64,
+    pub video_shards_lost: u64,
+    pub video_packets_reconstructed: u64,
+    pub video_packets_discarded: u64,
+    pub video_jitter: Duration,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -331,6 +440,13 @@ pub enum ServerRequest {
     InsertIdr,
     StartRecording,
     StopRecording,
+    StartTrackingRecording,
+    StopTrackingRecording,
+    // Latest recording if None
+    StartTrackingReplay(Option<PathBuf>),
+    StopTrackingReplay,
+    StartStatisticsRecording,
+    StopStatisticsRecording,
     FirewallRules(FirewallRulesAction),
     RegisterAlvrDriver,
     UnregisterDriver(PathBuf),
@@ -361,3 +477,52 @@ impl RealTimeConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities(min_version: u32, max_version: u32, features: &[&str]) -> ProtocolCapabilities {
+        ProtocolCapabilities {
+            min_version,
+            max_version,
+            features: features.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_negotiate_keeps_common_features() {
+        let protocol = capabilities(
+            alvr_common::MIN_PROTOCOL_VERSION,
+            alvr_common::PROTOCOL_VERSION,
+            &[FEATURE_QUIC, "unknown_feature"],
+        )
+        .negotiate()
+        .unwrap();
+
+        assert_eq!(protocol.features, HashSet::from([FEATURE_QUIC.to_owned()]));
+        assert!(protocol_supports(Some(&protocol), FEATURE_QUIC));
+        assert!(!protocol_supports(Some(&protocol), FEATURE_SHARD_FEC));
+    }
+
+    #[test]
+    fn test_negotiate_incompatible_versions() {
+        let newer = alvr_common::PROTOCOL_VERSION + 1;
+        assert!(capabilities(newer, newer + 1, &[]).negotiate().is_none());
+        assert!(capabilities(0, 0, &[]).negotiate().is_none());
+    }
+
+    #[test]
+    fn test_local_capabilities_negotiate_all_features() {
+        let protocol = ProtocolCapabilities::local().negotiate().unwrap();
+
+        for feature in SUPPORTED_FEATURES {
+            assert!(protocol_supports(Some(&protocol), feature));
+        }
+    }
+
+    #[test]
+    fn test_legacy_peer_supports_all_features() {
+        assert!(protocol_supports(None, FEATURE_OPUS_AUDIO));
+    }
+}
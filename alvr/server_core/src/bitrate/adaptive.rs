@@ -0,0 +1,192 @@
+use super::{BitrateController, BitrateEstimates};
+use alvr_common::SlidingWindowAverage;
+use alvr_events::BitrateDirectives;
+use alvr_session::{BitrateMode, settings_schema::Switch};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Sets the bitrate to the throughput calculated from the average frame size and network latency
+pub struct AdaptiveController {
+    nominal_frame_interval: Duration,
+    // note: why packet_sizes_bits_history is a queue and not a sliding average? Because some
+    // network samples will be dropped but not any packet size sample
+    packet_bytes_history: VecDeque<(Duration, usize)>,
+    packet_bytes_average: SlidingWindowAverage<f32>,
+    network_latency_average: SlidingWindowAverage<Duration>,
+    encoder_latency_average: SlidingWindowAverage<Duration>,
+    decoder_latency_overstep_count: usize,
+    last_update_instant: Instant,
+    dynamic_decoder_max_bytes_per_frame: f32,
+    update_needed: bool,
+}
+
+impl AdaptiveController {
+    pub fn new(max_history_size: usize, nominal_frame_interval: Duration) -> Self {
+        Self {
+            nominal_frame_interval,
+            packet_bytes_history: VecDeque::new(),
+            packet_bytes_average: SlidingWindowAverage::new(50000.0, max_history_size),
+            network_latency_average: SlidingWindowAverage::new(
+                Duration::from_millis(5),
+                max_history_size,
+            ),
+            encoder_latency_average: SlidingWindowAverage::new(
+                Duration::from_millis(5),
+                max_history_size,
+            ),
+            decoder_latency_overstep_count: 0,
+            last_update_instant: Instant::now(),
+            dynamic_decoder_max_bytes_per_frame: f32::MAX,
+            update_needed: false,
+        }
+    }
+}
+
+impl BitrateController for AdaptiveController {
+    fn report_frame_encoded(
+        &mut self,
+        timestamp: Duration,
+        encoder_latency: Duration,
+        size_bytes: usize,
+    ) {
+        self.encoder_latency_average.submit_sample(encoder_latency);
+
+        self.packet_bytes_history.push_back((timestamp, size_bytes));
+    }
+
+    fn report_frame_latencies(
+        &mut self,
+        config: &BitrateMode,
+        timestamp: Duration,
+        network_latency: Duration,
+        decoder_latency: Duration,
+    ) {
+        while let Some(&(history_timestamp, size_bytes)) = self.packet_bytes_history.front() {
+            if history_timestamp == timestamp {
+                self.packet_bytes_average.submit_sample(size_bytes as f32);
+                self.network_latency_average.submit_sample(network_latency);
+
+                self.packet_bytes_history.pop_front();
+
+                break;
+            } else {
+                self.packet_bytes_history.pop_front();
+            }
+        }
+
+        if let BitrateMode::Adaptive {
+            decoder_latency_limiter: Switch::Enabled(config),
+            ..
+        } = &config
+        {
+            if decoder_latency > Duration::from_millis(config.max_decoder_latency_ms) {
+                self.decoder_latency_overstep_count += 1;
+
+                if self.decoder_latency_overstep_count == config.latency_overstep_frames {
+                    self.dynamic_decoder_max_bytes_per_frame = f32::min(
+                        self.packet_bytes_average.get_average(),
+                        self.dynamic_decoder_max_bytes_per_frame,
+                    ) * config
+                        .latency_overstep_multiplier;
+
+                    self.update_needed = true;
+
+                    self.decoder_latency_overstep_count = 0;
+                }
+            } else {
+                self.decoder_latency_overstep_count = 0;
+            }
+        }
+    }
+
+    fn get_bitrate(
+        &mut self,
+        now: Instant,
+        config: &BitrateMode,
+        directives: &mut BitrateDirectives,
+        force_update: bool,
+    ) -> Option<f32> {
+        let BitrateMode::Adaptive {
+            saturation_multiplier,
+            max_throughput_mbps,
+            min_throughput_mbps,
+            max_network_latency_ms,
+            encoder_latency_limiter,
+            decoder_latency_limiter,
+        } = config
+        else {
+            return None;
+        };
+
+        if !force_update && !self.update_needed && now < self.last_update_instant + UPDATE_INTERVAL
+        {
+            return None;
+        }
+
+        self.last_update_instant = now;
+        self.update_needed = false;
+
+        let packet_bytes_average = self.packet_bytes_average.get_average();
+        let network_latency_average_s = self.network_latency_average.get_average().as_secs_f32();
+
+        let mut throughput_bps =
+            packet_bytes_average * 8.0 * saturation_multiplier / network_latency_average_s;
+        directives.scaled_calculated_throughput_bps = Some(throughput_bps);
+
+        if decoder_latency_limiter.enabled() {
+            throughput_bps = f32::min(throughput_bps, self.dynamic_decoder_max_bytes_per_frame);
+            directives.decoder_latency_limiter_bps = Some(self.dynamic_decoder_max_bytes_per_frame);
+        }
+
+        if let Switch::Enabled(max_ms) = max_network_latency_ms {
+            let max_bps = throughput_bps * (*max_ms as f32 / 1000.0) / network_latency_average_s;
+            throughput_bps = f32::min(throughput_bps, max_bps);
+
+            directives.network_latency_limiter_bps = Some(max_bps);
+        }
+
+        if let Switch::Enabled(config) = encoder_latency_limiter {
+            // Note: this assumes linear relationship between bitrate and encoder latency
+            // but this may not be the case
+            let saturation = self.encoder_latency_average.get_average().as_secs_f32()
+                / self.nominal_frame_interval.as_secs_f32();
+            let max_bps = throughput_bps * config.max_saturation_multiplier / saturation;
+            directives.encoder_latency_limiter_bps = Some(max_bps);
+
+            if saturation > config.max_saturation_multiplier {
+                throughput_bps = f32::min(throughput_bps, max_bps);
+            }
+        }
+
+        if let Switch::Enabled(max) = max_throughput_mbps {
+            let max_bps = *max as f32 * 1e6;
+            throughput_bps = f32::min(throughput_bps, max_bps);
+
+            directives.manual_max_throughput_bps = Some(max_bps);
+        }
+        if let Switch::Enabled(min) = min_throughput_mbps {
+            let min_bps = *min as f32 * 1e6;
+            throughput_bps = f32::max(throughput_bps, min_bps);
+
+            directives.manual_min_throughput_bps = Some(min_bps);
+        }
+
+        // NB: Here we assign the calculated throughput to the requested bitrate. This is crucial
+        // for the working of the adaptive bitrate algorithm. The goal is to optimally occupy the
+        // available bandwidth, which is when the bitrate corresponds to the throughput.
+        Some(throughput_bps)
+    }
+
+    fn fill_estimates(&self, estimates: &mut BitrateEstimates) {
+        estimates.packet_bytes = Some(self.packet_bytes_average.get_average());
+        estimates.network_latency = Some(self.network_latency_average.get_average());
+        estimates.encoder_latency = Some(self.encoder_latency_average.get_average());
+        estimates.decoder_max_bytes_per_frame = (self.dynamic_decoder_max_bytes_per_frame
+            != f32::MAX)
+            .then_some(self.dynamic_decoder_max_bytes_per_frame);
+    }
+}
@@ -0,0 +1,633 @@
+// Bitrate controller based on the trend of the network latency, in the style of the delay-based
+// estimator of Google Congestion Control. The network latency of each frame is compared with the
+// previous one. The accumulated variation is smoothed and a line is fitted over the last samples:
+// a positive slope means that a queue is building up on the link. The slope is compared with an
+// adaptive threshold to detect overuse, which drives an AIMD rate controller. Since the detection
+// runs on every frame, the bitrate is reduced a few frames after the latency starts growing.
+
+use super::BitrateController;
+use alvr_events::BitrateDirectives;
+use alvr_session::BitrateMode;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+const TRENDLINE_WINDOW_SIZE: usize = 20;
+const TRENDLINE_SMOOTHING: f64 = 0.9;
+const TRENDLINE_THRESHOLD_GAIN: f64 = 4.0;
+const MAX_DELTAS_COUNT: usize = 60;
+
+const INITIAL_THRESHOLD_MS: f64 = 12.5;
+const MIN_THRESHOLD_MS: f64 = 6.0;
+const MAX_THRESHOLD_MS: f64 = 600.0;
+const THRESHOLD_GAIN_UP: f64 = 0.0087;
+const THRESHOLD_GAIN_DOWN: f64 = 0.039;
+const MAX_THRESHOLD_ADAPT_OFFSET_MS: f64 = 15.0;
+const MAX_THRESHOLD_UPDATE_INTERVAL_MS: f64 = 100.0;
+const OVERUSE_TIME_THRESHOLD_MS: f64 = 10.0;
+
+// The incoming rate is measured from the arrival time of the frames
+const INCOMING_RATE_WINDOW: Duration = Duration::from_millis(250);
+// The bitrate can grow above the measured rate only by this factor, in case the encoder does not
+// use all the bitrate available
+const MAX_INCOMING_RATE_MULTIPLIER: f32 = 1.5;
+// Leaves time to the encoder to apply the new bitrate before reducing it again
+const MIN_DECREASE_INTERVAL: Duration = Duration::from_millis(200);
+// Frames are lost when the link is heavily congested, so their latency is never reported
+const REPORT_TIMEOUT: Duration = Duration::from_millis(500);
+// Incomplete frames are not counted in the incoming rate, which can be far below the capacity
+// after heavy losses
+const MAX_DECREASE_MULTIPLIER: f32 = 0.5;
+// Avoids reconfiguring the encoder for small increases
+const MIN_INCREASE_STEP: f32 = 0.02;
+
+#[derive(Clone, Copy, PartialEq)]
+enum NetworkUsage {
+    Normal,
+    Underusing,
+    Overusing,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RateState {
+    Hold,
+    Increase,
+}
+
+pub struct DelayGradientController {
+    // Frames waiting for the latency report
+    sent_frames: VecDeque<(Duration, usize)>,
+    // (arrival time, size)
+    received_frames: VecDeque<(Duration, usize)>,
+    first_arrival: Option<Duration>,
+    last_arrival: Duration,
+    first_timestamp: Option<Duration>,
+    // Timestamp and network latency of the last frame
+    previous_frame: Option<(Duration, Duration)>,
+    accumulated_delay_ms: f64,
+    smoothed_delay_ms: f64,
+    // (timestamp, smoothed delay), in milliseconds
+    delay_history: VecDeque<(f64, f64)>,
+    deltas_count: usize,
+    trend: f64,
+    previous_trend: f64,
+    threshold_ms: f64,
+    last_threshold_update: Option<Duration>,
+    overuse_time_ms: Option<f64>,
+    overuse_count: usize,
+    usage: NetworkUsage,
+    rate_state: RateState,
+    bitrate_bps: Option<f32>,
+    last_rate_update: Option<Duration>,
+    last_decrease: Option<Duration>,
+    last_returned_bitrate_bps: Option<f32>,
+}
+
+impl DelayGradientController {
+    pub fn new() -> Self {
+        Self {
+            sent_frames: VecDeque::new(),
+            received_frames: VecDeque::new(),
+            first_arrival: None,
+            last_arrival: Duration::ZERO,
+            first_timestamp: None,
+            previous_frame: None,
+            accumulated_delay_ms: 0.0,
+            smoothed_delay_ms: 0.0,
+            delay_history: VecDeque::new(),
+            deltas_count: 0,
+            trend: 0.0,
+            previous_trend: 0.0,
+            threshold_ms: INITIAL_THRESHOLD_MS,
+            last_threshold_update: None,
+            overuse_time_ms: None,
+            overuse_count: 0,
+            usage: NetworkUsage::Normal,
+            rate_state: RateState::Increase,
+            bitrate_bps: None,
+            last_rate_update: None,
+            last_decrease: None,
+            last_returned_bitrate_bps: None,
+        }
+    }
+
+    fn incoming_rate_bps(&self) -> Option<f32> {
+        if self.last_arrival < self.first_arrival? + INCOMING_RATE_WINDOW {
+            return None;
+        }
+
+        let bytes = self
+            .received_frames
+            .iter()
+            .map(|(_, size_bytes)| *size_bytes)
+            .sum::<usize>();
+
+        Some(bytes as f32 * 8.0 / INCOMING_RATE_WINDOW.as_secs_f32())
+    }
+
+    fn update_trend(&mut self, timestamp: Duration, network_latency: Duration) {
+        let first_timestamp = *self.first_timestamp.get_or_insert(timestamp);
+
+        let Some((previous_timestamp, previous_latency)) =
+            self.previous_frame.replace((timestamp, network_latency))
+        else {
+            return;
+        };
+        if timestamp <= previous_timestamp {
+            return;
+        }
+
+        let send_delta_ms = (timestamp - previous_timestamp).as_secs_f64() * 1000.0;
+        let delay_delta_ms =
+            (network_latency.as_secs_f64() - previous_latency.as_secs_f64()) * 1000.0;
+
+        self.deltas_count = usize::min(self.deltas_count + 1, MAX_DELTAS_COUNT);
+        self.accumulated_delay_ms += delay_delta_ms;
+        self.smoothed_delay_ms = TRENDLINE_SMOOTHING * self.smoothed_delay_ms
+            + (1.0 - TRENDLINE_SMOOTHING) * self.accumulated_delay_ms;
+
+        self.delay_history.push_back((
+            (timestamp - first_timestamp).as_secs_f64() * 1000.0,
+            self.smoothed_delay_ms,
+        ));
+        if self.delay_history.len() > TRENDLINE_WINDOW_SIZE {
+            self.delay_history.pop_front();
+        }
+        if self.delay_history.len() == TRENDLINE_WINDOW_SIZE
+            && let Some(trend) = linear_fit_slope(&self.delay_history)
+        {
+            self.trend = trend;
+        }
+
+        self.detect_usage(timestamp, send_delta_ms);
+    }
+
+    fn detect_usage(&mut self, timestamp: Duration, send_delta_ms: f64) {
+        let modified_trend = self.deltas_count as f64 * self.trend * TRENDLINE_THRESHOLD_GAIN;
+
+        if modified_trend > self.threshold_ms {
+            // The overuse must last for some time and more than one frame to be signaled
+            let overuse_time_ms = match self.overuse_time_ms {
+                Some(time_ms) => time_ms + send_delta_ms,
+                None => send_delta_ms / 2.0,
+            };
+            self.overuse_time_ms = Some(overuse_time_ms);
+            self.overuse_count += 1;
+
+            if overuse_time_ms > OVERUSE_TIME_THRESHOLD_MS
+                && self.overuse_count > 1
+                && self.trend >= self.previous_trend
+            {
+                self.overuse_time_ms = Some(0.0);
+                self.overuse_count = 0;
+                self.usage = NetworkUsage::Overusing;
+            }
+        } else {
+            self.overuse_time_ms = None;
+            self.overuse_count = 0;
+            self.usage = if modified_trend < -self.threshold_ms {
+                NetworkUsage::Underusing
+            } else {
+                NetworkUsage::Normal
+            };
+        }
+        self.previous_trend = self.trend;
+
+        // The threshold follows the trend, so that the detector is not triggered continuously by
+        // the jitter of the link. Big spikes are ignored.
+        let last_update = *self.last_threshold_update.get_or_insert(timestamp);
+        self.last_threshold_update = Some(timestamp);
+
+        let abs_trend = modified_trend.abs();
+        if abs_trend > self.threshold_ms + MAX_THRESHOLD_ADAPT_OFFSET_MS {
+            return;
+        }
+
+        let gain = if abs_trend < self.threshold_ms {
+            THRESHOLD_GAIN_DOWN
+        } else {
+            THRESHOLD_GAIN_UP
+        };
+        let interval_ms = f64::min(
+            (timestamp - last_update).as_secs_f64() * 1000.0,
+            MAX_THRESHOLD_UPDATE_INTERVAL_MS,
+        );
+        self.threshold_ms = (self.threshold_ms
+            + gain * (abs_trend - self.threshold_ms) * interval_ms)
+            .clamp(MIN_THRESHOLD_MS, MAX_THRESHOLD_MS);
+    }
+
+    fn update_bitrate(
+        &mut self,
+        timestamp: Duration,
+        max_bitrate_bps: f32,
+        min_bitrate_bps: f32,
+        increase_per_second: f32,
+        decrease_factor: f32,
+    ) {
+        let Some(mut bitrate_bps) = self.bitrate_bps else {
+            return;
+        };
+        let incoming_rate_bps = self.incoming_rate_bps();
+        let last_update = self
+            .last_rate_update
+            .replace(timestamp)
+            .unwrap_or(timestamp);
+
+        match self.usage {
+            NetworkUsage::Overusing => {
+                let reference_bps = incoming_rate_bps.unwrap_or(bitrate_bps);
+                let decreased_bps = (reference_bps * decrease_factor)
+                    .clamp(bitrate_bps * MAX_DECREASE_MULTIPLIER, bitrate_bps);
+                bitrate_bps = self.decrease(timestamp, bitrate_bps, decreased_bps);
+            }
+            NetworkUsage::Underusing => {
+                // The queue is draining, wait for it to empty before increasing again
+                self.rate_state = RateState::Hold;
+            }
+            NetworkUsage::Normal => {
+                if self.rate_state == RateState::Hold {
+                    self.rate_state = RateState::Increase;
+                } else {
+                    let interval_s = timestamp.saturating_sub(last_update).as_secs_f32();
+                    let mut increased_bps = bitrate_bps * increase_per_second.powf(interval_s);
+                    if let Some(rate_bps) = incoming_rate_bps {
+                        increased_bps = f32::min(
+                            increased_bps,
+                            f32::max(rate_bps * MAX_INCOMING_RATE_MULTIPLIER, bitrate_bps),
+                        );
+                    }
+                    bitrate_bps = increased_bps;
+                }
+            }
+        }
+
+        self.bitrate_bps = Some(bitrate_bps.clamp(min_bitrate_bps, max_bitrate_bps));
+    }
+
+    fn decrease(&mut self, timestamp: Duration, bitrate_bps: f32, decreased_bps: f32) -> f32 {
+        self.rate_state = RateState::Hold;
+
+        if self
+            .last_decrease
+            .is_none_or(|last| timestamp >= last + MIN_DECREASE_INTERVAL)
+        {
+            self.last_decrease = Some(timestamp);
+
+            decreased_bps
+        } else {
+            bitrate_bps
+        }
+    }
+}
+
+impl BitrateController for DelayGradientController {
+    fn report_frame_encoded(&mut self, timestamp: Duration, _: Duration, size_bytes: usize) {
+        self.sent_frames.push_back((timestamp, size_bytes));
+    }
+
+    fn report_frame_latencies(
+        &mut self,
+        config: &BitrateMode,
+        timestamp: Duration,
+        network_latency: Duration,
+        _: Duration,
+    ) {
+        let BitrateMode::DelayGradient {
+            initial_bitrate_mbps,
+            max_bitrate_mbps,
+            min_bitrate_mbps,
+            increase_per_second,
+            decrease_factor,
+        } = config
+        else {
+            return;
+        };
+
+        let arrival = timestamp + network_latency;
+        while let Some(&(sent_timestamp, size_bytes)) = self.sent_frames.front() {
+            if sent_timestamp > timestamp {
+                break;
+            }
+
+            self.sent_frames.pop_front();
+
+            if sent_timestamp == timestamp {
+                self.first_arrival.get_or_insert(arrival);
+                self.last_arrival = Duration::max(self.last_arrival, arrival);
+                self.received_frames.push_back((arrival, size_bytes));
+                break;
+            }
+        }
+        while let Some(&(received_arrival, _)) = self.received_frames.front()
+            && received_arrival + INCOMING_RATE_WINDOW <= self.last_arrival
+        {
+            self.received_frames.pop_front();
+        }
+
+        self.bitrate_bps
+            .get_or_insert(*initial_bitrate_mbps as f32 * 1e6);
+
+        self.update_trend(timestamp, network_latency);
+        self.update_bitrate(
+            timestamp,
+            *max_bitrate_mbps as f32 * 1e6,
+            *min_bitrate_mbps as f32 * 1e6,
+            *increase_per_second,
+            *decrease_factor,
+        );
+    }
+
+    fn get_bitrate(
+        &mut self,
+        _: Instant,
+        config: &BitrateMode,
+        directives: &mut BitrateDirectives,
+        force_update: bool,
+    ) -> Option<f32> {
+        let BitrateMode::DelayGradient {
+            initial_bitrate_mbps,
+            max_bitrate_mbps,
+            min_bitrate_mbps,
+            decrease_factor,
+            ..
+        } = config
+        else {
+            return None;
+        };
+
+        let max_bitrate_bps = *max_bitrate_mbps as f32 * 1e6;
+        let min_bitrate_bps = *min_bitrate_mbps as f32 * 1e6;
+
+        let mut bitrate_bps = self
+            .bitrate_bps
+            .unwrap_or(*initial_bitrate_mbps as f32 * 1e6);
+
+        if let Some(&(first_timestamp, _)) = self.sent_frames.front()
+            && let Some(&(last_timestamp, _)) = self.sent_frames.back()
+            && first_timestamp + REPORT_TIMEOUT < last_timestamp
+        {
+            // Forget the pending frames, so that the bitrate is decreased once per timeout
+            self.sent_frames.clear();
+            bitrate_bps = self.decrease(last_timestamp, bitrate_bps, bitrate_bps * decrease_factor);
+        }
+
+        let bitrate_bps = bitrate_bps.clamp(min_bitrate_bps, max_bitrate_bps);
+        self.bitrate_bps = Some(bitrate_bps);
+
+        // Decreases are applied immediately
+        if !force_update
+            && self.last_returned_bitrate_bps.is_some_and(|last_bps| {
+                bitrate_bps >= last_bps && bitrate_bps < last_bps * (1.0 + MIN_INCREASE_STEP)
+            })
+        {
+            return None;
+        }
+        self.last_returned_bitrate_bps = Some(bitrate_bps);
+
+        directives.scaled_calculated_throughput_bps = self.incoming_rate_bps();
+        directives.manual_max_throughput_bps = Some(max_bitrate_bps);
+        directives.manual_min_throughput_bps = Some(min_bitrate_bps);
+
+        Some(bitrate_bps)
+    }
+}
+
+fn linear_fit_slope(points: &VecDeque<(f64, f64)>) -> Option<f64> {
+    let count = points.len() as f64;
+    let x_average = points.iter().map(|(x, _)| x).sum::<f64>() / count;
+    let y_average = points.iter().map(|(_, y)| y).sum::<f64>() / count;
+
+    let mut numerator = 0.0;
+    let mut denominator = 0.0;
+    for (x, y) in points {
+        numerator += (x - x_average) * (y - y_average);
+        denominator += (x - x_average) * (x - x_average);
+    }
+
+    (denominator != 0.0).then(|| numerator / denominator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The frames are synthetic: a stream of about 50 Mbps at 90 fps goes through a link of 120 Mbps.
+    // In the queue buildup scenario the capacity of the link drops to 45 Mbps at frame 450, and
+    // the latency spikes scenario has a 15 ms spike every second. In the outage scenario the frames
+    // are lost for 1.5 s from frame 450, then the link comes back at 45 Mbps.
+    const FRAME_INTERVAL: Duration = Duration::from_nanos(1_000_000_000 / 90);
+    const LINK_CAPACITY_BPS: f64 = 120e6;
+    const QUEUE_BUILDUP_START_FRAME: usize = 450;
+    const OUTAGE_START_FRAME: usize = 450;
+    const OUTAGE_END_FRAME: usize = 585;
+    const REDUCED_CAPACITY_BPS: f32 = 45e6;
+
+    struct SyntheticFrame {
+        timestamp: Duration,
+        size_bytes: usize,
+        // Network and decoder latency, None if the frame is lost
+        latencies: Option<(Duration, Duration)>,
+    }
+
+    // The capacity of the link is given for each frame, None to lose it. The noise on sizes and
+    // latencies is deterministic
+    fn synthetic_frames(
+        count: usize,
+        capacity_bps: impl Fn(usize) -> Option<f64>,
+    ) -> Vec<SyntheticFrame> {
+        let mut random_state = 42_u64;
+        let mut noise = move || {
+            random_state ^= random_state << 13;
+            random_state ^= random_state >> 7;
+            random_state ^= random_state << 17;
+            (random_state >> 11) as f64 / (1_u64 << 53) as f64 * 2.0 - 1.0
+        };
+
+        let mut link_free_s = 0.0;
+        (0..count)
+            .map(|idx| {
+                let timestamp = Duration::from_secs(1) + FRAME_INTERVAL * idx as u32;
+                let timestamp_s = timestamp.as_secs_f64();
+                let size_bytes = (70_000.0 + 10_000.0 * noise()) as usize;
+                let decoder_s = 0.0045 + 0.0005 * noise();
+
+                let latencies = if let Some(capacity_bps) = capacity_bps(idx) {
+                    // Frames wait in the queue of the link until the previous ones are sent
+                    link_free_s =
+                        f64::max(link_free_s, timestamp_s) + size_bytes as f64 * 8.0 / capacity_bps;
+                    let network_s = link_free_s - timestamp_s + 0.002 + 0.0007 * noise();
+
+                    Some((
+                        Duration::from_secs_f64(network_s),
+                        Duration::from_secs_f64(decoder_s),
+                    ))
+                } else {
+                    link_free_s = timestamp_s;
+
+                    None
+                };
+
+                SyntheticFrame {
+                    timestamp,
+                    size_bytes,
+                    latencies,
+                }
+            })
+            .collect()
+    }
+
+    fn config() -> BitrateMode {
+        BitrateMode::DelayGradient {
+            initial_bitrate_mbps: 50,
+            max_bitrate_mbps: 200,
+            min_bitrate_mbps: 5,
+            increase_per_second: 1.08,
+            decrease_factor: 0.85,
+        }
+    }
+
+    // Returns the bitrate after each frame. The latency of lost frames is never reported
+    fn replay(frames: &[SyntheticFrame]) -> Vec<f32> {
+        let config = config();
+        let mut controller = DelayGradientController::new();
+        let mut directives = BitrateDirectives::default();
+
+        let mut bitrates = vec![];
+        for frame in frames {
+            controller.report_frame_encoded(frame.timestamp, Duration::ZERO, frame.size_bytes);
+            if let Some((network_latency, decoder_latency)) = frame.latencies {
+                controller.report_frame_latencies(
+                    &config,
+                    frame.timestamp,
+                    network_latency,
+                    decoder_latency,
+                );
+            }
+            controller.get_bitrate(Instant::now(), &config, &mut directives, false);
+            bitrates.push(controller.bitrate_bps.unwrap());
+        }
+
+        bitrates
+    }
+
+    fn outage_frames() -> Vec<SyntheticFrame> {
+        synthetic_frames(765, |idx| {
+            if idx < OUTAGE_START_FRAME {
+                Some(LINK_CAPACITY_BPS)
+            } else if idx < OUTAGE_END_FRAME {
+                None
+            } else {
+                Some(REDUCED_CAPACITY_BPS as f64)
+            }
+        })
+    }
+
+    fn first_decrease(bitrates: &[f32]) -> Option<usize> {
+        bitrates
+            .windows(2)
+            .position(|pair| pair[1] < pair[0])
+            .map(|idx| idx + 1)
+    }
+
+    #[test]
+    fn test_synthetic_stable_link_ramps_up() {
+        let bitrates = replay(&synthetic_frames(900, |_| Some(LINK_CAPACITY_BPS)));
+
+        assert_eq!(first_decrease(&bitrates), None);
+        assert!(*bitrates.last().unwrap() > 55e6);
+    }
+
+    #[test]
+    fn test_synthetic_latency_spikes_are_ignored() {
+        let mut frames = synthetic_frames(900, |_| Some(LINK_CAPACITY_BPS));
+        for frame in frames.iter_mut().step_by(90).skip(1) {
+            if let Some((network_latency, _)) = &mut frame.latencies {
+                *network_latency += Duration::from_millis(15);
+            }
+        }
+        let bitrates = replay(&frames);
+
+        assert_eq!(first_decrease(&bitrates), None);
+    }
+
+    #[test]
+    fn test_synthetic_queue_buildup_reduces_bitrate() {
+        let bitrates = replay(&synthetic_frames(630, |idx| {
+            if idx < QUEUE_BUILDUP_START_FRAME {
+                Some(LINK_CAPACITY_BPS)
+            } else {
+                Some(REDUCED_CAPACITY_BPS as f64)
+            }
+        }));
+
+        let decrease_frame = first_decrease(&bitrates).unwrap();
+        assert!(decrease_frame >= QUEUE_BUILDUP_START_FRAME);
+        assert!(decrease_frame <= QUEUE_BUILDUP_START_FRAME + 10);
+
+        // The incoming rate is measured from the arrival of the frames, so the first decrease
+        // already leaves room to drain the queue
+        assert!(bitrates[decrease_frame] < REDUCED_CAPACITY_BPS * 0.9);
+        assert!(*bitrates.last().unwrap() < REDUCED_CAPACITY_BPS);
+    }
+
+    #[test]
+    fn test_synthetic_link_outage_reduces_bitrate() {
+        let bitrates = replay(&outage_frames());
+        let initial_bitrate_bps = bitrates[OUTAGE_START_FRAME - 1];
+
+        // No latency is reported during the outage, the bitrate is decreased once per timeout
+        let timeout_frames = (REPORT_TIMEOUT.as_secs_f32() / FRAME_INTERVAL.as_secs_f32()) as usize;
+        let decrease_frame = first_decrease(&bitrates[OUTAGE_START_FRAME..]).unwrap();
+        assert!(decrease_frame > timeout_frames);
+        assert!(decrease_frame <= timeout_frames + 2);
+        assert!(bitrates[OUTAGE_END_FRAME - 1] < initial_bitrate_bps * 0.8);
+    }
+
+    #[test]
+    fn test_synthetic_capacity_drop_after_outage() {
+        let bitrates = replay(&outage_frames());
+
+        // Only few frames are counted in the incoming rate after the outage, which underestimates
+        // the capacity of the link
+        for pair in bitrates[OUTAGE_END_FRAME..].windows(2) {
+            assert!(pair[1] >= pair[0] * MAX_DECREASE_MULTIPLIER);
+        }
+
+        let min_bitrate_bps = bitrates[OUTAGE_END_FRAME..]
+            .iter()
+            .copied()
+            .fold(f32::MAX, f32::min);
+        assert!(min_bitrate_bps > REDUCED_CAPACITY_BPS * 0.75);
+        assert!(*bitrates.last().unwrap() < REDUCED_CAPACITY_BPS);
+    }
+
+    #[test]
+    fn test_only_significant_increases_are_returned() {
+        let config = config();
+        let mut controller = DelayGradientController::new();
+        let mut directives = BitrateDirectives::default();
+
+        assert_eq!(
+            controller.get_bitrate(Instant::now(), &config, &mut directives, false),
+            Some(50e6)
+        );
+
+        controller.bitrate_bps = Some(50.5e6);
+        assert_eq!(
+            controller.get_bitrate(Instant::now(), &config, &mut directives, false),
+            None
+        );
+        assert_eq!(
+            controller.get_bitrate(Instant::now(), &config, &mut directives, true),
+            Some(50.5e6)
+        );
+
+        controller.bitrate_bps = Some(49e6);
+        assert_eq!(
+            controller.get_bitrate(Instant::now(), &config, &mut directives, false),
+            Some(49e6)
+        );
+    }
+}
@@ -0,0 +1,265 @@
+mod adaptive;
+mod delay_gradient;
+#[cfg(test)]
+mod scenarios;
+
+use adaptive::AdaptiveController;
+use alvr_common::SlidingWindowAverage;
+use alvr_events::BitrateDirectives;
+use alvr_session::{
+    BitrateAdaptiveFramerateConfig, BitrateConfig, BitrateMode, settings_schema::Switch,
+};
+use delay_gradient::DelayGradientController;
+use std::{
+    mem,
+    time::{Duration, Instant},
+};
+
+pub struct DynamicEncoderParams {
+    pub bitrate_bps: f32,
+    pub framerate: f32,
+}
+
+// Averages used by the bitrate controllers. Fields are None if the current controller does not
+// track them
+pub struct BitrateEstimates {
+    pub frame_interval: Duration,
+    pub packet_bytes: Option<f32>,
+    pub network_latency: Option<Duration>,
+    pub encoder_latency: Option<Duration>,
+    // None until the decoder latency limiter is triggered
+    pub decoder_max_bytes_per_frame: Option<f32>,
+}
+
+// Algorithm that decides the bitrate of the encoder. There is one implementation for each variant
+// of BitrateMode. The controller is recreated when the variant changes.
+pub trait BitrateController: Send {
+    fn report_frame_present(&mut self, _interval: Duration) {}
+
+    fn report_frame_encoded(
+        &mut self,
+        _timestamp: Duration,
+        _encoder_latency: Duration,
+        _size_bytes: usize,
+    ) {
+    }
+
+    fn report_frame_latencies(
+        &mut self,
+        _config: &BitrateMode,
+        _timestamp: Duration,
+        _network_latency: Duration,
+        _decoder_latency: Duration,
+    ) {
+    }
+
+    // Returns the new bitrate, or None to keep the current one. A bitrate must be returned if
+    // force_update is true.
+    fn get_bitrate(
+        &mut self,
+        now: Instant,
+        config: &BitrateMode,
+        directives: &mut BitrateDirectives,
+        force_update: bool,
+    ) -> Option<f32>;
+
+    fn fill_estimates(&self, _estimates: &mut BitrateEstimates) {}
+}
+
+struct ConstantController;
+
+impl BitrateController for ConstantController {
+    fn get_bitrate(
+        &mut self,
+        _: Instant,
+        config: &BitrateMode,
+        _: &mut BitrateDirectives,
+        force_update: bool,
+    ) -> Option<f32> {
+        if let BitrateMode::ConstantMbps(bitrate_mbps) = config
+            && force_update
+        {
+            Some(*bitrate_mbps as f32 * 1e6)
+        } else {
+            None
+        }
+    }
+}
+
+fn create_controller(
+    config: &BitrateMode,
+    max_history_size: usize,
+    nominal_frame_interval: Duration,
+) -> Box<dyn BitrateController> {
+    match config {
+        BitrateMode::ConstantMbps(_) => Box::new(ConstantController),
+        BitrateMode::Adaptive { .. } => Box::new(AdaptiveController::new(
+            max_history_size,
+            nominal_frame_interval,
+        )),
+        BitrateMode::DelayGradient { .. } => Box::new(DelayGradientController::new()),
+    }
+}
+
+pub struct BitrateManager {
+    max_history_size: usize,
+    nominal_frame_interval: Duration,
+    frame_interval_average: SlidingWindowAverage<Duration>,
+    last_frame_instant: Instant,
+    controller: Box<dyn BitrateController>,
+    previous_config: Option<BitrateConfig>,
+    update_needed: bool,
+}
+
+impl BitrateManager {
+    pub fn new(max_history_size: usize, initial_framerate: f32) -> Self {
+        let nominal_frame_interval = Duration::from_secs_f32(1. / initial_framerate);
+
+        Self {
+            max_history_size,
+            nominal_frame_interval,
+            frame_interval_average: SlidingWindowAverage::new(
+                Duration::from_millis(16),
+                max_history_size,
+            ),
+            last_frame_instant: Instant::now(),
+            controller: Box::new(AdaptiveController::new(
+                max_history_size,
+                nominal_frame_interval,
+            )),
+            previous_config: None,
+            update_needed: true,
+        }
+    }
+
+    // Note: This is used to calculate the framerate/frame interval. The frame present is the most
+    // accurate event for this use.
+    pub fn report_frame_present(&mut self, config: &Switch<BitrateAdaptiveFramerateConfig>) {
+        self.report_frame_present_at(Instant::now(), config);
+    }
+
+    fn report_frame_present_at(
+        &mut self,
+        now: Instant,
+        config: &Switch<BitrateAdaptiveFramerateConfig>,
+    ) {
+        let interval = now - self.last_frame_instant;
+        self.last_frame_instant = now;
+
+        self.controller.report_frame_present(interval);
+
+        if let Some(config) = config.as_option() {
+            let interval_ratio =
+                interval.as_secs_f32() / self.frame_interval_average.get_average().as_secs_f32();
+
+            self.frame_interval_average.submit_sample(interval);
+
+            if interval_ratio > config.framerate_reset_threshold_multiplier
+                || interval_ratio < 1.0 / config.framerate_reset_threshold_multiplier
+            {
+                // Clear most of the samples, keep some for stability
+                self.frame_interval_average.retain(5);
+                self.update_needed = true;
+            }
+        }
+    }
+
+    pub fn report_frame_encoded(
+        &mut self,
+        timestamp: Duration,
+        encoder_latency: Duration,
+        size_bytes: usize,
+    ) {
+        self.controller
+            .report_frame_encoded(timestamp, encoder_latency, size_bytes);
+    }
+
+    // decoder_latency is used to learn a suitable maximum bitrate bound to avoid decoder runaway
+    // latency
+    pub fn report_frame_latencies(
+        &mut self,
+        config: &BitrateMode,
+        timestamp: Duration,
+        network_latency: Duration,
+        decoder_latency: Duration,
+    ) {
+        if network_latency.is_zero() {
+            return;
+        }
+
+        self.controller
+            .report_frame_latencies(config, timestamp, network_latency, decoder_latency);
+    }
+
+    pub fn estimates(&self) -> BitrateEstimates {
+        let mut estimates = BitrateEstimates {
+            frame_interval: self.frame_interval_average.get_average(),
+            packet_bytes: None,
+            network_latency: None,
+            encoder_latency: None,
+            decoder_max_bytes_per_frame: None,
+        };
+        self.controller.fill_estimates(&mut estimates);
+
+        estimates
+    }
+
+    pub fn get_encoder_params(
+        &mut self,
+        config: &BitrateConfig,
+    ) -> Option<(DynamicEncoderParams, BitrateDirectives)> {
+        self.get_encoder_params_at(Instant::now(), config)
+    }
+
+    fn get_encoder_params_at(
+        &mut self,
+        now: Instant,
+        config: &BitrateConfig,
+    ) -> Option<(DynamicEncoderParams, BitrateDirectives)> {
+        let force_update = if self.previous_config.as_ref() != Some(config) {
+            let mode_changed = self.previous_config.as_ref().is_none_or(|previous| {
+                mem::discriminant(&previous.mode) != mem::discriminant(&config.mode)
+            });
+            if mode_changed {
+                self.controller = create_controller(
+                    &config.mode,
+                    self.max_history_size,
+                    self.nominal_frame_interval,
+                );
+            }
+
+            self.previous_config = Some(config.clone());
+
+            // Always update bitrate in this case
+            true
+        } else {
+            self.update_needed
+        };
+
+        let mut bitrate_directives = BitrateDirectives::default();
+        let bitrate_bps = self.controller.get_bitrate(
+            now,
+            &config.mode,
+            &mut bitrate_directives,
+            force_update,
+        )?;
+
+        self.update_needed = false;
+
+        let frame_interval = if config.adapt_to_framerate.enabled() {
+            self.frame_interval_average.get_average()
+        } else {
+            self.nominal_frame_interval
+        };
+
+        bitrate_directives.requested_bitrate_bps = bitrate_bps;
+
+        Some((
+            DynamicEncoderParams {
+                bitrate_bps,
+                framerate: 1.0 / f32::min(frame_interval.as_secs_f32(), 1.0),
+            },
+            bitrate_directives,
+        ))
+    }
+}
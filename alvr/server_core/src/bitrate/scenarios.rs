@@ -0,0 +1,270 @@
+// Scenario tests of the bitrate adaptation. Video frames of the size requested by BitrateManager
+// are sent through stream sockets connected by emulated links, and the network latency of each
+// frame is reported back when it is received. The clock is virtual, so the scenarios run faster
+// than real time and always give the same result.
+
+use super::BitrateManager;
+use alvr_packets::MAX_VIDEO_PACKET_SIZE;
+use alvr_session::{
+    BitrateConfig, BitrateMode, DecoderLatencyLimiter, EncoderLatencyLimiter,
+    settings_schema::Switch,
+};
+use alvr_sockets::{
+    StreamReceiver, StreamSender,
+    network_emulation::{BurstLoss, EmulatedPeers, LinkConditions},
+};
+use std::time::{Duration, Instant};
+
+const VIDEO_STREAM: u16 = 0;
+const MAX_PACKET_SIZE: usize = 1400;
+const MAX_CONCURRENT_BUFFERS: usize = 8;
+const FRAMERATE: f32 = 90.0;
+const ENCODER_LATENCY: Duration = Duration::from_millis(3);
+const DECODER_LATENCY: Duration = Duration::from_millis(3);
+const MAX_NETWORK_LATENCY_MS: u64 = 15;
+
+fn adaptive_mode() -> BitrateMode {
+    BitrateMode::Adaptive {
+        saturation_multiplier: 0.95,
+        max_throughput_mbps: Switch::Enabled(300),
+        min_throughput_mbps: Switch::Enabled(5),
+        max_network_latency_ms: Switch::Enabled(MAX_NETWORK_LATENCY_MS),
+        encoder_latency_limiter: Switch::Enabled(EncoderLatencyLimiter {
+            max_saturation_multiplier: 0.9,
+        }),
+        decoder_latency_limiter: Switch::Enabled(DecoderLatencyLimiter {
+            max_decoder_latency_ms: 30,
+            latency_overstep_frames: 90,
+            latency_overstep_multiplier: 0.99,
+        }),
+    }
+}
+
+fn delay_gradient_mode() -> BitrateMode {
+    BitrateMode::DelayGradient {
+        initial_bitrate_mbps: 30,
+        max_bitrate_mbps: 300,
+        min_bitrate_mbps: 5,
+        increase_per_second: 1.08,
+        decrease_factor: 0.85,
+    }
+}
+
+fn wifi_link(bandwidth_mbps: f64) -> LinkConditions {
+    LinkConditions {
+        bandwidth_bps: bandwidth_mbps * 1e6,
+        rtt: Duration::from_millis(4),
+        jitter: Duration::from_millis(1),
+        ..Default::default()
+    }
+}
+
+struct Scenario {
+    peers: EmulatedPeers,
+    sender: StreamSender<Duration>,
+    receiver: StreamReceiver<Duration>,
+    manager: BitrateManager,
+    config: BitrateConfig,
+    start_instant: Instant,
+    next_frame_timestamp: Duration,
+    bitrate_bps: f32,
+    // (timestamp, bitrate) of each sent frame
+    sent_frames: Vec<(Duration, f32)>,
+    // (timestamp, network latency) of each received frame
+    received_frames: Vec<(Duration, Duration)>,
+}
+
+impl Scenario {
+    fn new(mode: BitrateMode, link: LinkConditions) -> Self {
+        let mut peers = EmulatedPeers::new(
+            link,
+            LinkConditions::default(),
+            0,
+            MAX_PACKET_SIZE,
+            None,
+            None,
+        );
+        let sender = peers.server_socket.request_stream(VIDEO_STREAM);
+        let receiver = peers
+            .client_socket
+            .subscribe_to_stream(VIDEO_STREAM, MAX_CONCURRENT_BUFFERS, MAX_VIDEO_PACKET_SIZE)
+            .unwrap();
+
+        let config = BitrateConfig {
+            mode,
+            adapt_to_framerate: Switch::Disabled,
+            history_size: 256,
+            image_corruption_fix: false,
+        };
+
+        Self {
+            peers,
+            sender,
+            receiver,
+            manager: BitrateManager::new(config.history_size, FRAMERATE),
+            config,
+            start_instant: Instant::now(),
+            next_frame_timestamp: Duration::ZERO,
+            bitrate_bps: 0.0,
+            sent_frames: vec![],
+            received_frames: vec![],
+        }
+    }
+
+    fn set_link_conditions(&self, link: LinkConditions) {
+        self.peers.forward_link.set_conditions(link);
+    }
+
+    fn run(&mut self, duration: Duration) {
+        let end = self.peers.now() + duration;
+        let frame_interval = Duration::from_secs_f32(1.0 / FRAMERATE);
+
+        while self.next_frame_timestamp < end {
+            // Advance to each delivery to measure the exact arrival time of the frames
+            while let Some(time) = self.peers.next_delivery_time()
+                && time <= self.next_frame_timestamp
+            {
+                self.peers.advance_to(time);
+                self.receive_frames();
+            }
+            self.peers.advance_to(self.next_frame_timestamp);
+            self.receive_frames();
+
+            self.send_frame(self.next_frame_timestamp);
+            self.next_frame_timestamp += frame_interval;
+        }
+    }
+
+    fn receive_frames(&mut self) {
+        while let Ok(data) = self.receiver.recv(Duration::ZERO) {
+            let timestamp = data.get_header().unwrap();
+            let network_latency = self.peers.now() - timestamp;
+
+            self.manager.report_frame_latencies(
+                &self.config.mode,
+                timestamp,
+                network_latency,
+                DECODER_LATENCY,
+            );
+            self.received_frames.push((timestamp, network_latency));
+        }
+    }
+
+    fn send_frame(&mut self, timestamp: Duration) {
+        let now = self.start_instant + timestamp;
+
+        self.manager
+            .report_frame_present_at(now, &self.config.adapt_to_framerate);
+        if let Some((params, _)) = self.manager.get_encoder_params_at(now, &self.config) {
+            self.bitrate_bps = params.bitrate_bps;
+        }
+
+        let size_bytes = (self.bitrate_bps / FRAMERATE / 8.0) as usize;
+        self.manager
+            .report_frame_encoded(timestamp, ENCODER_LATENCY, size_bytes);
+
+        let mut buffer = self.sender.get_buffer(&timestamp).unwrap();
+        buffer.get_range_mut(0, size_bytes);
+        self.sender.send(buffer).unwrap();
+
+        self.sent_frames.push((timestamp, self.bitrate_bps));
+    }
+
+    fn bitrates_mbps(&self, from_s: f32, to_s: f32) -> Vec<f32> {
+        self.sent_frames
+            .iter()
+            .filter(|(timestamp, _)| (from_s..to_s).contains(&timestamp.as_secs_f32()))
+            .map(|(_, bitrate_bps)| bitrate_bps / 1e6)
+            .collect()
+    }
+
+    fn max_network_latency_ms(&self, from_s: f32, to_s: f32) -> f32 {
+        self.received_frames
+            .iter()
+            .filter(|(timestamp, _)| (from_s..to_s).contains(&timestamp.as_secs_f32()))
+            .map(|(_, latency)| latency.as_secs_f32() * 1000.0)
+            .fold(0.0, f32::max)
+    }
+
+    // The bitrate stays within the given fraction of its average
+    fn assert_converged(&self, from_s: f32, to_s: f32, tolerance: f32) -> f32 {
+        let bitrates = self.bitrates_mbps(from_s, to_s);
+        let average = bitrates.iter().sum::<f32>() / bitrates.len() as f32;
+        for bitrate in bitrates {
+            assert!(
+                (bitrate - average).abs() <= average * tolerance,
+                "{bitrate} Mbps, average {average} Mbps"
+            );
+        }
+
+        average
+    }
+}
+
+#[test]
+fn test_adaptive_converges_on_stable_link() {
+    let mut scenario = Scenario::new(adaptive_mode(), wifi_link(100.0));
+    scenario.run(Duration::from_secs(30));
+
+    let bitrate_mbps = scenario.assert_converged(15.0, 30.0, 0.05);
+    assert!((50.0..100.0).contains(&bitrate_mbps), "{bitrate_mbps}");
+
+    let latency_ms = scenario.max_network_latency_ms(15.0, 30.0);
+    assert!(latency_ms < MAX_NETWORK_LATENCY_MS as f32, "{latency_ms}");
+}
+
+#[test]
+fn test_adaptive_follows_capacity_drop() {
+    let mut scenario = Scenario::new(adaptive_mode(), wifi_link(100.0));
+    scenario.run(Duration::from_secs(20));
+    scenario.set_link_conditions(wifi_link(40.0));
+    scenario.run(Duration::from_secs(30));
+
+    let bitrate_mbps = scenario.assert_converged(35.0, 50.0, 0.05);
+    assert!((20.0..40.0).contains(&bitrate_mbps), "{bitrate_mbps}");
+
+    let latency_ms = scenario.max_network_latency_ms(35.0, 50.0);
+    assert!(latency_ms < MAX_NETWORK_LATENCY_MS as f32, "{latency_ms}");
+}
+
+#[test]
+fn test_adaptive_with_packet_loss() {
+    let mut scenario = Scenario::new(
+        adaptive_mode(),
+        LinkConditions {
+            loss_probability: 0.001,
+            burst_loss: Some(BurstLoss {
+                start_probability: 0.0005,
+                mean_length: 20.0,
+            }),
+            ..wifi_link(100.0)
+        },
+    );
+    scenario.run(Duration::from_secs(30));
+
+    let bitrate_mbps = scenario.assert_converged(15.0, 30.0, 0.1);
+    assert!((50.0..100.0).contains(&bitrate_mbps), "{bitrate_mbps}");
+
+    let latency_ms = scenario.max_network_latency_ms(15.0, 30.0);
+    assert!(latency_ms < MAX_NETWORK_LATENCY_MS as f32, "{latency_ms}");
+}
+
+#[test]
+fn test_delay_gradient_follows_capacity_drop() {
+    let mut scenario = Scenario::new(delay_gradient_mode(), wifi_link(100.0));
+    scenario.run(Duration::from_secs(20));
+    scenario.set_link_conditions(wifi_link(40.0));
+    scenario.run(Duration::from_secs(10));
+
+    // The queue fills up before the overuse is detected, but the bitrate then stays below the
+    // capacity while slowly increasing again
+    let bitrates = scenario.bitrates_mbps(20.5, 30.0);
+    assert!(
+        bitrates.iter().all(|bitrate| *bitrate < 40.0),
+        "{bitrates:?}"
+    );
+    assert!(*bitrates.last().unwrap() > 20.0, "{bitrates:?}");
+
+    let latency_ms = scenario.max_network_latency_ms(22.0, 30.0);
+    assert!(latency_ms < MAX_NETWORK_LATENCY_MS as f32, "{latency_ms}");
+}
@@ -6,36 +6,43 @@ use crate::{
     sockets::WelcomeSocket,
     statistics::StatisticsManager,
     tracking::{self, TrackingManager},
+    video_recording::{AudioFormat, AudioSource},
 };
 use alvr_adb::{WiredConnection, WiredConnectionStatus};
 use alvr_common::{
-    AnyhowToCon, BUTTON_INFO, CONTROLLER_PROFILE_INFO, ConResult, ConnectionError, ConnectionState,
-    LifecycleState, QUEST_CONTROLLER_PROFILE_PATH, con_bail, dbg_connection, debug, error,
+    AnyhowToCon, BUTTON_INFO, ConResult, ConnectionError, ConnectionState, DEVICE_ID_TO_PATH,
+    LifecycleState, ViewParams, con_bail, dbg_connection, debug, error,
     glam::{UVec2, Vec2},
     info,
     parking_lot::{Condvar, Mutex, RwLock},
     settings_schema::Switch,
     warn,
 };
-use alvr_events::{AdbEvent, ButtonEvent, EventType};
+use alvr_events::{AdbEvent, BatteryEvent, ButtonEvent, EventType};
 use alvr_packets::{
     AUDIO, ClientConnectionResult, ClientControlPacket, ClientListAction, ClientStatistics,
-    HAPTICS, NegotiatedStreamingConfig, NegotiatedStreamingConfigExt, RealTimeConfig, STATISTICS,
-    ServerControlPacket, StreamConfigPacket, TRACKING, TrackingData, VIDEO, VideoPacketHeader,
+    EncryptionHandshakePacket, FEATURE_OPUS_AUDIO, FEATURE_QUIC, FEATURE_SHARD_FEC,
+    FEATURE_SHARD_RETRANSMISSION, FEATURE_STREAM_ENCRYPTION, HAPTICS, MAX_AUDIO_PACKET_SIZE,
+    MAX_STATISTICS_PACKET_SIZE, MAX_TRACKING_PACKET_SIZE, NegotiatedStreamingConfig,
+    NegotiatedStreamingConfigExt, RealTimeConfig, STATISTICS, ServerControlPacket,
+    StreamConfigPacket, TRACKING, TrackingData, VIDEO, VideoPacketHeader,
 };
 use alvr_session::{
     BodyTrackingSinkConfig, CodecType, ControllersEmulationMode, FrameSize, H264Profile,
-    OpenvrConfig, SessionConfig, SocketProtocol,
+    OpenvrConfig, PairingKeys, SessionConfig, SocketProtocol,
 };
 use alvr_sockets::{
-    CONTROL_PORT, KEEPALIVE_INTERVAL, KEEPALIVE_TIMEOUT, PeerType, ProtoControlSocket,
+    CONTROL_PORT, KEEPALIVE_INTERVAL, KEEPALIVE_TIMEOUT, PeerType, ProtoControlSocket, SessionKeys,
     StreamSocketBuilder, WIRED_CLIENT_HOSTNAME,
 };
 use std::{
     collections::HashMap,
     net::{IpAddr, Ipv4Addr},
     process::Command,
-    sync::{Arc, mpsc::RecvTimeoutError},
+    sync::{
+        Arc,
+        mpsc::{RecvTimeoutError, SyncSender, TrySendError},
+    },
     thread,
     time::{Duration, Instant},
 };
@@ -46,12 +53,83 @@ pub const STREAMING_RECV_TIMEOUT: Duration = Duration::from_millis(500);
 const REAL_TIME_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
 
 const MAX_UNREAD_PACKETS: usize = 10; // Applies per stream
+// The captured game audio is resampled to this rate, which is also supported by Opus
+const GAME_AUDIO_SAMPLE_RATE: u32 = 48000;
 
 pub struct VideoPacket {
     pub header: VideoPacketHeader,
     pub payload: Vec<u8>,
 }
 
+// Each spectator has its own queue, so that a slow spectator cannot stall the other clients
+pub struct SpectatorVideoSender {
+    sender: SyncSender<VideoPacket>,
+    waiting_for_idr: bool,
+}
+
+impl SpectatorVideoSender {
+    // Returns false if the packet has been dropped and an IDR frame is needed
+    pub fn send(
+        &mut self,
+        timestamp: Duration,
+        global_view_params: [ViewParams; 2],
+        is_idr: bool,
+        payload: &[u8],
+    ) -> bool {
+        if is_idr {
+            self.waiting_for_idr = false;
+        }
+        if self.waiting_for_idr {
+            return true;
+        }
+
+        let result = self.sender.try_send(VideoPacket {
+            header: VideoPacketHeader {
+                timestamp,
+                global_view_params,
+                is_idr,
+            },
+            payload: payload.to_vec(),
+        });
+        if matches!(result, Err(TrySendError::Full(_))) {
+            warn!("Dropping video packet for spectator. Reason: Can't push to network");
+            self.waiting_for_idr = true;
+
+            return false;
+        }
+
+        true
+    }
+}
+
+// On Linux the game audio is captured once, through the virtual devices of the main client. Its
+// packets are forwarded to the spectators, which must use the same codec to decode them
+pub struct SpectatorAudioSender {
+    sender: alvr_sockets::StreamSender<()>,
+    codec: alvr_audio::AudioCodec,
+}
+
+fn send_spectator_audio(ctx: &ConnectionContext, codec: alvr_audio::AudioCodec, packet: &[u8]) {
+    ctx.spectator_audio_senders
+        .lock()
+        .retain(|hostname, spectator| {
+            if spectator.codec != codec {
+                warn!("Game audio is not available to spectator {hostname}: different codec");
+
+                return false;
+            }
+
+            if let Ok(mut buffer) = spectator.sender.get_buffer(&()) {
+                buffer
+                    .get_range_mut(0, packet.len())
+                    .copy_from_slice(packet);
+                spectator.sender.send(buffer).ok();
+            }
+
+            true
+        });
+}
+
 fn align32(value: f32) -> u32 {
     ((value / 32.).floor() * 32.) as u32
 }
@@ -396,6 +474,13 @@ pub fn handshake_loop(ctx: Arc<ConnectionContext>, lifecycle_state: Arc<RwLock<L
                 let trusted = {
                     let mut session_manager = SESSION_MANAGER.write();
 
+                    if !session_manager.client_list().contains_key(&client_hostname) {
+                        alvr_events::send_event(EventType::ClientDiscovered {
+                            hostname: client_hostname.clone(),
+                            ip: client_ip,
+                        });
+                    }
+
                     session_manager.update_client_list(
                         client_hostname.clone(),
                         ClientListAction::AddIfMissing {
@@ -536,38 +621,135 @@ fn connection_pipeline(
         Err(e) => return Err(e),
     };
 
-    let maybe_streaming_caps = if let ClientConnectionResult::ConnectionAccepted {
-        client_protocol_id,
-        display_name,
-        streaming_capabilities,
-        ..
-    } = connection_result
+    let (maybe_streaming_caps, negotiated_protocol, client_public_key, client_handshake_nonce) =
+        if let ClientConnectionResult::ConnectionAccepted {
+            client_protocol_id,
+            display_name,
+            streaming_capabilities,
+            public_key,
+            handshake_nonce,
+            ..
+        } = connection_result
+        {
+            session_manager_lock.update_client_list(
+                client_hostname.clone(),
+                ClientListAction::SetDisplayName(display_name),
+            );
+
+            let protocol_caps = streaming_capabilities
+                .as_ref()
+                .and_then(|caps| caps.ext().ok())
+                .and_then(|ext| ext.protocol);
+
+            // Clients without protocol negotiation must match exactly
+            let negotiated_protocol = if let Some(protocol_caps) = protocol_caps {
+                let Some(protocol) = protocol_caps.negotiate() else {
+                    warn!(
+                        "Trusted client is incompatible! Supported protocol versions: {}, found: {}-{}",
+                        alvr_common::protocol_versions_string(),
+                        protocol_caps.min_version,
+                        protocol_caps.max_version,
+                    );
+
+                    return Ok(());
+                };
+                info!("Using protocol features: {:?}", protocol.features);
+
+                Some(protocol)
+            } else if client_protocol_id != alvr_common::protocol_id_u64() {
+                warn!(
+                    "Trusted client is incompatible! Expected protocol ID: {}, found: {}",
+                    alvr_common::protocol_id_u64(),
+                    client_protocol_id,
+                );
+
+                return Ok(());
+            } else {
+                None
+            };
+
+            (
+                streaming_capabilities,
+                negotiated_protocol,
+                public_key,
+                handshake_nonce,
+            )
+        } else {
+            debug!("Found client in standby. Retrying");
+            return Ok(());
+        };
+
+    let Some(streaming_caps) = maybe_streaming_caps else {
+        con_bail!("Only streaming clients are supported for now");
+    };
+
+    // While a client is streaming, other clients can only join as spectators
+    let is_spectator = if ctx.video_channel_sender.lock().is_some()
+        && let Switch::Enabled(config) = &session_manager_lock.settings().connection.spectators
     {
-        session_manager_lock.update_client_list(
-            client_hostname.clone(),
-            ClientListAction::SetDisplayName(display_name),
-        );
+        if ctx.spectator_video_senders.lock().len() >= config.max_spectators {
+            info!("Cannot accept {client_hostname} as spectator: too many spectators");
+
+            return Ok(());
+        }
+
+        info!("Accepting {client_hostname} as spectator");
 
-        if client_protocol_id != alvr_common::protocol_id_u64() {
+        true
+    } else {
+        false
+    };
+
+    dbg_connection!("connection_pipeline: Encryption handshake");
+    let mut pairing = false;
+    let pairing_keys = if let Some(keys) = session_manager_lock
+        .client_list()
+        .get(&client_hostname)
+        .and_then(|client| client.pairing_keys.clone())
+    {
+        if keys.client_public_key != client_public_key {
             warn!(
-                "Trusted client is incompatible! Expected protocol ID: {}, found: {}",
-                alvr_common::protocol_id_u64(),
-                client_protocol_id,
+                "{client_hostname} is not the client that has been paired. Remove it and trust it again to pair with the new client."
             );
 
             return Ok(());
         }
 
-        streaming_capabilities
+        keys
     } else {
-        debug!("Found client in standby. Retrying");
-        return Ok(());
-    };
+        info!("Pairing with {client_hostname}");
+        pairing = true;
 
-    let Some(streaming_caps) = maybe_streaming_caps else {
-        con_bail!("Only streaming clients are supported for now");
+        let keys = PairingKeys {
+            client_public_key,
+            server_secret_key: alvr_sockets::generate_secret_key(),
+        };
+        session_manager_lock.update_client_list(
+            client_hostname.clone(),
+            ClientListAction::SetPairingKeys(Some(keys.clone())),
+        );
+
+        keys
     };
 
+    let server_handshake_nonce = alvr_sockets::generate_handshake_nonce();
+    proto_socket
+        .send(&EncryptionHandshakePacket {
+            public_key: alvr_sockets::public_key(&pairing_keys.server_secret_key),
+            handshake_nonce: server_handshake_nonce,
+        })
+        .to_con()?;
+
+    let session_keys = SessionKeys::derive(
+        &pairing_keys.server_secret_key,
+        &client_public_key,
+        &server_handshake_nonce,
+        &client_handshake_nonce,
+        true,
+    )
+    .to_con()?;
+    proto_socket.enable_encryption(&session_keys.control);
+
     dbg_connection!("connection_pipeline: setting up negotiated streaming config");
 
     let initial_settings = session_manager_lock.settings().clone();
@@ -696,40 +878,112 @@ fn connection_pipeline(
         initial_settings.video.preferred_codec
     };
 
-    #[cfg(not(target_os = "windows"))]
-    let game_audio_sample_rate = 44100;
-
+    // A missing game audio device fails the handshake, before the client is told to stream
     #[cfg(target_os = "windows")]
-    let game_audio_sample_rate =
-        if let Switch::Enabled(game_audio_config) = &initial_settings.audio.game_audio {
-            let game_audio_device =
-                alvr_audio::new_output(game_audio_config.device.as_ref()).to_con()?;
-
-            if let Switch::Enabled(microphone_config) = &initial_settings.audio.microphone
-                && matches!(
-                    microphone_config.devices,
-                    alvr_session::MicrophoneDevicesConfig::VAC
-                        | alvr_session::MicrophoneDevicesConfig::VBCable
-                )
-            {
-                let (sink, _) =
-                    alvr_audio::new_virtual_microphone_pair(microphone_config.devices.clone())
-                        .to_con()?;
-
-                // VoiceMeeter and Custom devices may have arbitrary internal routing.
-                // Therefore, we cannot detect the loopback issue without knowing the routing.
-                if alvr_audio::is_same_device(&game_audio_device, &sink) {
-                    con_bail!("Game audio and microphone cannot point to the same device!");
-                }
+    if let Switch::Enabled(game_audio_config) = &initial_settings.audio.game_audio {
+        let game_audio_device =
+            alvr_audio::new_output(game_audio_config.device.as_ref()).to_con()?;
+
+        if let Switch::Enabled(microphone_config) = &initial_settings.audio.microphone
+            && matches!(
+                microphone_config.devices,
+                alvr_session::MicrophoneDevicesConfig::VAC
+                    | alvr_session::MicrophoneDevicesConfig::VBCable
+            )
+        {
+            let (sink, _) =
+                alvr_audio::new_virtual_microphone_pair(microphone_config.devices.clone())
+                    .to_con()?;
+
+            // VoiceMeeter and Custom devices may have arbitrary internal routing.
+            // Therefore, we cannot detect the loopback issue without knowing the routing.
+            if alvr_audio::is_same_device(&game_audio_device, &sink) {
+                con_bail!("Game audio and microphone cannot point to the same device!");
             }
+        }
+    }
 
-            alvr_audio::input_sample_rate(&game_audio_device).to_con()?
+    // The audio is resampled on capture, the rate does not depend on the device
+    let game_audio_sample_rate = GAME_AUDIO_SAMPLE_RATE;
+
+    // Spectators receive the video encoded for the main client, the encoder cannot be reconfigured
+    let (stream_view_resolution, fps, enable_foveated_encoding, encoding_gamma, enable_hdr) =
+        if is_spectator {
+            let config = &session_manager_lock.session().openvr_config;
+
+            (
+                UVec2::new(config.eye_resolution_width, config.eye_resolution_height),
+                config.refresh_rate as f32,
+                config.enable_foveated_encoding,
+                config.encoding_gamma,
+                config.enable_hdr,
+            )
         } else {
-            0
+            (
+                stream_view_resolution,
+                fps,
+                enable_foveated_encoding,
+                encoding_gamma,
+                enable_hdr,
+            )
         };
 
     let wired = client_ip.is_loopback();
 
+    let supports = |feature| alvr_packets::protocol_supports(negotiated_protocol.as_ref(), feature);
+
+    let stream_protocol = if wired {
+        SocketProtocol::Tcp
+    } else if matches!(
+        initial_settings.connection.stream_protocol,
+        SocketProtocol::Quic
+    ) && !supports(FEATURE_QUIC)
+    {
+        warn!("QUIC is not supported by the client. Using UDP");
+        SocketProtocol::Udp
+    } else {
+        initial_settings.connection.stream_protocol
+    };
+
+    // The stream port is held by the socket of the main client. Spectators use a port chosen by the
+    // system, which is sent to them. TCP sockets don't need this, they don't bind the stream port
+    let mut maybe_spectator_socket = if is_spectator {
+        Some(
+            StreamSocketBuilder::bind_for_client(
+                0,
+                initial_settings.connection.dscp,
+                initial_settings.connection.server_send_buffer_bytes,
+                initial_settings.connection.server_recv_buffer_bytes,
+            )
+            .to_con()?,
+        )
+    } else {
+        None
+    };
+    let server_stream_port = maybe_spectator_socket
+        .as_ref()
+        .map(|socket| socket.local_addr().map(|address| address.port()))
+        .transpose()
+        .to_con()?;
+
+    // The client connects to the QUIC endpoint, which must exist before its certificate is sent
+    let quic_listener = if matches!(stream_protocol, SocketProtocol::Quic) {
+        let local_socket = match maybe_spectator_socket.take() {
+            Some(socket) => socket,
+            None => StreamSocketBuilder::bind_for_client(
+                initial_settings.connection.stream_port,
+                initial_settings.connection.dscp,
+                initial_settings.connection.server_send_buffer_bytes,
+                initial_settings.connection.server_recv_buffer_bytes,
+            )
+            .to_con()?,
+        };
+
+        Some(StreamSocketBuilder::listen_for_client(local_socket).to_con()?)
+    } else {
+        None
+    };
+
     dbg_connection!("connection_pipeline: send streaming config");
     let stream_config_packet = StreamConfigPacket::new(
         session_manager_lock.session(),
@@ -743,7 +997,15 @@ fn connection_pipeline(
             wired,
             ext_str: String::new(),
         }
-        .with_ext(NegotiatedStreamingConfigExt {}),
+        .with_ext(NegotiatedStreamingConfigExt {
+            protocol: negotiated_protocol.clone(),
+            spectator: is_spectator,
+            server_stream_port,
+            pairing,
+            server_certificate: quic_listener
+                .as_ref()
+                .map(|listener| listener.certificate().to_vec()),
+        }),
     )
     .to_con()?;
     proto_socket.send(&stream_config_packet).to_con()?;
@@ -764,7 +1026,7 @@ fn connection_pipeline(
     new_openvr_config.encoding_gamma = encoding_gamma;
     new_openvr_config.codec = codec as _;
 
-    if session_manager_lock.session().openvr_config != new_openvr_config {
+    if !is_spectator && session_manager_lock.session().openvr_config != new_openvr_config {
         session_manager_lock.session_mut().openvr_config = new_openvr_config;
 
         control_sender.send(&ServerControlPacket::Restarting).ok();
@@ -783,54 +1045,153 @@ fn connection_pipeline(
     }
     dbg_connection!("connection_pipeline: Got StreamReady packet");
 
-    *ctx.statistics_manager.write() = Some(StatisticsManager::new(
-        initial_settings.connection.statistics_history_size,
-        Duration::from_secs_f32(1.0 / fps),
-        if let Switch::Enabled(config) = &initial_settings.headset.controllers {
-            config.steamvr_pipeline_frames
-        } else {
-            0.0
-        },
-    ));
-
-    *ctx.bitrate_manager.lock() =
-        BitrateManager::new(initial_settings.video.bitrate.history_size, fps);
+    if !is_spectator {
+        *ctx.statistics_manager.write() = Some(StatisticsManager::new(
+            initial_settings.connection.statistics_history_size,
+            Duration::from_secs_f32(1.0 / fps),
+            if let Switch::Enabled(config) = &initial_settings.headset.controllers {
+                config.steamvr_pipeline_frames
+            } else {
+                0.0
+            },
+            Arc::clone(&ctx.statistics_recorder),
+        ));
 
-    let stream_protocol = if wired {
-        SocketProtocol::Tcp
-    } else {
-        initial_settings.connection.stream_protocol
-    };
+        *ctx.bitrate_manager.lock() =
+            BitrateManager::new(initial_settings.video.bitrate.history_size, fps);
+    }
 
     dbg_connection!("connection_pipeline: StreamSocket connect_to_client");
     let mut stream_socket = StreamSocketBuilder::connect_to_client(
         HANDSHAKE_ACTION_TIMEOUT,
         client_ip,
         initial_settings.connection.stream_port,
+        maybe_spectator_socket,
         stream_protocol,
+        quic_listener,
         initial_settings.connection.dscp,
         initial_settings.connection.server_send_buffer_bytes,
         initial_settings.connection.server_recv_buffer_bytes,
         initial_settings.connection.packet_size as _,
+        initial_settings
+            .connection
+            .forward_error_correction
+            .clone()
+            .into_option()
+            .filter(|_| supports(FEATURE_SHARD_FEC)),
+        initial_settings
+            .connection
+            .shard_retransmission
+            .as_option()
+            .filter(|_| supports(FEATURE_SHARD_RETRANSMISSION))
+            .map(|config| Duration::from_secs_f32(config.deadline_frames / fps)),
+        (initial_settings.connection.stream_encryption && supports(FEATURE_STREAM_ENCRYPTION))
+            .then_some(session_keys.stream),
     )?;
+    stream_socket.carry_control(&mut control_sender, &mut control_receiver);
+
+    let opus_config = initial_settings
+        .audio
+        .opus
+        .as_option()
+        .filter(|_| supports(FEATURE_OPUS_AUDIO));
+    let game_audio_codec = alvr_audio::AudioCodec::select(
+        opus_config.map(|config| config.game_audio_bitrate_kbps),
+        game_audio_sample_rate,
+    );
+    let microphone_codec = alvr_audio::AudioCodec::select(
+        opus_config.map(|config| config.microphone_bitrate_kbps),
+        streaming_caps.microphone_sample_rate,
+    );
 
     let mut video_sender = stream_socket.request_stream(VIDEO);
-    let game_audio_sender: alvr_sockets::StreamSender<()> = stream_socket.request_stream(AUDIO);
-    let mut microphone_receiver: alvr_sockets::StreamReceiver<()> =
-        stream_socket.subscribe_to_stream(AUDIO, MAX_UNREAD_PACKETS);
-    let tracking_receiver =
-        stream_socket.subscribe_to_stream::<TrackingData>(TRACKING, MAX_UNREAD_PACKETS);
+    let mut game_audio_sender: alvr_sockets::StreamSender<()> = stream_socket.request_stream(AUDIO);
+    let mut microphone_receiver: alvr_sockets::StreamReceiver<()> = stream_socket
+        .subscribe_to_stream(AUDIO, MAX_UNREAD_PACKETS, MAX_AUDIO_PACKET_SIZE)
+        .to_con()?;
+    if !is_spectator {
+        // The audio streams are tapped to be added to the video recordings
+        let audio_config = &initial_settings.audio;
+        *ctx.game_audio_format.lock() = audio_config.game_audio.enabled().then_some(AudioFormat {
+            sample_rate: game_audio_sample_rate,
+            channels_count: 2,
+        });
+        *ctx.microphone_format.lock() = audio_config.microphone.enabled().then_some(AudioFormat {
+            sample_rate: streaming_caps.microphone_sample_rate,
+            channels_count: 1,
+        });
+
+        // The recordings are uncompressed, the taps have their own decoders
+        let game_audio_decoder =
+            alvr_audio::AudioDecoder::new(game_audio_codec, game_audio_sample_rate, 2).to_con()?;
+        let microphone_decoder = alvr_audio::AudioDecoder::new(
+            microphone_codec,
+            streaming_caps.microphone_sample_rate,
+            1,
+        )
+        .to_con()?;
+
+        game_audio_sender.set_tap(Some(Arc::new({
+            let ctx = Arc::clone(&ctx);
+            let decoder = Mutex::new(game_audio_decoder);
+            move |packet: &[u8]| {
+                send_spectator_audio(&ctx, game_audio_codec, packet);
+
+                // Avoids decoding the packets while not recording
+                if crate::is_recording_audio(&ctx, AudioSource::Game)
+                    && let Ok(samples) = decoder.lock().decode_to_pcm(packet)
+                {
+                    crate::record_audio(&ctx, AudioSource::Game, &samples)
+                }
+            }
+        })));
+        microphone_receiver.set_tap(Some(Arc::new({
+            let ctx = Arc::clone(&ctx);
+            let decoder = Mutex::new(microphone_decoder);
+            move |packet: &[u8]| {
+                // Avoids decoding the packets while not recording
+                if crate::is_recording_audio(&ctx, AudioSource::Microphone)
+                    && let Ok(samples) = decoder.lock().decode_to_pcm(packet)
+                {
+                    crate::record_audio(&ctx, AudioSource::Microphone, &samples)
+                }
+            }
+        })));
+    }
+    let tracking_receiver = stream_socket
+        .subscribe_to_stream::<TrackingData>(TRACKING, MAX_UNREAD_PACKETS, MAX_TRACKING_PACKET_SIZE)
+        .to_con()?;
     let haptics_sender = stream_socket.request_stream(HAPTICS);
-    let mut statics_receiver =
-        stream_socket.subscribe_to_stream::<ClientStatistics>(STATISTICS, MAX_UNREAD_PACKETS);
+    let mut statics_receiver = stream_socket
+        .subscribe_to_stream::<ClientStatistics>(
+            STATISTICS,
+            MAX_UNREAD_PACKETS,
+            MAX_STATISTICS_PACKET_SIZE,
+        )
+        .to_con()?;
 
     let (video_channel_sender, video_channel_receiver) =
         std::sync::mpsc::sync_channel(initial_settings.connection.max_queued_server_video_frames);
-    *ctx.video_channel_sender.lock() = Some(video_channel_sender);
-    *ctx.haptics_sender.lock() = Some(haptics_sender);
+    if is_spectator {
+        ctx.spectator_video_senders.lock().insert(
+            client_hostname.clone(),
+            SpectatorVideoSender {
+                sender: video_channel_sender,
+                waiting_for_idr: true,
+            },
+        );
+        ctx.events_sender.send(ServerCoreEvent::RequestIDR).ok();
+    } else {
+        *ctx.video_channel_sender.lock() = Some(video_channel_sender);
+        *ctx.haptics_sender.lock() = Some(haptics_sender);
+
+        // The replay thread cannot be joined here, it could be waiting for the session lock
+        ctx.is_replaying_tracking.set(false);
+    }
 
     let video_send_thread = thread::spawn({
         let ctx = Arc::clone(&ctx);
+        let disconnect_notif = Arc::clone(&disconnect_notif);
         let client_hostname = client_hostname.clone();
         move || {
             while is_streaming(&client_hostname) {
@@ -840,7 +1201,12 @@ fn connection_pipeline(
                 } = match video_channel_receiver.recv_timeout(STREAMING_RECV_TIMEOUT) {
                     Ok(packet) => packet,
                     Err(RecvTimeoutError::Timeout) => continue,
-                    Err(RecvTimeoutError::Disconnected) => return,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        // Spectators are disconnected together with the main client
+                        disconnect_notif.notify_one();
+
+                        return;
+                    }
                 };
 
                 ctx.tracking_manager
@@ -866,6 +1232,7 @@ fn connection_pipeline(
 
         let client_hostname = client_hostname.clone();
         thread::spawn(move || {
+            // Spectators capture the game audio separately, without changing the default devices
             #[cfg(not(target_os = "linux"))]
             while is_streaming(&client_hostname) {
                 {
@@ -879,20 +1246,23 @@ fn connection_pipeline(
                     };
 
                     #[cfg(windows)]
-                    if let Ok(id) = alvr_audio::get_windows_device_id(&device) {
-                        let prop = alvr_session::OpenvrProperty {
-                            key: alvr_session::OpenvrPropKey::AudioDefaultPlaybackDeviceIdString,
-                            value: id,
+                    if !is_spectator {
+                        if let Ok(id) = alvr_audio::get_windows_device_id(&device) {
+                            let prop = alvr_session::OpenvrProperty {
+                                key:
+                                    alvr_session::OpenvrPropKey::AudioDefaultPlaybackDeviceIdString,
+                                value: id,
+                            };
+                            ctx.events_sender
+                                .send(ServerCoreEvent::SetOpenvrProperty {
+                                    device_id: *alvr_common::HEAD_ID,
+                                    prop,
+                                })
+                                .ok();
+                        } else {
+                            continue;
                         };
-                        ctx.events_sender
-                            .send(ServerCoreEvent::SetOpenvrProperty {
-                                device_id: *alvr_common::HEAD_ID,
-                                prop,
-                            })
-                            .ok();
-                    } else {
-                        continue;
-                    };
+                    }
 
                     if let Err(e) = alvr_audio::record_audio_blocking(
                         Arc::new({
@@ -902,14 +1272,17 @@ fn connection_pipeline(
                         game_audio_sender.clone(),
                         &device,
                         2,
-                        config.mute_when_streaming,
+                        game_audio_sample_rate,
+                        config.mute_when_streaming && !is_spectator,
+                        game_audio_codec,
                     ) {
                         error!("Audio record error: {e:?}");
                     }
 
                     #[cfg(windows)]
-                    if let Ok(id) = alvr_audio::new_output(None)
-                        .and_then(|d| alvr_audio::get_windows_device_id(&d))
+                    if !is_spectator
+                        && let Ok(id) = alvr_audio::new_output(None)
+                            .and_then(|d| alvr_audio::get_windows_device_id(&d))
                     {
                         let prop = alvr_session::OpenvrProperty {
                             key: alvr_session::OpenvrPropKey::AudioDefaultPlaybackDeviceIdString,
@@ -930,8 +1303,8 @@ fn connection_pipeline(
     };
 
     #[cfg(not(target_os = "linux"))]
-    let microphone_thread = if let Switch::Enabled(config) =
-        initial_settings.audio.microphone.clone()
+    let microphone_thread = if !is_spectator
+        && let Switch::Enabled(config) = initial_settings.audio.microphone.clone()
     {
         #[allow(unused_variables)]
         let (sink, source) = alvr_audio::new_virtual_microphone_pair(config.devices).to_con()?;
@@ -961,6 +1334,7 @@ fn connection_pipeline(
                 streaming_caps.microphone_sample_rate,
                 config.buffering,
                 &mut microphone_receiver,
+                microphone_codec,
             ));
         })
     } else {
@@ -970,11 +1344,25 @@ fn connection_pipeline(
     #[cfg(target_os = "linux")]
     let microphone_thread = {
         use alvr_audio::linux::{self, AudioInfo};
-        let mic = if let Switch::Enabled(config) = initial_settings.audio.microphone.clone() {
+        // The virtual devices cannot be shared with the main client, which forwards its game audio
+        if is_spectator && initial_settings.audio.game_audio.enabled() {
+            ctx.spectator_audio_senders.lock().insert(
+                client_hostname.clone(),
+                SpectatorAudioSender {
+                    sender: game_audio_sender.clone(),
+                    codec: game_audio_codec,
+                },
+            );
+        }
+
+        let mic = if !is_spectator
+            && let Switch::Enabled(config) = initial_settings.audio.microphone.clone()
+        {
             Some((
                 AudioInfo {
                     sample_rate: streaming_caps.microphone_sample_rate,
                     channel_count: 1,
+                    codec: microphone_codec,
                 },
                 config.buffering,
             ))
@@ -982,13 +1370,11 @@ fn connection_pipeline(
             None
         };
 
-        let audio_info = initial_settings
-            .audio
-            .game_audio
-            .enabled()
-            .then_some(AudioInfo {
+        let audio_info =
+            (!is_spectator && initial_settings.audio.game_audio.enabled()).then_some(AudioInfo {
                 sample_rate: game_audio_sample_rate,
                 channel_count: 2,
+                codec: game_audio_codec,
             });
 
         if mic.is_some() || audio_info.is_some() {
@@ -1010,60 +1396,70 @@ fn connection_pipeline(
         }
     };
 
-    *ctx.tracking_manager.write() =
-        TrackingManager::new(initial_settings.connection.statistics_history_size);
     let hand_gesture_manager = Arc::new(Mutex::new(HandGestureManager::new()));
 
-    let tracking_receive_thread = thread::spawn({
-        let ctx = Arc::clone(&ctx);
-        let hand_gesture_manager = Arc::clone(&hand_gesture_manager);
-        let initial_settings = initial_settings.clone();
-        let client_hostname = client_hostname.clone();
-        move || {
-            tracking::tracking_loop(
-                &ctx,
-                initial_settings,
-                hand_gesture_manager,
-                tracking_receiver,
-                || is_streaming(&client_hostname),
-            );
-        }
-    });
+    // The tracking and statistics of spectators are ignored
+    let tracking_receive_thread = if is_spectator {
+        thread::spawn(|| ())
+    } else {
+        *ctx.tracking_manager.write() =
+            TrackingManager::new(initial_settings.connection.statistics_history_size);
 
-    let statistics_thread = thread::spawn({
-        let ctx = Arc::clone(&ctx);
-        let client_hostname = client_hostname.clone();
-        move || {
-            while is_streaming(&client_hostname) {
-                let data = match statics_receiver.recv(STREAMING_RECV_TIMEOUT) {
-                    Ok(stats) => stats,
-                    Err(ConnectionError::TryAgain(_)) => continue,
-                    Err(ConnectionError::Other(_)) => return,
-                };
-                let Ok(client_stats) = data.get_header() else {
-                    return;
-                };
+        thread::spawn({
+            let ctx = Arc::clone(&ctx);
+            let hand_gesture_manager = Arc::clone(&hand_gesture_manager);
+            let initial_settings = initial_settings.clone();
+            let client_hostname = client_hostname.clone();
+            move || {
+                tracking::tracking_loop(
+                    &ctx,
+                    initial_settings,
+                    hand_gesture_manager,
+                    tracking_receiver,
+                    || is_streaming(&client_hostname),
+                );
+            }
+        })
+    };
 
-                if let Some(stats) = &mut *ctx.statistics_manager.write() {
-                    let timestamp = client_stats.target_timestamp;
-                    let decoder_latency = client_stats.video_decode;
-                    let (network_latency, game_latency) = stats.report_statistics(client_stats);
+    let statistics_thread = if is_spectator {
+        thread::spawn(|| ())
+    } else {
+        thread::spawn({
+            let ctx = Arc::clone(&ctx);
+            let client_hostname = client_hostname.clone();
+            move || {
+                while is_streaming(&client_hostname) {
+                    let data = match statics_receiver.recv(STREAMING_RECV_TIMEOUT) {
+                        Ok(stats) => stats,
+                        Err(ConnectionError::TryAgain(_)) => continue,
+                        Err(ConnectionError::Other(_)) => return,
+                    };
+                    let Ok(client_stats) = data.get_header() else {
+                        return;
+                    };
 
-                    ctx.events_sender
-                        .send(ServerCoreEvent::GameRenderLatencyFeedback(game_latency))
-                        .ok();
+                    if let Some(stats) = &mut *ctx.statistics_manager.write() {
+                        let timestamp = client_stats.target_timestamp;
+                        let decoder_latency = client_stats.video_decode;
+                        let (network_latency, game_latency) = stats.report_statistics(client_stats);
 
-                    let session_manager_lock = SESSION_MANAGER.read();
-                    ctx.bitrate_manager.lock().report_frame_latencies(
-                        &session_manager_lock.settings().video.bitrate.mode,
-                        timestamp,
-                        network_latency,
-                        decoder_latency,
-                    );
+                        ctx.events_sender
+                            .send(ServerCoreEvent::GameRenderLatencyFeedback(game_latency))
+                            .ok();
+
+                        let session_manager_lock = SESSION_MANAGER.read();
+                        ctx.bitrate_manager.lock().report_frame_latencies(
+                            &session_manager_lock.settings().video.bitrate.mode,
+                            timestamp,
+                            network_latency,
+                            decoder_latency,
+                        );
+                    }
                 }
             }
-        }
-    });
+        })
+    };
 
     let control_sender = Arc::new(Mutex::new(control_sender));
 
@@ -1122,28 +1518,16 @@ fn connection_pipeline(
             .headset
             .controllers
             .as_option();
-        let mut controller_button_mapping_manager = controllers_config.map(|config| {
-            if let Some(mappings) = &config.button_mappings {
-                ButtonMappingManager::new_manual(mappings)
-            } else {
-                ButtonMappingManager::new_automatic(
-                    &CONTROLLER_PROFILE_INFO
-                        .get(&alvr_common::hash_string(QUEST_CONTROLLER_PROFILE_PATH))
-                        .unwrap()
-                        .button_set,
-                    &config.emulation_mode,
-                    &config.button_mapping_config,
-                )
-            }
-        });
-        let controllers_emulation_mode =
-            controllers_config.map(|config| config.emulation_mode.clone());
+        let mut controller_button_mapping_manager =
+            controllers_config.map(ButtonMappingManager::new_default);
 
         let disconnect_notif = Arc::clone(&disconnect_notif);
         let control_sender = Arc::clone(&control_sender);
         let client_hostname = client_hostname.clone();
         move || {
             let mut disconnection_deadline = Instant::now() + KEEPALIVE_TIMEOUT;
+            // Gauge value and plugged state of each device
+            let mut battery_states = HashMap::new();
             while is_streaming(&client_hostname) {
                 let packet = match control_receiver.recv(STREAMING_RECV_TIMEOUT) {
                     Ok(packet) => packet,
@@ -1161,6 +1545,19 @@ fn connection_pipeline(
                     }
                 };
 
+                // Spectators can only request IDR frames
+                if is_spectator
+                    && !matches!(
+                        packet,
+                        ClientControlPacket::RequestIdr
+                            | ClientControlPacket::Log { .. }
+                            | ClientControlPacket::KeepAlive
+                    )
+                {
+                    disconnection_deadline = Instant::now() + KEEPALIVE_TIMEOUT;
+                    continue;
+                }
+
                 match packet {
                     ClientControlPacket::PlayspaceSync(packet) => {
                         if !initial_settings.headset.tracking_ref_only {
@@ -1205,6 +1602,19 @@ fn connection_pipeline(
                             .send(ServerCoreEvent::Battery(packet.clone()))
                             .ok();
 
+                        // Events are logged, so they are sent only when the state changes
+                        let state = (packet.gauge_value, packet.is_plugged);
+                        if battery_states.insert(packet.device_id, state) != Some(state) {
+                            alvr_events::send_event(EventType::Battery(BatteryEvent {
+                                device_path: DEVICE_ID_TO_PATH.get(&packet.device_id).map_or_else(
+                                    || format!("Unknown (ID: {:#16x})", packet.device_id),
+                                    |p| (*p).to_owned(),
+                                ),
+                                gauge_value: packet.gauge_value,
+                                is_plugged: packet.is_plugged,
+                            }));
+                        }
+
                         if let Some(stats) = &mut *ctx.statistics_manager.write() {
                             stats.report_battery(
                                 packet.device_id,
@@ -1214,6 +1624,16 @@ fn connection_pipeline(
                         }
                     }
                     ClientControlPacket::Buttons(entries) => {
+                        {
+                            let mut recorder_lock = ctx.tracking_recorder.lock();
+                            if let Some(recorder) = &mut *recorder_lock
+                                && let Err(e) = recorder.record_buttons(&entries)
+                            {
+                                warn!("Failed to record buttons, stopping: {e}");
+                                *recorder_lock = None;
+                            }
+                        }
+
                         {
                             let session_manager_lock = SESSION_MANAGER.read();
                             if session_manager_lock
@@ -1250,24 +1670,31 @@ fn connection_pipeline(
                             }
                         };
                     }
-                    ClientControlPacket::ActiveInteractionProfile { input_ids, .. } => {
-                        controller_button_mapping_manager = if let Switch::Enabled(config) =
-                            &SESSION_MANAGER.read().settings().headset.controllers
+                    ClientControlPacket::ActiveInteractionProfile {
+                        device_id,
+                        profile_id,
+                        input_ids,
+                    } => {
                         {
-                            if let Some(mappings) = &config.button_mappings {
-                                Some(ButtonMappingManager::new_manual(mappings))
-                            } else {
-                                controllers_emulation_mode.as_ref().map(|emulation_mode| {
-                                    ButtonMappingManager::new_automatic(
-                                        &input_ids,
-                                        emulation_mode,
-                                        &config.button_mapping_config,
-                                    )
-                                })
+                            let mut recorder_lock = ctx.tracking_recorder.lock();
+                            if let Some(recorder) = &mut *recorder_lock
+                                && let Err(e) = recorder
+                                    .record_interaction_profile(device_id, profile_id, &input_ids)
+                            {
+                                warn!("Failed to record interaction profile, stopping: {e}");
+                                *recorder_lock = None;
                             }
-                        } else {
-                            None
-                        };
+                        }
+
+                        controller_button_mapping_manager = SESSION_MANAGER
+                            .read()
+                            .settings()
+                            .headset
+                            .controllers
+                            .as_option()
+                            .map(|config| {
+                                ButtonMappingManager::new_for_profile(config, &input_ids)
+                            });
                     }
                     ClientControlPacket::Log { level, message } => {
                         info!("Client {client_hostname}: [{level:?}] {message}")
@@ -1321,7 +1748,7 @@ fn connection_pipeline(
         }
     });
 
-    {
+    if !is_spectator {
         if initial_settings.connection.enable_on_connect_script {
             let on_connect_script = FILESYSTEM_LAYOUT.get().map(|l| l.connect_script()).unwrap();
             info!(
@@ -1337,39 +1764,63 @@ fn connection_pipeline(
         }
     }
 
-    if initial_settings.extra.capture.startup_video_recording {
+    if !is_spectator && initial_settings.extra.capture.startup_video_recording {
         info!("Creating recording file");
         crate::create_recording_file(&ctx, session_manager_lock.settings());
     }
 
+    if !is_spectator && initial_settings.extra.capture.startup_tracking_recording {
+        crate::create_tracking_recording_file(&ctx);
+    }
+
     session_manager_lock.update_client_list(
         client_hostname.clone(),
         ClientListAction::SetConnectionState(ConnectionState::Streaming),
     );
 
-    ctx.events_sender
-        .send(ServerCoreEvent::ClientConnected)
-        .ok();
+    if !is_spectator {
+        ctx.events_sender
+            .send(ServerCoreEvent::ClientConnected)
+            .ok();
+
+        alvr_events::send_event(EventType::StreamingStarted {
+            hostname: client_hostname.clone(),
+        });
+    }
 
     dbg_connection!("connection_pipeline: handshake finished; unlocking streams");
     alvr_common::wait_rwlock(&disconnect_notif, &mut session_manager_lock);
     dbg_connection!("connection_pipeline: Begin connection shutdown");
 
     // This requests shutdown from threads
-    *ctx.video_channel_sender.lock() = None;
-    *ctx.haptics_sender.lock() = None;
-
-    *ctx.video_recording_file.lock() = None;
+    if is_spectator {
+        ctx.spectator_video_senders.lock().remove(&client_hostname);
+        ctx.spectator_audio_senders.lock().remove(&client_hostname);
+    } else {
+        *ctx.video_channel_sender.lock() = None;
+        *ctx.haptics_sender.lock() = None;
+
+        // This also disconnects the spectators
+        ctx.spectator_video_senders.lock().clear();
+        ctx.spectator_audio_senders.lock().clear();
+
+        *ctx.video_recorder.lock() = None;
+        *ctx.tracking_recorder.lock() = None;
+        *ctx.statistics_recorder.lock() = None;
+        *ctx.game_audio_format.lock() = None;
+        *ctx.microphone_format.lock() = None;
+    }
 
     session_manager_lock.update_client_list(
-        client_hostname,
+        client_hostname.clone(),
         ClientListAction::SetConnectionState(ConnectionState::Disconnecting),
     );
 
-    let enable_on_disconnect_script = session_manager_lock
-        .settings()
-        .connection
-        .enable_on_disconnect_script;
+    let enable_on_disconnect_script = !is_spectator
+        && session_manager_lock
+            .settings()
+            .connection
+            .enable_on_disconnect_script;
     if enable_on_disconnect_script {
         let on_disconnect_script = FILESYSTEM_LAYOUT
             .get()
@@ -1403,9 +1854,15 @@ fn connection_pipeline(
     keepalive_thread.join().ok();
     lifecycle_check_thread.join().ok();
 
-    ctx.events_sender
-        .send(ServerCoreEvent::ClientDisconnected)
-        .ok();
+    if !is_spectator {
+        ctx.events_sender
+            .send(ServerCoreEvent::ClientDisconnected)
+            .ok();
+
+        alvr_events::send_event(EventType::StreamingStopped {
+            hostname: client_hostname,
+        });
+    }
 
     dbg_connection!("connection_pipeline: End");
 
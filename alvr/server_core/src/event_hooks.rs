@@ -0,0 +1,298 @@
+// Runs the scripts and webhooks configured in the settings when an event happens. All events are
+// forwarded by the logging backend, so any of them can trigger a hook.
+
+use crate::{SESSION_MANAGER, logging_backend::LOGGING_EVENTS_SENDER};
+use alvr_common::{
+    HEAD_PATH,
+    anyhow::{Result, bail},
+    info, warn,
+};
+use alvr_events::{Event, EventType};
+use alvr_session::{EventHook, EventHookAction, EventHookTrigger};
+use hyper::header::CONTENT_TYPE;
+use reqwest::{Url, redirect::Policy};
+use serde_json as json;
+use std::{cell::OnceCell, net::IpAddr, process::Stdio};
+use tokio::{io::AsyncWriteExt, process::Command, sync::broadcast::error::RecvError};
+
+fn event_id(event_type: &EventType) -> Option<String> {
+    json::to_value(event_type)
+        .ok()?
+        .get("id")?
+        .as_str()
+        .map(str::to_owned)
+}
+
+// Some triggers depend on a condition instead of a single event. They fire only when the
+// condition becomes true. Returns None if the event is not related to the trigger.
+fn trigger_condition(trigger: &EventHookTrigger, event_type: &EventType) -> Option<bool> {
+    match (trigger, event_type) {
+        (EventHookTrigger::BatteryLow { threshold_percent }, EventType::Battery(battery))
+            if battery.device_path == HEAD_PATH =>
+        {
+            Some(!battery.is_plugged && battery.gauge_value * 100.0 < *threshold_percent as f32)
+        }
+        (EventHookTrigger::BitrateLimiterActive, EventType::GraphStatistics(statistics)) => {
+            let directives = &statistics.bitrate_directives;
+
+            Some(
+                [
+                    directives.network_latency_limiter_bps,
+                    directives.encoder_latency_limiter_bps,
+                    directives.decoder_latency_limiter_bps,
+                ]
+                .into_iter()
+                .flatten()
+                .any(|limit_bps| limit_bps <= directives.requested_bitrate_bps),
+            )
+        }
+        _ => None,
+    }
+}
+
+// The event ID is computed only once per event, and only if needed
+fn is_triggered(
+    trigger: &EventHookTrigger,
+    event_type: &EventType,
+    cached_event_id: &OnceCell<Option<String>>,
+) -> bool {
+    match trigger {
+        EventHookTrigger::StreamingStarted => {
+            matches!(event_type, EventType::StreamingStarted { .. })
+        }
+        EventHookTrigger::StreamingStopped => {
+            matches!(event_type, EventType::StreamingStopped { .. })
+        }
+        EventHookTrigger::ClientDiscovered => {
+            matches!(event_type, EventType::ClientDiscovered { .. })
+        }
+        EventHookTrigger::SteamvrRestart => {
+            matches!(event_type, EventType::ServerRequestsSelfRestart)
+        }
+        // Failures are logged, hooks on log events would trigger themselves
+        EventHookTrigger::EventId(id) => {
+            !matches!(event_type, EventType::Log(_))
+                && cached_event_id
+                    .get_or_init(|| event_id(event_type))
+                    .as_ref()
+                    .is_some_and(|event_id| event_id == id)
+        }
+        EventHookTrigger::BatteryLow { .. } | EventHookTrigger::BitrateLimiterActive => false,
+    }
+}
+
+fn is_local_url(url: &Url) -> bool {
+    let host = url.host_str().unwrap_or_default();
+
+    // IPv6 hosts are enclosed in brackets
+    host.eq_ignore_ascii_case("localhost")
+        || host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+async fn run_action(action: EventHookAction, event_json: String) -> Result<()> {
+    match action {
+        EventHookAction::Script(path) => {
+            let mut child = Command::new(&path).stdin(Stdio::piped()).spawn()?;
+
+            if let Some(mut stdin) = child.stdin.take() {
+                // The script may not read the event
+                stdin.write_all(event_json.as_bytes()).await.ok();
+            }
+
+            let status = child.wait().await?;
+            if !status.success() {
+                warn!("Event hook script {path} exited with {status}");
+            }
+        }
+        EventHookAction::Webhook {
+            url,
+            allow_remote_host,
+        } => {
+            let parsed_url = Url::parse(&url)?;
+            if !allow_remote_host && !is_local_url(&parsed_url) {
+                bail!("Webhook {url} is not on this PC and remote hosts are not allowed");
+            }
+
+            // A local server could redirect the request to a remote host
+            let redirect_policy = if allow_remote_host {
+                Policy::default()
+            } else {
+                Policy::none()
+            };
+            let response = reqwest::Client::builder()
+                .redirect(redirect_policy)
+                .build()?
+                .post(parsed_url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(event_json)
+                .send()
+                .await?;
+
+            if !response.status().is_success() {
+                warn!("Event hook webhook {url} replied {}", response.status());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn run_hook(action: EventHookAction, event: &Event) {
+    let event_json = json::to_string(event).unwrap();
+    info!("Running event hook for {}", event.event_type_string());
+
+    tokio::spawn(async move {
+        if let Err(e) = run_action(action, event_json).await {
+            warn!("Event hook failed: {e}");
+        }
+    });
+}
+
+// Updates the state of the condition of the hook. Returns true if the condition became true
+fn update_condition(
+    conditions: &mut Vec<(EventHook, bool)>,
+    hook: &EventHook,
+    new_condition: bool,
+) -> bool {
+    let condition = match conditions.iter_mut().position(|(h, _)| h == hook) {
+        Some(idx) => &mut conditions[idx].1,
+        None => {
+            conditions.push((hook.clone(), false));
+            &mut conditions.last_mut().unwrap().1
+        }
+    };
+    let rising = new_condition && !*condition;
+    *condition = new_condition;
+
+    rising
+}
+
+pub async fn event_hooks_loop() {
+    let mut events_receiver = LOGGING_EVENTS_SENDER.subscribe();
+
+    // Refreshed when the session is updated, so that the session is not locked for every event
+    let mut hooks = SESSION_MANAGER
+        .read()
+        .settings()
+        .connection
+        .event_hooks
+        .clone();
+
+    // State of the conditions. The hook configuration is used as the ID, so that the state follows
+    // the hook when other hooks are added, removed or reordered
+    let mut conditions: Vec<(EventHook, bool)> = vec![];
+
+    loop {
+        let event = match events_receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        };
+
+        if let EventType::Session(_) = &event.event_type {
+            hooks = SESSION_MANAGER
+                .read()
+                .settings()
+                .connection
+                .event_hooks
+                .clone();
+            conditions.retain(|(hook, _)| hooks.contains(hook));
+        }
+
+        let cached_event_id = OnceCell::new();
+        for hook in &hooks {
+            let triggered =
+                if let Some(new_condition) = trigger_condition(&hook.trigger, &event.event_type) {
+                    update_condition(&mut conditions, hook, new_condition)
+                } else {
+                    is_triggered(&hook.trigger, &event.event_type, &cached_event_id)
+                };
+
+            if triggered {
+                run_hook(hook.action.clone(), &event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alvr_common::HAND_LEFT_PATH;
+    use alvr_events::BatteryEvent;
+
+    fn battery(device_path: &str, gauge_value: f32, is_plugged: bool) -> EventType {
+        EventType::Battery(BatteryEvent {
+            device_path: device_path.into(),
+            gauge_value,
+            is_plugged,
+        })
+    }
+
+    #[test]
+    fn test_battery_low_condition() {
+        let trigger = EventHookTrigger::BatteryLow {
+            threshold_percent: 20,
+        };
+
+        assert_eq!(
+            trigger_condition(&trigger, &battery(HEAD_PATH, 0.1, false)),
+            Some(true)
+        );
+        assert_eq!(
+            trigger_condition(&trigger, &battery(HEAD_PATH, 0.3, false)),
+            Some(false)
+        );
+        assert_eq!(
+            trigger_condition(&trigger, &battery(HEAD_PATH, 0.1, true)),
+            Some(false)
+        );
+
+        // Only the headset battery is checked
+        assert_eq!(
+            trigger_condition(&trigger, &battery(HAND_LEFT_PATH, 0.1, false)),
+            None
+        );
+        assert_eq!(
+            trigger_condition(&trigger, &EventType::ServerRequestsSelfRestart),
+            None
+        );
+    }
+
+    #[test]
+    fn test_condition_rising_edge() {
+        let hook = EventHook {
+            trigger: EventHookTrigger::BatteryLow {
+                threshold_percent: 20,
+            },
+            action: EventHookAction::Script("hook.sh".into()),
+        };
+        let mut conditions = vec![];
+
+        assert!(!update_condition(&mut conditions, &hook, false));
+        assert!(update_condition(&mut conditions, &hook, true));
+        assert!(!update_condition(&mut conditions, &hook, true));
+        assert!(!update_condition(&mut conditions, &hook, false));
+        assert!(update_condition(&mut conditions, &hook, true));
+        assert_eq!(conditions.len(), 1);
+    }
+
+    #[test]
+    fn test_is_local_url() {
+        let is_local = |url| is_local_url(&Url::parse(url).unwrap());
+
+        assert!(is_local("http://localhost:8080/hook"));
+        assert!(is_local("http://LocalHost/hook"));
+        assert!(is_local("http://[::1]:8080/hook"));
+        assert!(is_local("http://127.0.0.1/hook"));
+        assert!(is_local("http://127.1.2.3:8080/hook"));
+
+        assert!(!is_local("http://example.com/hook"));
+        assert!(!is_local("http://192.168.1.2/hook"));
+        assert!(!is_local("http://[fe80::1]/hook"));
+    }
+}
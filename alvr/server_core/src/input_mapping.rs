@@ -2,7 +2,7 @@ use alvr_common::*;
 use alvr_packets::{ButtonEntry, ButtonValue};
 use alvr_session::{
     AutomaticButtonMappingConfig, BinaryToScalarStates, ButtonBindingTarget, ButtonMappingType,
-    ControllersEmulationMode, HysteresisThreshold, Range,
+    ControllersConfig, ControllersEmulationMode, HysteresisThreshold, Range,
 };
 use std::collections::{HashMap, HashSet};
 
@@ -577,6 +577,30 @@ impl ButtonMappingManager {
         }
     }
 
+    // Used until the client reports its active interaction profile
+    pub fn new_default(config: &ControllersConfig) -> Self {
+        Self::new_for_profile(
+            config,
+            &CONTROLLER_PROFILE_INFO
+                .get(&alvr_common::hash_string(QUEST_CONTROLLER_PROFILE_PATH))
+                .unwrap()
+                .button_set,
+        )
+    }
+
+    // Used when the client reports the inputs of its active interaction profile
+    pub fn new_for_profile(config: &ControllersConfig, input_ids: &HashSet<u64>) -> Self {
+        if let Some(mappings) = &config.button_mappings {
+            Self::new_manual(mappings)
+        } else {
+            Self::new_automatic(
+                input_ids,
+                &config.emulation_mode,
+                &config.button_mapping_config,
+            )
+        }
+    }
+
     // Apply any button changes that are mapped to this specific button
     pub fn map_button(&mut self, source_button: &ButtonEntry) -> Vec<ButtonEntry> {
         if let ButtonValue::Binary(value) = source_button.value {
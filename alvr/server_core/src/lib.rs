@@ -1,24 +1,28 @@
 mod bitrate;
 mod c_api;
 mod connection;
+mod event_hooks;
 mod hand_gestures;
 mod haptics;
 mod input_mapping;
 mod logging_backend;
+mod metrics;
 mod sockets;
 mod statistics;
 mod tracking;
+mod video_recording;
 mod web_server;
 
 pub use c_api::*;
 pub use logging_backend::init_logging;
 pub use tracking::HandType;
 
-use crate::connection::VideoPacket;
+use crate::connection::{SpectatorAudioSender, SpectatorVideoSender, VideoPacket};
 use alvr_common::{
     ConnectionState, DEVICE_ID_TO_PATH, DeviceMotion, LifecycleState, Pose, RelaxedAtomic,
     ViewParams, dbg_server_core, error,
     glam::Vec2,
+    info,
     parking_lot::{Mutex, RwLock},
     settings_schema::Switch,
     warn,
@@ -33,13 +37,13 @@ use alvr_server_io::ServerSessionManager;
 use alvr_session::{CodecType, OpenvrProperty, Settings};
 use alvr_sockets::StreamSender;
 use bitrate::{BitrateManager, DynamicEncoderParams};
-use statistics::StatisticsManager;
+use statistics::{STATISTICS_RECORDING_EXTENSION, StatisticsManager, StatisticsRecorder};
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     env,
     ffi::OsStr,
-    fs::File,
-    io::Write,
+    fs,
+    path::PathBuf,
     sync::{
         Arc, LazyLock, OnceLock,
         atomic::{AtomicBool, Ordering},
@@ -49,7 +53,10 @@ use std::{
     time::{Duration, Instant},
 };
 use tokio::{runtime::Runtime, sync::broadcast};
-use tracking::TrackingManager;
+use tracking::{
+    TRACKING_RECORDING_EXTENSION, TrackingManager, TrackingRecorder, TrackingRecordingReader,
+};
+use video_recording::{AudioFormat, AudioSource, VIDEO_RECORDING_EXTENSION, VideoRecorder};
 
 static FILESYSTEM_LAYOUT: OnceLock<afs::Layout> = OnceLock::new();
 
@@ -94,37 +101,86 @@ pub enum ServerCoreEvent {
 pub struct ConnectionContext {
     events_sender: mpsc::Sender<ServerCoreEvent>,
     statistics_manager: RwLock<Option<StatisticsManager>>,
+    statistics_recorder: Arc<Mutex<Option<StatisticsRecorder>>>,
     bitrate_manager: Mutex<BitrateManager>,
     tracking_manager: RwLock<TrackingManager>,
     decoder_config: Mutex<Option<DecoderInitializationConfig>>,
     video_mirror_sender: Mutex<Option<broadcast::Sender<Vec<u8>>>>,
-    video_recording_file: Mutex<Option<File>>,
+    video_recorder: Mutex<Option<VideoRecorder>>,
+    // Formats of the audio streams of the connected client, None if disabled
+    game_audio_format: Mutex<Option<AudioFormat>>,
+    microphone_format: Mutex<Option<AudioFormat>>,
+    tracking_recorder: Mutex<Option<TrackingRecorder>>,
+    is_replaying_tracking: RelaxedAtomic,
+    tracking_replay_thread: Mutex<Option<JoinHandle<()>>>,
     connection_threads: Mutex<Vec<JoinHandle<()>>>,
     clients_to_be_removed: Mutex<HashSet<String>>,
     video_channel_sender: Mutex<Option<SyncSender<VideoPacket>>>,
+    // The hashmap key is the hostname
+    spectator_video_senders: Mutex<HashMap<String, SpectatorVideoSender>>,
+    spectator_audio_senders: Mutex<HashMap<String, SpectatorAudioSender>>,
     haptics_sender: Mutex<Option<StreamSender<Haptics>>>,
 }
 
-pub fn create_recording_file(connection_context: &ConnectionContext, settings: &Settings) {
-    let codec = settings.video.preferred_codec;
-    let ext = match codec {
-        CodecType::H264 => "h264",
-        CodecType::Hevc => "h265",
-        CodecType::AV1 => "av1",
-    };
+impl ConnectionContext {
+    fn new(events_sender: mpsc::Sender<ServerCoreEvent>, statistics_history_size: usize) -> Self {
+        Self {
+            events_sender,
+            statistics_manager: RwLock::new(None),
+            statistics_recorder: Arc::new(Mutex::new(None)),
+            bitrate_manager: Mutex::new(BitrateManager::new(256, 60.0)),
+            tracking_manager: RwLock::new(TrackingManager::new(statistics_history_size)),
+            decoder_config: Mutex::new(None),
+            video_mirror_sender: Mutex::new(None),
+            video_recorder: Mutex::new(None),
+            game_audio_format: Mutex::new(None),
+            microphone_format: Mutex::new(None),
+            tracking_recorder: Mutex::new(None),
+            is_replaying_tracking: RelaxedAtomic::new(false),
+            tracking_replay_thread: Mutex::new(None),
+            connection_threads: Mutex::new(Vec::new()),
+            clients_to_be_removed: Mutex::new(HashSet::new()),
+            video_channel_sender: Mutex::new(None),
+            spectator_video_senders: Mutex::new(HashMap::new()),
+            spectator_audio_senders: Mutex::new(HashMap::new()),
+            haptics_sender: Mutex::new(None),
+        }
+    }
+}
 
+pub fn create_recording_file(connection_context: &ConnectionContext, settings: &Settings) {
     let path = FILESYSTEM_LAYOUT.get().unwrap().log_dir.join(format!(
-        "recording.{}.{ext}",
+        "recording.{}.{VIDEO_RECORDING_EXTENSION}",
         chrono::Local::now().format("%F.%H-%M-%S")
     ));
 
-    match File::create(path) {
-        Ok(mut file) => {
-            if let Some(config) = &*connection_context.decoder_config.lock() {
-                file.write_all(&config.config_buffer).ok();
-            }
-
-            *connection_context.video_recording_file.lock() = Some(file);
+    // The codec can differ from the preferred one if the client doesn't support it
+    let (codec, config_buffer) = connection_context
+        .decoder_config
+        .lock()
+        .as_ref()
+        .map(|config| (config.codec, config.config_buffer.clone()))
+        .unwrap_or((settings.video.preferred_codec, vec![]));
+
+    let capture_config = &settings.extra.capture;
+    let game_audio_format = capture_config
+        .record_game_audio
+        .then(|| *connection_context.game_audio_format.lock())
+        .flatten();
+    let microphone_format = capture_config
+        .record_microphone
+        .then(|| *connection_context.microphone_format.lock())
+        .flatten();
+
+    match VideoRecorder::create(
+        &path,
+        codec,
+        config_buffer,
+        game_audio_format,
+        microphone_format,
+    ) {
+        Ok(recorder) => {
+            *connection_context.video_recorder.lock() = Some(recorder);
 
             connection_context
                 .events_sender
@@ -137,6 +193,120 @@ pub fn create_recording_file(connection_context: &ConnectionContext, settings: &
     }
 }
 
+// Called by the audio stream taps
+fn is_recording_audio(connection_context: &ConnectionContext, source: AudioSource) -> bool {
+    connection_context
+        .video_recorder
+        .lock()
+        .as_ref()
+        .is_some_and(|recorder| recorder.is_recording_audio(source))
+}
+
+fn record_audio(connection_context: &ConnectionContext, source: AudioSource, samples: &[u8]) {
+    let mut recorder_lock = connection_context.video_recorder.lock();
+    if let Some(recorder) = &mut *recorder_lock
+        && let Err(e) = recorder.write_audio(source, samples)
+    {
+        error!("Failed to record audio on disk: {e}");
+        *recorder_lock = None;
+    }
+}
+
+pub fn create_statistics_recording_file(connection_context: &ConnectionContext) {
+    let path = FILESYSTEM_LAYOUT.get().unwrap().log_dir.join(format!(
+        "statistics.{}.{STATISTICS_RECORDING_EXTENSION}",
+        chrono::Local::now().format("%F.%H-%M-%S")
+    ));
+
+    match StatisticsRecorder::create(&path) {
+        Ok(recorder) => {
+            info!("Recording statistics to {}", path.display());
+            *connection_context.statistics_recorder.lock() = Some(recorder);
+        }
+        Err(e) => {
+            error!("Failed to record statistics on disk: {e}");
+        }
+    }
+}
+
+pub fn create_tracking_recording_file(connection_context: &ConnectionContext) {
+    let path = FILESYSTEM_LAYOUT.get().unwrap().log_dir.join(format!(
+        "tracking.{}.{TRACKING_RECORDING_EXTENSION}",
+        chrono::Local::now().format("%F.%H-%M-%S")
+    ));
+
+    match TrackingRecorder::create(&path) {
+        Ok(recorder) => {
+            info!("Recording tracking to {}", path.display());
+            *connection_context.tracking_recorder.lock() = Some(recorder);
+        }
+        Err(e) => {
+            error!("Failed to record tracking on disk: {e}");
+        }
+    }
+}
+
+// Replays a tracking recording as if it was received from a client. If no path is specified, the
+// latest recording in the log directory is used.
+pub fn start_tracking_replay(connection_context: Arc<ConnectionContext>, path: Option<PathBuf>) {
+    stop_tracking_replay(&connection_context);
+
+    if connection_context.video_channel_sender.lock().is_some() {
+        warn!("Cannot replay tracking while a client is streaming");
+        return;
+    }
+
+    let Some(path) = path.or_else(|| {
+        fs::read_dir(&FILESYSTEM_LAYOUT.get().unwrap().log_dir)
+            .ok()?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|ext| ext == TRACKING_RECORDING_EXTENSION)
+            })
+            // The file names start with the date
+            .max()
+    }) else {
+        warn!("No tracking recording found");
+        return;
+    };
+
+    let reader = match TrackingRecordingReader::open(&path) {
+        Ok(reader) => reader,
+        Err(e) => {
+            error!("Failed to open tracking recording: {e}");
+            return;
+        }
+    };
+
+    info!("Replaying tracking from {}", path.display());
+
+    connection_context.is_replaying_tracking.set(true);
+    *connection_context.tracking_replay_thread.lock() = Some(thread::spawn({
+        let connection_context = Arc::clone(&connection_context);
+        move || {
+            let settings = SESSION_MANAGER.read().settings().clone();
+
+            if let Err(e) = tracking::replay_tracking(&connection_context, settings, reader, || {
+                connection_context.is_replaying_tracking.value()
+            }) {
+                error!("Tracking replay error: {e}");
+            }
+
+            connection_context.is_replaying_tracking.set(false);
+            info!("Tracking replay finished");
+        }
+    }));
+}
+
+pub fn stop_tracking_replay(connection_context: &ConnectionContext) {
+    connection_context.is_replaying_tracking.set(false);
+
+    if let Some(thread) = connection_context.tracking_replay_thread.lock().take() {
+        thread.join().ok();
+    }
+}
+
 pub fn notify_restart_driver() {
     if sysinfo::System::new_all()
         .processes_by_name(OsStr::new(&afs::dashboard_fname()))
@@ -188,9 +358,14 @@ impl ServerCoreContext {
 
         let (events_sender, events_receiver) = mpsc::channel();
 
-        // Create a temporary StatisticsManager until a headset connects
         let initial_settings = SESSION_MANAGER.read().settings().clone();
-        let stats = StatisticsManager::new(
+        let connection_context = Arc::new(ConnectionContext::new(
+            events_sender,
+            initial_settings.connection.statistics_history_size,
+        ));
+
+        // Create a temporary StatisticsManager until a headset connects
+        *connection_context.statistics_manager.write() = Some(StatisticsManager::new(
             initial_settings.connection.statistics_history_size,
             Duration::from_secs_f32(1.0 / 90.0),
             if let Switch::Enabled(config) = &initial_settings.headset.controllers {
@@ -198,29 +373,15 @@ impl ServerCoreContext {
             } else {
                 0.0
             },
-        );
-
-        let connection_context = Arc::new(ConnectionContext {
-            events_sender,
-            statistics_manager: RwLock::new(Some(stats)),
-            bitrate_manager: Mutex::new(BitrateManager::new(256, 60.0)),
-            tracking_manager: RwLock::new(TrackingManager::new(
-                initial_settings.connection.statistics_history_size,
-            )),
-            decoder_config: Mutex::new(None),
-            video_mirror_sender: Mutex::new(None),
-            video_recording_file: Mutex::new(None),
-            connection_threads: Mutex::new(Vec::new()),
-            clients_to_be_removed: Mutex::new(HashSet::new()),
-            video_channel_sender: Mutex::new(None),
-            haptics_sender: Mutex::new(None),
-        });
+            Arc::clone(&connection_context.statistics_recorder),
+        ));
 
         let webserver_runtime = Runtime::new().unwrap();
         webserver_runtime.spawn({
             let connection_context = Arc::clone(&connection_context);
             async move { alvr_common::show_err(web_server::web_server(connection_context).await) }
         });
+        webserver_runtime.spawn(event_hooks::event_hooks_loop());
 
         (
             Self {
@@ -352,8 +513,8 @@ impl ServerCoreContext {
             sender.send(config_buffer.clone()).ok();
         }
 
-        if let Some(file) = &mut *self.connection_context.video_recording_file.lock() {
-            file.write_all(&config_buffer).ok();
+        if let Some(recorder) = &mut *self.connection_context.video_recorder.lock() {
+            recorder.set_config(codec, config_buffer.clone());
         }
 
         *self.connection_context.decoder_config.lock() = Some(DecoderInitializationConfig {
@@ -418,8 +579,29 @@ impl ServerCoreContext {
                     sender.send(nal_buffer.clone()).ok();
                 }
 
-                if let Some(file) = &mut *self.connection_context.video_recording_file.lock() {
-                    file.write_all(&nal_buffer).ok();
+                {
+                    let mut recorder_lock = self.connection_context.video_recorder.lock();
+                    if let Some(recorder) = &mut *recorder_lock
+                        && let Err(e) =
+                            recorder.write_frame(timestamp, global_view_params, is_idr, &nal_buffer)
+                    {
+                        error!("Failed to record video on disk: {e}");
+                        *recorder_lock = None;
+                    }
+                }
+
+                for spectator in self
+                    .connection_context
+                    .spectator_video_senders
+                    .lock()
+                    .values_mut()
+                {
+                    if !spectator.send(timestamp, global_view_params, is_idr, &nal_buffer) {
+                        self.connection_context
+                            .events_sender
+                            .send(ServerCoreEvent::RequestIDR)
+                            .ok();
+                    }
                 }
 
                 let sender_result = sender.try_send(VideoPacket {
@@ -529,6 +711,8 @@ impl Drop for ServerCoreContext {
         // Invoke connection runtimes shutdown
         *self.lifecycle_state.write() = LifecycleState::ShuttingDown;
 
+        stop_tracking_replay(&self.connection_context);
+
         dbg_server_core!("Setting clients as Disconnecting");
         {
             let mut session_manager_lock = SESSION_MANAGER.write();
@@ -0,0 +1,335 @@
+// Statistics exported in the OpenMetrics text format, scraped by Prometheus from the /metrics
+// endpoint of the web server. Latencies and rates refer to the last frame reported by the client,
+// like the graphs of the dashboard. Counters are cumulative since the client connected.
+
+use crate::{ConnectionContext, SESSION_MANAGER};
+use alvr_common::{ALVR_VERSION, ConnectionState, DEVICE_ID_TO_PATH};
+use std::fmt::Write;
+
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+const CONNECTION_STATES: [ConnectionState; 5] = [
+    ConnectionState::Disconnected,
+    ConnectionState::Connecting,
+    ConnectionState::Connected,
+    ConnectionState::Streaming,
+    ConnectionState::Disconnecting,
+];
+
+#[derive(Default)]
+struct MetricsWriter {
+    text: String,
+}
+
+impl MetricsWriter {
+    // The name of the family must end with the unit, if any. The samples of counters are suffixed
+    // with "_total"
+    fn family(&mut self, name: &str, metric_type: &str, unit: Option<&str>, help: &str) {
+        writeln!(self.text, "# TYPE {name} {metric_type}").ok();
+        if let Some(unit) = unit {
+            writeln!(self.text, "# UNIT {name} {unit}").ok();
+        }
+        writeln!(self.text, "# HELP {name} {help}").ok();
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.text.push_str(name);
+
+        if !labels.is_empty() {
+            self.text.push('{');
+            for (idx, (label, label_value)) in labels.iter().enumerate() {
+                if idx > 0 {
+                    self.text.push(',');
+                }
+                let label_value = label_value
+                    .replace('\\', r"\\")
+                    .replace('"', r#"\""#)
+                    .replace('\n', r"\n");
+                write!(self.text, r#"{label}="{label_value}""#).ok();
+            }
+            self.text.push('}');
+        }
+
+        if value.is_infinite() {
+            let sign = if value > 0.0 { '+' } else { '-' };
+            writeln!(self.text, " {sign}Inf").ok();
+        } else {
+            writeln!(self.text, " {value}").ok();
+        }
+    }
+
+    fn gauge(&mut self, name: &str, unit: Option<&str>, help: &str, value: f64) {
+        self.family(name, "gauge", unit, help);
+        self.sample(name, &[], value);
+    }
+
+    fn counter(&mut self, name: &str, unit: Option<&str>, help: &str, value: u64) {
+        self.family(name, "counter", unit, help);
+        self.sample(&format!("{name}_total"), &[], value as f64);
+    }
+
+    fn finish(mut self) -> String {
+        self.text.push_str("# EOF\n");
+
+        self.text
+    }
+}
+
+pub fn openmetrics_text(connection_context: &ConnectionContext) -> String {
+    let mut writer = MetricsWriter::default();
+
+    writer.family("alvr_build", "info", None, "ALVR version");
+    let version = ALVR_VERSION.to_string();
+    writer.sample("alvr_build_info", &[("version", version.as_str())], 1.0);
+
+    {
+        let session_manager_lock = SESSION_MANAGER.read();
+        let mut clients = session_manager_lock
+            .client_list()
+            .iter()
+            .collect::<Vec<_>>();
+        clients.sort_by_key(|(hostname, _)| *hostname);
+
+        let name = "alvr_client_connection_state";
+        writer.family(
+            name,
+            "stateset",
+            None,
+            "Connection state of each known client",
+        );
+        for (hostname, client) in clients {
+            for state in &CONNECTION_STATES {
+                let state_name = format!("{state:?}");
+                writer.sample(
+                    name,
+                    &[("hostname", hostname.as_str()), (name, state_name.as_str())],
+                    (client.connection_state == *state) as u8 as f64,
+                );
+            }
+        }
+    }
+
+    if let Some(stats) = &*connection_context.statistics_manager.read() {
+        writer.gauge(
+            "alvr_motion_to_photon_latency_average_seconds",
+            Some("seconds"),
+            "Average total latency, from the tracking poll to the display of the frame",
+            stats.motion_to_photon_latency_average().as_secs_f64(),
+        );
+
+        if let Some(graph) = stats.last_graph_statistics() {
+            let name = "alvr_latency_seconds";
+            writer.family(
+                name,
+                "gauge",
+                Some("seconds"),
+                "Latency of each stage of the pipeline for the last frame",
+            );
+            for (stage, latency_s) in [
+                ("total", graph.total_pipeline_latency_s),
+                ("game", graph.game_time_s),
+                ("server_compositor", graph.server_compositor_s),
+                ("encoder", graph.encoder_s),
+                ("network", graph.network_s),
+                ("decoder", graph.decoder_s),
+                ("decoder_queue", graph.decoder_queue_s),
+                ("client_compositor", graph.client_compositor_s),
+                ("vsync_queue", graph.vsync_queue_s),
+            ] {
+                writer.sample(name, &[("stage", stage)], latency_s as f64);
+            }
+
+            writer.gauge(
+                "alvr_client_fps",
+                None,
+                "Frame rate of the client",
+                graph.client_fps as f64,
+            );
+            writer.gauge(
+                "alvr_server_fps",
+                None,
+                "Frame rate of the game",
+                graph.server_fps as f64,
+            );
+            writer.gauge(
+                "alvr_video_bitrate_bits_per_second",
+                None,
+                "Bitrate of the last video frame",
+                graph.bitrate_bps as f64,
+            );
+            writer.gauge(
+                "alvr_network_throughput_bits_per_second",
+                None,
+                "Network throughput estimated from the last video frame",
+                graph.throughput_bps as f64,
+            );
+            writer.gauge(
+                "alvr_video_jitter_seconds",
+                Some("seconds"),
+                "Jitter of the arrival of the video packets on the client",
+                graph.video_jitter_s as f64,
+            );
+
+            let directives = &graph.bitrate_directives;
+            let name = "alvr_bitrate_directive_bits_per_second";
+            writer.family(
+                name,
+                "gauge",
+                None,
+                "Bitrate requested to the encoder and the limits used to compute it",
+            );
+            for (directive, bitrate_bps) in [
+                ("requested", Some(directives.requested_bitrate_bps)),
+                (
+                    "scaled_calculated_throughput",
+                    directives.scaled_calculated_throughput_bps,
+                ),
+                (
+                    "decoder_latency_limiter",
+                    directives.decoder_latency_limiter_bps,
+                ),
+                (
+                    "network_latency_limiter",
+                    directives.network_latency_limiter_bps,
+                ),
+                (
+                    "encoder_latency_limiter",
+                    directives.encoder_latency_limiter_bps,
+                ),
+                (
+                    "manual_max_throughput",
+                    directives.manual_max_throughput_bps,
+                ),
+                (
+                    "manual_min_throughput",
+                    directives.manual_min_throughput_bps,
+                ),
+            ] {
+                if let Some(bitrate_bps) = bitrate_bps {
+                    writer.sample(name, &[("directive", directive)], bitrate_bps as f64);
+                }
+            }
+        }
+
+        writer.counter(
+            "alvr_video_packets_sent",
+            None,
+            "Video packets sent",
+            stats.video_packets_total() as u64,
+        );
+        writer.counter(
+            "alvr_video_sent_bytes",
+            Some("bytes"),
+            "Video data sent",
+            stats.video_bytes_total() as u64,
+        );
+
+        let client_stats = stats.last_client_statistics();
+        writer.counter(
+            "alvr_video_shards_received",
+            None,
+            "Video shards received by the client, including parity shards",
+            client_stats.video_shards_received,
+        );
+        writer.counter(
+            "alvr_video_shards_lost",
+            None,
+            "Video shards of the packets that the client could not recover",
+            client_stats.video_shards_lost,
+        );
+        writer.counter(
+            "alvr_video_packets_reconstructed",
+            None,
+            "Video packets recovered by the client with forward error correction",
+            client_stats.video_packets_reconstructed,
+        );
+        writer.counter(
+            "alvr_video_packets_discarded",
+            None,
+            "Video packets discarded by the client because they could not be read in time",
+            client_stats.video_packets_discarded,
+        );
+
+        let mut battery_gauges = stats
+            .battery_gauges()
+            .map(|(id, gauge_value, is_plugged)| {
+                let path = DEVICE_ID_TO_PATH
+                    .get(&id)
+                    .map(|path| path.to_string())
+                    .unwrap_or_else(|| id.to_string());
+                (path, gauge_value, is_plugged)
+            })
+            .collect::<Vec<_>>();
+        battery_gauges.sort_by(|(a, ..), (b, ..)| a.cmp(b));
+
+        writer.family(
+            "alvr_battery_ratio",
+            "gauge",
+            Some("ratio"),
+            "Battery level of each device",
+        );
+        for (path, gauge_value, _) in &battery_gauges {
+            writer.sample(
+                "alvr_battery_ratio",
+                &[("device", path.as_str())],
+                *gauge_value as f64,
+            );
+        }
+        writer.family(
+            "alvr_battery_plugged",
+            "gauge",
+            None,
+            "1 if the device is charging",
+        );
+        for (path, _, is_plugged) in &battery_gauges {
+            writer.sample(
+                "alvr_battery_plugged",
+                &[("device", path.as_str())],
+                *is_plugged as u8 as f64,
+            );
+        }
+    }
+
+    let estimates = connection_context.bitrate_manager.lock().estimates();
+    writer.gauge(
+        "alvr_bitrate_frame_interval_average_seconds",
+        Some("seconds"),
+        "Average interval between the frames of the game",
+        estimates.frame_interval.as_secs_f64(),
+    );
+    if let Some(packet_bytes) = estimates.packet_bytes {
+        writer.gauge(
+            "alvr_bitrate_frame_size_average_bytes",
+            Some("bytes"),
+            "Average size of the video frames",
+            packet_bytes as f64,
+        );
+    }
+    if let Some(network_latency) = estimates.network_latency {
+        writer.gauge(
+            "alvr_bitrate_network_latency_average_seconds",
+            Some("seconds"),
+            "Average network latency of the video frames",
+            network_latency.as_secs_f64(),
+        );
+    }
+    if let Some(encoder_latency) = estimates.encoder_latency {
+        writer.gauge(
+            "alvr_bitrate_encoder_latency_average_seconds",
+            Some("seconds"),
+            "Average encoder latency",
+            encoder_latency.as_secs_f64(),
+        );
+    }
+    if let Some(max_bytes) = estimates.decoder_max_bytes_per_frame {
+        writer.gauge(
+            "alvr_bitrate_decoder_max_frame_size_bytes",
+            Some("bytes"),
+            "Frame size limit learned by the decoder latency limiter",
+            max_bytes as f64,
+        );
+    }
+
+    writer.finish()
+}
@@ -1,12 +1,27 @@
 use alvr_common::{
     ToAny,
     anyhow::{Result, bail},
-    warn,
+    debug, warn,
 };
 use flume::TryRecvError;
 use mdns_sd::{Receiver, ServiceDaemon, ServiceEvent};
 use std::{collections::HashMap, net::IpAddr};
 
+// Lower is better. IPv4 is preferred since it's the most tested path, then routable IPv6
+// addresses. Link-local IPv6 addresses cannot be used without a scope ID.
+fn address_priority(address: &IpAddr) -> Option<u8> {
+    if address.is_unspecified() || address.is_loopback() {
+        return None;
+    }
+
+    match address {
+        IpAddr::V4(address) if address.is_link_local() => Some(2),
+        IpAddr::V4(_) => Some(0),
+        IpAddr::V6(address) if address.is_unicast_link_local() => None,
+        IpAddr::V6(_) => Some(1),
+    }
+}
+
 pub struct WelcomeSocket {
     mdns_receiver: Receiver<ServiceEvent>,
 }
@@ -29,7 +44,16 @@ impl WelcomeSocket {
                         let hostname = info
                             .get_property_val_str(alvr_sockets::MDNS_DEVICE_ID_KEY)
                             .unwrap_or_else(|| info.get_hostname());
-                        let address = *info.get_addresses().iter().next().to_any()?;
+                        let Some(address) = info
+                            .get_addresses()
+                            .iter()
+                            .filter(|address| address_priority(address).is_some())
+                            .min_by_key(|address| address_priority(address))
+                            .copied()
+                        else {
+                            debug!("Found client {hostname} without usable addresses");
+                            continue;
+                        };
 
                         let client_protocol = info
                             .get_property_val_str(alvr_sockets::MDNS_PROTOCOL_KEY)
@@ -38,7 +62,18 @@ impl WelcomeSocket {
                         let client_is_dev = client_protocol.contains("-dev");
                         let server_is_dev = server_protocol.contains("-dev");
 
-                        if client_protocol != server_protocol {
+                        // Clients that don't advertise a version range must match exactly
+                        let compatible = if let Some((min_version, max_version)) = info
+                            .get_property_val_str(alvr_sockets::MDNS_PROTOCOL_VERSIONS_KEY)
+                            .and_then(alvr_common::parse_protocol_versions)
+                        {
+                            alvr_common::negotiate_protocol_version(min_version, max_version)
+                                .is_some()
+                        } else {
+                            client_protocol == server_protocol
+                        };
+
+                        if !compatible {
                             let reason = if client_is_dev && server_is_dev {
                                 "Please use matching nightly versions."
                             } else if client_is_dev {
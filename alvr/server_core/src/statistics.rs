@@ -1,14 +1,115 @@
-use alvr_common::{HEAD_ID, SlidingWindowAverage};
+use alvr_common::{HEAD_ID, SlidingWindowAverage, anyhow::Result, error, parking_lot::Mutex};
 use alvr_events::{BitrateDirectives, EventType, GraphStatistics, StatisticsSummary};
 use alvr_packets::ClientStatistics;
 use std::{
     collections::{HashMap, VecDeque},
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
 const FULL_REPORT_INTERVAL: Duration = Duration::from_millis(500);
 const EPS_INTERVAL: Duration = Duration::from_micros(1);
 
+pub const STATISTICS_RECORDING_EXTENSION: &str = "csv";
+
+// Durations are in seconds. The bitrate directives are empty when not used. The client counters
+// are cumulative since the start of the stream
+const CSV_COLUMNS: [&str; 27] = [
+    "target_timestamp_s",
+    "total_pipeline_latency_s",
+    "game_time_s",
+    "server_compositor_s",
+    "encoder_s",
+    "network_s",
+    "decoder_s",
+    "decoder_queue_s",
+    "client_compositor_s",
+    "vsync_queue_s",
+    "client_frame_interval_s",
+    "server_fps",
+    "video_packet_bytes",
+    "throughput_bps",
+    "bitrate_bps",
+    "requested_bitrate_bps",
+    "scaled_calculated_throughput_bps",
+    "decoder_latency_limiter_bps",
+    "network_latency_limiter_bps",
+    "encoder_latency_limiter_bps",
+    "manual_max_throughput_bps",
+    "manual_min_throughput_bps",
+    "video_shards_received",
+    "video_shards_lost",
+    "video_packets_reconstructed",
+    "video_packets_discarded",
+    "video_jitter_s",
+];
+
+// Writes one CSV row for each frame with complete statistics
+pub struct StatisticsRecorder {
+    writer: BufWriter<File>,
+}
+
+impl StatisticsRecorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "{}", CSV_COLUMNS.join(","))?;
+
+        Ok(Self { writer })
+    }
+
+    fn write_frame(
+        &mut self,
+        video_packet_bytes: usize,
+        graph: &GraphStatistics,
+        client_stats: &ClientStatistics,
+    ) -> Result<()> {
+        let optional = |value: Option<f32>| value.map(|v| v.to_string()).unwrap_or_default();
+        let directives = &graph.bitrate_directives;
+
+        let fields: [String; CSV_COLUMNS.len()] = [
+            client_stats.target_timestamp.as_secs_f64().to_string(),
+            graph.total_pipeline_latency_s.to_string(),
+            graph.game_time_s.to_string(),
+            graph.server_compositor_s.to_string(),
+            graph.encoder_s.to_string(),
+            graph.network_s.to_string(),
+            graph.decoder_s.to_string(),
+            graph.decoder_queue_s.to_string(),
+            graph.client_compositor_s.to_string(),
+            graph.vsync_queue_s.to_string(),
+            client_stats.frame_interval.as_secs_f32().to_string(),
+            graph.server_fps.to_string(),
+            video_packet_bytes.to_string(),
+            graph.throughput_bps.to_string(),
+            graph.bitrate_bps.to_string(),
+            directives.requested_bitrate_bps.to_string(),
+            optional(directives.scaled_calculated_throughput_bps),
+            optional(directives.decoder_latency_limiter_bps),
+            optional(directives.network_latency_limiter_bps),
+            optional(directives.encoder_latency_limiter_bps),
+            optional(directives.manual_max_throughput_bps),
+            optional(directives.manual_min_throughput_bps),
+            client_stats.video_shards_received.to_string(),
+            client_stats.video_shards_lost.to_string(),
+            client_stats.video_packets_reconstructed.to_string(),
+            client_stats.video_packets_discarded.to_string(),
+            client_stats.video_jitter.as_secs_f32().to_string(),
+        ];
+        writeln!(self.writer, "{}", fields.join(","))?;
+
+        Ok(())
+    }
+}
+
+impl Drop for StatisticsRecorder {
+    fn drop(&mut self) {
+        self.writer.flush().ok();
+    }
+}
+
 pub struct HistoryFrame {
     target_timestamp: Duration,
     tracking_received: Instant,
@@ -56,6 +157,12 @@ pub struct StatisticsManager {
     last_vsync_time: Instant,
     frame_interval: Duration,
     last_throughput_directives: BitrateDirectives,
+    last_client_stats: ClientStatistics,
+    last_graph_statistics: Option<GraphStatistics>,
+    video_shards_received_partial_sum: u64,
+    video_shards_lost_partial_sum: u64,
+    // Owned by the connection context, so a recording started before the client connects is kept
+    recorder: Arc<Mutex<Option<StatisticsRecorder>>>,
 }
 
 impl StatisticsManager {
@@ -64,6 +171,7 @@ impl StatisticsManager {
         max_history_size: usize,
         nominal_server_frame_interval: Duration,
         steamvr_pipeline_frames: f32,
+        recorder: Arc<Mutex<Option<StatisticsRecorder>>>,
     ) -> Self {
         Self {
             history_buffer: VecDeque::new(),
@@ -86,6 +194,11 @@ impl StatisticsManager {
             last_vsync_time: Instant::now(),
             frame_interval: nominal_server_frame_interval,
             last_throughput_directives: BitrateDirectives::default(),
+            last_client_stats: ClientStatistics::default(),
+            last_graph_statistics: None,
+            video_shards_received_partial_sum: 0,
+            video_shards_lost_partial_sum: 0,
+            recorder,
         }
     }
 
@@ -178,6 +291,20 @@ impl StatisticsManager {
         self.motion_to_photon_latency_average
             .submit_sample(client_stats.total_pipeline_latency);
 
+        // The client counters are cumulative. Statistics packets may be lost, so only the
+        // difference with the last received packet is meaningful
+        let video_shards_lost = client_stats
+            .video_shards_lost
+            .saturating_sub(self.last_client_stats.video_shards_lost);
+        let video_packets_discarded = client_stats
+            .video_packets_discarded
+            .saturating_sub(self.last_client_stats.video_packets_discarded);
+        self.video_shards_received_partial_sum += client_stats
+            .video_shards_received
+            .saturating_sub(self.last_client_stats.video_shards_received);
+        self.video_shards_lost_partial_sum += video_shards_lost;
+        self.last_client_stats = client_stats.clone();
+
         if let Some(frame) = self
             .history_buffer
             .iter_mut()
@@ -250,10 +377,22 @@ impl StatisticsManager {
                         .cloned()
                         .unwrap_or_default()
                         .is_plugged,
+                    video_shards_lost_total: client_stats.video_shards_lost,
+                    video_shards_loss_percent: self.video_shards_lost_partial_sum as f32
+                        / u64::max(
+                            self.video_shards_received_partial_sum
+                                + self.video_shards_lost_partial_sum,
+                            1,
+                        ) as f32
+                        * 100.,
+                    video_packets_discarded_total: client_stats.video_packets_discarded,
+                    video_jitter_ms: client_stats.video_jitter.as_secs_f32() * 1000.,
                 }));
 
                 self.video_packets_partial_sum = 0;
                 self.video_bytes_partial_sum = 0;
+                self.video_shards_received_partial_sum = 0;
+                self.video_shards_lost_partial_sum = 0;
             }
 
             let packet_bits = frame.video_packet_bytes as f32 * 8.0;
@@ -264,7 +403,7 @@ impl StatisticsManager {
 
             // todo: use target timestamp in nanoseconds. the dashboard needs to use the first
             // timestamp as the graph time origin.
-            alvr_events::send_event(EventType::GraphStatistics(GraphStatistics {
+            let graph_statistics = GraphStatistics {
                 total_pipeline_latency_s: client_stats.total_pipeline_latency.as_secs_f32(),
                 game_time_s: game_time_latency.as_secs_f32(),
                 server_compositor_s: server_compositor_latency.as_secs_f32(),
@@ -279,7 +418,21 @@ impl StatisticsManager {
                 bitrate_directives: self.last_throughput_directives.clone(),
                 throughput_bps,
                 bitrate_bps,
-            }));
+                video_shards_lost,
+                video_packets_discarded,
+                video_jitter_s: client_stats.video_jitter.as_secs_f32(),
+            };
+            let mut recorder_lock = self.recorder.lock();
+            if let Some(recorder) = &mut *recorder_lock
+                && let Err(e) =
+                    recorder.write_frame(frame.video_packet_bytes, &graph_statistics, &client_stats)
+            {
+                error!("Failed to record statistics on disk: {e}");
+                *recorder_lock = None;
+            }
+
+            self.last_graph_statistics = Some(graph_statistics.clone());
+            alvr_events::send_event(EventType::GraphStatistics(graph_statistics));
 
             (network_latency, game_time_latency)
         } else {
@@ -291,6 +444,29 @@ impl StatisticsManager {
         self.motion_to_photon_latency_average.get_average()
     }
 
+    pub fn last_graph_statistics(&self) -> Option<&GraphStatistics> {
+        self.last_graph_statistics.as_ref()
+    }
+
+    pub fn last_client_statistics(&self) -> &ClientStatistics {
+        &self.last_client_stats
+    }
+
+    pub fn video_packets_total(&self) -> usize {
+        self.video_packets_total
+    }
+
+    pub fn video_bytes_total(&self) -> usize {
+        self.video_bytes_total
+    }
+
+    // Returns (device ID, gauge value, is plugged)
+    pub fn battery_gauges(&self) -> impl Iterator<Item = (u64, f32, bool)> + '_ {
+        self.battery_gauges
+            .iter()
+            .map(|(id, data)| (*id, data.gauge_value, data.is_plugged))
+    }
+
     pub fn tracker_pose_time_offset(&self) -> Duration {
         // This is the opposite of the client's StatisticsManager::tracker_prediction_offset().
         self.steamvr_pipeline_latency
@@ -1,9 +1,11 @@
 mod body;
 mod face;
+mod recording;
 mod vmc;
 
 pub use body::*;
 pub use face::*;
+pub use recording::*;
 pub use vmc::*;
 
 use crate::{
@@ -18,6 +20,7 @@ use alvr_common::{
     DEVICE_ID_TO_PATH, DeviceMotion, HAND_LEFT_ID, HAND_RIGHT_ID, HEAD_ID, Pose, ViewParams,
     glam::{EulerRot, Quat, Vec3},
     parking_lot::Mutex,
+    warn,
 };
 use alvr_events::{EventType, TrackingEvent};
 use alvr_packets::TrackingData;
@@ -296,58 +299,68 @@ impl TrackingManager {
     }
 }
 
-pub fn tracking_loop(
-    ctx: &ConnectionContext,
-    initial_settings: Settings,
+// Applies the tracking data received from the client or replayed from a recording
+pub struct TrackingProcessor {
     hand_gesture_manager: Arc<Mutex<HandGestureManager>>,
-    mut tracking_receiver: StreamReceiver<TrackingData>,
-    is_streaming: impl Fn() -> bool,
-) {
-    let mut gestures_button_mapping_manager =
-        initial_settings
+    gestures_button_mapping_manager: Option<ButtonMappingManager>,
+    face_tracking_sink: Option<FaceTrackingSink>,
+    body_tracking_sink: Option<BodyTrackingSink>,
+    vmc_sink: Option<VMCSink>,
+}
+
+impl TrackingProcessor {
+    pub fn new(
+        initial_settings: &Settings,
+        hand_gesture_manager: Arc<Mutex<HandGestureManager>>,
+    ) -> Self {
+        let gestures_button_mapping_manager =
+            initial_settings
+                .headset
+                .controllers
+                .as_option()
+                .map(|config| {
+                    ButtonMappingManager::new_automatic(
+                        &HAND_GESTURE_BUTTON_SET,
+                        &config.emulation_mode,
+                        &config.button_mapping_config,
+                    )
+                });
+
+        let face_tracking_sink = initial_settings
             .headset
-            .controllers
-            .as_option()
-            .map(|config| {
-                ButtonMappingManager::new_automatic(
-                    &HAND_GESTURE_BUTTON_SET,
-                    &config.emulation_mode,
-                    &config.button_mapping_config,
-                )
+            .face_tracking
+            .clone()
+            .into_option()
+            .and_then(|config| {
+                FaceTrackingSink::new(config.sink, initial_settings.connection.osc_local_port).ok()
             });
 
-    let mut face_tracking_sink = initial_settings
-        .headset
-        .face_tracking
-        .into_option()
-        .and_then(|config| {
-            FaceTrackingSink::new(config.sink, initial_settings.connection.osc_local_port).ok()
-        });
-
-    let mut body_tracking_sink = initial_settings
-        .headset
-        .body_tracking
-        .into_option()
-        .and_then(|config| {
-            BodyTrackingSink::new(config.sink, initial_settings.connection.osc_local_port).ok()
-        });
-
-    let mut vmc_sink = initial_settings
-        .headset
-        .vmc
-        .into_option()
-        .and_then(|config| VMCSink::new(config).ok());
+        let body_tracking_sink = initial_settings
+            .headset
+            .body_tracking
+            .clone()
+            .into_option()
+            .and_then(|config| {
+                BodyTrackingSink::new(config.sink, initial_settings.connection.osc_local_port).ok()
+            });
 
-    while is_streaming() {
-        let data = match tracking_receiver.recv(STREAMING_RECV_TIMEOUT) {
-            Ok(tracking) => tracking,
-            Err(ConnectionError::TryAgain(_)) => continue,
-            Err(ConnectionError::Other(_)) => return,
-        };
-        let Ok(mut tracking) = data.get_header() else {
-            return;
-        };
+        let vmc_sink = initial_settings
+            .headset
+            .vmc
+            .clone()
+            .into_option()
+            .and_then(|config| VMCSink::new(config).ok());
+
+        Self {
+            hand_gesture_manager,
+            gestures_button_mapping_manager,
+            face_tracking_sink,
+            body_tracking_sink,
+            vmc_sink,
+        }
+    }
 
+    pub fn process(&mut self, ctx: &ConnectionContext, mut tracking: TrackingData) {
         let timestamp = tracking.poll_timestamp;
 
         if let Some(stats) = &mut *ctx.statistics_manager.write() {
@@ -397,7 +410,7 @@ pub fn tracking_loop(
                 tracking_manager_lock.report_hand_skeleton(HandType::Right, timestamp, skeleton);
             }
 
-            if let Some(sink) = &mut face_tracking_sink {
+            if let Some(sink) = &mut self.face_tracking_sink {
                 sink.send_tracking(&tracking.face);
             }
 
@@ -429,9 +442,9 @@ pub fn tracking_loop(
             controllers_config
                 .as_ref()
                 .and_then(|c| c.hand_tracking_interaction.as_option()),
-            &mut gestures_button_mapping_manager,
+            &mut self.gestures_button_mapping_manager,
         ) {
-            let mut hand_gesture_manager_lock = hand_gesture_manager.lock();
+            let mut hand_gesture_manager_lock = self.hand_gesture_manager.lock();
 
             if !device_motion_keys.contains(&*HAND_LEFT_ID)
                 && let Some(hand_skeleton) = tracking.hand_skeletons[0]
@@ -490,7 +503,7 @@ pub fn tracking_loop(
                 })
             );
 
-            if let Some(sink) = &mut vmc_sink {
+            if let Some(sink) = &mut self.vmc_sink {
                 let tracking_manager_lock = ctx.tracking_manager.read();
                 let device_motions = device_motion_keys
                     .iter()
@@ -518,7 +531,7 @@ pub fn tracking_loop(
             SESSION_MANAGER.read().settings().headset.body_tracking,
             Switch::Enabled(BodyTrackingConfig { tracked: true, .. })
         );
-        if track_body && let Some(sink) = &mut body_tracking_sink {
+        if track_body && let Some(sink) = &mut self.body_tracking_sink {
             let tracking_manager_lock = ctx.tracking_manager.read();
             let device_motions = device_motion_keys
                 .iter()
@@ -535,3 +548,36 @@ pub fn tracking_loop(
         }
     }
 }
+
+pub fn tracking_loop(
+    ctx: &ConnectionContext,
+    initial_settings: Settings,
+    hand_gesture_manager: Arc<Mutex<HandGestureManager>>,
+    mut tracking_receiver: StreamReceiver<TrackingData>,
+    is_streaming: impl Fn() -> bool,
+) {
+    let mut processor = TrackingProcessor::new(&initial_settings, hand_gesture_manager);
+
+    while is_streaming() {
+        let data = match tracking_receiver.recv(STREAMING_RECV_TIMEOUT) {
+            Ok(tracking) => tracking,
+            Err(ConnectionError::TryAgain(_)) => continue,
+            Err(ConnectionError::Other(_)) => return,
+        };
+        let Ok(tracking) = data.get_header() else {
+            return;
+        };
+
+        {
+            let mut recorder_lock = ctx.tracking_recorder.lock();
+            if let Some(recorder) = &mut *recorder_lock
+                && let Err(e) = recorder.record_tracking(&tracking)
+            {
+                warn!("Failed to record tracking, stopping: {e}");
+                *recorder_lock = None;
+            }
+        }
+
+        processor.process(ctx, tracking);
+    }
+}
@@ -0,0 +1,345 @@
+// Tracking recordings are used to reproduce tracking and input issues without a headset. The file
+// starts with a magic string, followed by records prefixed by their size (u32, little endian). Each
+// record is encoded with bincode and contains the time elapsed since the start of the recording.
+// The format is not stable across versions. Interaction profiles are recorded so that the replay
+// maps buttons like the live session. Until the first one, the default controller mapping is used.
+
+use super::{TrackingManager, TrackingProcessor};
+use crate::{
+    ConnectionContext, ServerCoreEvent, hand_gestures::HandGestureManager,
+    input_mapping::ButtonMappingManager,
+};
+use alvr_common::{
+    anyhow::{Result, bail},
+    parking_lot::Mutex,
+};
+use alvr_packets::{ButtonEntry, TrackingData};
+use alvr_session::Settings;
+use bincode::config;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+const MAGIC: &[u8; 8] = b"ALVRTRK1";
+
+// Much bigger than any tracking packet. Protects against corrupted files
+const MAX_RECORD_SIZE: usize = 1024 * 1024;
+
+// Allows stopping the replay during long pauses of the recording
+const REPLAY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub const TRACKING_RECORDING_EXTENSION: &str = "alvrtrk";
+
+// Serialized like TrackingRecord, without cloning the data
+#[derive(Serialize)]
+enum TrackingRecordRef<'a> {
+    Tracking(&'a TrackingData),
+    Buttons(&'a [ButtonEntry]),
+    InteractionProfile {
+        device_id: u64,
+        profile_id: u64,
+        input_ids: &'a HashSet<u64>,
+    },
+}
+
+#[derive(Deserialize)]
+pub enum TrackingRecord {
+    Tracking(TrackingData),
+    Buttons(Vec<ButtonEntry>),
+    InteractionProfile {
+        device_id: u64,
+        profile_id: u64,
+        input_ids: HashSet<u64>,
+    },
+}
+
+pub struct TrackingRecorder {
+    writer: BufWriter<File>,
+    start_instant: Instant,
+    buffer: Vec<u8>,
+}
+
+impl TrackingRecorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+
+        Ok(Self {
+            writer,
+            start_instant: Instant::now(),
+            buffer: vec![],
+        })
+    }
+
+    fn write(&mut self, record: TrackingRecordRef) -> Result<()> {
+        self.buffer.clear();
+        bincode::serde::encode_into_std_write(
+            (self.start_instant.elapsed(), record),
+            &mut self.buffer,
+            config::standard(),
+        )?;
+
+        self.writer
+            .write_all(&(self.buffer.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&self.buffer)?;
+
+        Ok(())
+    }
+
+    pub fn record_tracking(&mut self, tracking: &TrackingData) -> Result<()> {
+        self.write(TrackingRecordRef::Tracking(tracking))
+    }
+
+    pub fn record_buttons(&mut self, entries: &[ButtonEntry]) -> Result<()> {
+        self.write(TrackingRecordRef::Buttons(entries))
+    }
+
+    pub fn record_interaction_profile(
+        &mut self,
+        device_id: u64,
+        profile_id: u64,
+        input_ids: &HashSet<u64>,
+    ) -> Result<()> {
+        self.write(TrackingRecordRef::InteractionProfile {
+            device_id,
+            profile_id,
+            input_ids,
+        })
+    }
+}
+
+impl Drop for TrackingRecorder {
+    fn drop(&mut self) {
+        self.writer.flush().ok();
+    }
+}
+
+pub struct TrackingRecordingReader {
+    reader: BufReader<File>,
+    buffer: Vec<u8>,
+}
+
+impl TrackingRecordingReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != *MAGIC {
+            bail!("Not a tracking recording, or recorded by an incompatible version");
+        }
+
+        Ok(Self {
+            reader,
+            buffer: vec![],
+        })
+    }
+
+    // Returns the time since the start of the recording and the record. None at the end of the file
+    pub fn next_record(&mut self) -> Result<Option<(Duration, TrackingRecord)>> {
+        let mut size_bytes = [0; 4];
+        match self.reader.read_exact(&mut size_bytes) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let size = u32::from_le_bytes(size_bytes) as usize;
+        if size > MAX_RECORD_SIZE {
+            bail!("Tracking recording is corrupted");
+        }
+
+        self.buffer.resize(size, 0);
+        self.reader.read_exact(&mut self.buffer)?;
+
+        let (record, _) = bincode::serde::decode_from_slice(&self.buffer, config::standard())?;
+
+        Ok(Some(record))
+    }
+}
+
+// Feeds a recording to the tracking manager and to the driver, with the original timing
+pub fn replay_tracking(
+    ctx: &ConnectionContext,
+    initial_settings: Settings,
+    mut reader: TrackingRecordingReader,
+    is_running: impl Fn() -> bool,
+) -> Result<()> {
+    *ctx.tracking_manager.write() =
+        TrackingManager::new(initial_settings.connection.statistics_history_size);
+
+    let mut processor = TrackingProcessor::new(
+        &initial_settings,
+        Arc::new(Mutex::new(HandGestureManager::new())),
+    );
+    let controllers_config = initial_settings.headset.controllers.as_option();
+    let mut button_mapping_manager = controllers_config.map(ButtonMappingManager::new_default);
+
+    let start_instant = Instant::now();
+    while is_running()
+        && let Some((timestamp, record)) = reader.next_record()?
+    {
+        while is_running() && start_instant.elapsed() < timestamp {
+            thread::sleep(Duration::min(
+                timestamp.saturating_sub(start_instant.elapsed()),
+                REPLAY_POLL_INTERVAL,
+            ));
+        }
+
+        match record {
+            TrackingRecord::Tracking(tracking) => processor.process(ctx, tracking),
+            TrackingRecord::Buttons(entries) => {
+                if let Some(manager) = &mut button_mapping_manager {
+                    let button_entries = entries
+                        .iter()
+                        .flat_map(|entry| manager.map_button(entry))
+                        .collect::<Vec<_>>();
+
+                    if !button_entries.is_empty() {
+                        ctx.events_sender
+                            .send(ServerCoreEvent::Buttons(button_entries))
+                            .ok();
+                    }
+                }
+            }
+            TrackingRecord::InteractionProfile { input_ids, .. } => {
+                button_mapping_manager = controllers_config
+                    .map(|config| ButtonMappingManager::new_for_profile(config, &input_ids));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SESSION_MANAGER;
+    use alvr_common::{
+        HAND_LEFT_ID, LEFT_SQUEEZE_CLICK_ID, LEFT_SQUEEZE_VALUE_ID, LEFT_TRIGGER_TOUCH_ID,
+        LEFT_TRIGGER_VALUE_ID,
+    };
+    use alvr_packets::ButtonValue;
+    use std::sync::mpsc;
+
+    // 200ms of tracking at 50Hz with the left hand pinching and no controllers. The squeeze click
+    // (not part of the Quest profile) is pressed at 0ms and 120ms, and the client switches to the
+    // Vive controller profile at 100ms
+    const RECORDING_PATH: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/tracking/test_data/pinch_and_vive_profile.alvrtrk"
+    );
+
+    #[test]
+    fn test_recording_roundtrip() {
+        let path = std::env::temp_dir().join(format!(
+            "alvr_roundtrip_{}.{TRACKING_RECORDING_EXTENSION}",
+            std::process::id()
+        ));
+
+        let input_ids = HashSet::from([*LEFT_SQUEEZE_CLICK_ID]);
+        {
+            let mut recorder = TrackingRecorder::create(&path).unwrap();
+            recorder
+                .record_buttons(&[ButtonEntry {
+                    path_id: *LEFT_SQUEEZE_CLICK_ID,
+                    value: ButtonValue::Binary(true),
+                }])
+                .unwrap();
+            recorder
+                .record_interaction_profile(*HAND_LEFT_ID, 1, &input_ids)
+                .unwrap();
+        }
+
+        let mut reader = TrackingRecordingReader::open(&path).unwrap();
+        let Some((_, TrackingRecord::Buttons(entries))) = reader.next_record().unwrap() else {
+            panic!("Expected buttons");
+        };
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path_id, *LEFT_SQUEEZE_CLICK_ID);
+
+        let Some((
+            _,
+            TrackingRecord::InteractionProfile {
+                device_id,
+                profile_id,
+                input_ids: recorded_ids,
+            },
+        )) = reader.next_record().unwrap()
+        else {
+            panic!("Expected interaction profile");
+        };
+        assert_eq!(device_id, *HAND_LEFT_ID);
+        assert_eq!(profile_id, 1);
+        assert_eq!(recorded_ids, input_ids);
+
+        assert!(reader.next_record().unwrap().is_none());
+
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_replay_tracking() {
+        SESSION_MANAGER
+            .write()
+            .session_mut()
+            .session_settings
+            .headset
+            .controllers
+            .content
+            .hand_tracking_interaction
+            .enabled = true;
+        let settings = SESSION_MANAGER.read().settings().clone();
+
+        let (events_sender, events_receiver) = mpsc::channel();
+        let ctx =
+            ConnectionContext::new(events_sender, settings.connection.statistics_history_size);
+
+        let reader = TrackingRecordingReader::open(Path::new(RECORDING_PATH)).unwrap();
+        replay_tracking(&ctx, settings, reader, || true).unwrap();
+
+        let mut tracking_count = 0;
+        let mut buttons = vec![];
+        for event in events_receiver.try_iter() {
+            match event {
+                ServerCoreEvent::Tracking { .. } => tracking_count += 1,
+                ServerCoreEvent::Buttons(entries) => buttons.extend(entries),
+                _ => (),
+            }
+        }
+        assert_eq!(tracking_count, 11);
+
+        // The pinch becomes active after the activation delay
+        assert!(
+            buttons
+                .iter()
+                .any(|entry| entry.path_id == *LEFT_TRIGGER_TOUCH_ID
+                    && matches!(entry.value, ButtonValue::Binary(true)))
+        );
+        assert!(
+            buttons
+                .iter()
+                .any(|entry| entry.path_id == *LEFT_TRIGGER_VALUE_ID
+                    && matches!(entry.value, ButtonValue::Scalar(value) if value == 1.0))
+        );
+
+        // Only the press after the profile switch is mapped, to the squeeze value. The gesture
+        // mapping reports the squeeze value as 0 since the hand is open
+        let squeeze_presses = buttons
+            .iter()
+            .filter(|entry| {
+                entry.path_id == *LEFT_SQUEEZE_VALUE_ID
+                    && matches!(entry.value, ButtonValue::Scalar(value) if value > 0.0)
+            })
+            .count();
+        assert_eq!(squeeze_presses, 1);
+    }
+}
@@ -0,0 +1,826 @@
+// Conversion of the encoder output to the Matroska codec mappings. H.264 and HEVC NALs are stored
+// with a length prefix instead of a start code, and the parameter sets are stored in the track
+// codec private data (avcC and hvcC records). AV1 has no separate configuration: the sequence
+// header is extracted from the first keyframe (av1C record).
+
+use alvr_common::anyhow::{Context, Result, bail};
+use alvr_session::CodecType;
+
+const H264_NAL_TYPE_SPS: u8 = 7;
+const H264_NAL_TYPE_PPS: u8 = 8;
+const HEVC_NAL_TYPE_VPS: u8 = 32;
+const HEVC_NAL_TYPE_SPS: u8 = 33;
+const HEVC_NAL_TYPE_PPS: u8 = 34;
+const AV1_OBU_SEQUENCE_HEADER: u8 = 1;
+const AV1_OBU_TEMPORAL_DELIMITER: u8 = 2;
+
+// H.264 profiles that signal the chroma format and bit depth
+const H264_HIGH_PROFILES: [u8; 13] = [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135];
+
+pub struct CodecParameters {
+    pub codec_id: &'static str,
+    pub codec_private: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn bits(&mut self, count: u32) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..count {
+            let byte = self.data.get(self.position / 8)?;
+            let bit = (byte >> (7 - self.position % 8)) & 1;
+            value = (value << 1) | bit as u64;
+            self.position += 1;
+        }
+
+        Some(value)
+    }
+
+    fn flag(&mut self) -> Option<bool> {
+        Some(self.bits(1)? != 0)
+    }
+
+    // Exp-Golomb code
+    fn ue(&mut self) -> Option<u64> {
+        let mut leading_zeros = 0;
+        while !self.flag()? {
+            leading_zeros += 1;
+            if leading_zeros > 32 {
+                return None;
+            }
+        }
+
+        Some((1 << leading_zeros) - 1 + self.bits(leading_zeros)?)
+    }
+
+    fn se(&mut self) -> Option<i64> {
+        let value = self.ue()? as i64;
+
+        Some(if value % 2 == 1 {
+            (value + 1) / 2
+        } else {
+            -value / 2
+        })
+    }
+}
+
+// Returns the NALs without start codes
+fn split_annex_b(data: &[u8]) -> Vec<&[u8]> {
+    let mut start_code_ends = vec![];
+    let mut idx = 0;
+    while idx + 3 <= data.len() {
+        if data[idx..idx + 3] == [0, 0, 1] {
+            start_code_ends.push(idx + 3);
+            idx += 3;
+        } else {
+            idx += 1;
+        }
+    }
+
+    let mut nals = vec![];
+    for (i, &start) in start_code_ends.iter().enumerate() {
+        let end = start_code_ends
+            .get(i + 1)
+            .map_or(data.len(), |next_end| next_end - 3);
+
+        // Removes the leading zero of 4 byte start codes. NALs never end with a zero byte
+        let mut nal = &data[start..end];
+        while let Some((0, rest)) = nal.split_last() {
+            nal = rest;
+        }
+
+        if !nal.is_empty() {
+            nals.push(nal);
+        }
+    }
+
+    nals
+}
+
+fn remove_emulation_prevention(nal: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal.len());
+    let mut zeros_count = 0;
+    for &byte in nal {
+        if zeros_count >= 2 && byte == 3 {
+            zeros_count = 0;
+            continue;
+        }
+        rbsp.push(byte);
+
+        zeros_count = if byte == 0 { zeros_count + 1 } else { 0 };
+    }
+
+    rbsp
+}
+
+fn write_length_prefixed_nal(out: &mut Vec<u8>, nal: &[u8]) {
+    out.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+    out.extend_from_slice(nal);
+}
+
+fn write_parameter_set(out: &mut Vec<u8>, nal: &[u8]) {
+    out.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+    out.extend_from_slice(nal);
+}
+
+fn nal_type(codec: CodecType, nal: &[u8]) -> u8 {
+    if codec == CodecType::Hevc {
+        (nal[0] >> 1) & 0x3f
+    } else {
+        nal[0] & 0x1f
+    }
+}
+
+// Returns (width, height)
+fn parse_h264_sps(nal: &[u8]) -> Option<(u32, u32)> {
+    let rbsp = remove_emulation_prevention(nal);
+    // Skip the NAL header
+    let mut reader = BitReader::new(rbsp.get(1..)?);
+
+    let profile_idc = reader.bits(8)? as u8;
+    reader.bits(16)?; // constraint flags, level_idc
+    reader.ue()?; // seq_parameter_set_id
+
+    let mut chroma_format_idc = 1;
+    if H264_HIGH_PROFILES.contains(&profile_idc) {
+        chroma_format_idc = reader.ue()?;
+        if chroma_format_idc == 3 {
+            reader.flag()?; // separate_colour_plane_flag
+        }
+        reader.ue()?; // bit_depth_luma_minus8
+        reader.ue()?; // bit_depth_chroma_minus8
+        reader.flag()?; // qpprime_y_zero_transform_bypass_flag
+
+        if reader.flag()? {
+            let lists_count = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists_count {
+                if reader.flag()? {
+                    let size = if i < 6 { 16 } else { 64 };
+                    let mut last_scale = 8;
+                    let mut next_scale = 8;
+                    for _ in 0..size {
+                        if next_scale != 0 {
+                            next_scale = (last_scale + reader.se()?).rem_euclid(256);
+                        }
+                        if next_scale != 0 {
+                            last_scale = next_scale;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    reader.ue()?; // log2_max_frame_num_minus4
+    match reader.ue()? {
+        0 => {
+            reader.ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            reader.flag()?; // delta_pic_order_always_zero_flag
+            reader.se()?; // offset_for_non_ref_pic
+            reader.se()?; // offset_for_top_to_bottom_field
+            for _ in 0..reader.ue()? {
+                reader.se()?; // offset_for_ref_frame
+            }
+        }
+        _ => (),
+    }
+    reader.ue()?; // max_num_ref_frames
+    reader.flag()?; // gaps_in_frame_num_value_allowed_flag
+
+    let width_mbs = reader.ue()? + 1;
+    let height_map_units = reader.ue()? + 1;
+    let frame_mbs_only = reader.flag()?;
+    if !frame_mbs_only {
+        reader.flag()?; // mb_adaptive_frame_field_flag
+    }
+    reader.flag()?; // direct_8x8_inference_flag
+
+    let field_factor = if frame_mbs_only { 1 } else { 2 };
+    let mut width = width_mbs * 16;
+    let mut height = height_map_units * 16 * field_factor;
+
+    if reader.flag()? {
+        let (crop_unit_x, crop_unit_y) = match chroma_format_idc {
+            1 => (2, 2 * field_factor),
+            2 => (2, field_factor),
+            _ => (1, field_factor),
+        };
+
+        let left = reader.ue()?;
+        let right = reader.ue()?;
+        let top = reader.ue()?;
+        let bottom = reader.ue()?;
+        width = width.checked_sub(crop_unit_x * (left + right))?;
+        height = height.checked_sub(crop_unit_y * (top + bottom))?;
+    }
+
+    Some((width as u32, height as u32))
+}
+
+struct HevcSpsInfo {
+    // general_profile_space to general_level_idc, as found in the hvcC record
+    profile_tier_level: [u8; 12],
+    max_sub_layers: u8,
+    temporal_id_nesting: bool,
+    chroma_format_idc: u8,
+    bit_depth_luma_minus8: u8,
+    bit_depth_chroma_minus8: u8,
+    width: u32,
+    height: u32,
+}
+
+fn parse_hevc_sps(nal: &[u8]) -> Option<HevcSpsInfo> {
+    let rbsp = remove_emulation_prevention(nal);
+    // Skip the NAL header
+    let mut reader = BitReader::new(rbsp.get(2..)?);
+
+    reader.bits(4)?; // sps_video_parameter_set_id
+    let max_sub_layers_minus1 = reader.bits(3)? as u8;
+    let temporal_id_nesting = reader.flag()?;
+
+    let profile_tier_level = rbsp.get(3..15)?.try_into().ok()?;
+    reader.bits(96)?;
+
+    let mut sub_layer_flags = vec![];
+    for _ in 0..max_sub_layers_minus1 {
+        // (profile present, level present)
+        sub_layer_flags.push((reader.flag()?, reader.flag()?));
+    }
+    if max_sub_layers_minus1 > 0 {
+        reader.bits(2 * (8 - max_sub_layers_minus1 as u32))?; // reserved_zero_2bits
+    }
+    for (profile_present, level_present) in sub_layer_flags {
+        if profile_present {
+            reader.bits(88)?;
+        }
+        if level_present {
+            reader.bits(8)?;
+        }
+    }
+
+    reader.ue()?; // sps_seq_parameter_set_id
+    let chroma_format_idc = reader.ue()? as u8;
+    if chroma_format_idc == 3 {
+        reader.flag()?; // separate_colour_plane_flag
+    }
+
+    let mut width = reader.ue()?;
+    let mut height = reader.ue()?;
+    if reader.flag()? {
+        let (sub_width, sub_height) = match chroma_format_idc {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+
+        let left = reader.ue()?;
+        let right = reader.ue()?;
+        let top = reader.ue()?;
+        let bottom = reader.ue()?;
+        width = width.checked_sub(sub_width * (left + right))?;
+        height = height.checked_sub(sub_height * (top + bottom))?;
+    }
+
+    let bit_depth_luma_minus8 = reader.ue()? as u8;
+    let bit_depth_chroma_minus8 = reader.ue()? as u8;
+
+    Some(HevcSpsInfo {
+        profile_tier_level,
+        max_sub_layers: max_sub_layers_minus1 + 1,
+        temporal_id_nesting,
+        chroma_format_idc,
+        bit_depth_luma_minus8,
+        bit_depth_chroma_minus8,
+        width: width as u32,
+        height: height as u32,
+    })
+}
+
+struct Av1SequenceHeaderInfo {
+    seq_profile: u8,
+    seq_level_idx: u8,
+    seq_tier: u8,
+    high_bitdepth: bool,
+    twelve_bit: bool,
+    monochrome: bool,
+    chroma_subsampling_x: bool,
+    chroma_subsampling_y: bool,
+    chroma_sample_position: u8,
+    width: u32,
+    height: u32,
+}
+
+fn parse_av1_sequence_header(payload: &[u8]) -> Option<Av1SequenceHeaderInfo> {
+    let mut reader = BitReader::new(payload);
+
+    let seq_profile = reader.bits(3)? as u8;
+    reader.flag()?; // still_picture
+    let reduced_still_picture_header = reader.flag()?;
+
+    let mut seq_level_idx = 0;
+    let mut seq_tier = 0;
+    if reduced_still_picture_header {
+        seq_level_idx = reader.bits(5)? as u8;
+    } else {
+        let mut decoder_model_info_present = false;
+        let mut buffer_delay_length = 0;
+        if reader.flag()? {
+            // timing_info
+            reader.bits(64)?; // num_units_in_display_tick, time_scale
+            if reader.flag()? {
+                // num_ticks_per_picture_minus_1. uvlc() uses the same coding as ue(v)
+                reader.ue()?;
+            }
+
+            decoder_model_info_present = reader.flag()?;
+            if decoder_model_info_present {
+                buffer_delay_length = reader.bits(5)? as u32 + 1;
+                reader.bits(32)?; // num_units_in_decoding_tick
+                reader.bits(10)?; // buffer_removal_time_length_minus_1, ...
+            }
+        }
+
+        let initial_display_delay_present = reader.flag()?;
+        let operating_points_count = reader.bits(5)? + 1;
+        for i in 0..operating_points_count {
+            reader.bits(12)?; // operating_point_idc
+            let level_idx = reader.bits(5)? as u8;
+            let tier = if level_idx > 7 {
+                reader.bits(1)? as u8
+            } else {
+                0
+            };
+            if i == 0 {
+                seq_level_idx = level_idx;
+                seq_tier = tier;
+            }
+
+            if decoder_model_info_present && reader.flag()? {
+                // decoder_buffer_delay, encoder_buffer_delay, low_delay_mode_flag
+                reader.bits(2 * buffer_delay_length + 1)?;
+            }
+            if initial_display_delay_present && reader.flag()? {
+                reader.bits(4)?; // initial_display_delay_minus_1
+            }
+        }
+    }
+
+    let frame_width_bits = reader.bits(4)? as u32 + 1;
+    let frame_height_bits = reader.bits(4)? as u32 + 1;
+    let width = reader.bits(frame_width_bits)? as u32 + 1;
+    let height = reader.bits(frame_height_bits)? as u32 + 1;
+
+    if !reduced_still_picture_header && reader.flag()? {
+        // delta_frame_id_length_minus_2, additional_frame_id_length_minus_1
+        reader.bits(7)?;
+    }
+    // use_128x128_superblock, enable_filter_intra, enable_intra_edge_filter
+    reader.bits(3)?;
+    if !reduced_still_picture_header {
+        // enable_interintra_compound, enable_masked_compound, enable_warped_motion,
+        // enable_dual_filter
+        reader.bits(4)?;
+        let enable_order_hint = reader.flag()?;
+        if enable_order_hint {
+            reader.bits(2)?; // enable_jnt_comp, enable_ref_frame_mvs
+        }
+        let force_screen_content_tools = if reader.flag()? { 2 } else { reader.bits(1)? };
+        if force_screen_content_tools > 0 && !reader.flag()? {
+            reader.flag()?; // seq_force_integer_mv
+        }
+        if enable_order_hint {
+            reader.bits(3)?; // order_hint_bits_minus_1
+        }
+    }
+    // enable_superres, enable_cdef, enable_restoration
+    reader.bits(3)?;
+
+    // color_config
+    let high_bitdepth = reader.flag()?;
+    let twelve_bit = seq_profile == 2 && high_bitdepth && reader.flag()?;
+    let monochrome = seq_profile != 1 && reader.flag()?;
+    let (mut color_primaries, mut transfer_characteristics, mut matrix_coefficients) = (2, 2, 2);
+    if reader.flag()? {
+        color_primaries = reader.bits(8)?;
+        transfer_characteristics = reader.bits(8)?;
+        matrix_coefficients = reader.bits(8)?;
+    }
+
+    let (chroma_subsampling_x, chroma_subsampling_y) = if monochrome {
+        (true, true)
+    } else if color_primaries == 1 && transfer_characteristics == 13 && matrix_coefficients == 0 {
+        (false, false)
+    } else {
+        reader.flag()?; // color_range
+        match seq_profile {
+            0 => (true, true),
+            1 => (false, false),
+            _ if twelve_bit => {
+                let x = reader.flag()?;
+                (x, x && reader.flag()?)
+            }
+            _ => (true, false),
+        }
+    };
+    let chroma_sample_position = if !monochrome && chroma_subsampling_x && chroma_subsampling_y {
+        reader.bits(2)? as u8
+    } else {
+        0
+    };
+
+    Some(Av1SequenceHeaderInfo {
+        seq_profile,
+        seq_level_idx,
+        seq_tier,
+        high_bitdepth,
+        twelve_bit,
+        monochrome,
+        chroma_subsampling_x,
+        chroma_subsampling_y,
+        chroma_sample_position,
+        width,
+        height,
+    })
+}
+
+fn read_leb128(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0;
+    for (i, byte) in data.iter().take(8).enumerate() {
+        value |= ((byte & 0x7f) as usize) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+
+    None
+}
+
+struct Obu<'a> {
+    obu_type: u8,
+    // Including the header
+    data: &'a [u8],
+    payload: &'a [u8],
+}
+
+fn split_obus(mut data: &[u8]) -> Result<Vec<Obu<'_>>> {
+    let mut obus = vec![];
+    while let Some(&header) = data.first() {
+        let obu_type = (header >> 3) & 0xf;
+        let header_size = if header & 0x04 != 0 { 2 } else { 1 };
+        if header & 0x02 == 0 {
+            bail!("AV1 OBUs without size field are not supported");
+        }
+
+        let (payload_size, size_length) =
+            read_leb128(data.get(header_size..).unwrap_or_default()).context("Invalid OBU size")?;
+        let payload_start = header_size + size_length;
+        let end = payload_start + payload_size;
+        if end > data.len() {
+            bail!("Truncated OBU");
+        }
+
+        obus.push(Obu {
+            obu_type,
+            data: &data[..end],
+            payload: &data[payload_start..end],
+        });
+        data = &data[end..];
+    }
+
+    Ok(obus)
+}
+
+pub fn codec_parameters(
+    codec: CodecType,
+    config_buffer: &[u8],
+    keyframe: &[u8],
+) -> Result<CodecParameters> {
+    match codec {
+        CodecType::H264 => {
+            let nals = split_annex_b(config_buffer);
+            let spss = nals
+                .iter()
+                .filter(|nal| nal_type(codec, nal) == H264_NAL_TYPE_SPS)
+                .collect::<Vec<_>>();
+            let ppss = nals
+                .iter()
+                .filter(|nal| nal_type(codec, nal) == H264_NAL_TYPE_PPS)
+                .collect::<Vec<_>>();
+            let Some(sps) = spss.first().filter(|sps| sps.len() >= 4) else {
+                bail!("Missing SPS");
+            };
+            let (width, height) = parse_h264_sps(sps).context("Invalid SPS")?;
+
+            // avcC record
+            let mut codec_private = vec![1, sps[1], sps[2], sps[3], 0xfc | 3];
+            codec_private.push(0xe0 | spss.len() as u8);
+            for sps in &spss {
+                write_parameter_set(&mut codec_private, sps);
+            }
+            codec_private.push(ppss.len() as u8);
+            for pps in &ppss {
+                write_parameter_set(&mut codec_private, pps);
+            }
+
+            Ok(CodecParameters {
+                codec_id: "V_MPEG4/ISO/AVC",
+                codec_private,
+                width,
+                height,
+            })
+        }
+        CodecType::Hevc => {
+            let nals = split_annex_b(config_buffer);
+            let Some(sps) = nals
+                .iter()
+                .find(|nal| nal_type(codec, nal) == HEVC_NAL_TYPE_SPS)
+            else {
+                bail!("Missing SPS");
+            };
+            let info = parse_hevc_sps(sps).context("Invalid SPS")?;
+
+            // hvcC record
+            let mut codec_private = vec![1];
+            codec_private.extend_from_slice(&info.profile_tier_level);
+            codec_private.extend_from_slice(&0xf000_u16.to_be_bytes()); // min_spatial_segmentation
+            codec_private.push(0xfc); // parallelismType
+            codec_private.push(0xfc | info.chroma_format_idc);
+            codec_private.push(0xf8 | info.bit_depth_luma_minus8);
+            codec_private.push(0xf8 | info.bit_depth_chroma_minus8);
+            codec_private.extend_from_slice(&0_u16.to_be_bytes()); // avgFrameRate
+            codec_private
+                .push((info.max_sub_layers << 3) | ((info.temporal_id_nesting as u8) << 2) | 3);
+
+            let arrays = [HEVC_NAL_TYPE_VPS, HEVC_NAL_TYPE_SPS, HEVC_NAL_TYPE_PPS]
+                .into_iter()
+                .map(|ty| {
+                    let nals = nals
+                        .iter()
+                        .filter(|nal| nal.len() >= 2 && nal_type(codec, nal) == ty)
+                        .collect::<Vec<_>>();
+                    (ty, nals)
+                })
+                .filter(|(_, nals)| !nals.is_empty())
+                .collect::<Vec<_>>();
+            codec_private.push(arrays.len() as u8);
+            for (ty, nals) in arrays {
+                // array_completeness is set
+                codec_private.push(0x80 | ty);
+                codec_private.extend_from_slice(&(nals.len() as u16).to_be_bytes());
+                for nal in nals {
+                    write_parameter_set(&mut codec_private, nal);
+                }
+            }
+
+            Ok(CodecParameters {
+                codec_id: "V_MPEGH/ISO/HEVC",
+                codec_private,
+                width: info.width,
+                height: info.height,
+            })
+        }
+        CodecType::AV1 => {
+            let Some(sequence_header) = split_obus(keyframe)?
+                .into_iter()
+                .find(|obu| obu.obu_type == AV1_OBU_SEQUENCE_HEADER)
+            else {
+                bail!("Missing sequence header");
+            };
+            let info = parse_av1_sequence_header(sequence_header.payload)
+                .context("Invalid sequence header")?;
+
+            // av1C record
+            let mut codec_private = vec![
+                0x81, // marker, version
+                (info.seq_profile << 5) | info.seq_level_idx,
+                (info.seq_tier << 7)
+                    | ((info.high_bitdepth as u8) << 6)
+                    | ((info.twelve_bit as u8) << 5)
+                    | ((info.monochrome as u8) << 4)
+                    | ((info.chroma_subsampling_x as u8) << 3)
+                    | ((info.chroma_subsampling_y as u8) << 2)
+                    | info.chroma_sample_position,
+                0, // initial_presentation_delay not present
+            ];
+            codec_private.extend_from_slice(sequence_header.data);
+
+            Ok(CodecParameters {
+                codec_id: "V_AV1",
+                codec_private,
+                width: info.width,
+                height: info.height,
+            })
+        }
+    }
+}
+
+// Converts a frame to the format of the Matroska block. If present, the parameter sets are inserted
+// in band, this is used if the configuration changes during the recording.
+pub fn convert_frame(
+    codec: CodecType,
+    frame: &[u8],
+    in_band_config: Option<&[u8]>,
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    out.clear();
+
+    match codec {
+        CodecType::H264 | CodecType::Hevc => {
+            for nal in in_band_config
+                .map(split_annex_b)
+                .unwrap_or_default()
+                .into_iter()
+                .chain(split_annex_b(frame))
+            {
+                write_length_prefixed_nal(out, nal);
+            }
+        }
+        CodecType::AV1 => {
+            for obu in split_obus(frame)? {
+                // Temporal delimiters are implied by the blocks
+                if obu.obu_type != AV1_OBU_TEMPORAL_DELIMITER {
+                    out.extend_from_slice(obu.data);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // x264, High profile, level 4.0, 1920x1088 cropped to 1920x1080
+    const H264_SPS: &[u8] = &[
+        0x67, 0x64, 0x00, 0x28, 0xac, 0xd9, 0x40, 0x78, 0x02, 0x27, 0xe5, 0xc0, 0x44, 0x00, 0x00,
+        0x03, 0x00, 0x04, 0x00, 0x00, 0x03, 0x00, 0xf0, 0x3c, 0x60, 0xc6, 0x58,
+    ];
+    const H264_PPS: &[u8] = &[0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0];
+
+    // x265, Main profile, level 4.1, 1920x1088 cropped to 1920x1080
+    const HEVC_VPS: &[u8] = &[
+        0x40, 0x01, 0x0c, 0x01, 0xff, 0xff, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0xb0, 0x00, 0x00,
+        0x03, 0x00, 0x00, 0x03, 0x00, 0x7b, 0xac, 0x09,
+    ];
+    const HEVC_SPS: &[u8] = &[
+        0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0xb0, 0x00, 0x00, 0x03, 0x00, 0x00,
+        0x03, 0x00, 0x7b, 0xa0, 0x03, 0xc0, 0x80, 0x10, 0xe5, 0x96, 0x56, 0x69, 0x24, 0xca, 0xe0,
+        0x10, 0x00, 0x00, 0x03, 0x00, 0x10, 0x00, 0x00, 0x03, 0x01, 0xe0, 0x80,
+    ];
+    const HEVC_PPS: &[u8] = &[0x44, 0x01, 0xc1, 0x72, 0xb4, 0x62, 0x40];
+
+    // Main profile, level 4.1, 1920x1080, 8 bit 4:2:0
+    const AV1_SEQUENCE_HEADER_OBU: &[u8] = &[
+        0x0a, 0x0e, 0x00, 0x00, 0x00, 0x4a, 0xab, 0xbf, 0xc3, 0x77, 0x6b, 0xe4, 0x40, 0x40, 0x40,
+        0x40,
+    ];
+    const AV1_TEMPORAL_DELIMITER_OBU: &[u8] = &[0x12, 0x00];
+    const AV1_FRAME_OBU: &[u8] = &[0x32, 0x03, 0x10, 0x00, 0x80];
+
+    fn annex_b(nals: &[&[u8]]) -> Vec<u8> {
+        let mut buffer = vec![];
+        for (i, nal) in nals.iter().enumerate() {
+            // Mix 3 and 4 byte start codes
+            if i % 2 == 0 {
+                buffer.push(0);
+            }
+            buffer.extend_from_slice(&[0, 0, 1]);
+            buffer.extend_from_slice(nal);
+        }
+
+        buffer
+    }
+
+    fn parameter_set(nal: &[u8]) -> Vec<u8> {
+        let mut buffer = (nal.len() as u16).to_be_bytes().to_vec();
+        buffer.extend_from_slice(nal);
+
+        buffer
+    }
+
+    #[test]
+    fn test_exp_golomb() {
+        // ue: 1, 010, 011, 00100. se: 00101 (-2)
+        let mut reader = BitReader::new(&[0b1010_0110, 0b0100_0010, 0b1000_0000]);
+        assert_eq!(reader.ue(), Some(0));
+        assert_eq!(reader.ue(), Some(1));
+        assert_eq!(reader.ue(), Some(2));
+        assert_eq!(reader.ue(), Some(3));
+        assert_eq!(reader.se(), Some(-2));
+        assert_eq!(reader.ue(), None);
+    }
+
+    #[test]
+    fn test_split_annex_b() {
+        let buffer = annex_b(&[H264_SPS, H264_PPS, &[0x65, 0x88, 0x00]]);
+
+        // Trailing zeros are removed, they are indistinguishable from a 4 byte start code
+        assert_eq!(
+            split_annex_b(&buffer),
+            [H264_SPS, H264_PPS, &[0x65, 0x88][..]]
+        );
+    }
+
+    #[test]
+    fn test_h264_avcc() {
+        let parameters =
+            codec_parameters(CodecType::H264, &annex_b(&[H264_SPS, H264_PPS]), &[]).unwrap();
+
+        assert_eq!(parameters.codec_id, "V_MPEG4/ISO/AVC");
+        assert_eq!((parameters.width, parameters.height), (1920, 1080));
+
+        // version, profile, compatibility, level, length size, SPS count
+        let mut avcc = vec![0x01, 0x64, 0x00, 0x28, 0xff, 0xe1];
+        avcc.extend(parameter_set(H264_SPS));
+        avcc.push(0x01);
+        avcc.extend(parameter_set(H264_PPS));
+        assert_eq!(parameters.codec_private, avcc);
+    }
+
+    #[test]
+    fn test_hevc_hvcc() {
+        let parameters = codec_parameters(
+            CodecType::Hevc,
+            &annex_b(&[HEVC_VPS, HEVC_SPS, HEVC_PPS]),
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(parameters.codec_id, "V_MPEGH/ISO/HEVC");
+        assert_eq!((parameters.width, parameters.height), (1920, 1080));
+
+        let mut hvcc = vec![
+            0x01, // version
+            0x01, // profile space, tier, profile
+            0x60, 0x00, 0x00, 0x00, // profile compatibility
+            0xb0, 0x00, 0x00, 0x00, 0x00, 0x00, // constraint flags
+            0x7b, // level
+            0xf0, 0x00, // min_spatial_segmentation
+            0xfc, // parallelismType
+            0xfd, // chroma format
+            0xf8, 0xf8, // bit depths
+            0x00, 0x00, // avgFrameRate
+            0x0f, // sub layers, temporal id nesting, length size
+            0x03, // arrays count
+        ];
+        for (ty, nal) in [(0xa0, HEVC_VPS), (0xa1, HEVC_SPS), (0xa2, HEVC_PPS)] {
+            hvcc.extend([ty, 0x00, 0x01]);
+            hvcc.extend(parameter_set(nal));
+        }
+        assert_eq!(parameters.codec_private, hvcc);
+    }
+
+    #[test]
+    fn test_av1_av1c() {
+        let keyframe = [
+            AV1_TEMPORAL_DELIMITER_OBU,
+            AV1_SEQUENCE_HEADER_OBU,
+            AV1_FRAME_OBU,
+        ]
+        .concat();
+        let parameters = codec_parameters(CodecType::AV1, &[], &keyframe).unwrap();
+
+        assert_eq!(parameters.codec_id, "V_AV1");
+        assert_eq!((parameters.width, parameters.height), (1920, 1080));
+
+        // marker and version, profile and level, 4:2:0 subsampling, no presentation delay
+        let mut av1c = vec![0x81, 0x09, 0x0c, 0x00];
+        av1c.extend_from_slice(AV1_SEQUENCE_HEADER_OBU);
+        assert_eq!(parameters.codec_private, av1c);
+    }
+
+    #[test]
+    fn test_convert_frame() {
+        let mut out = vec![];
+
+        convert_frame(
+            CodecType::H264,
+            &annex_b(&[&[0x65, 0x88]]),
+            Some(&annex_b(&[H264_SPS])),
+            &mut out,
+        )
+        .unwrap();
+        let mut expected = (H264_SPS.len() as u32).to_be_bytes().to_vec();
+        expected.extend_from_slice(H264_SPS);
+        expected.extend([0x00, 0x00, 0x00, 0x02, 0x65, 0x88]);
+        assert_eq!(out, expected);
+
+        let frame = [AV1_TEMPORAL_DELIMITER_OBU, AV1_FRAME_OBU].concat();
+        convert_frame(CodecType::AV1, &frame, None, &mut out).unwrap();
+        assert_eq!(out, AV1_FRAME_OBU);
+    }
+}
@@ -0,0 +1,679 @@
+// Video recordings are written in the Matroska format. Track 1 contains the video and track 2 is a
+// subtitle track with the global view params of each frame as JSON, which can be extracted with
+// the usual tools (for example `ffmpeg -i recording.mkv -map 0:s out.srt`). Tracks 3 and 4, if
+// present, contain the game audio and the microphone as 16 bit PCM. Audio blocks are timestamped
+// by counting the samples from the time of arrival of the first packet, relative to the first
+// keyframe. The count is anchored again if it drifts from the time of arrival (packet loss or
+// different clocks).
+//
+// The segment is written with an unknown size first and is finalized when the recorder is dropped
+// (size, duration, cues and seek head). If the process crashes, the file is still playable but
+// cannot be seeked efficiently.
+
+mod codec;
+
+use alvr_common::{
+    ALVR_VERSION, ViewParams,
+    anyhow::{Result, bail},
+};
+use alvr_session::CodecType;
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+pub const VIDEO_RECORDING_EXTENSION: &str = "mkv";
+
+const ID_EBML: u32 = 0x1a45dfa3;
+const ID_EBML_VERSION: u32 = 0x4286;
+const ID_EBML_READ_VERSION: u32 = 0x42f7;
+const ID_EBML_MAX_ID_LENGTH: u32 = 0x42f2;
+const ID_EBML_MAX_SIZE_LENGTH: u32 = 0x42f3;
+const ID_DOC_TYPE: u32 = 0x4282;
+const ID_DOC_TYPE_VERSION: u32 = 0x4287;
+const ID_DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const ID_VOID: u32 = 0xec;
+const ID_SEGMENT: u32 = 0x18538067;
+const ID_SEEK_HEAD: u32 = 0x114d9b74;
+const ID_SEEK: u32 = 0x4dbb;
+const ID_SEEK_ID: u32 = 0x53ab;
+const ID_SEEK_POSITION: u32 = 0x53ac;
+const ID_INFO: u32 = 0x1549a966;
+const ID_TIMESTAMP_SCALE: u32 = 0x2ad7b1;
+const ID_DURATION: u32 = 0x4489;
+const ID_MUXING_APP: u32 = 0x4d80;
+const ID_WRITING_APP: u32 = 0x5741;
+const ID_TRACKS: u32 = 0x1654ae6b;
+const ID_TRACK_ENTRY: u32 = 0xae;
+const ID_TRACK_NUMBER: u32 = 0xd7;
+const ID_TRACK_UID: u32 = 0x73c5;
+const ID_TRACK_TYPE: u32 = 0x83;
+const ID_FLAG_DEFAULT: u32 = 0x88;
+const ID_FLAG_LACING: u32 = 0x9c;
+const ID_NAME: u32 = 0x536e;
+const ID_CODEC_ID: u32 = 0x86;
+const ID_CODEC_PRIVATE: u32 = 0x63a2;
+const ID_VIDEO: u32 = 0xe0;
+const ID_PIXEL_WIDTH: u32 = 0xb0;
+const ID_PIXEL_HEIGHT: u32 = 0xba;
+const ID_AUDIO: u32 = 0xe1;
+const ID_SAMPLING_FREQUENCY: u32 = 0xb5;
+const ID_CHANNELS: u32 = 0x9f;
+const ID_BIT_DEPTH: u32 = 0x6264;
+const ID_CLUSTER: u32 = 0x1f43b675;
+const ID_CLUSTER_TIMESTAMP: u32 = 0xe7;
+const ID_SIMPLE_BLOCK: u32 = 0xa3;
+const ID_CUES: u32 = 0x1c53bb6b;
+const ID_CUE_POINT: u32 = 0xbb;
+const ID_CUE_TIME: u32 = 0xb3;
+const ID_CUE_TRACK_POSITIONS: u32 = 0xb7;
+const ID_CUE_TRACK: u32 = 0xf7;
+const ID_CUE_CLUSTER_POSITION: u32 = 0xf1;
+
+const TRACK_TYPE_VIDEO: u64 = 1;
+const TRACK_TYPE_AUDIO: u64 = 2;
+const TRACK_TYPE_SUBTITLE: u64 = 0x11;
+const VIDEO_TRACK_NUMBER: u8 = 1;
+const VIEW_PARAMS_TRACK_NUMBER: u8 = 2;
+const GAME_AUDIO_TRACK_NUMBER: u8 = 3;
+const MICROPHONE_TRACK_NUMBER: u8 = 4;
+const BLOCK_FLAG_KEYFRAME: u8 = 0x80;
+
+// Timestamps are in milliseconds
+const TIMESTAMP_SCALE_NS: u64 = 1_000_000;
+
+// Block timestamps are relative to the cluster and stored as i16
+const MAX_CLUSTER_DURATION_MS: u64 = i16::MAX as u64;
+
+// Audio timestamps are anchored again beyond this drift
+const MAX_AUDIO_DRIFT: Duration = Duration::from_millis(200);
+
+// Enough for the 3 seek entries
+const SEEK_HEAD_RESERVED_SIZE: usize = 96;
+
+// Size that is patched when finalizing the file. The initial value means "unknown size"
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+
+fn write_id(buffer: &mut Vec<u8>, id: u32) {
+    let bytes = id.to_be_bytes();
+    let leading_zeros = (id.leading_zeros() / 8) as usize;
+    buffer.extend_from_slice(&bytes[leading_zeros..]);
+}
+
+fn write_size(buffer: &mut Vec<u8>, size: u64) {
+    // All ones is reserved for the unknown size
+    let length = (1..=8)
+        .find(|length| size < (1_u64 << (7 * length)) - 1)
+        .unwrap_or(8);
+    let value = size | (1_u64 << (7 * length));
+    buffer.extend_from_slice(&value.to_be_bytes()[8 - length..]);
+}
+
+fn fixed_size(size: u64) -> [u8; 8] {
+    (size | (1 << 56)).to_be_bytes()
+}
+
+fn write_element(buffer: &mut Vec<u8>, id: u32, payload: &[u8]) {
+    write_id(buffer, id);
+    write_size(buffer, payload.len() as u64);
+    buffer.extend_from_slice(payload);
+}
+
+fn write_uint(buffer: &mut Vec<u8>, id: u32, value: u64) {
+    let bytes = value.to_be_bytes();
+    let leading_zeros = usize::min((value.leading_zeros() / 8) as usize, 7);
+    write_element(buffer, id, &bytes[leading_zeros..]);
+}
+
+fn write_master(buffer: &mut Vec<u8>, id: u32, write_children: impl FnOnce(&mut Vec<u8>)) {
+    let mut children = vec![];
+    write_children(&mut children);
+    write_element(buffer, id, &children);
+}
+
+fn write_void(buffer: &mut Vec<u8>, total_size: usize) {
+    // One byte for the ID and one for the size, which limits the total size to 128 bytes
+    debug_assert!(total_size <= 128);
+    write_id(buffer, ID_VOID);
+    write_size(buffer, (total_size - 2) as u64);
+    buffer.resize(buffer.len() + total_size - 2, 0);
+}
+
+// Samples are always 16 bit
+#[derive(Clone, Copy)]
+pub struct AudioFormat {
+    pub sample_rate: u32,
+    pub channels_count: u16,
+}
+
+#[derive(Clone, Copy)]
+pub enum AudioSource {
+    Game,
+    Microphone,
+}
+
+struct AudioTrack {
+    format: AudioFormat,
+    // Timestamp of the first sample counted
+    start_timestamp: Option<Duration>,
+    frames_count: u64,
+}
+
+impl AudioTrack {
+    fn new(format: AudioFormat) -> Self {
+        Self {
+            format,
+            start_timestamp: None,
+            frames_count: 0,
+        }
+    }
+
+    // Returns the timestamp of the packet
+    fn advance(&mut self, frames_count: u64, arrival_timestamp: Duration) -> Duration {
+        let timestamp = self
+            .start_timestamp
+            .map(|start| {
+                start
+                    + Duration::from_nanos(
+                        self.frames_count * 1_000_000_000 / self.format.sample_rate as u64,
+                    )
+            })
+            .filter(|timestamp| timestamp.abs_diff(arrival_timestamp) <= MAX_AUDIO_DRIFT);
+
+        let timestamp = timestamp.unwrap_or_else(|| {
+            self.start_timestamp = Some(arrival_timestamp);
+            self.frames_count = 0;
+
+            arrival_timestamp
+        });
+        self.frames_count += frames_count;
+
+        timestamp
+    }
+}
+
+struct Cluster {
+    // Offset of the cluster element, used to patch its size
+    file_position: u64,
+    timestamp_ms: u64,
+}
+
+pub struct VideoRecorder {
+    writer: BufWriter<File>,
+    codec: CodecType,
+    config_buffer: Vec<u8>,
+    game_audio_track: Option<AudioTrack>,
+    microphone_track: Option<AudioTrack>,
+    // Configuration written in the tracks, None until the first keyframe
+    track_config: Option<Vec<u8>>,
+    segment_data_position: u64,
+    info_position: u64,
+    tracks_position: u64,
+    duration_position: u64,
+    first_timestamp: Option<Duration>,
+    // Time of the first keyframe, used for the audio timestamps
+    start_instant: Option<Instant>,
+    last_timestamp_ms: u64,
+    duration_ms: u64,
+    cluster: Option<Cluster>,
+    // (timestamp, cluster position)
+    cues: Vec<(u64, u64)>,
+    buffer: Vec<u8>,
+    frame_buffer: Vec<u8>,
+}
+
+impl VideoRecorder {
+    // Audio tracks are created only for the specified formats
+    pub fn create(
+        path: &Path,
+        codec: CodecType,
+        config_buffer: Vec<u8>,
+        game_audio_format: Option<AudioFormat>,
+        microphone_format: Option<AudioFormat>,
+    ) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        let mut buffer = vec![];
+        write_master(&mut buffer, ID_EBML, |buffer| {
+            write_uint(buffer, ID_EBML_VERSION, 1);
+            write_uint(buffer, ID_EBML_READ_VERSION, 1);
+            write_uint(buffer, ID_EBML_MAX_ID_LENGTH, 4);
+            write_uint(buffer, ID_EBML_MAX_SIZE_LENGTH, 8);
+            write_element(buffer, ID_DOC_TYPE, b"matroska");
+            write_uint(buffer, ID_DOC_TYPE_VERSION, 4);
+            write_uint(buffer, ID_DOC_TYPE_READ_VERSION, 2);
+        });
+        write_id(&mut buffer, ID_SEGMENT);
+        buffer.extend_from_slice(&UNKNOWN_SIZE);
+        writer.write_all(&buffer)?;
+        let segment_data_position = writer.stream_position()?;
+
+        // Replaced by the seek head when finalizing
+        buffer.clear();
+        write_void(&mut buffer, SEEK_HEAD_RESERVED_SIZE);
+        writer.write_all(&buffer)?;
+
+        let info_position = writer.stream_position()?;
+        let app_name = format!("ALVR {}", *ALVR_VERSION);
+        buffer.clear();
+        write_master(&mut buffer, ID_INFO, |buffer| {
+            write_uint(buffer, ID_TIMESTAMP_SCALE, TIMESTAMP_SCALE_NS);
+            write_element(buffer, ID_MUXING_APP, app_name.as_bytes());
+            write_element(buffer, ID_WRITING_APP, app_name.as_bytes());
+            write_element(buffer, ID_DURATION, &0_f64.to_be_bytes());
+        });
+        // The duration is the last element
+        let duration_position = info_position + (buffer.len() - size_of::<f64>()) as u64;
+        writer.write_all(&buffer)?;
+
+        Ok(Self {
+            writer,
+            codec,
+            config_buffer,
+            game_audio_track: game_audio_format.map(AudioTrack::new),
+            microphone_track: microphone_format.map(AudioTrack::new),
+            track_config: None,
+            segment_data_position,
+            info_position,
+            tracks_position: 0,
+            duration_position,
+            first_timestamp: None,
+            start_instant: None,
+            last_timestamp_ms: 0,
+            duration_ms: 0,
+            cluster: None,
+            cues: vec![],
+            buffer,
+            frame_buffer: vec![],
+        })
+    }
+
+    pub fn set_config(&mut self, codec: CodecType, config_buffer: Vec<u8>) {
+        // The codec of the track cannot be changed
+        if self.track_config.is_none() {
+            self.codec = codec;
+        }
+        self.config_buffer = config_buffer;
+    }
+
+    fn write_tracks(&mut self, keyframe: &[u8]) -> Result<()> {
+        let parameters = codec::codec_parameters(self.codec, &self.config_buffer, keyframe)?;
+
+        self.tracks_position = self.writer.stream_position()?;
+
+        self.buffer.clear();
+        write_master(&mut self.buffer, ID_TRACKS, |buffer| {
+            write_master(buffer, ID_TRACK_ENTRY, |buffer| {
+                write_uint(buffer, ID_TRACK_NUMBER, VIDEO_TRACK_NUMBER as u64);
+                write_uint(buffer, ID_TRACK_UID, VIDEO_TRACK_NUMBER as u64);
+                write_uint(buffer, ID_TRACK_TYPE, TRACK_TYPE_VIDEO);
+                write_uint(buffer, ID_FLAG_LACING, 0);
+                write_element(buffer, ID_CODEC_ID, parameters.codec_id.as_bytes());
+                write_element(buffer, ID_CODEC_PRIVATE, &parameters.codec_private);
+                write_master(buffer, ID_VIDEO, |buffer| {
+                    write_uint(buffer, ID_PIXEL_WIDTH, parameters.width as u64);
+                    write_uint(buffer, ID_PIXEL_HEIGHT, parameters.height as u64);
+                });
+            });
+            write_master(buffer, ID_TRACK_ENTRY, |buffer| {
+                write_uint(buffer, ID_TRACK_NUMBER, VIEW_PARAMS_TRACK_NUMBER as u64);
+                write_uint(buffer, ID_TRACK_UID, VIEW_PARAMS_TRACK_NUMBER as u64);
+                write_uint(buffer, ID_TRACK_TYPE, TRACK_TYPE_SUBTITLE);
+                write_uint(buffer, ID_FLAG_DEFAULT, 0);
+                write_uint(buffer, ID_FLAG_LACING, 0);
+                write_element(buffer, ID_NAME, b"View params");
+                write_element(buffer, ID_CODEC_ID, b"S_TEXT/UTF8");
+            });
+            let audio_tracks = [
+                (
+                    GAME_AUDIO_TRACK_NUMBER,
+                    "Game audio",
+                    self.game_audio_track.as_ref().map(|track| track.format),
+                ),
+                (
+                    MICROPHONE_TRACK_NUMBER,
+                    "Microphone",
+                    self.microphone_track.as_ref().map(|track| track.format),
+                ),
+            ];
+            for (track_number, name, format) in audio_tracks {
+                let Some(format) = format else {
+                    continue;
+                };
+                write_master(buffer, ID_TRACK_ENTRY, |buffer| {
+                    write_uint(buffer, ID_TRACK_NUMBER, track_number as u64);
+                    write_uint(buffer, ID_TRACK_UID, track_number as u64);
+                    write_uint(buffer, ID_TRACK_TYPE, TRACK_TYPE_AUDIO);
+                    // Players should pick the game audio
+                    if track_number == MICROPHONE_TRACK_NUMBER {
+                        write_uint(buffer, ID_FLAG_DEFAULT, 0);
+                    }
+                    write_uint(buffer, ID_FLAG_LACING, 0);
+                    write_element(buffer, ID_NAME, name.as_bytes());
+                    // Only little endian platforms are supported
+                    write_element(buffer, ID_CODEC_ID, b"A_PCM/INT/LIT");
+                    write_master(buffer, ID_AUDIO, |buffer| {
+                        write_element(
+                            buffer,
+                            ID_SAMPLING_FREQUENCY,
+                            &(format.sample_rate as f64).to_be_bytes(),
+                        );
+                        write_uint(buffer, ID_CHANNELS, format.channels_count as u64);
+                        write_uint(buffer, ID_BIT_DEPTH, 16);
+                    });
+                });
+            }
+        });
+        self.writer.write_all(&self.buffer)?;
+
+        self.track_config = Some(self.config_buffer.clone());
+
+        Ok(())
+    }
+
+    fn finish_cluster(&mut self) -> Result<()> {
+        if let Some(cluster) = self.cluster.take() {
+            let end_position = self.writer.stream_position()?;
+            // ID (4 bytes) + size (8 bytes)
+            let size = end_position - cluster.file_position - 12;
+
+            self.writer
+                .seek(SeekFrom::Start(cluster.file_position + 4))?;
+            self.writer.write_all(&fixed_size(size))?;
+            self.writer.seek(SeekFrom::Start(end_position))?;
+        }
+
+        Ok(())
+    }
+
+    fn start_cluster(&mut self, timestamp_ms: u64) -> Result<()> {
+        let file_position = self.writer.stream_position()?;
+
+        self.buffer.clear();
+        write_id(&mut self.buffer, ID_CLUSTER);
+        self.buffer.extend_from_slice(&UNKNOWN_SIZE);
+        write_uint(&mut self.buffer, ID_CLUSTER_TIMESTAMP, timestamp_ms);
+        self.writer.write_all(&self.buffer)?;
+
+        self.cluster = Some(Cluster {
+            file_position,
+            timestamp_ms,
+        });
+
+        Ok(())
+    }
+
+    fn write_block(&mut self, track_number: u8, relative_timestamp: i16, flags: u8) -> Result<()> {
+        // Track number (1 byte), timestamp (2 bytes), flags (1 byte)
+        self.buffer.clear();
+        write_id(&mut self.buffer, ID_SIMPLE_BLOCK);
+        write_size(&mut self.buffer, 4 + self.frame_buffer.len() as u64);
+        self.buffer.push(0x80 | track_number);
+        self.buffer
+            .extend_from_slice(&relative_timestamp.to_be_bytes());
+        self.buffer.push(flags);
+
+        self.writer.write_all(&self.buffer)?;
+        self.writer.write_all(&self.frame_buffer)?;
+
+        Ok(())
+    }
+
+    // Frames are skipped until the first keyframe
+    pub fn write_frame(
+        &mut self,
+        timestamp: Duration,
+        global_view_params: [ViewParams; 2],
+        is_idr: bool,
+        nal_buffer: &[u8],
+    ) -> Result<()> {
+        let mut in_band_config = None;
+        if self.track_config.is_none() {
+            if !is_idr {
+                return Ok(());
+            }
+
+            self.write_tracks(nal_buffer)?;
+            self.start_instant = Some(Instant::now());
+        } else if is_idr && self.track_config.as_ref() != Some(&self.config_buffer) {
+            in_band_config = Some(self.config_buffer.as_slice());
+        }
+
+        codec::convert_frame(
+            self.codec,
+            nal_buffer,
+            in_band_config,
+            &mut self.frame_buffer,
+        )?;
+        if is_idr && in_band_config.is_some() {
+            self.track_config = Some(self.config_buffer.clone());
+        }
+
+        let first_timestamp = *self.first_timestamp.get_or_insert(timestamp);
+        // Timestamps must not decrease
+        let timestamp_ms = u64::max(
+            timestamp.saturating_sub(first_timestamp).as_millis() as u64,
+            self.last_timestamp_ms,
+        );
+        self.last_timestamp_ms = timestamp_ms;
+        self.duration_ms = u64::max(self.duration_ms, timestamp_ms);
+
+        // The current cluster could have been started by a later audio packet
+        let cluster_timestamp_ms = self.cluster.as_ref().map(|cluster| cluster.timestamp_ms);
+        if is_idr
+            || cluster_timestamp_ms.is_none_or(|cluster_ts| {
+                timestamp_ms.abs_diff(cluster_ts) > MAX_CLUSTER_DURATION_MS
+            })
+        {
+            self.finish_cluster()?;
+            self.start_cluster(timestamp_ms)?;
+
+            if is_idr {
+                let cluster_position = self.cluster.as_ref().unwrap().file_position;
+                self.cues
+                    .push((timestamp_ms, cluster_position - self.segment_data_position));
+            }
+        }
+        let relative_timestamp =
+            (timestamp_ms as i64 - self.cluster.as_ref().unwrap().timestamp_ms as i64) as i16;
+
+        let flags = if is_idr { BLOCK_FLAG_KEYFRAME } else { 0 };
+        self.write_block(VIDEO_TRACK_NUMBER, relative_timestamp, flags)?;
+
+        self.frame_buffer = serde_json::to_vec(&global_view_params)?;
+        self.write_block(
+            VIEW_PARAMS_TRACK_NUMBER,
+            relative_timestamp,
+            BLOCK_FLAG_KEYFRAME,
+        )?;
+
+        Ok(())
+    }
+
+    // False if the samples would be discarded
+    pub fn is_recording_audio(&self, source: AudioSource) -> bool {
+        let track = match source {
+            AudioSource::Game => &self.game_audio_track,
+            AudioSource::Microphone => &self.microphone_track,
+        };
+
+        track.is_some() && self.start_instant.is_some()
+    }
+
+    // Samples are interleaved. Audio is skipped until the first video keyframe
+    pub fn write_audio(&mut self, source: AudioSource, samples: &[u8]) -> Result<()> {
+        let (track_number, track) = match source {
+            AudioSource::Game => (GAME_AUDIO_TRACK_NUMBER, &mut self.game_audio_track),
+            AudioSource::Microphone => (MICROPHONE_TRACK_NUMBER, &mut self.microphone_track),
+        };
+        let (Some(track), Some(start_instant), Some(cluster)) =
+            (track, self.start_instant, &self.cluster)
+        else {
+            return Ok(());
+        };
+
+        // The packet is received when its last sample has been captured
+        let frames_count =
+            samples.len() / (size_of::<i16>() * track.format.channels_count as usize);
+        let packet_duration =
+            Duration::from_secs_f64(frames_count as f64 / track.format.sample_rate as f64);
+        let arrival_timestamp = start_instant.elapsed().saturating_sub(packet_duration);
+        let timestamp_ms = track
+            .advance(frames_count as u64, arrival_timestamp)
+            .as_millis() as u64;
+        self.duration_ms = u64::max(self.duration_ms, timestamp_ms);
+
+        let mut relative_timestamp = timestamp_ms as i64 - cluster.timestamp_ms as i64;
+        if relative_timestamp > MAX_CLUSTER_DURATION_MS as i64 {
+            self.finish_cluster()?;
+            self.start_cluster(timestamp_ms)?;
+            relative_timestamp = 0;
+        }
+        // Packets that arrive late can precede the start of the cluster
+        let relative_timestamp = i64::max(relative_timestamp, i16::MIN as i64) as i16;
+
+        self.frame_buffer.clear();
+        self.frame_buffer.extend_from_slice(samples);
+        self.write_block(track_number, relative_timestamp, BLOCK_FLAG_KEYFRAME)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish_cluster()?;
+
+        let cues_position = self.writer.stream_position()?;
+        if !self.cues.is_empty() {
+            self.buffer.clear();
+            write_master(&mut self.buffer, ID_CUES, |buffer| {
+                for &(timestamp_ms, cluster_position) in &self.cues {
+                    write_master(buffer, ID_CUE_POINT, |buffer| {
+                        write_uint(buffer, ID_CUE_TIME, timestamp_ms);
+                        write_master(buffer, ID_CUE_TRACK_POSITIONS, |buffer| {
+                            write_uint(buffer, ID_CUE_TRACK, VIDEO_TRACK_NUMBER as u64);
+                            write_uint(buffer, ID_CUE_CLUSTER_POSITION, cluster_position);
+                        });
+                    });
+                }
+            });
+            self.writer.write_all(&self.buffer)?;
+        }
+        let end_position = self.writer.stream_position()?;
+
+        let mut seek_entries = vec![(ID_INFO, self.info_position)];
+        if self.track_config.is_some() {
+            seek_entries.push((ID_TRACKS, self.tracks_position));
+        }
+        if !self.cues.is_empty() {
+            seek_entries.push((ID_CUES, cues_position));
+        }
+
+        self.buffer.clear();
+        write_master(&mut self.buffer, ID_SEEK_HEAD, |buffer| {
+            for (id, position) in seek_entries {
+                write_master(buffer, ID_SEEK, |buffer| {
+                    write_element(buffer, ID_SEEK_ID, &id.to_be_bytes());
+                    write_uint(
+                        buffer,
+                        ID_SEEK_POSITION,
+                        position - self.segment_data_position,
+                    );
+                });
+            }
+        });
+        let Some(void_size) = SEEK_HEAD_RESERVED_SIZE.checked_sub(self.buffer.len()) else {
+            bail!("Seek head too big");
+        };
+        // A void element is at least 2 bytes
+        if void_size == 1 {
+            bail!("Seek head does not fit");
+        }
+        if void_size > 0 {
+            write_void(&mut self.buffer, void_size);
+        }
+
+        self.writer
+            .seek(SeekFrom::Start(self.segment_data_position))?;
+        self.writer.write_all(&self.buffer)?;
+
+        self.writer
+            .seek(SeekFrom::Start(self.segment_data_position - 8))?;
+        self.writer
+            .write_all(&fixed_size(end_position - self.segment_data_position))?;
+
+        self.writer.seek(SeekFrom::Start(self.duration_position))?;
+        self.writer
+            .write_all(&(self.duration_ms as f64).to_be_bytes())?;
+
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+impl Drop for VideoRecorder {
+    fn drop(&mut self) {
+        self.finish().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ebml_size() {
+        let encode = |size| {
+            let mut buffer = vec![];
+            write_size(&mut buffer, size);
+            buffer
+        };
+
+        assert_eq!(encode(0), [0x80]);
+        assert_eq!(encode(126), [0xfe]);
+        // 0xff is reserved for the unknown size
+        assert_eq!(encode(127), [0x40, 0x7f]);
+        assert_eq!(encode(16382), [0x7f, 0xfe]);
+        assert_eq!(encode(16383), [0x20, 0x3f, 0xff]);
+        assert_eq!(
+            encode((1 << 56) - 2),
+            [0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe]
+        );
+
+        assert_eq!(fixed_size(0x1234), [0x01, 0, 0, 0, 0, 0, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn test_ebml_elements() {
+        let mut buffer = vec![];
+        write_uint(&mut buffer, ID_TRACK_NUMBER, 0);
+        write_uint(&mut buffer, ID_TIMESTAMP_SCALE, TIMESTAMP_SCALE_NS);
+        assert_eq!(
+            buffer,
+            [0xd7, 0x81, 0x00, 0x2a, 0xd7, 0xb1, 0x83, 0x0f, 0x42, 0x40]
+        );
+
+        buffer.clear();
+        write_void(&mut buffer, SEEK_HEAD_RESERVED_SIZE);
+        assert_eq!(buffer.len(), SEEK_HEAD_RESERVED_SIZE);
+        assert_eq!(buffer[..2], [0xec, 0xde]);
+    }
+
+    #[test]
+    fn test_audio_timestamps() {
+        let ms = Duration::from_millis;
+        let mut track = AudioTrack::new(AudioFormat {
+            sample_rate: 48000,
+            channels_count: 2,
+        });
+
+        // 10 ms packets, the arrival jitter is ignored
+        assert_eq!(track.advance(480, ms(100)), ms(100));
+        assert_eq!(track.advance(480, ms(115)), ms(110));
+        assert_eq!(track.advance(480, ms(118)), ms(120));
+
+        // Anchored again after packets have been lost
+        assert_eq!(track.advance(480, ms(1200)), ms(1200));
+        assert_eq!(track.advance(480, ms(1205)), ms(1210));
+    }
+}
@@ -1,6 +1,7 @@
 use crate::{
     ConnectionContext, FILESYSTEM_LAYOUT, SESSION_MANAGER, ServerCoreEvent,
     logging_backend::LOGGING_EVENTS_SENDER,
+    metrics::{self, OPENMETRICS_CONTENT_TYPE},
 };
 use alvr_common::{
     ConnectionState, RelaxedAtomic,
@@ -9,6 +10,7 @@ use alvr_common::{
 };
 use alvr_events::{ButtonEvent, EventType};
 use alvr_packets::{ButtonEntry, ClientListAction, ServerRequest};
+use alvr_server_io::ApiTokens;
 use bytes::Buf;
 use futures::SinkExt;
 use headers::{
@@ -18,18 +20,76 @@ use headers::{
 use hyper::{
     Body, Method, Request, Response, StatusCode,
     header::{
-        self, ACCESS_CONTROL_ALLOW_ORIGIN, CACHE_CONTROL, CONTENT_TYPE, HeaderName, HeaderValue,
+        self, ACCESS_CONTROL_ALLOW_ORIGIN, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, HeaderName,
+        HeaderValue,
     },
     service,
 };
 use serde::de::DeserializeOwned;
 use serde_json as json;
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
 use tokio::sync::broadcast::{self, error::RecvError};
 use tokio_tungstenite::{WebSocketStream, tungstenite::protocol};
 
 pub const WS_BROADCAST_CAPACITY: usize = 256;
 
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Access {
+    None,
+    ReadOnly,
+    Full,
+}
+
+fn required_access(path: &str) -> Access {
+    match path {
+        "/api/version" | "/api/ping" => Access::None,
+        "/api/events" | "/metrics" => Access::ReadOnly,
+        _ => Access::Full,
+    }
+}
+
+// Constant time comparison, to not leak the tokens through the response time
+fn token_matches(candidate: &str, token: &str) -> bool {
+    candidate.len() == token.len()
+        && candidate
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+// Browsers cannot set headers for WebSockets, so the token can also be passed in the query
+fn granted_access(request: &Request<Body>, api_tokens: &ApiTokens) -> Access {
+    let header_token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let query_token = request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+    });
+    let Some(token) = header_token.or(query_token) else {
+        return Access::None;
+    };
+
+    if token_matches(token, &api_tokens.token) {
+        Access::Full
+    } else if api_tokens
+        .read_only_tokens
+        .iter()
+        .any(|read_only_token| token_matches(token, read_only_token))
+    {
+        Access::ReadOnly
+    } else {
+        Access::None
+    }
+}
+
 fn reply(code: StatusCode) -> Result<Response<Body>> {
     Ok(Response::builder().status(code).body(Body::empty())?)
 }
@@ -92,7 +152,8 @@ fn websocket<T: Clone + Send + 'static>(
 }
 
 async fn http_api(
-    connection_context: &ConnectionContext,
+    connection_context: &Arc<ConnectionContext>,
+    api_tokens: &ApiTokens,
     request: Request<Body>,
 ) -> Result<Response<Body>> {
     const X_ALVR: &str = "X-ALVR";
@@ -129,7 +190,7 @@ async fn http_api(
             for header in requested_headers.iter() {
                 if header == HeaderName::from_static(X_ALVR) {
                     found_x_alvr = true;
-                } else if header != CONTENT_TYPE {
+                } else if header != CONTENT_TYPE && header != AUTHORIZATION {
                     return Ok(bad_request);
                 }
             }
@@ -146,7 +207,7 @@ async fn http_api(
         let allowed_methods = [Method::GET, Method::POST, Method::OPTIONS]
             .into_iter()
             .collect::<AccessControlAllowMethods>();
-        let allowed_headers = [CONTENT_TYPE, HeaderName::from_static(X_ALVR)]
+        let allowed_headers = [CONTENT_TYPE, AUTHORIZATION, HeaderName::from_static(X_ALVR)]
             .into_iter()
             .collect::<AccessControlAllowHeaders>();
 
@@ -169,6 +230,29 @@ async fn http_api(
             .body("invalid method".into())?);
     }
 
+    // The X-ALVR header only protects from browsers, the token is needed for everything else
+    let granted_access = granted_access(&request, api_tokens);
+    if granted_access < required_access(request.uri().path()) {
+        let status = if granted_access == Access::ReadOnly {
+            StatusCode::FORBIDDEN
+        } else {
+            StatusCode::UNAUTHORIZED
+        };
+
+        return Ok(Response::builder()
+            .status(status)
+            .body("invalid or missing API token".into())?);
+    }
+
+    // Scrapers like Prometheus cannot set the X-ALVR header. The metrics are read-only, and are not
+    // shared with other origins (no CORS header)
+    if request.method() == Method::GET && request.uri().path() == "/metrics" {
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, OPENMETRICS_CONTENT_TYPE)
+            .header(CACHE_CONTROL, "no-cache, no-store, must-revalidate")
+            .body(metrics::openmetrics_text(connection_context).into())?);
+    }
+
     // This is the actual core part of cors
     // We require the X-ALVR header, but the browser forces a cors preflight
     // if the site tries to send a request with it set since it's not-whitelisted
@@ -239,7 +323,25 @@ async fn http_api(
                         crate::SESSION_MANAGER.read().settings(),
                     ),
                     ServerRequest::StopRecording => {
-                        *connection_context.video_recording_file.lock() = None
+                        *connection_context.video_recorder.lock() = None
+                    }
+                    ServerRequest::StartTrackingRecording => {
+                        crate::create_tracking_recording_file(connection_context)
+                    }
+                    ServerRequest::StopTrackingRecording => {
+                        *connection_context.tracking_recorder.lock() = None
+                    }
+                    ServerRequest::StartTrackingReplay(path) => {
+                        crate::start_tracking_replay(Arc::clone(connection_context), path)
+                    }
+                    ServerRequest::StopTrackingReplay => {
+                        crate::stop_tracking_replay(connection_context)
+                    }
+                    ServerRequest::StartStatisticsRecording => {
+                        crate::create_statistics_recording_file(connection_context)
+                    }
+                    ServerRequest::StopStatisticsRecording => {
+                        *connection_context.statistics_recorder.lock() = None
                     }
                     ServerRequest::FirewallRules(action) => {
                         if let Err(e) =
@@ -370,19 +472,28 @@ async fn http_api(
 }
 
 pub async fn web_server(connection_context: Arc<ConnectionContext>) -> Result<()> {
-    let web_server_port = crate::SESSION_MANAGER
-        .read()
-        .settings()
-        .connection
-        .web_server_port;
+    let (web_server_port, localhost_only) = {
+        let connection_config = &crate::SESSION_MANAGER.read().settings().connection;
+        (
+            connection_config.web_server_port,
+            connection_config.web_server_localhost_only,
+        )
+    };
+
+    // Changes to the tokens file are applied at the next launch
+    let api_tokens = Arc::new(alvr_server_io::load_or_create_api_tokens(
+        &FILESYSTEM_LAYOUT.get().unwrap().api_tokens(),
+    )?);
 
     let service = service::make_service_fn(move |_| {
         let connection_context = Arc::clone(&connection_context);
+        let api_tokens = Arc::clone(&api_tokens);
         async move {
             Ok::<_, anyhow::Error>(service::service_fn(move |request| {
                 let connection_context = Arc::clone(&connection_context);
+                let api_tokens = Arc::clone(&api_tokens);
                 async move {
-                    let res = http_api(&connection_context, request).await;
+                    let res = http_api(&connection_context, &api_tokens, request).await;
                     if let Err(e) = &res {
                         alvr_common::show_e(e);
                     }
@@ -393,10 +504,15 @@ pub async fn web_server(connection_context: Arc<ConnectionContext>) -> Result<()
         }
     });
 
-    Ok(hyper::Server::bind(&SocketAddr::new(
-        "0.0.0.0".parse().unwrap(),
-        web_server_port,
-    ))
-    .serve(service)
-    .await?)
+    let address = if localhost_only {
+        Ipv4Addr::LOCALHOST
+    } else {
+        Ipv4Addr::UNSPECIFIED
+    };
+
+    Ok(
+        hyper::Server::bind(&SocketAddr::new(IpAddr::V4(address), web_server_port))
+            .serve(service)
+            .await?,
+    )
 }
@@ -0,0 +1,304 @@
+mod pattern;
+mod video_file;
+
+use alvr_common::{
+    HEAD_ID, Pose, RelaxedAtomic, ViewParams, info,
+    parking_lot::{Mutex, RwLock},
+    warn,
+};
+use alvr_filesystem as afs;
+use alvr_server_core::{ServerCoreContext, ServerCoreEvent};
+use alvr_session::CodecType;
+use pattern::PatternEncoder;
+use pico_args::Arguments;
+use std::{
+    collections::VecDeque,
+    env,
+    path::PathBuf,
+    process,
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use video_file::{VideoFile, VideoFrame};
+
+const HELP_STR: &str = r#"
+alvr_server_headless
+Streamer without SteamVR. Streams a test pattern or a pre-encoded video file to the client.
+
+USAGE:
+    alvr_server_headless [FLAGS] [VIDEO_FILE]
+
+ARGS:
+    VIDEO_FILE          H.264 or HEVC file in Annex B format, streamed in a loop. If not
+                        specified, a test pattern is streamed
+
+FLAGS:
+    --help              Print this text
+    --root <PATH>       ALVR root directory, containing the session file. Defaults to the
+                        directory of the executable
+    --codec <CODEC>     Codec of the video file: h264 or hevc. Defaults to the file extension
+    --width <WIDTH>     Width of the test pattern (both eyes). Default: 512
+    --height <HEIGHT>   Height of the test pattern. Default: 256
+
+The stream codec is chosen by the client from the session settings: it must match the codec of the
+streamed frames. The frames are streamed with their original resolution.
+"#;
+
+const DEFAULT_PATTERN_SIZE: (u32, u32) = (512, 256);
+
+// Used until the statistics manager provides the vsync timing
+const FALLBACK_FRAME_INTERVAL: Duration = Duration::from_millis(8);
+
+const HEAD_POSE_QUEUE_SIZE: usize = 360;
+
+static SERVER_CORE_CONTEXT: RwLock<Option<ServerCoreContext>> = RwLock::new(None);
+static LOCAL_VIEW_PARAMS: RwLock<[ViewParams; 2]> = RwLock::new([ViewParams::DUMMY; 2]);
+static HEAD_POSE_QUEUE: Mutex<VecDeque<(Duration, Pose)>> = Mutex::new(VecDeque::new());
+static IS_STREAMING: RelaxedAtomic = RelaxedAtomic::new(false);
+static IDR_REQUESTED: RelaxedAtomic = RelaxedAtomic::new(false);
+
+#[derive(Clone)]
+enum FrameSource {
+    Pattern(PatternEncoder),
+    File(VideoFile),
+}
+
+impl FrameSource {
+    fn codec(&self) -> CodecType {
+        match self {
+            FrameSource::Pattern(_) => CodecType::H264,
+            FrameSource::File(file) => file.codec(),
+        }
+    }
+
+    fn next_frame(&mut self, idr_requested: bool) -> VideoFrame {
+        match self {
+            FrameSource::Pattern(encoder) => VideoFrame {
+                // Every frame is an IDR
+                config_nals: encoder.config_nals(),
+                nals: encoder.next_frame(),
+                is_idr: true,
+            },
+            FrameSource::File(file) => file.next_frame(idr_requested),
+        }
+    }
+}
+
+fn codec_from_name(name: &str) -> Option<CodecType> {
+    match name.to_lowercase().as_str() {
+        "h264" | "264" | "avc" => Some(CodecType::H264),
+        "h265" | "265" | "hevc" => Some(CodecType::Hevc),
+        _ => None,
+    }
+}
+
+fn frame_loop(mut source: FrameSource) {
+    let mut last_timestamp = None;
+    while IS_STREAMING.value() {
+        // Don't sleep while locking SERVER_CORE_CONTEXT
+        let sleep_duration = SERVER_CORE_CONTEXT
+            .read()
+            .as_ref()
+            .and_then(|context| context.duration_until_next_vsync());
+        thread::sleep(sleep_duration.unwrap_or(FALLBACK_FRAME_INTERVAL));
+
+        // Like SteamVR, render with the most recent head pose
+        let Some((timestamp, head_pose)) = HEAD_POSE_QUEUE.lock().back().copied() else {
+            continue;
+        };
+        if last_timestamp == Some(timestamp) {
+            continue;
+        }
+        last_timestamp = Some(timestamp);
+
+        let frame = source.next_frame(IDR_REQUESTED.value());
+        if frame.is_idr {
+            IDR_REQUESTED.set(false);
+        }
+
+        let local_view_params = *LOCAL_VIEW_PARAMS.read();
+        let global_view_params = [
+            ViewParams {
+                pose: head_pose * local_view_params[0].pose,
+                fov: local_view_params[0].fov,
+            },
+            ViewParams {
+                pose: head_pose * local_view_params[1].pose,
+                fov: local_view_params[1].fov,
+            },
+        ];
+
+        if let Some(context) = &*SERVER_CORE_CONTEXT.read() {
+            context.report_composed(timestamp, Duration::ZERO);
+            context.report_present(timestamp, Duration::ZERO);
+
+            if !frame.config_nals.is_empty() {
+                context.set_video_config_nals(frame.config_nals, source.codec());
+            }
+            context.send_video_nal(timestamp, global_view_params, frame.is_idr, frame.nals);
+        }
+    }
+}
+
+fn stop_streaming(streaming_thread: &mut Option<JoinHandle<()>>) {
+    if let Some(thread) = streaming_thread.take() {
+        IS_STREAMING.set(false);
+        thread.join().ok();
+    }
+}
+
+// Returns true if the server should be restarted
+fn event_loop(events_receiver: mpsc::Receiver<ServerCoreEvent>, source: &FrameSource) -> bool {
+    let mut streaming_thread = None;
+    let mut restart = false;
+    while let Ok(event) = events_receiver.recv() {
+        match event {
+            ServerCoreEvent::ClientConnected => {
+                stop_streaming(&mut streaming_thread);
+
+                HEAD_POSE_QUEUE.lock().clear();
+                IDR_REQUESTED.set(true);
+                IS_STREAMING.set(true);
+
+                let source = source.clone();
+                streaming_thread = Some(thread::spawn(move || frame_loop(source)));
+            }
+            ServerCoreEvent::ClientDisconnected => stop_streaming(&mut streaming_thread),
+            ServerCoreEvent::LocalViewParams(params) => *LOCAL_VIEW_PARAMS.write() = params,
+            ServerCoreEvent::Tracking { poll_timestamp } => {
+                if let Some(context) = &*SERVER_CORE_CONTEXT.read()
+                    && let Some(motion) = context.get_device_motion(*HEAD_ID, poll_timestamp)
+                {
+                    let mut head_pose_queue_lock = HEAD_POSE_QUEUE.lock();
+                    head_pose_queue_lock.push_back((poll_timestamp, motion.pose));
+                    while head_pose_queue_lock.len() > HEAD_POSE_QUEUE_SIZE {
+                        head_pose_queue_lock.pop_front();
+                    }
+                }
+            }
+            ServerCoreEvent::RequestIDR => IDR_REQUESTED.set(true),
+            ServerCoreEvent::ShutdownPending => break,
+            ServerCoreEvent::RestartPending => {
+                restart = true;
+                break;
+            }
+            // There are no devices or compositor to update
+            ServerCoreEvent::SetOpenvrProperty { .. }
+            | ServerCoreEvent::Battery(_)
+            | ServerCoreEvent::PlayspaceSync(_)
+            | ServerCoreEvent::Buttons(_)
+            | ServerCoreEvent::CaptureFrame
+            | ServerCoreEvent::GameRenderLatencyFeedback(_) => (),
+        }
+    }
+
+    stop_streaming(&mut streaming_thread);
+
+    restart
+}
+
+fn main() {
+    let mut args = Arguments::from_env();
+
+    if args.contains(["-h", "--help"]) {
+        println!("{HELP_STR}");
+        return;
+    }
+
+    let root: Option<PathBuf> = args.opt_value_from_str("--root").unwrap();
+    let codec_name: Option<String> = args.opt_value_from_str("--codec").unwrap();
+    let width = args.opt_value_from_str("--width").unwrap();
+    let height = args.opt_value_from_str("--height").unwrap();
+    let video_path: Option<PathBuf> = args.opt_free_from_str().unwrap();
+
+    if !args.finish().is_empty() {
+        eprintln!("Unrecognized arguments\n{HELP_STR}");
+        process::exit(1);
+    }
+
+    let filesystem_layout = if let Some(root) = root {
+        afs::Layout::new(&root)
+    } else {
+        env::current_exe()
+            .ok()
+            .and_then(|path| afs::filesystem_layout_from_dashboard_exe(&path))
+            .unwrap_or_else(afs::filesystem_layout_invalid)
+    };
+
+    alvr_server_core::initialize_environment(filesystem_layout.clone());
+
+    let log_to_disk = alvr_server_core::settings().extra.logging.log_to_disk;
+    alvr_server_core::init_logging(
+        log_to_disk.then(|| filesystem_layout.session_log()),
+        Some(filesystem_layout.crash_log()),
+    );
+
+    let source = if let Some(path) = video_path {
+        let codec = codec_name
+            .as_deref()
+            .or_else(|| path.extension().and_then(|extension| extension.to_str()));
+        let Some(codec) = codec.and_then(codec_from_name) else {
+            eprintln!("Cannot determine the codec of the video file. Use --codec");
+            process::exit(1);
+        };
+
+        match VideoFile::load(&path, codec) {
+            Ok(file) => {
+                info!(
+                    "Streaming {} frames from {}",
+                    file.frame_count(),
+                    path.display()
+                );
+
+                FrameSource::File(file)
+            }
+            Err(e) => {
+                eprintln!("Failed to load {}: {e}", path.display());
+                process::exit(1);
+            }
+        }
+    } else {
+        let (default_width, default_height) = DEFAULT_PATTERN_SIZE;
+        let encoder = PatternEncoder::new(
+            width.unwrap_or(default_width),
+            height.unwrap_or(default_height),
+        );
+        info!(
+            "Streaming a {}x{} test pattern",
+            encoder.width(),
+            encoder.height()
+        );
+
+        FrameSource::Pattern(encoder)
+    };
+
+    let preferred_codec = alvr_server_core::settings().video.preferred_codec;
+    if preferred_codec != source.codec() {
+        warn!(
+            "The preferred codec is {preferred_codec:?} but the streamed frames are {:?}",
+            source.codec()
+        );
+    }
+
+    // The first connection of a client changes the stream configuration and the client is asked to
+    // reconnect, like when SteamVR has to be restarted. There is nothing to restart here.
+    loop {
+        let (context, events_receiver) = ServerCoreContext::new();
+        context.start_connection();
+        *SERVER_CORE_CONTEXT.write() = Some(context);
+
+        let restart = event_loop(events_receiver, &source);
+
+        // Don't drop the context while locking SERVER_CORE_CONTEXT
+        let context = SERVER_CORE_CONTEXT.write().take();
+        if !restart {
+            break;
+        }
+
+        info!("Restarting");
+        if let Some(context) = context {
+            context.restart();
+        }
+    }
+}
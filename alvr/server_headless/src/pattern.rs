@@ -0,0 +1,215 @@
+// Procedural test pattern encoded as H.264 without an encoder library. Every frame is an IDR
+// picture made only of I_PCM macroblocks, which store the raw samples. This is very inefficient, so
+// the resolution should be kept small, but any H.264 decoder can decode it.
+
+const MB_SIZE: u32 = 16;
+const MB_TYPE_I_PCM: u32 = 25;
+const SLICE_TYPE_I: u32 = 7;
+const PROFILE_IDC_BASELINE: u8 = 66;
+const CONSTRAINT_SET1_FLAG: u8 = 0x40;
+const LEVEL_IDC: u8 = 51;
+
+const NAL_HEADER_IDR_SLICE: u8 = 0x65;
+const NAL_HEADER_SPS: u8 = 0x67;
+const NAL_HEADER_PPS: u8 = 0x68;
+
+// Video range luma values. Samples never contain zeros, which makes start code emulation rare
+const LUMA_DARK: u8 = 16;
+const LUMA_BRIGHT: u8 = 235;
+const SQUARE_SIZE: u32 = 32;
+const SCROLL_SPEED: u32 = 4;
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    current: u8,
+    bit_count: u8,
+}
+
+impl BitWriter {
+    fn bit(&mut self, value: bool) {
+        self.current = (self.current << 1) | value as u8;
+        self.bit_count += 1;
+
+        if self.bit_count == 8 {
+            self.bytes.push(self.current);
+            self.current = 0;
+            self.bit_count = 0;
+        }
+    }
+
+    fn bits(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.bit((value >> i) & 1 != 0);
+        }
+    }
+
+    // Exp-Golomb code
+    fn ue(&mut self, value: u32) {
+        let length = u32::BITS - (value + 1).leading_zeros();
+        self.bits(0, length - 1);
+        self.bits(value + 1, length);
+    }
+
+    fn se(&mut self, value: i32) {
+        if value > 0 {
+            self.ue(value as u32 * 2 - 1);
+        } else {
+            self.ue(value.unsigned_abs() * 2);
+        }
+    }
+
+    fn align_with_zeros(&mut self) {
+        while self.bit_count != 0 {
+            self.bit(false);
+        }
+    }
+
+    // Must be aligned
+    fn bytes(&mut self, bytes: &[u8]) {
+        debug_assert_eq!(self.bit_count, 0);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn finish_rbsp(mut self) -> Vec<u8> {
+        self.bit(true);
+        self.align_with_zeros();
+
+        self.bytes
+    }
+}
+
+// Adds the start code and the emulation prevention bytes
+fn write_nal(out: &mut Vec<u8>, header: u8, rbsp: &[u8]) {
+    out.extend_from_slice(&[0, 0, 0, 1, header]);
+
+    let mut zeros_count = 0;
+    for &byte in rbsp {
+        if zeros_count >= 2 && byte <= 3 {
+            out.push(3);
+            zeros_count = 0;
+        }
+        out.push(byte);
+
+        zeros_count = if byte == 0 { zeros_count + 1 } else { 0 };
+    }
+}
+
+#[derive(Clone)]
+pub struct PatternEncoder {
+    width_mbs: u32,
+    height_mbs: u32,
+    frame_index: u32,
+}
+
+impl PatternEncoder {
+    // The size is rounded up to a multiple of 16
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width_mbs: u32::max(width.div_ceil(MB_SIZE), 1),
+            height_mbs: u32::max(height.div_ceil(MB_SIZE), 1),
+            frame_index: 0,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width_mbs * MB_SIZE
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height_mbs * MB_SIZE
+    }
+
+    // SPS and PPS
+    pub fn config_nals(&self) -> Vec<u8> {
+        let mut sps = BitWriter::default();
+        sps.bits(PROFILE_IDC_BASELINE as u32, 8);
+        sps.bits(CONSTRAINT_SET1_FLAG as u32, 8);
+        sps.bits(LEVEL_IDC as u32, 8);
+        sps.ue(0); // seq_parameter_set_id
+        sps.ue(0); // log2_max_frame_num_minus4
+        sps.ue(2); // pic_order_cnt_type
+        sps.ue(1); // max_num_ref_frames
+        sps.bit(false); // gaps_in_frame_num_value_allowed_flag
+        sps.ue(self.width_mbs - 1);
+        sps.ue(self.height_mbs - 1);
+        sps.bit(true); // frame_mbs_only_flag
+        sps.bit(true); // direct_8x8_inference_flag
+        sps.bit(false); // frame_cropping_flag
+        sps.bit(false); // vui_parameters_present_flag
+
+        let mut pps = BitWriter::default();
+        pps.ue(0); // pic_parameter_set_id
+        pps.ue(0); // seq_parameter_set_id
+        pps.bit(false); // entropy_coding_mode_flag (CAVLC)
+        pps.bit(false); // bottom_field_pic_order_in_frame_present_flag
+        pps.ue(0); // num_slice_groups_minus1
+        pps.ue(0); // num_ref_idx_l0_default_active_minus1
+        pps.ue(0); // num_ref_idx_l1_default_active_minus1
+        pps.bit(false); // weighted_pred_flag
+        pps.bits(0, 2); // weighted_bipred_idc
+        pps.se(0); // pic_init_qp_minus26
+        pps.se(0); // pic_init_qs_minus26
+        pps.se(0); // chroma_qp_index_offset
+        pps.bit(true); // deblocking_filter_control_present_flag
+        pps.bit(false); // constrained_intra_pred_flag
+        pps.bit(false); // redundant_pic_cnt_present_flag
+
+        let mut buffer = vec![];
+        write_nal(&mut buffer, NAL_HEADER_SPS, &sps.finish_rbsp());
+        write_nal(&mut buffer, NAL_HEADER_PPS, &pps.finish_rbsp());
+
+        buffer
+    }
+
+    // Scrolling checkerboard. The left and right halves (eyes) are tinted differently
+    fn macroblock_samples(&self, mb_x: u32, mb_y: u32, samples: &mut Vec<u8>) {
+        samples.clear();
+
+        let offset = self.frame_index * SCROLL_SPEED;
+        for y in mb_y * MB_SIZE..(mb_y + 1) * MB_SIZE {
+            for x in mb_x * MB_SIZE..(mb_x + 1) * MB_SIZE {
+                let is_bright = ((x + offset) / SQUARE_SIZE + y / SQUARE_SIZE) % 2 == 0;
+                samples.push(if is_bright { LUMA_BRIGHT } else { LUMA_DARK });
+            }
+        }
+
+        let is_left = mb_x < self.width_mbs.div_ceil(2);
+        let (cb, cr) = if is_left { (128, 176) } else { (176, 128) };
+        let chroma_count = (MB_SIZE * MB_SIZE / 4) as usize;
+        samples.extend(std::iter::repeat_n(cb, chroma_count));
+        samples.extend(std::iter::repeat_n(cr, chroma_count));
+    }
+
+    // Returns the slice NAL of an IDR frame
+    pub fn next_frame(&mut self) -> Vec<u8> {
+        let mut slice = BitWriter::default();
+        slice.ue(0); // first_mb_in_slice
+        slice.ue(SLICE_TYPE_I);
+        slice.ue(0); // pic_parameter_set_id
+        slice.bits(0, 4); // frame_num
+        slice.ue(self.frame_index % 2); // idr_pic_id, must differ between consecutive IDRs
+        slice.bit(false); // no_output_of_prior_pics_flag
+        slice.bit(false); // long_term_reference_flag
+        slice.se(0); // slice_qp_delta
+        slice.ue(1); // disable_deblocking_filter_idc
+
+        let mut samples = vec![];
+        for mb_y in 0..self.height_mbs {
+            for mb_x in 0..self.width_mbs {
+                slice.ue(MB_TYPE_I_PCM);
+                slice.align_with_zeros();
+
+                self.macroblock_samples(mb_x, mb_y, &mut samples);
+                slice.bytes(&samples);
+            }
+        }
+
+        self.frame_index = self.frame_index.wrapping_add(1);
+
+        let mut buffer = vec![];
+        write_nal(&mut buffer, NAL_HEADER_IDR_SLICE, &slice.finish_rbsp());
+
+        buffer
+    }
+}
@@ -0,0 +1,196 @@
+// Pre-encoded H.264 or HEVC stream in Annex B format (raw NALs separated by start codes), as
+// produced for example by `ffmpeg -c:v libx264 -bsf:v h264_mp4toannexb out.h264`. The file is split
+// into access units, which are sent in a loop. Like with the hardware encoders, the parameter sets
+// are sent separately with set_video_config_nals(), and the access unit delimiters are removed.
+
+use alvr_common::anyhow::{Result, bail};
+use alvr_session::CodecType;
+use std::{fs, path::Path, sync::Arc};
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+enum NalKind {
+    // Slice data. The flag is set on the first slice of a picture
+    Vcl { is_first_slice: bool, is_idr: bool },
+    // VPS, SPS, PPS
+    ParameterSet,
+    AccessUnitDelimiter,
+    // Non-VCL NALs that can only be found before the first slice of an access unit
+    Prefix,
+    Other,
+}
+
+fn nal_kind(codec: CodecType, nal: &[u8]) -> NalKind {
+    match codec {
+        CodecType::Hevc => {
+            let nal_type = (nal[0] >> 1) & 0x3f;
+            match nal_type {
+                0..=31 => NalKind::Vcl {
+                    is_first_slice: nal.get(2).is_some_and(|byte| byte & 0x80 != 0),
+                    // IRAP pictures (BLA, IDR, CRA)
+                    is_idr: (16..=23).contains(&nal_type),
+                },
+                32..=34 => NalKind::ParameterSet,
+                35 => NalKind::AccessUnitDelimiter,
+                39 | 41..=44 | 48..=55 => NalKind::Prefix,
+                _ => NalKind::Other,
+            }
+        }
+        _ => {
+            let nal_type = nal[0] & 0x1f;
+            match nal_type {
+                // first_mb_in_slice == 0 is coded as a single bit set
+                1..=5 => NalKind::Vcl {
+                    is_first_slice: nal.get(1).is_some_and(|byte| byte & 0x80 != 0),
+                    is_idr: nal_type == 5,
+                },
+                7 | 8 => NalKind::ParameterSet,
+                9 => NalKind::AccessUnitDelimiter,
+                6 | 14..=18 => NalKind::Prefix,
+                _ => NalKind::Other,
+            }
+        }
+    }
+}
+
+// Returns the NALs without start codes
+fn split_nals(data: &[u8]) -> Vec<&[u8]> {
+    let mut start_code_ends = vec![];
+    let mut idx = 0;
+    while idx + 3 <= data.len() {
+        if data[idx..idx + 3] == [0, 0, 1] {
+            start_code_ends.push(idx + 3);
+            idx += 3;
+        } else {
+            idx += 1;
+        }
+    }
+
+    let mut nals = vec![];
+    for (i, &start) in start_code_ends.iter().enumerate() {
+        let end = start_code_ends
+            .get(i + 1)
+            .map(|next_end| next_end - 3)
+            .unwrap_or(data.len());
+
+        // Removes the leading zero of 4 byte start codes and the trailing_zero_8bits. NALs never
+        // end with a zero byte
+        let mut nal = &data[start..end];
+        while let Some((0, rest)) = nal.split_last() {
+            nal = rest;
+        }
+
+        if !nal.is_empty() {
+            nals.push(nal);
+        }
+    }
+
+    nals
+}
+
+#[derive(Default)]
+struct AccessUnit {
+    config_nals: Vec<u8>,
+    nals: Vec<u8>,
+    has_slices: bool,
+    is_idr: bool,
+}
+
+pub struct VideoFrame {
+    // Empty if the parameter sets did not change
+    pub config_nals: Vec<u8>,
+    pub nals: Vec<u8>,
+    pub is_idr: bool,
+}
+
+#[derive(Clone)]
+pub struct VideoFile {
+    codec: CodecType,
+    access_units: Arc<[AccessUnit]>,
+    cursor: usize,
+}
+
+impl VideoFile {
+    pub fn load(path: &Path, codec: CodecType) -> Result<Self> {
+        if codec == CodecType::AV1 {
+            bail!("AV1 files are not supported");
+        }
+
+        let data = fs::read(path)?;
+
+        let mut access_units = vec![];
+        let mut current = AccessUnit::default();
+        for nal in split_nals(&data) {
+            let kind = nal_kind(codec, nal);
+
+            let starts_access_unit = match kind {
+                NalKind::Vcl { is_first_slice, .. } => is_first_slice,
+                NalKind::ParameterSet | NalKind::AccessUnitDelimiter | NalKind::Prefix => true,
+                NalKind::Other => false,
+            };
+            if starts_access_unit && current.has_slices {
+                access_units.push(std::mem::take(&mut current));
+            }
+
+            match kind {
+                NalKind::Vcl { is_idr, .. } => {
+                    current.has_slices = true;
+                    current.is_idr |= is_idr;
+                    current.nals.extend_from_slice(&START_CODE);
+                    current.nals.extend_from_slice(nal);
+                }
+                NalKind::ParameterSet => {
+                    current.config_nals.extend_from_slice(&START_CODE);
+                    current.config_nals.extend_from_slice(nal);
+                }
+                NalKind::AccessUnitDelimiter => (),
+                NalKind::Prefix | NalKind::Other => {
+                    current.nals.extend_from_slice(&START_CODE);
+                    current.nals.extend_from_slice(nal);
+                }
+            }
+        }
+        if current.has_slices {
+            access_units.push(current);
+        }
+
+        // The stream must start with a keyframe, which also allows looping it
+        let Some(first_idr_index) = access_units
+            .iter()
+            .position(|unit| unit.is_idr && !unit.config_nals.is_empty())
+        else {
+            bail!("No keyframe with parameter sets found. Is the file in Annex B format?");
+        };
+        access_units.drain(..first_idr_index);
+
+        Ok(Self {
+            codec,
+            access_units: access_units.into(),
+            cursor: 0,
+        })
+    }
+
+    pub fn codec(&self) -> CodecType {
+        self.codec
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.access_units.len()
+    }
+
+    // Restarts from the first keyframe if requested
+    pub fn next_frame(&mut self, restart: bool) -> VideoFrame {
+        if restart {
+            self.cursor = 0;
+        }
+
+        let unit = &self.access_units[self.cursor];
+        self.cursor = (self.cursor + 1) % self.access_units.len();
+
+        VideoFrame {
+            config_nals: unit.config_nals.clone(),
+            nals: unit.nals.clone(),
+            is_idr: unit.is_idr,
+        }
+    }
+}
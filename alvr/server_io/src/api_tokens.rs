@@ -0,0 +1,63 @@
+use alvr_common::anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json as json;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+const TOKEN_SIZE: usize = 32;
+
+// Tokens accepted by the HTTP API of the server, sent as "Authorization: Bearer <token>" or as the
+// "token" query parameter (for WebSockets). More read-only tokens can be added to the file.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApiTokens {
+    // Full access
+    pub token: String,
+    // Access only to /api/events and /metrics
+    pub read_only_tokens: Vec<String>,
+}
+
+impl ApiTokens {
+    pub fn generate() -> Self {
+        Self {
+            token: generate_token(),
+            read_only_tokens: vec![generate_token()],
+        }
+    }
+}
+
+pub fn generate_token() -> String {
+    rand::random::<[u8; TOKEN_SIZE]>()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+// The file is created at the first launch
+pub fn load_or_create_api_tokens(path: &Path) -> Result<ApiTokens> {
+    if let Ok(text) = fs::read_to_string(path) {
+        return Ok(json::from_str(&text)?);
+    }
+
+    let tokens = ApiTokens::generate();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    // The permissions are set on creation, so the tokens are never readable by other users. An
+    // existing file is never overwritten
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)?
+        .write_all(json::to_string_pretty(&tokens)?.as_bytes())?;
+
+    Ok(tokens)
+}
@@ -1,7 +1,9 @@
+mod api_tokens;
 mod firewall;
 mod openvr_drivers;
 mod openvrpaths;
 
+pub use api_tokens::*;
 pub use firewall::*;
 pub use openvr_drivers::*;
 pub use openvrpaths::*;
@@ -214,6 +216,7 @@ impl ServerSessionManager {
                         manual_ips: manual_ips.into_iter().collect(),
                         trusted,
                         connection_state: ConnectionState::Disconnected,
+                        pairing_keys: None,
                     };
                     new_entry.insert(client_connection_desc);
 
@@ -228,8 +231,12 @@ impl ServerSessionManager {
                 }
             }
             ClientListAction::Trust => {
-                if let Entry::Occupied(mut entry) = maybe_client_entry {
+                if let Entry::Occupied(mut entry) = maybe_client_entry
+                    && !entry.get().trusted
+                {
+                    // Pairing happens again at the next connection
                     entry.get_mut().trusted = true;
+                    entry.get_mut().pairing_keys = None;
 
                     updated = true;
                 }
@@ -263,6 +270,13 @@ impl ServerSessionManager {
                 {
                     entry.get_mut().connection_state = state;
 
+                    updated = true;
+                }
+            }
+            ClientListAction::SetPairingKeys(keys) => {
+                if let Entry::Occupied(mut entry) = maybe_client_entry {
+                    entry.get_mut().pairing_keys = keys;
+
                     updated = true;
                 }
             }
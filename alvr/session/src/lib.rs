@@ -115,6 +115,13 @@ pub struct OpenvrConfig {
     pub _decoder_debug: bool,
 }
 
+// Keys exchanged with a client during the first connection after it has been trusted
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PairingKeys {
+    pub client_public_key: [u8; 32],
+    pub server_secret_key: [u8; 32],
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ClientConnectionConfig {
     pub display_name: String,
@@ -122,6 +129,8 @@ pub struct ClientConnectionConfig {
     pub manual_ips: HashSet<IpAddr>,
     pub trusted: bool,
     pub connection_state: ConnectionState,
+    #[serde(default)]
+    pub pairing_keys: Option<PairingKeys>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
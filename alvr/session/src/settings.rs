@@ -395,6 +395,41 @@ pub enum BitrateMode {
         #[schema(flag = "real-time")]
         decoder_latency_limiter: Switch<DecoderLatencyLimiter>,
     },
+
+    #[schema(strings(
+        display_name = "Delay gradient",
+        help = "Lowers the bitrate as soon as the network latency starts growing"
+    ))]
+    #[schema(collapsible)]
+    DelayGradient {
+        #[schema(strings(display_name = "Initial bitrate"))]
+        #[schema(gui(slider(min = 5, max = 1000, logarithmic)), suffix = "Mbps")]
+        initial_bitrate_mbps: u64,
+
+        #[schema(strings(display_name = "Maximum bitrate"))]
+        #[schema(flag = "real-time")]
+        #[schema(gui(slider(min = 1, max = 1000, logarithmic)), suffix = "Mbps")]
+        max_bitrate_mbps: u64,
+
+        #[schema(strings(display_name = "Minimum bitrate"))]
+        #[schema(flag = "real-time")]
+        #[schema(gui(slider(min = 1, max = 100, logarithmic)), suffix = "Mbps")]
+        min_bitrate_mbps: u64,
+
+        #[schema(strings(
+            help = "Bitrate multiplier applied every second while the network latency is stable"
+        ))]
+        #[schema(flag = "real-time")]
+        #[schema(gui(slider(min = 1.0, max = 1.5, step = 0.01)))]
+        increase_per_second: f32,
+
+        #[schema(strings(
+            help = "Fraction of the measured throughput used as bitrate when the latency grows"
+        ))]
+        #[schema(flag = "real-time")]
+        #[schema(gui(slider(min = 0.5, max = 0.95, step = 0.01)))]
+        decrease_factor: f32,
+    },
 }
 
 #[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
@@ -835,7 +870,7 @@ pub enum MicrophoneDevicesConfig {
 }
 
 // Note: sample rate is a free parameter for microphone, because both server and client supports
-// resampling. In contrary, for game audio, the server does not support resampling.
+// resampling. Game audio is resampled by the server to a fixed rate.
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
 #[schema(collapsible)]
 pub struct MicrophoneConfig {
@@ -845,6 +880,18 @@ pub struct MicrophoneConfig {
     pub buffering: AudioBufferingConfig,
 }
 
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+#[schema(collapsible)]
+pub struct OpusConfig {
+    #[schema(strings(display_name = "Headset speaker bitrate"))]
+    #[schema(gui(slider(min = 32, max = 256, step = 8)), suffix = "kbps")]
+    pub game_audio_bitrate_kbps: u32,
+
+    #[schema(strings(display_name = "Headset microphone bitrate"))]
+    #[schema(gui(slider(min = 16, max = 128, step = 8)), suffix = "kbps")]
+    pub microphone_bitrate_kbps: u32,
+}
+
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
 pub struct AudioConfig {
     #[schema(strings(display_name = "Headset speaker"))]
@@ -859,6 +906,12 @@ pub struct AudioConfig {
     )]
     #[cfg_attr(not(windows), schema(strings(display_name = "Headset microphone")))]
     pub microphone: Switch<MicrophoneConfig>,
+
+    #[schema(strings(
+        help = r"Compress the audio streams with Opus, and conceal lost packets instead of fading out.
+Used only if both the server and the client support it, and the sample rate is 8, 12, 16, 24 or 48 kHz."
+    ))]
+    pub opus: Switch<OpusConfig>,
 }
 
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
@@ -1290,6 +1343,8 @@ pub enum SocketProtocol {
     Udp,
     #[schema(strings(display_name = "TCP"))]
     Tcp,
+    #[schema(strings(display_name = "QUIC"))]
+    Quic,
 }
 
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
@@ -1301,6 +1356,31 @@ pub struct DiscoveryConfig {
     pub auto_trust_clients: bool,
 }
 
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy)]
+pub struct ForwardErrorCorrectionConfig {
+    #[schema(strings(
+        help = r#"Number of video shards protected by one parity shard. One lost shard per group can be rebuilt without requesting a new IDR frame.
+Lower values recover more losses but use more bandwidth."#
+    ))]
+    #[schema(gui(slider(min = 2, max = 64)), suffix = " shards")]
+    pub data_shards_per_parity_shard: u32,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy)]
+pub struct ShardRetransmissionConfig {
+    #[schema(strings(
+        help = "Lost shards are sent again only if the request arrives within this time, relative to the frame interval."
+    ))]
+    #[schema(gui(slider(min = 0.5, max = 4.0, step = 0.1)), suffix = " frames")]
+    pub deadline_frames: f32,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy)]
+pub struct SpectatorsConfig {
+    #[schema(gui(slider(min = 1, max = 8)))]
+    pub max_spectators: usize,
+}
+
 #[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy)]
 pub enum SocketBufferSize {
     Default,
@@ -1308,11 +1388,60 @@ pub enum SocketBufferSize {
     Custom(#[schema(suffix = "B")] u32),
 }
 
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
+pub enum EventHookTrigger {
+    StreamingStarted,
+    StreamingStopped,
+    ClientDiscovered,
+    #[schema(strings(display_name = "SteamVR restart"))]
+    SteamvrRestart,
+    #[schema(strings(
+        help = "Triggered once when the headset battery drops below the threshold while unplugged"
+    ))]
+    BatteryLow {
+        #[schema(gui(slider(min = 5, max = 95, step = 5)), suffix = "%")]
+        threshold_percent: u32,
+    },
+    #[schema(strings(
+        help = "Triggered once when the network, encoder or decoder latency limiter starts reducing the bitrate"
+    ))]
+    BitrateLimiterActive,
+    #[schema(strings(
+        display_name = "Event ID",
+        help = r#"Triggered by every event with this ID, for example "Session" or "Adb". Log events are not supported."#
+    ))]
+    EventId(String),
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
+pub enum EventHookAction {
+    #[schema(strings(
+        help = "Path of the script. The event is passed as JSON on the standard input"
+    ))]
+    Script(String),
+    #[schema(strings(help = "The event is sent as JSON in the body of a POST request"))]
+    Webhook {
+        #[schema(strings(display_name = "URL"))]
+        url: String,
+        #[schema(strings(
+            help = "Allow sending the events to another PC. Otherwise only localhost URLs are accepted"
+        ))]
+        allow_remote_host: bool,
+    },
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EventHook {
+    pub trigger: EventHookTrigger,
+    pub action: EventHookAction,
+}
+
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
 pub struct ConnectionConfig {
     #[schema(strings(
         help = r#"UDP: Faster, but less stable than TCP. Try this if your network is well optimized and free of interference.
-TCP: Slower than UDP, but more stable. Pick this if you experience video or audio stutters with UDP."#
+TCP: Slower than UDP, but more stable. Pick this if you experience video or audio stutters with UDP.
+QUIC: Like UDP, with congestion control. The stream survives when the headset changes address, for example when roaming between access points. The packet size is limited to 1150 bytes."#
     ))]
     pub stream_protocol: SocketProtocol,
 
@@ -1357,6 +1486,12 @@ TCP: Slower than UDP, but more stable. Pick this if you experience video or audi
     #[schema(flag = "real-time")]
     pub enable_on_disconnect_script: bool,
 
+    #[schema(strings(
+        help = "Run a script or call a webhook when an event happens, for example to pause recording equipment when the headset battery is low."
+    ))]
+    #[schema(flag = "real-time")]
+    pub event_hooks: Vec<EventHook>,
+
     #[schema(strings(
         help = "Allow cross-origin browser requests to control ALVR settings remotely."
     ))]
@@ -1371,8 +1506,35 @@ TCP: Slower than UDP, but more stable. Pick this if you experience video or audi
     #[schema(gui(slider(min = 1024, max = 65507, logarithmic)), suffix = "B")]
     pub packet_size: i32,
 
+    #[schema(strings(
+        help = r#"Send parity shards alongside UDP stream packets so that packets with few lost shards can be rebuilt. Has no effect with TCP."#
+    ))]
+    pub forward_error_correction: Switch<ForwardErrorCorrectionConfig>,
+
+    #[schema(strings(
+        help = r#"Request the UDP stream shards lost by the network to be sent again (NACK). This can recover big IDR frames on lossy Wi-Fi. Has no effect with TCP."#
+    ))]
+    pub shard_retransmission: Switch<ShardRetransmissionConfig>,
+
+    #[schema(strings(
+        help = r#"Encrypt and authenticate the video, audio and tracking streams with the keys exchanged when the client was paired. The control connection is always encrypted. Disabling this reduces the CPU usage on the client."#
+    ))]
+    pub stream_encryption: bool,
+
+    #[schema(strings(
+        help = r#"While a client is streaming, other trusted clients can connect and watch the same video and game audio. Their tracking and inputs are ignored."#
+    ))]
+    pub spectators: Switch<SpectatorsConfig>,
+
     pub stream_port: u16,
     pub web_server_port: u16,
+
+    #[schema(strings(
+        help = "Accept HTTP API requests only from this PC. Remote dashboards and scrapers will not be able to connect."
+    ))]
+    #[schema(flag = "steamvr-restart")]
+    pub web_server_localhost_only: bool,
+
     pub osc_local_port: u16,
 
     #[schema(strings(display_name = "Streamer send buffer size"))]
@@ -1486,6 +1648,18 @@ pub struct CaptureConfig {
     #[schema(strings(display_name = "Start video recording at client connection"))]
     pub startup_video_recording: bool,
 
+    #[schema(strings(
+        display_name = "Start tracking recording at client connection",
+        help = "Record the tracking data and the button presses, to be replayed from the Debug tab without a headset."
+    ))]
+    pub startup_tracking_recording: bool,
+
+    #[schema(strings(help = "Add the game audio to the video recordings"))]
+    pub record_game_audio: bool,
+
+    #[schema(strings(help = "Add the headset microphone to the video recordings"))]
+    pub record_microphone: bool,
+
     pub rolling_video_files: Switch<RollingVideoFilesConfig>,
 
     #[schema(flag = "steamvr-restart")]
@@ -1665,6 +1839,14 @@ pub fn session_settings_default() -> SettingsDefault {
                             },
                         },
                     },
+                    DelayGradient: BitrateModeDelayGradientDefault {
+                        gui_collapsed: true,
+                        initial_bitrate_mbps: 30,
+                        max_bitrate_mbps: 200,
+                        min_bitrate_mbps: 5,
+                        increase_per_second: 1.08,
+                        decrease_factor: 0.85,
+                    },
                     variant: BitrateModeDefaultVariant::ConstantMbps,
                 },
                 adapt_to_framerate: SwitchDefault {
@@ -1860,6 +2042,14 @@ pub fn session_settings_default() -> SettingsDefault {
                     },
                 },
             },
+            opus: SwitchDefault {
+                enabled: false,
+                content: OpusConfigDefault {
+                    gui_collapsed: true,
+                    game_audio_bitrate_kbps: 128,
+                    microphone_bitrate_kbps: 32,
+                },
+            },
         },
         headset: HeadsetConfigDefault {
             emulation_mode: HeadsetEmulationModeDefault {
@@ -2063,6 +2253,7 @@ pub fn session_settings_default() -> SettingsDefault {
             },
             wired_client_autolaunch: true,
             web_server_port: 8082,
+            web_server_localhost_only: false,
             stream_port: 9944,
             osc_local_port: 9942,
             dscp: OptionalDefault {
@@ -2087,8 +2278,46 @@ pub fn session_settings_default() -> SettingsDefault {
             minimum_idr_interval_ms: 100,
             enable_on_connect_script: false,
             enable_on_disconnect_script: false,
+            event_hooks: VectorDefault {
+                gui_collapsed: true,
+                element: EventHookDefault {
+                    trigger: EventHookTriggerDefault {
+                        BatteryLow: EventHookTriggerBatteryLowDefault {
+                            threshold_percent: 20,
+                        },
+                        EventId: "".into(),
+                        variant: EventHookTriggerDefaultVariant::StreamingStarted,
+                    },
+                    action: EventHookActionDefault {
+                        Script: "".into(),
+                        Webhook: EventHookActionWebhookDefault {
+                            url: "http://localhost:8080".into(),
+                            allow_remote_host: false,
+                        },
+                        variant: EventHookActionDefaultVariant::Script,
+                    },
+                },
+                content: vec![],
+            },
             allow_untrusted_http: false,
             packet_size: 1400,
+            forward_error_correction: SwitchDefault {
+                enabled: false,
+                content: ForwardErrorCorrectionConfigDefault {
+                    data_shards_per_parity_shard: 10,
+                },
+            },
+            shard_retransmission: SwitchDefault {
+                enabled: false,
+                content: ShardRetransmissionConfigDefault {
+                    deadline_frames: 1.0,
+                },
+            },
+            stream_encryption: true,
+            spectators: SwitchDefault {
+                enabled: false,
+                content: SpectatorsConfigDefault { max_spectators: 1 },
+            },
             statistics_history_size: 256,
         },
         extra: ExtraConfigDefault {
@@ -2136,6 +2365,9 @@ pub fn session_settings_default() -> SettingsDefault {
             },
             capture: CaptureConfigDefault {
                 startup_video_recording: false,
+                startup_tracking_recording: false,
+                record_game_audio: true,
+                record_microphone: false,
                 rolling_video_files: SwitchDefault {
                     enabled: false,
                     content: RollingVideoFilesConfigDefault { duration_s: 5 },
@@ -0,0 +1,317 @@
+// Network emulation, used to test the adaptation to the link conditions without a real network.
+// The writer of a socket is wrapped: sent datagrams go through a bottleneck with limited bandwidth
+// and queue, then are delayed, possibly lost, and finally passed to the inner writer at the time
+// they would be received. The time is virtual and advanced by the caller, and losses and jitter
+// come from a seeded generator, so that every run can be reproduced.
+
+use super::SocketWriter;
+use alvr_common::{anyhow::Result, parking_lot::Mutex};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+// Gilbert model: each datagram can start a burst of losses, during which all datagrams are lost
+#[derive(Clone, Debug)]
+pub struct BurstLoss {
+    pub start_probability: f64,
+    pub mean_length: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct LinkConditions {
+    // Capacity of the bottleneck
+    pub bandwidth_bps: f64,
+    // Round trip time without queuing. Each direction adds half of it
+    pub rtt: Duration,
+    // Maximum additional delay, uniformly distributed. Datagrams are not reordered
+    pub jitter: Duration,
+    pub loss_probability: f64,
+    pub burst_loss: Option<BurstLoss>,
+    // Datagrams that would wait longer than this in the bottleneck queue are dropped
+    pub max_queue_delay: Duration,
+}
+
+impl Default for LinkConditions {
+    fn default() -> Self {
+        Self {
+            bandwidth_bps: 1e9,
+            rtt: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss_probability: 0.0,
+            burst_loss: None,
+            max_queue_delay: Duration::from_millis(200),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct LinkStatistics {
+    pub datagrams_sent: usize,
+    pub datagrams_delivered: usize,
+    pub datagrams_lost: usize,
+    // Dropped because the bottleneck queue was full
+    pub datagrams_dropped: usize,
+}
+
+struct Link {
+    conditions: LinkConditions,
+    rng: StdRng,
+    now: Duration,
+    // Time at which the bottleneck finishes sending the queued datagrams
+    bottleneck_free_time: Duration,
+    last_delivery_time: Duration,
+    in_burst: bool,
+    // (delivery time, datagram), in order of delivery
+    in_flight: VecDeque<(Duration, Vec<u8>)>,
+    inner_writer: Option<Box<dyn SocketWriter>>,
+    statistics: LinkStatistics,
+}
+
+impl Link {
+    fn is_lost(&mut self) -> bool {
+        if let Some(burst) = &self.conditions.burst_loss {
+            self.in_burst = if self.in_burst {
+                !self.rng.random_bool(f64::min(1.0 / burst.mean_length, 1.0))
+            } else {
+                self.rng.random_bool(burst.start_probability)
+            };
+        } else {
+            self.in_burst = false;
+        }
+
+        self.in_burst || self.rng.random_bool(self.conditions.loss_probability)
+    }
+
+    fn send(&mut self, buffer: &[u8]) {
+        self.statistics.datagrams_sent += 1;
+
+        let queue_delay = self.bottleneck_free_time.saturating_sub(self.now);
+        if queue_delay > self.conditions.max_queue_delay {
+            self.statistics.datagrams_dropped += 1;
+
+            return;
+        }
+
+        let transmission_time = Duration::from_nanos(
+            (buffer.len() as f64 * 8e9 / self.conditions.bandwidth_bps) as u64,
+        );
+        self.bottleneck_free_time = self.now + queue_delay + transmission_time;
+
+        // The datagram still uses the bandwidth if it is lost after the bottleneck
+        if self.is_lost() {
+            self.statistics.datagrams_lost += 1;
+
+            return;
+        }
+
+        let jitter = self.conditions.jitter.mul_f64(self.rng.random::<f64>());
+        let delivery_time = Duration::max(
+            self.bottleneck_free_time + self.conditions.rtt / 2 + jitter,
+            self.last_delivery_time,
+        );
+        self.last_delivery_time = delivery_time;
+
+        self.in_flight.push_back((delivery_time, buffer.to_vec()));
+
+        self.deliver();
+    }
+
+    fn deliver(&mut self) {
+        while let Some((delivery_time, _)) = self.in_flight.front()
+            && *delivery_time <= self.now
+        {
+            let (_, datagram) = self.in_flight.pop_front().unwrap();
+            if let Some(writer) = &mut self.inner_writer {
+                writer.send(&datagram).ok();
+            }
+            self.statistics.datagrams_delivered += 1;
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EmulatedLink(Arc<Mutex<Link>>);
+
+impl EmulatedLink {
+    pub fn new(conditions: LinkConditions, seed: u64) -> Self {
+        Self(Arc::new(Mutex::new(Link {
+            conditions,
+            rng: StdRng::seed_from_u64(seed),
+            now: Duration::ZERO,
+            bottleneck_free_time: Duration::ZERO,
+            last_delivery_time: Duration::ZERO,
+            in_burst: false,
+            in_flight: VecDeque::new(),
+            inner_writer: None,
+            statistics: LinkStatistics::default(),
+        })))
+    }
+
+    // Applies to the datagrams sent from now on, for example to emulate a sudden capacity drop
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        self.0.lock().conditions = conditions;
+    }
+
+    pub fn now(&self) -> Duration {
+        self.0.lock().now
+    }
+
+    // Move the clock forward, delivering the datagrams that arrived in the meantime
+    pub fn advance_to(&self, time: Duration) {
+        let link = &mut *self.0.lock();
+        link.now = Duration::max(link.now, time);
+        link.deliver();
+    }
+
+    // Time of the next delivery, useful to advance the clock exactly to it
+    pub fn next_delivery_time(&self) -> Option<Duration> {
+        self.0.lock().in_flight.front().map(|(time, _)| *time)
+    }
+
+    pub fn statistics(&self) -> LinkStatistics {
+        self.0.lock().statistics
+    }
+
+    // Only one writer can be wrapped by each link
+    pub fn wrap_writer(&self, inner: Box<dyn SocketWriter>) -> EmulatedSocketWriter {
+        self.0.lock().inner_writer = Some(inner);
+
+        EmulatedSocketWriter(self.clone())
+    }
+}
+
+pub struct EmulatedSocketWriter(EmulatedLink);
+
+impl SocketWriter for EmulatedSocketWriter {
+    fn send(&mut self, buffer: &[u8]) -> Result<()> {
+        self.0.0.lock().send(buffer);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{
+        SocketReader,
+        memory::{Framing, MemoryLink},
+    };
+
+    const DATAGRAM_SIZE: usize = 1250;
+
+    fn emulated_link(
+        conditions: LinkConditions,
+    ) -> (EmulatedLink, MemoryLink, EmulatedSocketWriter) {
+        let memory_link = MemoryLink::new(Framing::Datagram);
+        let link = EmulatedLink::new(conditions, 0);
+        let writer = link.wrap_writer(Box::new(memory_link.writer()));
+
+        (link, memory_link, writer)
+    }
+
+    #[test]
+    fn test_bandwidth_and_delay() {
+        // 1250 bytes at 10 Mbps take 1 ms
+        let (link, memory_link, mut writer) = emulated_link(LinkConditions {
+            bandwidth_bps: 10e6,
+            rtt: Duration::from_millis(10),
+            ..Default::default()
+        });
+
+        for _ in 0..3 {
+            writer.send(&[0; DATAGRAM_SIZE]).unwrap();
+        }
+
+        link.advance_to(Duration::from_micros(5999));
+        assert_eq!(memory_link.pending_count(), 0);
+        assert_eq!(link.next_delivery_time(), Some(Duration::from_millis(6)));
+
+        link.advance_to(Duration::from_millis(6));
+        assert_eq!(memory_link.pending_count(), 1);
+
+        link.advance_to(Duration::from_millis(8));
+        assert_eq!(memory_link.pending_count(), 3);
+        assert_eq!(link.next_delivery_time(), None);
+    }
+
+    #[test]
+    fn test_conditions_change() {
+        let (link, memory_link, mut writer) = emulated_link(LinkConditions {
+            bandwidth_bps: 10e6,
+            ..Default::default()
+        });
+
+        writer.send(&[0; DATAGRAM_SIZE]).unwrap();
+        link.advance_to(Duration::from_millis(1));
+        assert_eq!(memory_link.pending_count(), 1);
+
+        // 1250 bytes at 1 Mbps take 10 ms
+        link.set_conditions(LinkConditions {
+            bandwidth_bps: 1e6,
+            ..Default::default()
+        });
+        writer.send(&[0; DATAGRAM_SIZE]).unwrap();
+        assert_eq!(
+            link.next_delivery_time(),
+            Some(link.now() + Duration::from_millis(10))
+        );
+    }
+
+    #[test]
+    fn test_queue_limit() {
+        let (link, memory_link, mut writer) = emulated_link(LinkConditions {
+            bandwidth_bps: 10e6,
+            max_queue_delay: Duration::from_millis(5),
+            ..Default::default()
+        });
+
+        // The queue is 5 ms long after 5 datagrams, the sixth one still fits
+        for _ in 0..10 {
+            writer.send(&[0; DATAGRAM_SIZE]).unwrap();
+        }
+
+        link.advance_to(Duration::from_secs(1));
+        assert_eq!(memory_link.pending_count(), 6);
+        assert_eq!(link.statistics().datagrams_dropped, 4);
+    }
+
+    #[test]
+    fn test_jitter_does_not_reorder() {
+        let (link, memory_link, mut writer) = emulated_link(LinkConditions {
+            jitter: Duration::from_millis(20),
+            ..Default::default()
+        });
+        let mut reader = memory_link.reader();
+
+        for idx in 0..100_u8 {
+            writer.send(&[idx]).unwrap();
+        }
+        link.advance_to(Duration::from_secs(1));
+
+        let mut buffer = [0];
+        for idx in 0..100 {
+            reader.recv(&mut buffer).unwrap();
+            assert_eq!(buffer[0], idx);
+        }
+    }
+
+    #[test]
+    fn test_losses() {
+        let (link, _, mut writer) = emulated_link(LinkConditions {
+            loss_probability: 0.1,
+            burst_loss: Some(BurstLoss {
+                start_probability: 0.01,
+                mean_length: 10.0,
+            }),
+            ..Default::default()
+        });
+
+        for _ in 0..100_000 {
+            writer.send(&[0]).unwrap();
+        }
+
+        // About 10% random losses plus 9% in bursts
+        let loss_ratio = link.statistics().datagrams_lost as f64 / 100_000.0;
+        assert!((0.15..0.22).contains(&loss_ratio), "{loss_ratio}");
+    }
+}
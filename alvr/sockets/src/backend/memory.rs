@@ -0,0 +1,196 @@
+// In-memory backend, used to test and fuzz the receive paths without a network. The link can drop,
+// duplicate, reorder and truncate datagrams. The conditions are specified as a list of events
+// applied to the sent datagrams in order, so that every failure can be reproduced.
+
+use super::{SocketReader, SocketWriter};
+use alvr_common::{
+    ConResult,
+    anyhow::{Result, bail},
+    con_bail,
+    parking_lot::Mutex,
+};
+use std::{collections::VecDeque, sync::Arc};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framing {
+    // Like UDP: each send() produces one datagram, that is received whole or not at all
+    Datagram,
+    // Like TCP: the bytes of all sends are concatenated and can be received in any chunk size
+    Stream,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkEvent {
+    Deliver,
+    Drop,
+    Duplicate,
+    // Deliver after the specified number of following datagrams have been sent
+    Delay(usize),
+    // Keep only the first bytes of the datagram
+    Truncate(usize),
+    // The send() call returns an error, the datagram is not sent
+    Fail,
+}
+
+struct Link {
+    framing: Framing,
+    events: VecDeque<LinkEvent>,
+    datagrams: VecDeque<Vec<u8>>,
+    // (datagrams to be sent before delivery, datagram)
+    delayed: Vec<(usize, Vec<u8>)>,
+}
+
+impl Link {
+    fn send(&mut self, buffer: &[u8]) -> Result<()> {
+        let event = self.events.pop_front().unwrap_or(LinkEvent::Deliver);
+        if event == LinkEvent::Fail {
+            bail!("Send failure");
+        }
+
+        for (countdown, _) in &mut self.delayed {
+            *countdown = countdown.saturating_sub(1);
+        }
+
+        match event {
+            LinkEvent::Deliver => self.datagrams.push_back(buffer.to_vec()),
+            LinkEvent::Drop => (),
+            LinkEvent::Duplicate => {
+                self.datagrams.push_back(buffer.to_vec());
+                self.datagrams.push_back(buffer.to_vec());
+            }
+            LinkEvent::Delay(count) => self.delayed.push((count, buffer.to_vec())),
+            LinkEvent::Truncate(size) => self
+                .datagrams
+                .push_back(buffer[..usize::min(size, buffer.len())].to_vec()),
+            LinkEvent::Fail => unreachable!(),
+        }
+
+        while let Some(idx) = self
+            .delayed
+            .iter()
+            .position(|(countdown, _)| *countdown == 0)
+        {
+            let (_, datagram) = self.delayed.remove(idx);
+            self.datagrams.push_back(datagram);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct MemoryLink(Arc<Mutex<Link>>);
+
+impl MemoryLink {
+    pub fn new(framing: Framing) -> Self {
+        Self(Arc::new(Mutex::new(Link {
+            framing,
+            events: VecDeque::new(),
+            datagrams: VecDeque::new(),
+            delayed: vec![],
+        })))
+    }
+
+    // Events applied to the next sent datagrams. Once the list is exhausted, datagrams are
+    // delivered normally
+    pub fn push_events(&self, events: impl IntoIterator<Item = LinkEvent>) {
+        self.0.lock().events.extend(events);
+    }
+
+    // Inject bytes as if they were sent by the peer, bypassing the link events
+    pub fn inject(&self, buffer: &[u8]) {
+        self.0.lock().datagrams.push_back(buffer.to_vec());
+    }
+
+    // Deliver all delayed datagrams
+    pub fn flush(&self) {
+        let link = &mut *self.0.lock();
+        link.delayed.sort_by_key(|(countdown, _)| *countdown);
+        link.datagrams
+            .extend(link.delayed.drain(..).map(|(_, datagram)| datagram));
+    }
+
+    // Number of datagrams that can be received
+    pub fn pending_count(&self) -> usize {
+        self.0.lock().datagrams.len()
+    }
+
+    pub fn remove_pending(&self, index: usize) {
+        self.0.lock().datagrams.remove(index);
+    }
+
+    pub fn writer(&self) -> MemorySocketWriter {
+        MemorySocketWriter(self.clone())
+    }
+
+    pub fn reader(&self) -> MemorySocketReader {
+        MemorySocketReader(self.clone())
+    }
+}
+
+pub struct MemorySocketWriter(MemoryLink);
+
+impl SocketWriter for MemorySocketWriter {
+    fn send(&mut self, buffer: &[u8]) -> Result<()> {
+        self.0.0.lock().send(buffer)
+    }
+}
+
+pub struct MemorySocketReader(MemoryLink);
+
+impl SocketReader for MemorySocketReader {
+    fn recv(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+        let link = &mut *self.0.0.lock();
+
+        let Some(datagram) = link.datagrams.front_mut() else {
+            return alvr_common::try_again();
+        };
+
+        let size = usize::min(buffer.len(), datagram.len());
+        buffer[..size].copy_from_slice(&datagram[..size]);
+
+        if link.framing == Framing::Stream && size < datagram.len() {
+            datagram.drain(..size);
+        } else {
+            link.datagrams.pop_front();
+        }
+
+        Ok(size)
+    }
+
+    fn peek(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+        let link = &mut *self.0.0.lock();
+
+        let mut size = 0;
+        for datagram in &link.datagrams {
+            let count = usize::min(buffer.len() - size, datagram.len());
+            buffer[size..size + count].copy_from_slice(&datagram[..count]);
+            size += count;
+
+            if link.framing == Framing::Datagram || size == buffer.len() {
+                break;
+            }
+        }
+
+        if link.framing == Framing::Datagram && size < buffer.len() {
+            // Same as the UDP backend
+            link.datagrams.pop_front();
+
+            return alvr_common::try_again();
+        }
+
+        Ok(size)
+    }
+
+    fn discard_shard(&mut self) -> ConResult {
+        let link = &mut *self.0.0.lock();
+
+        if link.framing == Framing::Stream {
+            con_bail!("Stream corrupted, cannot find the next shard");
+        }
+
+        link.datagrams.pop_front();
+
+        Ok(())
+    }
+}
@@ -1,3 +1,8 @@
+#[cfg(any(test, feature = "network-emulation"))]
+pub mod emulated;
+#[cfg(any(test, feature = "fuzzing", feature = "network-emulation"))]
+pub mod memory;
+pub mod quic;
 pub mod tcp;
 pub mod udp;
 
@@ -5,6 +10,11 @@ use alvr_common::{ConResult, anyhow::Result};
 
 pub trait SocketWriter: Send {
     fn send(&mut self, buffer: &[u8]) -> Result<()>;
+
+    // Send a shard that must not be lost. Backends without reliable delivery fall back to send()
+    fn send_reliable(&mut self, buffer: &[u8]) -> Result<()> {
+        self.send(buffer)
+    }
 }
 
 // Trait used to abstract different socket (or other input/output) implementations. The funtionality
@@ -15,5 +25,11 @@ pub trait SocketReader: Send {
     // packet (size of MTU) otherwise data will be corrupted. The size of the data is
     fn recv(&mut self, buffer: &mut [u8]) -> ConResult<usize>;
 
-    fn peek(&self, buffer: &mut [u8]) -> ConResult<usize>;
+    // Datagram based backends drop datagrams shorter than the buffer, since the buffer is always
+    // the size of the prefix the caller needs to read
+    fn peek(&mut self, buffer: &mut [u8]) -> ConResult<usize>;
+
+    // Drop the rest of the shard whose prefix has just been peeked, without trusting its length
+    // field. Fails if the boundaries of the next shards cannot be found anymore
+    fn discard_shard(&mut self) -> ConResult;
 }
@@ -0,0 +1,542 @@
+// QUIC backend. Shards are sent as unreliable datagrams, or on a reliable unidirectional stream
+// with send_reliable(). This provides congestion control and encryption.
+//
+// Unlike the other backends, the client (headset) connects to the server, which runs the QUIC
+// server endpoint. QUIC connection migration is initiated by the client endpoint, so this way the
+// connection survives when the headset changes address, for example when roaming between access
+// points. The server endpoint uses a self-signed certificate, which is sent to the client through
+// the control socket, which is already authenticated, so that it can be pinned when connecting.
+//
+// The control socket is needed to negotiate the stream protocol, so it starts on TCP. The first
+// unidirectional stream opened by each peer is reserved for the control packets, which are moved
+// there once the connection is established (see StreamSocket::carry_control()).
+//
+// quinn is asynchronous: all sockets share one small tokio runtime. The received data is forwarded
+// from the runtime tasks to the readers through channels.
+
+use super::{SocketReader, SocketWriter};
+use alvr_common::{
+    AnyhowToCon, ConResult, ConnectionError, HandleTryAgain, ToCon, anyhow::Result, con_bail,
+};
+use bytes::Bytes;
+use quinn::{
+    ClientConfig, Connection, Endpoint, EndpointConfig, IdleTimeout, SendStream, ServerConfig,
+    TokioRuntime, TransportConfig,
+    rustls::{
+        RootCertStore,
+        pki_types::{CertificateDer, PrivatePkcs8KeyDer},
+    },
+};
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::{Arc, LazyLock, mpsc},
+    time::Duration,
+};
+use tokio::runtime::Runtime;
+
+const SERVER_NAME: &str = "alvr.server";
+
+// Shards must fit in a single datagram. This accounts for the QUIC packet and frame overhead with
+// the default initial MTU (1200 bytes), which is guaranteed to work on every path. The size is
+// fixed because both peers need to use the same shard size.
+pub const MAX_PACKET_SIZE: usize = 1150;
+
+const DATAGRAM_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_millis(500);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Shared by all QUIC sockets, including the ones of the spectators
+static RUNTIME: LazyLock<Runtime> = LazyLock::new(|| {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("alvr-quic")
+        .enable_all()
+        .build()
+        .expect("Failed to create tokio runtime")
+});
+
+fn transport_config() -> Result<Arc<TransportConfig>> {
+    let mut config = TransportConfig::default();
+    config
+        .datagram_receive_buffer_size(Some(DATAGRAM_BUFFER_SIZE))
+        .datagram_send_buffer_size(DATAGRAM_BUFFER_SIZE)
+        .keep_alive_interval(Some(KEEP_ALIVE_INTERVAL))
+        .max_idle_timeout(Some(IdleTimeout::try_from(IDLE_TIMEOUT)?));
+
+    Ok(Arc::new(config))
+}
+
+pub struct QuicListener {
+    endpoint: Endpoint,
+    certificate: Vec<u8>,
+}
+
+impl QuicListener {
+    // DER encoded certificate, to be pinned by the peer
+    pub fn certificate(&self) -> &[u8] {
+        &self.certificate
+    }
+}
+
+pub struct QuicSockets {
+    pub writer: QuicSocketWriter,
+    pub reader: QuicSocketReader,
+    pub control_writer: QuicStreamWriter,
+    pub control_reader: QuicStreamReader,
+}
+
+// Used by the server. The socket is bound by the caller, so that the port can be sent to the client
+pub fn bind(socket: UdpSocket) -> Result<QuicListener> {
+    let certified_key = rcgen::generate_simple_self_signed(vec![SERVER_NAME.into()])?;
+    let certificate = certified_key.cert.der().to_vec();
+
+    let mut server_config = ServerConfig::with_single_cert(
+        vec![CertificateDer::from(certificate.clone())],
+        PrivatePkcs8KeyDer::from(certified_key.key_pair.serialize_der()).into(),
+    )?;
+    server_config.transport_config(transport_config()?);
+
+    let endpoint = {
+        let _guard = RUNTIME.enter();
+        Endpoint::new(
+            EndpointConfig::default(),
+            Some(server_config),
+            socket,
+            Arc::new(TokioRuntime),
+        )?
+    };
+
+    Ok(QuicListener {
+        endpoint,
+        certificate,
+    })
+}
+
+pub fn accept_from_client(
+    listener: QuicListener,
+    client_ip: IpAddr,
+    timeout: Duration,
+) -> ConResult<QuicSockets> {
+    let QuicListener { endpoint, .. } = listener;
+
+    let connection = RUNTIME.block_on(async {
+        let Some(incoming) = tokio::time::timeout(timeout, endpoint.accept())
+            .await
+            .map_err(|e| ConnectionError::TryAgain(e.into()))?
+        else {
+            con_bail!("QUIC endpoint closed");
+        };
+
+        let found_ip = incoming.remote_address().ip().to_canonical();
+        if found_ip != client_ip.to_canonical() {
+            incoming.refuse();
+
+            con_bail!("Connected to wrong client: Expected: {client_ip}, Found {found_ip}");
+        }
+
+        tokio::time::timeout(timeout, incoming)
+            .await
+            .map_err(|e| ConnectionError::TryAgain(e.into()))?
+            .to_con()
+    })?;
+
+    split(endpoint, connection, timeout)
+}
+
+pub fn connect_to_server(
+    socket: UdpSocket,
+    server_ip: IpAddr,
+    port: u16,
+    server_certificate: &[u8],
+    timeout: Duration,
+) -> ConResult<QuicSockets> {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from(server_certificate.to_vec()))
+        .to_con()?;
+    let mut client_config = ClientConfig::with_root_certificates(Arc::new(roots)).to_con()?;
+    client_config.transport_config(transport_config().to_con()?);
+
+    let endpoint = {
+        let _guard = RUNTIME.enter();
+        Endpoint::new(
+            EndpointConfig::default(),
+            None,
+            socket,
+            Arc::new(TokioRuntime),
+        )
+        .to_con()?
+    };
+
+    let connection = RUNTIME.block_on(async {
+        let connecting = endpoint
+            .connect_with(client_config, SocketAddr::new(server_ip, port), SERVER_NAME)
+            .to_con()?;
+
+        tokio::time::timeout(timeout, connecting)
+            .await
+            .map_err(|e| ConnectionError::TryAgain(e.into()))?
+            .to_con()
+    })?;
+
+    split(endpoint, connection, timeout)
+}
+
+fn split(endpoint: Endpoint, connection: Connection, timeout: Duration) -> ConResult<QuicSockets> {
+    // Opening a stream does not send anything yet, the peer sees it once it is written to
+    let control_stream = RUNTIME.block_on(connection.open_uni()).to_con()?;
+
+    let (shard_sender, shard_receiver) = mpsc::channel();
+    let (control_sender, control_receiver) = mpsc::channel();
+
+    RUNTIME.spawn({
+        let connection = connection.clone();
+        let shard_sender = shard_sender.clone();
+        async move {
+            while let Ok(datagram) = connection.read_datagram().await {
+                if shard_sender.send(datagram.to_vec()).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    // Streams are accepted in the order they have been opened, so the first one is the control
+    // stream. Shards sent on the other streams are concatenated. They are split again using the
+    // length field of the prefix
+    RUNTIME.spawn({
+        let connection = connection.clone();
+        async move {
+            let Ok(mut stream) = connection.accept_uni().await else {
+                return;
+            };
+            tokio::spawn(async move {
+                while let Ok(Some(chunk)) = stream.read_chunk(usize::MAX, true).await {
+                    if control_sender.send(chunk.bytes.to_vec()).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            while let Ok(mut stream) = connection.accept_uni().await {
+                let shard_sender = shard_sender.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let mut length_bytes = [0; 4];
+                        if stream.read_exact(&mut length_bytes).await.is_err() {
+                            break;
+                        }
+                        let length = u32::from_le_bytes(length_bytes) as usize;
+                        // The shard boundaries cannot be trusted anymore
+                        if length > MAX_PACKET_SIZE {
+                            break;
+                        }
+
+                        let mut shard = vec![0; usize::max(length, length_bytes.len())];
+                        shard[..4].copy_from_slice(&length_bytes);
+                        if stream.read_exact(&mut shard[4..]).await.is_err()
+                            || shard_sender.send(shard).is_err()
+                        {
+                            break;
+                        }
+                    }
+                });
+            }
+        }
+    });
+
+    Ok(QuicSockets {
+        writer: QuicSocketWriter {
+            connection: connection.clone(),
+            reliable_stream: None,
+        },
+        reader: QuicSocketReader {
+            _endpoint: endpoint,
+            connection,
+            shard_receiver,
+            timeout,
+            shard: None,
+            read_cursor: 0,
+        },
+        control_writer: QuicStreamWriter {
+            stream: control_stream,
+        },
+        control_reader: QuicStreamReader {
+            chunk_receiver: control_receiver,
+            timeout,
+            pending: VecDeque::new(),
+        },
+    })
+}
+
+pub struct QuicSocketWriter {
+    connection: Connection,
+    reliable_stream: Option<SendStream>,
+}
+
+impl SocketWriter for QuicSocketWriter {
+    fn send(&mut self, buffer: &[u8]) -> Result<()> {
+        self.connection
+            .send_datagram(Bytes::copy_from_slice(buffer))?;
+
+        Ok(())
+    }
+
+    fn send_reliable(&mut self, buffer: &[u8]) -> Result<()> {
+        let stream = if let Some(stream) = &mut self.reliable_stream {
+            stream
+        } else {
+            let stream = RUNTIME.block_on(self.connection.open_uni())?;
+            self.reliable_stream.insert(stream)
+        };
+
+        RUNTIME.block_on(stream.write_all(buffer))?;
+
+        Ok(())
+    }
+}
+
+pub struct QuicSocketReader {
+    // The endpoint must be kept alive as long as the connection is used
+    _endpoint: Endpoint,
+    connection: Connection,
+    shard_receiver: mpsc::Receiver<Vec<u8>>,
+    timeout: Duration,
+    shard: Option<Vec<u8>>,
+    read_cursor: usize,
+}
+
+impl QuicSocketReader {
+    fn next_shard(&mut self) -> ConResult<&[u8]> {
+        if self.shard.is_none() {
+            // Disconnected: the connection has been closed
+            let shard = self
+                .shard_receiver
+                .recv_timeout(self.timeout)
+                .handle_try_again()?;
+
+            self.shard = Some(shard);
+            self.read_cursor = 0;
+        }
+
+        Ok(&self.shard.as_ref().unwrap()[self.read_cursor..])
+    }
+}
+
+impl SocketReader for QuicSocketReader {
+    fn recv(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+        let shard = self.next_shard()?;
+
+        let size = usize::min(buffer.len(), shard.len());
+        buffer[..size].copy_from_slice(&shard[..size]);
+
+        self.read_cursor += size;
+        if self.read_cursor == self.shard.as_ref().unwrap().len() {
+            self.shard = None;
+        }
+
+        Ok(size)
+    }
+
+    fn peek(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+        let shard = self.next_shard()?;
+
+        let size = usize::min(buffer.len(), shard.len());
+        buffer[..size].copy_from_slice(&shard[..size]);
+
+        // Same as the UDP backend
+        if size < buffer.len() {
+            self.shard = None;
+
+            return alvr_common::try_again();
+        }
+
+        Ok(size)
+    }
+
+    fn discard_shard(&mut self) -> ConResult {
+        self.shard = None;
+
+        Ok(())
+    }
+}
+
+impl Drop for QuicSocketReader {
+    fn drop(&mut self) {
+        self.connection.close(0_u32.into(), b"");
+    }
+}
+
+// Byte stream, with the same semantics as a TCP socket
+pub struct QuicStreamWriter {
+    stream: SendStream,
+}
+
+impl SocketWriter for QuicStreamWriter {
+    fn send(&mut self, buffer: &[u8]) -> Result<()> {
+        RUNTIME.block_on(self.stream.write_all(buffer))?;
+
+        Ok(())
+    }
+}
+
+pub struct QuicStreamReader {
+    chunk_receiver: mpsc::Receiver<Vec<u8>>,
+    timeout: Duration,
+    pending: VecDeque<u8>,
+}
+
+impl QuicStreamReader {
+    // Waits for one more chunk if less than min_size bytes are pending
+    fn fill(&mut self, min_size: usize) -> ConResult {
+        if self.pending.len() < min_size {
+            let chunk = self
+                .chunk_receiver
+                .recv_timeout(self.timeout)
+                .handle_try_again()?;
+
+            self.pending.extend(chunk);
+        }
+
+        Ok(())
+    }
+}
+
+impl SocketReader for QuicStreamReader {
+    fn recv(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+        self.fill(1)?;
+
+        let size = usize::min(buffer.len(), self.pending.len());
+        for (dst, src) in buffer.iter_mut().zip(self.pending.drain(..size)) {
+            *dst = src;
+        }
+
+        Ok(size)
+    }
+
+    fn peek(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+        self.fill(buffer.len())?;
+
+        let size = usize::min(buffer.len(), self.pending.len());
+        for (dst, src) in buffer.iter_mut().zip(&self.pending) {
+            *dst = *src;
+        }
+
+        Ok(size)
+    }
+
+    fn discard_shard(&mut self) -> ConResult {
+        con_bail!("Stream corrupted, cannot find the next shard")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::udp;
+    use alvr_session::SocketBufferSize;
+    use std::{net::Ipv4Addr, thread};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn bind_loopback() -> UdpSocket {
+        udp::bind(
+            0,
+            None,
+            SocketBufferSize::Default,
+            SocketBufferSize::Default,
+        )
+        .unwrap()
+    }
+
+    fn connect() -> (QuicSockets, QuicSockets) {
+        let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let server_socket = bind_loopback();
+        let server_port = server_socket.local_addr().unwrap().port();
+        let listener = super::bind(server_socket).unwrap();
+        let certificate = listener.certificate().to_vec();
+
+        let client_thread = thread::spawn(move || {
+            connect_to_server(
+                bind_loopback(),
+                loopback,
+                server_port,
+                &certificate,
+                TIMEOUT,
+            )
+            .unwrap()
+        });
+        let server = accept_from_client(listener, loopback, TIMEOUT).unwrap();
+
+        (server, client_thread.join().unwrap())
+    }
+
+    fn recv_exact(reader: &mut dyn SocketReader, size: usize) -> Vec<u8> {
+        let mut buffer = vec![0; size];
+        let mut cursor = 0;
+        while cursor < size {
+            cursor += reader.recv(&mut buffer[cursor..]).unwrap();
+        }
+
+        buffer
+    }
+
+    #[test]
+    fn test_wrong_certificate() {
+        let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let server_socket = bind_loopback();
+        let server_port = server_socket.local_addr().unwrap().port();
+        let listener = super::bind(server_socket).unwrap();
+        let other_certificate = rcgen::generate_simple_self_signed(vec![SERVER_NAME.into()])
+            .unwrap()
+            .cert
+            .der()
+            .to_vec();
+
+        let server_thread =
+            thread::spawn(move || accept_from_client(listener, loopback, TIMEOUT).is_err());
+        let client_result = connect_to_server(
+            bind_loopback(),
+            loopback,
+            server_port,
+            &other_certificate,
+            TIMEOUT,
+        );
+
+        // Rejected during the handshake, not after the timeout
+        assert!(matches!(client_result, Err(ConnectionError::Other(_))));
+        assert!(server_thread.join().unwrap());
+    }
+
+    #[test]
+    fn test_shards_and_control() {
+        let (mut server, mut client) = connect();
+
+        // Reliable shards are opened after the control stream, and must not be mistaken for it
+        let mut shard = vec![0; 100];
+        shard[..4].copy_from_slice(&100_u32.to_le_bytes());
+        server.writer.send_reliable(&shard).unwrap();
+        assert_eq!(recv_exact(&mut client.reader, 100), shard);
+
+        server.control_writer.send(b"to client").unwrap();
+        client.control_writer.send(b"to server").unwrap();
+        client.control_writer.send(b" again").unwrap();
+
+        let mut prefix = [0; 2];
+        assert_eq!(client.control_reader.peek(&mut prefix).unwrap(), 2);
+        assert_eq!(&prefix, b"to");
+        assert_eq!(recv_exact(&mut client.control_reader, 9), b"to client");
+        assert_eq!(
+            recv_exact(&mut server.control_reader, 15),
+            b"to server again"
+        );
+
+        // Datagrams may be lost even on loopback, at least one of them should arrive
+        for _ in 0..10 {
+            client.writer.send(&shard).unwrap();
+        }
+        assert_eq!(recv_exact(&mut server.reader, 100), shard);
+    }
+}
@@ -1,8 +1,7 @@
-use crate::LOCAL_IP;
-
 use super::{SocketReader, SocketWriter};
 use alvr_common::{ConResult, HandleTryAgain, ToCon, anyhow::Result, con_bail};
 use alvr_session::{DscpTos, SocketBufferSize};
+use socket2::{Protocol, Type};
 use std::{
     io::Read,
     io::Write,
@@ -17,7 +16,8 @@ pub fn bind(
     send_buffer_bytes: SocketBufferSize,
     recv_buffer_bytes: SocketBufferSize,
 ) -> Result<TcpListener> {
-    let socket = TcpListener::bind((LOCAL_IP, port))?.into();
+    let socket = crate::bind_any(port, Type::STREAM, Protocol::TCP)?;
+    socket.listen(128)?;
 
     crate::set_socket_buffers(&socket, send_buffer_bytes, recv_buffer_bytes).ok();
 
@@ -36,12 +36,13 @@ pub fn accept_from_server(
     // Uses timeout set during bind()
     let (socket, server_address) = listener.accept().handle_try_again()?;
 
+    // Dual-stack sockets report IPv4 peers as IPv4-mapped IPv6 addresses
     if let Some(ip) = server_ip
-        && server_address.ip() != ip
+        && server_address.ip().to_canonical() != ip.to_canonical()
     {
         con_bail!(
             "Connected to wrong client: Expected: {ip}, Found {}",
-            server_address.ip()
+            server_address.ip().to_canonical()
         );
     }
 
@@ -94,7 +95,11 @@ impl SocketReader for TcpStream {
         Read::read(self, buffer).handle_try_again()
     }
 
-    fn peek(&self, buffer: &mut [u8]) -> ConResult<usize> {
+    fn peek(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
         TcpStream::peek(self, buffer).handle_try_again()
     }
+
+    fn discard_shard(&mut self) -> ConResult {
+        con_bail!("Stream corrupted, cannot find the next shard")
+    }
 }
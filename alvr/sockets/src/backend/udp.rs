@@ -1,9 +1,7 @@
-use crate::LOCAL_IP;
-
 use super::{SocketReader, SocketWriter};
 use alvr_common::{ConResult, HandleTryAgain, anyhow::Result};
 use alvr_session::{DscpTos, SocketBufferSize};
-use socket2::{MaybeUninitSlice, Socket};
+use socket2::{MaybeUninitSlice, Protocol, Socket, Type};
 use std::{
     ffi::c_int,
     mem::MaybeUninit,
@@ -20,7 +18,7 @@ pub fn bind(
     send_buffer_bytes: SocketBufferSize,
     recv_buffer_bytes: SocketBufferSize,
 ) -> Result<UdpSocket> {
-    let socket = UdpSocket::bind((LOCAL_IP, port))?.into();
+    let socket = crate::bind_any(port, Type::DGRAM, Protocol::UDP)?;
 
     crate::set_socket_buffers(&socket, send_buffer_bytes, recv_buffer_bytes).ok();
 
@@ -35,6 +33,12 @@ pub fn connect(
     port: u16,
     timeout: Duration,
 ) -> Result<(UdpSocket, Socket)> {
+    // IPv6 sockets can reach IPv4 peers only through IPv4-mapped addresses
+    let peer_ip = match peer_ip {
+        IpAddr::V4(ip) if socket.local_addr()?.is_ipv6() => IpAddr::V6(ip.to_ipv6_mapped()),
+        ip => ip,
+    };
+
     socket.connect((peer_ip, port))?;
     socket.set_read_timeout(Some(timeout))?;
 
@@ -57,18 +61,36 @@ impl SocketReader for Socket {
         .handle_try_again()
     }
 
-    fn peek(&self, buffer: &mut [u8]) -> ConResult<usize> {
+    fn peek(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
         #[cfg(windows)]
         const FLAGS: c_int = 0x02 | 0x8000; // MSG_PEEK | MSG_PARTIAL
         #[cfg(not(windows))]
         const FLAGS: c_int = 0x02 | 0x20; // MSG_PEEK | MSG_TRUNC
 
+        let requested_size = buffer.len();
         let buffer = MaybeUninitSlice::new(unsafe {
             &mut *(ptr::from_mut(buffer) as *mut [MaybeUninit<u8>])
         });
-        Ok(self
+        let size = self
             .recv_vectored_with_flags(&mut [buffer], FLAGS)
             .handle_try_again()?
-            .0)
+            .0;
+
+        // The datagram is too short to contain what the caller is looking for. Drop it, otherwise
+        // it would block the socket forever
+        if size < requested_size {
+            self.discard_shard()?;
+
+            return alvr_common::try_again();
+        }
+
+        Ok(size)
+    }
+
+    // Each shard is a datagram, receiving a part of it drops the rest
+    fn discard_shard(&mut self) -> ConResult {
+        Socket::recv(self, &mut [MaybeUninit::uninit()]).handle_try_again()?;
+
+        Ok(())
     }
 }
@@ -1,7 +1,11 @@
-use crate::backend::{SocketReader, SocketWriter, tcp};
+use crate::{
+    EncryptionKeys,
+    backend::{SocketReader, SocketWriter, tcp},
+    crypto::{FRAME_ENCRYPTION_OVERHEAD, FrameCipher},
+};
 
 use super::CONTROL_PORT;
-use alvr_common::{ConResult, HandleTryAgain, ToCon, anyhow::Result};
+use alvr_common::{AnyhowToCon, ConResult, ToCon, anyhow::Result, con_bail};
 use alvr_session::SocketBufferSize;
 use bincode::config;
 use serde::{Serialize, de::DeserializeOwned};
@@ -12,29 +16,43 @@ use std::{
     time::{Duration, Instant},
 };
 
-// This corresponds to the length of the payload
+// This corresponds to the length of the payload (sealed, if encryption is enabled)
 const FRAMED_PREFIX_LENGTH: usize = mem::size_of::<u32>();
 
+// The length prefix is not trusted, to avoid huge allocations
+const MAX_FRAMED_PACKET_SIZE: usize = 16 * 1024 * 1024;
+
 fn framed_send<S: Serialize>(
-    socket: &mut TcpStream,
+    socket: &mut dyn SocketWriter,
     buffer: &mut Vec<u8>,
+    cipher: Option<&mut FrameCipher>,
     packet: &S,
 ) -> Result<()> {
     buffer.resize(FRAMED_PREFIX_LENGTH, 0);
 
     let encoded_size = bincode::serde::encode_into_std_write(packet, buffer, config::standard())?;
 
-    buffer[0..FRAMED_PREFIX_LENGTH].copy_from_slice(&(encoded_size as u32).to_le_bytes());
+    let payload_size = if cipher.is_some() {
+        encoded_size + FRAME_ENCRYPTION_OVERHEAD
+    } else {
+        encoded_size
+    };
+    buffer[0..FRAMED_PREFIX_LENGTH].copy_from_slice(&(payload_size as u32).to_le_bytes());
+
+    if let Some(cipher) = cipher {
+        cipher.seal(buffer, FRAMED_PREFIX_LENGTH)?;
+    }
 
-    socket.send(&buffer[0..FRAMED_PREFIX_LENGTH + encoded_size])?;
+    socket.send(&buffer[0..FRAMED_PREFIX_LENGTH + payload_size])?;
 
     Ok(())
 }
 
-fn framed_recv<R: DeserializeOwned>(
-    socket: &mut TcpStream,
+pub(crate) fn framed_recv<R: DeserializeOwned>(
+    socket: &mut dyn SocketReader,
     buffer: &mut Vec<u8>,
     recv_cursor: &mut Option<usize>,
+    cipher: Option<&mut FrameCipher>,
     timeout: Duration,
 ) -> ConResult<R> {
     let deadline = Instant::now() + timeout;
@@ -45,7 +63,7 @@ fn framed_recv<R: DeserializeOwned>(
         let mut payload_length_bytes = [0; FRAMED_PREFIX_LENGTH];
 
         loop {
-            let count = socket.peek(&mut payload_length_bytes).handle_try_again()?;
+            let count = socket.peek(&mut payload_length_bytes)?;
             if count == FRAMED_PREFIX_LENGTH {
                 break;
             } else if Instant::now() > deadline {
@@ -55,6 +73,9 @@ fn framed_recv<R: DeserializeOwned>(
 
         let packet_length =
             FRAMED_PREFIX_LENGTH + u32::from_le_bytes(payload_length_bytes) as usize;
+        if packet_length > MAX_FRAMED_PACKET_SIZE {
+            con_bail!("Control packet too large: {packet_length} bytes");
+        }
 
         buffer.resize(packet_length, 0);
 
@@ -71,40 +92,65 @@ fn framed_recv<R: DeserializeOwned>(
         }
     }
 
+    *recv_cursor = None;
+
+    if let Some(cipher) = cipher {
+        cipher.open(buffer, FRAMED_PREFIX_LENGTH).to_con()?;
+    }
+
     let (packet, _) =
         bincode::serde::decode_from_slice(&buffer[FRAMED_PREFIX_LENGTH..], config::standard())
             .to_con()?;
 
-    *recv_cursor = None;
-
     Ok(packet)
 }
 
 pub struct ControlSocketSender<T> {
-    inner: TcpStream,
+    inner: Box<dyn SocketWriter>,
     buffer: Vec<u8>,
+    cipher: Option<FrameCipher>,
     _phantom: PhantomData<T>,
 }
 
+impl<T> ControlSocketSender<T> {
+    // The peer must switch socket at the same point
+    pub(crate) fn replace_socket(&mut self, socket: Box<dyn SocketWriter>) {
+        self.inner = socket;
+    }
+}
+
 impl<S: Serialize> ControlSocketSender<S> {
     pub fn send(&mut self, packet: &S) -> Result<()> {
-        framed_send(&mut self.inner, &mut self.buffer, packet)
+        framed_send(
+            self.inner.as_mut(),
+            &mut self.buffer,
+            self.cipher.as_mut(),
+            packet,
+        )
     }
 }
 
 pub struct ControlSocketReceiver<T> {
-    inner: TcpStream,
+    inner: Box<dyn SocketReader>,
     buffer: Vec<u8>,
     recv_cursor: Option<usize>,
+    cipher: Option<FrameCipher>,
     _phantom: PhantomData<T>,
 }
 
+impl<T> ControlSocketReceiver<T> {
+    pub(crate) fn replace_socket(&mut self, socket: Box<dyn SocketReader>) {
+        self.inner = socket;
+    }
+}
+
 impl<R: DeserializeOwned> ControlSocketReceiver<R> {
     pub fn recv(&mut self, timeout: Duration) -> ConResult<R> {
         framed_recv(
-            &mut self.inner,
+            self.inner.as_mut(),
             &mut self.buffer,
             &mut self.recv_cursor,
+            self.cipher.as_mut(),
             timeout,
         )
     }
@@ -126,6 +172,8 @@ pub fn get_server_listener(timeout: Duration) -> Result<TcpListener> {
 // the specified types can be exchanged
 pub struct ProtoControlSocket {
     inner: TcpStream,
+    send_cipher: Option<FrameCipher>,
+    recv_cipher: Option<FrameCipher>,
 }
 
 pub enum PeerType<'a> {
@@ -149,17 +197,43 @@ impl ProtoControlSocket {
             PeerType::Server(listener) => tcp::accept_from_server(listener, None, timeout)?.0,
         };
 
-        let peer_ip = socket.peer_addr().to_con()?.ip();
+        // Dual-stack sockets report IPv4 peers as IPv4-mapped IPv6 addresses
+        let peer_ip = socket.peer_addr().to_con()?.ip().to_canonical();
+
+        Ok((
+            Self {
+                inner: socket,
+                send_cipher: None,
+                recv_cipher: None,
+            },
+            peer_ip,
+        ))
+    }
 
-        Ok((Self { inner: socket }, peer_ip))
+    // All packets exchanged after this call are encrypted and authenticated, including after the
+    // split. Both peers must call this at the same point of the handshake.
+    pub fn enable_encryption(&mut self, keys: &EncryptionKeys) {
+        self.send_cipher = Some(FrameCipher::new(&keys.send));
+        self.recv_cipher = Some(FrameCipher::new(&keys.recv));
     }
 
     pub fn send<S: Serialize>(&mut self, packet: &S) -> Result<()> {
-        framed_send(&mut self.inner, &mut vec![], packet)
+        framed_send(
+            &mut self.inner,
+            &mut vec![],
+            self.send_cipher.as_mut(),
+            packet,
+        )
     }
 
     pub fn recv<R: DeserializeOwned>(&mut self, timeout: Duration) -> ConResult<R> {
-        framed_recv(&mut self.inner, &mut vec![], &mut None, timeout)
+        framed_recv(
+            &mut self.inner,
+            &mut vec![],
+            &mut None,
+            self.recv_cipher.as_mut(),
+            timeout,
+        )
     }
 
     pub fn split<S: Serialize, R: DeserializeOwned>(
@@ -170,16 +244,75 @@ impl ProtoControlSocket {
 
         Ok((
             ControlSocketSender {
-                inner: self.inner.try_clone()?,
+                inner: Box::new(self.inner.try_clone()?),
                 buffer: vec![0; FRAMED_PREFIX_LENGTH],
+                cipher: self.send_cipher,
                 _phantom: PhantomData,
             },
             ControlSocketReceiver {
-                inner: self.inner,
+                inner: Box::new(self.inner),
                 buffer: vec![0; FRAMED_PREFIX_LENGTH],
                 recv_cursor: None,
+                cipher: self.recv_cipher,
                 _phantom: PhantomData,
             },
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::memory::{Framing, MemoryLink};
+    use alvr_common::ConnectionError;
+
+    #[test]
+    fn test_framed_roundtrip() {
+        let link = MemoryLink::new(Framing::Stream);
+        let mut send_cipher = FrameCipher::new(&[1; 32]);
+        let mut recv_cipher = FrameCipher::new(&[1; 32]);
+
+        let packets = ["hello".to_owned(), String::new(), "x".repeat(100_000)];
+        for packet in &packets {
+            framed_send(
+                &mut link.writer(),
+                &mut vec![],
+                Some(&mut send_cipher),
+                packet,
+            )
+            .unwrap();
+        }
+
+        let mut reader = link.reader();
+        let mut buffer = vec![];
+        let mut recv_cursor = None;
+        for packet in &packets {
+            let received = framed_recv::<String>(
+                &mut reader,
+                &mut buffer,
+                &mut recv_cursor,
+                Some(&mut recv_cipher),
+                Duration::ZERO,
+            )
+            .unwrap();
+
+            assert_eq!(&received, packet);
+        }
+    }
+
+    #[test]
+    fn test_framed_recv_rejects_huge_length() {
+        let link = MemoryLink::new(Framing::Stream);
+        link.inject(&u32::MAX.to_le_bytes());
+
+        let result = framed_recv::<String>(
+            &mut link.reader(),
+            &mut vec![],
+            &mut None,
+            None,
+            Duration::ZERO,
+        );
+
+        assert!(matches!(result, Err(ConnectionError::Other(_))));
+    }
+}
@@ -0,0 +1,658 @@
+// Encryption of the control and stream sockets.
+//
+// Key exchange: the client and the server each own a static X25519 key pair. During pairing (first
+// connection after the client is trusted) the server records the public key of the client and
+// creates a dedicated key pair, both stored in the client entry of the session. At every
+// connection, the Diffie-Hellman shared secret of the static keys is expanded with HKDF, salted
+// with random nonces chosen by both peers, into a set of session keys, one per purpose and
+// direction. Only the owner of the paired keys can derive them, so decryption succeeding also
+// authenticates the peer.
+//
+// Control socket frames are sealed with ChaCha20-Poly1305 with an implicit nonce counter, since TCP
+// preserves the order. Stream socket shards are sealed one by one with an explicit nonce counter
+// appended to the shard, since UDP can lose and reorder them. The shard prefix is left in plaintext
+// (it is needed for reassembly) but it is authenticated.
+
+use crate::backend::{SocketReader, SocketWriter};
+use alvr_common::{
+    ConResult,
+    anyhow::{Result, anyhow, bail},
+    debug,
+};
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit, Nonce, Tag};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::mem;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub const KEY_SIZE: usize = 32;
+pub const HANDSHAKE_NONCE_SIZE: usize = 32;
+
+const TAG_SIZE: usize = 16;
+const COUNTER_SIZE: usize = mem::size_of::<u64>();
+
+// Bytes added to each shard: authentication tag + nonce counter
+pub(crate) const SHARD_ENCRYPTION_OVERHEAD: usize = TAG_SIZE + COUNTER_SIZE;
+// Bytes added to each control frame: authentication tag
+pub(crate) const FRAME_ENCRYPTION_OVERHEAD: usize = TAG_SIZE;
+
+// Number of past shard counters tracked to reject replayed shards
+const REPLAY_WINDOW_SIZE: u64 = u64::BITS as u64;
+
+pub fn generate_secret_key() -> [u8; KEY_SIZE] {
+    rand::random()
+}
+
+pub fn generate_handshake_nonce() -> [u8; HANDSHAKE_NONCE_SIZE] {
+    rand::random()
+}
+
+pub fn public_key(secret_key: &[u8; KEY_SIZE]) -> [u8; KEY_SIZE] {
+    PublicKey::from(&StaticSecret::from(*secret_key)).to_bytes()
+}
+
+#[derive(Clone)]
+pub struct EncryptionKeys {
+    pub send: [u8; KEY_SIZE],
+    pub recv: [u8; KEY_SIZE],
+}
+
+#[derive(Clone)]
+pub struct SessionKeys {
+    pub control: EncryptionKeys,
+    pub stream: EncryptionKeys,
+}
+
+impl SessionKeys {
+    // Both peers call this function with their own secret key and the public key of the other peer,
+    // and obtain mirrored send and receive keys.
+    pub fn derive(
+        own_secret_key: &[u8; KEY_SIZE],
+        peer_public_key: &[u8; KEY_SIZE],
+        server_nonce: &[u8; HANDSHAKE_NONCE_SIZE],
+        client_nonce: &[u8; HANDSHAKE_NONCE_SIZE],
+        is_server: bool,
+    ) -> Result<Self> {
+        let shared_secret =
+            StaticSecret::from(*own_secret_key).diffie_hellman(&PublicKey::from(*peer_public_key));
+        if !shared_secret.was_contributory() {
+            bail!("Invalid peer public key");
+        }
+
+        let salt = [&server_nonce[..], &client_nonce[..]].concat();
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared_secret.as_bytes());
+
+        let expand = |info: &str| -> Result<[u8; KEY_SIZE]> {
+            let mut key = [0; KEY_SIZE];
+            hkdf.expand(info.as_bytes(), &mut key)
+                .map_err(|e| anyhow!("{e}"))?;
+
+            Ok(key)
+        };
+
+        let control_to_client = expand("alvr control server to client")?;
+        let control_to_server = expand("alvr control client to server")?;
+        let stream_to_client = expand("alvr stream server to client")?;
+        let stream_to_server = expand("alvr stream client to server")?;
+
+        Ok(if is_server {
+            Self {
+                control: EncryptionKeys {
+                    send: control_to_client,
+                    recv: control_to_server,
+                },
+                stream: EncryptionKeys {
+                    send: stream_to_client,
+                    recv: stream_to_server,
+                },
+            }
+        } else {
+            Self {
+                control: EncryptionKeys {
+                    send: control_to_server,
+                    recv: control_to_client,
+                },
+                stream: EncryptionKeys {
+                    send: stream_to_server,
+                    recv: stream_to_client,
+                },
+            }
+        })
+    }
+}
+
+fn nonce_from_counter(counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+
+    nonce
+}
+
+// Seals or opens control socket frames. Each direction needs its own instance.
+pub(crate) struct FrameCipher {
+    cipher: ChaCha20Poly1305,
+    next_counter: u64,
+}
+
+impl FrameCipher {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(key.into()),
+            next_counter: 0,
+        }
+    }
+
+    // Encrypt buffer[payload_offset..] in place and append the tag. The bytes before the offset
+    // are authenticated.
+    pub fn seal(&mut self, buffer: &mut Vec<u8>, payload_offset: usize) -> Result<()> {
+        let (aad, payload) = buffer.split_at_mut(payload_offset);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&nonce_from_counter(self.next_counter), aad, payload)
+            .map_err(|e| anyhow!("{e}"))?;
+        self.next_counter += 1;
+
+        buffer.extend_from_slice(&tag);
+
+        Ok(())
+    }
+
+    // Inverse of seal(). On success the tag is removed from the buffer.
+    pub fn open(&mut self, buffer: &mut Vec<u8>, payload_offset: usize) -> Result<()> {
+        if buffer.len() < payload_offset + TAG_SIZE {
+            bail!("Frame too short");
+        }
+
+        let tag_offset = buffer.len() - TAG_SIZE;
+        let (aad, rest) = buffer.split_at_mut(payload_offset);
+        let (payload, tag) = rest.split_at_mut(tag_offset - payload_offset);
+        self.cipher
+            .decrypt_in_place_detached(
+                &nonce_from_counter(self.next_counter),
+                aad,
+                payload,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| anyhow!("Control frame authentication failed"))?;
+        self.next_counter += 1;
+
+        buffer.truncate(tag_offset);
+
+        Ok(())
+    }
+}
+
+// Wraps the stream socket writer to encrypt each shard. The first 4 bytes of the shard prefix
+// (shard length) are updated to contain the length of the sealed shard.
+pub(crate) struct EncryptedSocketWriter {
+    inner: Box<dyn SocketWriter>,
+    cipher: ChaCha20Poly1305,
+    prefix_size: usize,
+    next_counter: u64,
+    buffer: Vec<u8>,
+}
+
+impl EncryptedSocketWriter {
+    pub fn new(inner: Box<dyn SocketWriter>, key: &[u8; KEY_SIZE], prefix_size: usize) -> Self {
+        Self {
+            inner,
+            cipher: ChaCha20Poly1305::new(key.into()),
+            prefix_size,
+            next_counter: 0,
+            buffer: vec![],
+        }
+    }
+
+    fn seal(&mut self, buffer: &[u8]) -> Result<()> {
+        let sealed_length = buffer.len() + SHARD_ENCRYPTION_OVERHEAD;
+
+        self.buffer.clear();
+        self.buffer.extend_from_slice(buffer);
+        self.buffer[0..4].copy_from_slice(&(sealed_length as u32).to_le_bytes());
+
+        let (aad, payload) = self.buffer.split_at_mut(self.prefix_size);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&nonce_from_counter(self.next_counter), aad, payload)
+            .map_err(|e| anyhow!("{e}"))?;
+
+        self.buffer.extend_from_slice(&tag);
+        self.buffer
+            .extend_from_slice(&self.next_counter.to_le_bytes());
+        self.next_counter += 1;
+
+        Ok(())
+    }
+}
+
+impl SocketWriter for EncryptedSocketWriter {
+    fn send(&mut self, buffer: &[u8]) -> Result<()> {
+        self.seal(buffer)?;
+        self.inner.send(&self.buffer)
+    }
+
+    fn send_reliable(&mut self, buffer: &[u8]) -> Result<()> {
+        self.seal(buffer)?;
+        self.inner.send_reliable(&self.buffer)
+    }
+}
+
+// Wraps the stream socket reader to authenticate and decrypt each shard sealed by
+// EncryptedSocketWriter. Shards that fail authentication or that have already been received are
+// dropped.
+pub(crate) struct DecryptedSocketReader {
+    inner: Box<dyn SocketReader>,
+    cipher: ChaCha20Poly1305,
+    prefix_size: usize,
+    max_sealed_length: usize,
+    highest_counter: Option<u64>,
+    // bit N set: counter highest_counter - N has been received
+    received_counters_mask: u64,
+    buffer: Vec<u8>,
+    sealed_length: Option<usize>,
+    recv_cursor: usize,
+    plaintext_length: Option<usize>,
+    read_cursor: usize,
+}
+
+impl DecryptedSocketReader {
+    // max_shard_length: maximum length of a shard before sealing
+    pub fn new(
+        inner: Box<dyn SocketReader>,
+        key: &[u8; KEY_SIZE],
+        prefix_size: usize,
+        max_shard_length: usize,
+    ) -> Self {
+        Self {
+            inner,
+            cipher: ChaCha20Poly1305::new(key.into()),
+            prefix_size,
+            max_sealed_length: max_shard_length + SHARD_ENCRYPTION_OVERHEAD,
+            highest_counter: None,
+            received_counters_mask: 0,
+            buffer: vec![],
+            sealed_length: None,
+            recv_cursor: 0,
+            plaintext_length: None,
+            read_cursor: 0,
+        }
+    }
+
+    fn is_replayed(&self, counter: u64) -> bool {
+        match self.highest_counter {
+            Some(highest) if counter <= highest => {
+                let age = highest - counter;
+                age >= REPLAY_WINDOW_SIZE || self.received_counters_mask & (1 << age) != 0
+            }
+            _ => false,
+        }
+    }
+
+    fn mark_received(&mut self, counter: u64) {
+        match self.highest_counter {
+            Some(highest) if counter <= highest => {
+                self.received_counters_mask |= 1 << (highest - counter);
+            }
+            Some(highest) => {
+                let shift = counter - highest;
+                self.received_counters_mask = if shift >= REPLAY_WINDOW_SIZE {
+                    1
+                } else {
+                    (self.received_counters_mask << shift) | 1
+                };
+                self.highest_counter = Some(counter);
+            }
+            None => {
+                self.received_counters_mask = 1;
+                self.highest_counter = Some(counter);
+            }
+        }
+    }
+
+    // Authenticate and decrypt the sealed shard in place. Returns the length of the plaintext
+    // shard, whose prefix length field is restored.
+    fn open(&mut self) -> Result<usize> {
+        let sealed_length = self.buffer.len();
+        if sealed_length < self.prefix_size + SHARD_ENCRYPTION_OVERHEAD {
+            bail!("Shard too short");
+        }
+
+        let plaintext_length = sealed_length - SHARD_ENCRYPTION_OVERHEAD;
+        let counter = u64::from_le_bytes(
+            self.buffer[sealed_length - COUNTER_SIZE..]
+                .try_into()
+                .unwrap(),
+        );
+        if self.is_replayed(counter) {
+            bail!("Replayed shard");
+        }
+
+        let (aad, rest) = self.buffer.split_at_mut(self.prefix_size);
+        let (payload, rest) = rest.split_at_mut(plaintext_length - self.prefix_size);
+        self.cipher
+            .decrypt_in_place_detached(
+                &nonce_from_counter(counter),
+                aad,
+                payload,
+                Tag::from_slice(&rest[..TAG_SIZE]),
+            )
+            .map_err(|_| anyhow!("Shard authentication failed"))?;
+
+        self.mark_received(counter);
+
+        self.buffer[0..4].copy_from_slice(&(plaintext_length as u32).to_le_bytes());
+
+        Ok(plaintext_length)
+    }
+
+    // Make sure a whole decrypted shard is available. This may bail out at any time if a timeout is
+    // reached, the progress is kept for the next call.
+    fn fill(&mut self) -> ConResult {
+        while self.plaintext_length.is_none() {
+            let sealed_length = if let Some(length) = self.sealed_length {
+                length
+            } else {
+                let mut length_bytes = [0; 4];
+                if self.inner.peek(&mut length_bytes)? < length_bytes.len() {
+                    return alvr_common::try_again();
+                }
+
+                let length = u32::from_le_bytes(length_bytes) as usize;
+                if !(self.prefix_size + SHARD_ENCRYPTION_OVERHEAD..=self.max_sealed_length)
+                    .contains(&length)
+                {
+                    debug!("Dropping stream shard: invalid length {length}");
+                    self.inner.discard_shard()?;
+
+                    continue;
+                }
+
+                self.buffer.resize(length, 0);
+                self.recv_cursor = 0;
+
+                *self.sealed_length.insert(length)
+            };
+
+            while self.recv_cursor < sealed_length {
+                self.recv_cursor += self
+                    .inner
+                    .recv(&mut self.buffer[self.recv_cursor..sealed_length])?;
+            }
+            self.sealed_length = None;
+
+            match self.open() {
+                Ok(length) => {
+                    self.plaintext_length = Some(length);
+                    self.read_cursor = 0;
+                }
+                Err(e) => debug!("Dropping stream shard: {e}"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SocketReader for DecryptedSocketReader {
+    fn recv(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+        self.fill()?;
+
+        let plaintext_length = self.plaintext_length.unwrap();
+        let size = usize::min(buffer.len(), plaintext_length - self.read_cursor);
+        buffer[..size].copy_from_slice(&self.buffer[self.read_cursor..][..size]);
+        self.read_cursor += size;
+
+        if self.read_cursor == plaintext_length {
+            self.plaintext_length = None;
+        }
+
+        Ok(size)
+    }
+
+    fn peek(&mut self, buffer: &mut [u8]) -> ConResult<usize> {
+        self.fill()?;
+
+        let plaintext_length = self.plaintext_length.unwrap();
+        let size = usize::min(buffer.len(), plaintext_length - self.read_cursor);
+        buffer[..size].copy_from_slice(&self.buffer[self.read_cursor..][..size]);
+
+        Ok(size)
+    }
+
+    fn discard_shard(&mut self) -> ConResult {
+        self.fill()?;
+
+        self.plaintext_length = None;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::memory::{Framing, LinkEvent, MemoryLink};
+
+    const PREFIX_SIZE: usize = 8;
+    const MAX_SHARD_LENGTH: usize = 64;
+
+    fn shard(payload: &[u8]) -> Vec<u8> {
+        let mut shard = vec![0; PREFIX_SIZE];
+        shard[0..4].copy_from_slice(&((PREFIX_SIZE + payload.len()) as u32).to_le_bytes());
+        shard[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        shard.extend_from_slice(payload);
+
+        shard
+    }
+
+    fn encrypted_link() -> (MemoryLink, EncryptedSocketWriter, DecryptedSocketReader) {
+        let key = generate_secret_key();
+        let link = MemoryLink::new(Framing::Datagram);
+        let writer = EncryptedSocketWriter::new(Box::new(link.writer()), &key, PREFIX_SIZE);
+        let reader = DecryptedSocketReader::new(
+            Box::new(link.reader()),
+            &key,
+            PREFIX_SIZE,
+            MAX_SHARD_LENGTH,
+        );
+
+        (link, writer, reader)
+    }
+
+    fn recv_shard(reader: &mut DecryptedSocketReader) -> Option<Vec<u8>> {
+        let mut buffer = vec![0; MAX_SHARD_LENGTH];
+        let size = reader.recv(&mut buffer).ok()?;
+        buffer.truncate(size);
+
+        Some(buffer)
+    }
+
+    #[test]
+    fn test_derived_keys_are_mirrored() {
+        let server_secret_key = generate_secret_key();
+        let client_secret_key = generate_secret_key();
+        let server_nonce = generate_handshake_nonce();
+        let client_nonce = generate_handshake_nonce();
+
+        let server_keys = SessionKeys::derive(
+            &server_secret_key,
+            &public_key(&client_secret_key),
+            &server_nonce,
+            &client_nonce,
+            true,
+        )
+        .unwrap();
+        let client_keys = SessionKeys::derive(
+            &client_secret_key,
+            &public_key(&server_secret_key),
+            &server_nonce,
+            &client_nonce,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(server_keys.control.send, client_keys.control.recv);
+        assert_eq!(server_keys.control.recv, client_keys.control.send);
+        assert_eq!(server_keys.stream.send, client_keys.stream.recv);
+        assert_eq!(server_keys.stream.recv, client_keys.stream.send);
+
+        // Each purpose and direction has its own key
+        let keys = [
+            server_keys.control.send,
+            server_keys.control.recv,
+            server_keys.stream.send,
+            server_keys.stream.recv,
+        ];
+        for (idx, key) in keys.iter().enumerate() {
+            assert!(!keys[idx + 1..].contains(key));
+        }
+
+        // Other nonces produce other keys
+        let other_keys = SessionKeys::derive(
+            &server_secret_key,
+            &public_key(&client_secret_key),
+            &generate_handshake_nonce(),
+            &client_nonce,
+            true,
+        )
+        .unwrap();
+        assert_ne!(other_keys.control.send, server_keys.control.send);
+
+        // The all zero key produces a non contributory shared secret
+        assert!(
+            SessionKeys::derive(
+                &server_secret_key,
+                &[0; KEY_SIZE],
+                &server_nonce,
+                &client_nonce,
+                true
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_frame_seal_and_open() {
+        let key = generate_secret_key();
+        let mut sealer = FrameCipher::new(&key);
+        let mut opener = FrameCipher::new(&key);
+
+        for payload in [&b"first"[..], &b"second frame"[..]] {
+            let mut buffer = [&[4, 3, 2, 1], payload].concat();
+            sealer.seal(&mut buffer, 4).unwrap();
+            assert_eq!(buffer.len(), 4 + payload.len() + FRAME_ENCRYPTION_OVERHEAD);
+            assert_ne!(&buffer[4..][..payload.len()], payload);
+
+            opener.open(&mut buffer, 4).unwrap();
+            assert_eq!(buffer, [&[4, 3, 2, 1], payload].concat());
+        }
+    }
+
+    #[test]
+    fn test_tampered_frame_is_rejected() {
+        let key = generate_secret_key();
+
+        // Both the payload and the authenticated prefix are protected
+        for tampered_idx in [0, 6] {
+            let mut buffer = b"prefix payload".to_vec();
+            FrameCipher::new(&key).seal(&mut buffer, 4).unwrap();
+            buffer[tampered_idx] ^= 1;
+
+            assert!(FrameCipher::new(&key).open(&mut buffer, 4).is_err());
+        }
+
+        // Frames cannot be opened out of order
+        let mut sealer = FrameCipher::new(&key);
+        let mut buffer = b"prefix payload".to_vec();
+        sealer.seal(&mut buffer, 4).unwrap();
+        sealer.seal(&mut buffer, 4).unwrap();
+        let mut opener = FrameCipher::new(&key);
+        assert!(opener.open(&mut buffer, 4).is_err());
+    }
+
+    #[test]
+    fn test_shard_seal_and_open() {
+        let (_, mut writer, mut reader) = encrypted_link();
+
+        let shards = [shard(b"first"), shard(&[7; MAX_SHARD_LENGTH - PREFIX_SIZE])];
+        for shard in &shards {
+            writer.send(shard).unwrap();
+        }
+
+        for shard in &shards {
+            assert_eq!(recv_shard(&mut reader).as_ref(), Some(shard));
+        }
+        assert!(recv_shard(&mut reader).is_none());
+    }
+
+    #[test]
+    fn test_tampered_shard_is_dropped() {
+        let (link, mut writer, mut reader) = encrypted_link();
+
+        let sealed_shard = |writer: &mut EncryptedSocketWriter| {
+            writer.send(&shard(b"payload")).unwrap();
+            let mut datagram = vec![0; MAX_SHARD_LENGTH + SHARD_ENCRYPTION_OVERHEAD];
+            let size = link.reader().recv(&mut datagram).unwrap();
+            datagram.truncate(size);
+
+            datagram
+        };
+
+        // Modified in the authenticated prefix and in the payload
+        let mut tampered_shards = vec![];
+        for tampered_idx in [5, PREFIX_SIZE + 1] {
+            let mut datagram = sealed_shard(&mut writer);
+            datagram[tampered_idx] ^= 1;
+            tampered_shards.push(datagram);
+        }
+
+        // Sealed with another key
+        let mut other_writer = EncryptedSocketWriter::new(
+            Box::new(link.writer()),
+            &generate_secret_key(),
+            PREFIX_SIZE,
+        );
+        tampered_shards.push(sealed_shard(&mut other_writer));
+
+        for datagram in &tampered_shards {
+            link.inject(datagram);
+        }
+        assert!(recv_shard(&mut reader).is_none());
+
+        writer.send(&shard(b"intact")).unwrap();
+        assert_eq!(recv_shard(&mut reader), Some(shard(b"intact")));
+    }
+
+    #[test]
+    fn test_replayed_shard_is_dropped() {
+        let (link, mut writer, mut reader) = encrypted_link();
+
+        link.push_events([LinkEvent::Duplicate]);
+        writer.send(&shard(b"payload")).unwrap();
+
+        assert_eq!(recv_shard(&mut reader), Some(shard(b"payload")));
+        assert!(recv_shard(&mut reader).is_none());
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let (_, _, mut reader) = encrypted_link();
+
+        // Counters received out of order within the window are accepted once
+        for counter in [10, 5, 100, 40] {
+            assert!(!reader.is_replayed(counter));
+            reader.mark_received(counter);
+            assert!(reader.is_replayed(counter));
+        }
+        assert!(reader.is_replayed(10));
+
+        // The window covers the 64 most recent counters
+        assert!(!reader.is_replayed(100 - REPLAY_WINDOW_SIZE + 1));
+        assert!(reader.is_replayed(100 - REPLAY_WINDOW_SIZE));
+
+        // A jump larger than the window clears it
+        reader.mark_received(1000);
+        assert!(!reader.is_replayed(1000 - REPLAY_WINDOW_SIZE + 1));
+        assert!(reader.is_replayed(100));
+    }
+}
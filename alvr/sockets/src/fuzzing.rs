@@ -0,0 +1,104 @@
+// Entry points of the fuzz targets (see sockets/fuzz). Untrusted bytes are fed to the receive paths
+// through in-memory sockets. Malformed input must only result in errors or dropped shards, never in
+// panics, hangs or huge allocations.
+
+pub use crate::backend::memory::{
+    Framing, LinkEvent, MemoryLink, MemorySocketReader, MemorySocketWriter,
+};
+
+use crate::{control_socket, crypto::FrameCipher, stream_socket::StreamSocket};
+use alvr_session::{ForwardErrorCorrectionConfig, SocketProtocol};
+use std::time::Duration;
+
+const MAX_PACKET_SIZE: usize = 1400;
+const STREAMS_COUNT: u16 = 4;
+const MAX_CONCURRENT_BUFFERS: usize = 4;
+const MAX_RECEIVED_PACKET_SIZE: usize = 64 * 1024;
+const FUZZ_KEY: [u8; 32] = [0; 32];
+
+// Covers the most common serde data types
+type FuzzPacket = (u32, Option<f32>, String, Vec<(u8, Vec<u8>)>);
+
+// The first byte selects the socket options: bit 0 enables FEC, bit 1 enables retransmission and
+// the other bits set the FEC group size. The rest is split into datagrams, each one prefixed by its
+// length (u16).
+pub fn stream_socket_recv(data: &[u8]) {
+    let Some((&options, mut data)) = data.split_first() else {
+        return;
+    };
+
+    let link = MemoryLink::new(Framing::Datagram);
+    while data.len() >= 2 {
+        let length = usize::min(
+            u16::from_le_bytes([data[0], data[1]]) as usize,
+            data.len() - 2,
+        );
+        link.inject(&data[2..2 + length]);
+        data = &data[2 + length..];
+    }
+
+    let mut socket = StreamSocket::new(
+        // NACKs and retransmitted shards are lost
+        Box::new(MemoryLink::new(Framing::Datagram).writer()),
+        Box::new(link.reader()),
+        SocketProtocol::Udp,
+        MAX_PACKET_SIZE,
+        (options & 1 != 0).then_some(ForwardErrorCorrectionConfig {
+            data_shards_per_parity_shard: (options >> 2) as u32,
+        }),
+        (options & 2 != 0).then_some(Duration::from_millis(100)),
+        None,
+    );
+    let mut receivers = (0..STREAMS_COUNT)
+        .map(|stream_id| {
+            socket
+                .subscribe_to_stream::<u32>(
+                    stream_id,
+                    MAX_CONCURRENT_BUFFERS,
+                    MAX_RECEIVED_PACKET_SIZE,
+                )
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    // Shards of streams without subscribers are never consumed. Each call consumes at most one
+    // datagram, so this is enough to process all of them otherwise.
+    for _ in 0..link.pending_count() * 2 {
+        if socket.recv().is_err() && link.pending_count() == 0 {
+            break;
+        }
+
+        for receiver in &mut receivers {
+            while let Ok(data) = receiver.recv(Duration::ZERO) {
+                data.get().ok();
+            }
+        }
+    }
+}
+
+// The first byte enables encryption, to also exercise the authentication failures. The rest is the
+// content of the control stream.
+pub fn framed_recv(data: &[u8]) {
+    let Some((&options, data)) = data.split_first() else {
+        return;
+    };
+
+    let link = MemoryLink::new(Framing::Stream);
+    link.inject(data);
+
+    let mut reader = link.reader();
+    let mut buffer = vec![];
+    let mut recv_cursor = None;
+    let mut cipher = (options & 1 != 0).then(|| FrameCipher::new(&FUZZ_KEY));
+
+    // All the data is available from the start: a timeout means that the stream is exhausted
+    while control_socket::framed_recv::<FuzzPacket>(
+        &mut reader,
+        &mut buffer,
+        &mut recv_cursor,
+        cipher.as_mut(),
+        Duration::ZERO,
+    )
+    .is_ok()
+    {}
+}
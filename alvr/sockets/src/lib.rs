@@ -1,19 +1,30 @@
 mod backend;
 mod control_socket;
+mod crypto;
 mod stream_socket;
 
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+#[cfg(feature = "network-emulation")]
+pub mod network_emulation;
+
 use alvr_common::{anyhow::Result, info};
 use alvr_session::{DscpTos, SocketBufferSize};
-use socket2::Socket;
+use socket2::{Domain, Protocol, Socket, Type};
 use std::{
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     time::Duration,
 };
 
 pub use control_socket::*;
+pub use crypto::{
+    EncryptionKeys, HANDSHAKE_NONCE_SIZE, KEY_SIZE, SessionKeys, generate_handshake_nonce,
+    generate_secret_key, public_key,
+};
 pub use stream_socket::*;
 
-pub const LOCAL_IP: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+// Sockets bound to this address are dual-stack: they accept both IPv6 and IPv4 peers
+pub const LOCAL_IP: IpAddr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
 pub const CONTROL_PORT: u16 = 9943;
 pub const HANDSHAKE_PACKET_SIZE_BYTES: usize = 56; // this may change in future protocols
 pub const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(500);
@@ -21,10 +32,37 @@ pub const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub const MDNS_SERVICE_TYPE: &str = "_alvr._tcp.local.";
 pub const MDNS_PROTOCOL_KEY: &str = "protocol";
+pub const MDNS_PROTOCOL_VERSIONS_KEY: &str = "protocol_versions";
 pub const MDNS_DEVICE_ID_KEY: &str = "device_id";
 
 pub const WIRED_CLIENT_HOSTNAME: &str = "client.wired";
 
+// Bind to all interfaces. Fall back to IPv4 only if IPv6 is disabled on this system.
+fn bind_any(port: u16, ty: Type, protocol: Protocol) -> Result<Socket> {
+    let bind = |ip: IpAddr| -> Result<Socket> {
+        let address = SocketAddr::new(ip, port);
+
+        let socket = Socket::new(Domain::for_address(address), ty, Some(protocol))?;
+        if address.is_ipv6() {
+            // The default is platform dependent
+            socket.set_only_v6(false)?;
+        }
+        // Same as TcpListener::bind()
+        #[cfg(not(windows))]
+        if ty == Type::STREAM {
+            socket.set_reuse_address(true)?;
+        }
+        socket.bind(&address.into())?;
+
+        Ok(socket)
+    };
+
+    bind(LOCAL_IP).or_else(|e| {
+        info!("Failed to bind dual-stack socket, using IPv4 only: {e}");
+        bind(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    })
+}
+
 fn set_socket_buffers(
     socket: &socket2::Socket,
     send_buffer_bytes: SocketBufferSize,
@@ -90,6 +128,10 @@ fn set_dscp(socket: &Socket, dscp: Option<DscpTos>) {
             DscpTos::ExpeditedForwarding => 0b101110,
         };
 
+        // Dual-stack sockets can carry both IPv4 and IPv6 traffic. Only one of these will succeed
+        // on single-stack sockets
         socket.set_tos_v4((tos << 2) as u32).ok();
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+        socket.set_tclass_v6((tos << 2) as u32).ok();
     }
 }
@@ -0,0 +1,86 @@
+// Stream sockets connected through emulated links, used to test the bitrate adaptation of the
+// server against controlled network conditions. The clock of the links is virtual: the caller
+// advances it, and the shards that arrived in the meantime are processed by the sockets.
+
+pub use crate::backend::{
+    emulated::{BurstLoss, EmulatedLink, EmulatedSocketWriter, LinkConditions, LinkStatistics},
+    memory::{Framing, LinkEvent, MemoryLink, MemorySocketReader, MemorySocketWriter},
+};
+
+use crate::stream_socket::StreamSocket;
+use alvr_session::{ForwardErrorCorrectionConfig, SocketProtocol};
+use std::time::Duration;
+
+pub struct EmulatedPeers {
+    // From the server to the client
+    pub forward_link: EmulatedLink,
+    pub backward_link: EmulatedLink,
+    pub server_socket: StreamSocket,
+    pub client_socket: StreamSocket,
+}
+
+impl EmulatedPeers {
+    pub fn new(
+        forward_conditions: LinkConditions,
+        backward_conditions: LinkConditions,
+        seed: u64,
+        max_packet_size: usize,
+        forward_error_correction: Option<ForwardErrorCorrectionConfig>,
+        retransmission_deadline: Option<Duration>,
+    ) -> Self {
+        let forward_link = EmulatedLink::new(forward_conditions, seed);
+        let backward_link = EmulatedLink::new(backward_conditions, seed.wrapping_add(1));
+
+        let socket = |writer_link: &EmulatedLink| {
+            let memory_link = MemoryLink::new(Framing::Datagram);
+            let writer = writer_link.wrap_writer(Box::new(memory_link.writer()));
+
+            (writer, memory_link)
+        };
+        let (server_writer, forward_memory_link) = socket(&forward_link);
+        let (client_writer, backward_memory_link) = socket(&backward_link);
+
+        let stream_socket = |writer: EmulatedSocketWriter, reader_link: &MemoryLink| {
+            StreamSocket::new(
+                Box::new(writer),
+                Box::new(reader_link.reader()),
+                SocketProtocol::Udp,
+                max_packet_size,
+                forward_error_correction,
+                retransmission_deadline,
+                None,
+            )
+        };
+
+        Self {
+            server_socket: stream_socket(server_writer, &backward_memory_link),
+            client_socket: stream_socket(client_writer, &forward_memory_link),
+            forward_link,
+            backward_link,
+        }
+    }
+
+    pub fn now(&self) -> Duration {
+        self.forward_link.now()
+    }
+
+    // Move the clock of both links and process the shards that arrived, including NACKs and
+    // retransmissions
+    pub fn advance_to(&mut self, time: Duration) {
+        self.forward_link.advance_to(time);
+        self.backward_link.advance_to(time);
+
+        while self.client_socket.recv().is_ok() || self.server_socket.recv().is_ok() {}
+    }
+
+    // Time of the next datagram delivery in any direction
+    pub fn next_delivery_time(&self) -> Option<Duration> {
+        [
+            self.forward_link.next_delivery_time(),
+            self.backward_link.next_delivery_time(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+}
@@ -1,5 +1,10 @@
 // Note: for StreamSocket, the client uses a server socket, the server uses a client socket.
 // This is because of certificate management. The server needs to trust a client and its certificate
+// QUIC is the exception: the client connects to the server, so that the connection can migrate
+// when the headset changes address (see backend/quic.rs).
+//
+// When encryption is enabled, each shard is sealed separately (see crypto.rs), after the prefix has
+// been written. The length field of the prefix on the wire refers to the sealed shard.
 //
 // StreamSender and StreamReceiver endpoints allow for convenient conversion of the header to/from
 // bytes while still handling the additional byte buffer with zero copies and extra allocations.
@@ -16,29 +21,71 @@
 // Note: We can't clone the underlying socket for each StreamSender and the mutex around the socket
 // cannot be removed. This is because we need to make sure at least shards are written whole.
 
-use crate::backend::{SocketReader, SocketWriter, tcp, udp};
+use crate::{
+    ControlSocketReceiver, ControlSocketSender, EncryptionKeys,
+    backend::{
+        SocketReader, SocketWriter,
+        quic::{self, QuicListener},
+        tcp, udp,
+    },
+    crypto::{DecryptedSocketReader, EncryptedSocketWriter, SHARD_ENCRYPTION_OVERHEAD},
+};
 use alvr_common::{
-    AnyhowToCon, ConResult, HandleTryAgain, ToCon, anyhow::Result, debug, parking_lot::Mutex,
+    AnyhowToCon, ConResult, HandleTryAgain, ToCon,
+    anyhow::{Result, bail},
+    con_bail, debug,
+    parking_lot::Mutex,
+    warn,
 };
-use alvr_session::{DscpTos, SocketBufferSize, SocketProtocol};
+use alvr_session::{DscpTos, ForwardErrorCorrectionConfig, SocketBufferSize, SocketProtocol};
 use bincode::config;
 use serde::{Serialize, de::DeserializeOwned};
 use std::{
     cmp::Ordering,
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     marker::PhantomData,
     mem,
     net::{IpAddr, TcpListener, UdpSocket},
     sync::{Arc, mpsc},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+// Reliable byte stream that can carry the control packets
+type ControlStream = (Box<dyn SocketWriter>, Box<dyn SocketReader>);
+
 const SHARD_PREFIX_SIZE: usize = mem::size_of::<u32>() // packet length - field itself (4 bytes)
     + mem::size_of::<u16>() // stream ID
     + mem::size_of::<u32>() // packet index
     + mem::size_of::<u32>() // shards count
     + mem::size_of::<u32>(); // shards index
 
+// Parity shards are sent after each group of data shards when forward error correction is
+// enabled. Their shard index starts at `shards count`. The payload is prefixed by the total buffer
+// length of the packet, needed to reconstruct the length of the last shard, followed by the XOR of
+// all the data shards of the group.
+const PARITY_HEADER_SIZE: usize = mem::size_of::<u32>(); // packet buffer length
+
+// Negative acknowledgements (NACKs) are sent as single shard packets on this reserved stream. The
+// packet index of the prefix refers to the packet with missing shards. The payload contains the
+// stream ID of that packet followed by the indices of the missing shards.
+const NACK_STREAM_ID: u16 = u16::MAX;
+const NACK_HEADER_SIZE: usize = mem::size_of::<u16>(); // stream ID
+
+// Upper bound for the number of sent shards kept per stream, in case the deadline is too long
+const MAX_RETRANSMISSION_HISTORY_SHARDS: usize = 4096;
+
+// Upper bound for the memory of the receive buffers of all streams. Each stream reserves the size of
+// its packets (see subscribe_to_stream()) for each of its buffers, so this limits the memory that a
+// peer can make the receiver allocate.
+const MAX_RECEIVE_BUFFERS_SIZE: usize = 256 * 1024 * 1024;
+
+// Same gain as the RTP interarrival jitter estimator (RFC 3550)
+const JITTER_SMOOTHING_FACTOR: f32 = 1.0 / 16.0;
+
+/// Callback invoked with the payload (without the header) of every packet sent or received on a
+/// stream, for example to record it. It runs on the sending or receiving thread, so it must be fast
+pub type PacketTap = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
 /// Memory buffer that contains a hidden prefix
 #[derive(Default)]
 pub struct Buffer<H = ()> {
@@ -81,6 +128,57 @@ impl<H> Buffer<H> {
     }
 }
 
+struct SentShard {
+    packet_index: u32,
+    shard_index: u32,
+    timestamp: Instant,
+    buffer: Vec<u8>, // contains the prefix
+}
+
+// Copies of the recently sent shards, used to answer NACKs from the peer
+struct RetransmissionHistory {
+    deadline: Duration,
+    shards: HashMap<u16, VecDeque<SentShard>>,
+}
+
+impl RetransmissionHistory {
+    fn push(&mut self, stream_id: u16, packet_index: u32, shard_index: u32, shard: &[u8]) {
+        let now = Instant::now();
+        let shards = self.shards.entry(stream_id).or_default();
+
+        // Recycle the buffer of the expired shards
+        let mut buffer = vec![];
+        while let Some(sent_shard) = shards.front()
+            && (now.duration_since(sent_shard.timestamp) > self.deadline
+                || shards.len() >= MAX_RETRANSMISSION_HISTORY_SHARDS)
+        {
+            buffer = shards.pop_front().unwrap().buffer;
+        }
+
+        buffer.clear();
+        buffer.extend_from_slice(shard);
+
+        shards.push_back(SentShard {
+            packet_index,
+            shard_index,
+            timestamp: now,
+            buffer,
+        });
+    }
+
+    fn get(&self, stream_id: u16, packet_index: u32, shard_index: u32) -> Option<&[u8]> {
+        let now = Instant::now();
+
+        self.shards
+            .get(&stream_id)?
+            .iter()
+            .rev()
+            .find(|shard| shard.packet_index == packet_index && shard.shard_index == shard_index)
+            .filter(|shard| now.duration_since(shard.timestamp) <= self.deadline)
+            .map(|shard| shard.buffer.as_slice())
+    }
+}
+
 #[derive(Clone)]
 pub struct StreamSender<H> {
     inner: Arc<Mutex<Box<dyn SocketWriter>>>,
@@ -89,23 +187,59 @@ pub struct StreamSender<H> {
     // if the packet index overflows the worst that happens is a false positive packet loss
     next_packet_index: u32,
     used_buffers: Vec<Vec<u8>>,
+    reliable: bool,
+    fec_group_size: Option<usize>,
+    parity_buffer: Vec<u8>,
+    retransmission_history: Option<Arc<Mutex<RetransmissionHistory>>>,
+    tap: Option<PacketTap>,
     _phantom: PhantomData<H>,
 }
 
 impl<H> StreamSender<H> {
+    // Clones of this sender created afterwards share the same tap
+    pub fn set_tap(&mut self, tap: Option<PacketTap>) {
+        self.tap = tap;
+    }
+
     /// Shard and send a buffer with zero copies and zero allocations.
     /// The prefix of each shard is written over the previously sent shard to avoid reallocations.
     pub fn send(&mut self, mut buffer: Buffer<H>) -> Result<()> {
+        if let Some(tap) = &self.tap {
+            tap(&buffer.inner[buffer.hidden_offset..]);
+        }
+
+        let res = self.send_shards(&mut buffer.inner);
+
+        // The index is consumed even if a shard could not be sent. The receiver sees the rest of
+        // the packet as lost
+        self.next_packet_index = self.next_packet_index.wrapping_add(1);
+
+        self.used_buffers.push(buffer.inner);
+
+        res
+    }
+
+    fn send_shards(&mut self, buffer: &mut [u8]) -> Result<()> {
         let max_shard_data_size = self.max_packet_size - SHARD_PREFIX_SIZE;
-        let actual_buffer_size = buffer.inner.len();
+        let actual_buffer_size = buffer.len();
         let data_size = actual_buffer_size - SHARD_PREFIX_SIZE;
         let shards_count = (data_size as f32 / max_shard_data_size as f32).ceil() as usize;
 
+        if self.fec_group_size.is_some() {
+            self.parity_buffer.resize(
+                SHARD_PREFIX_SIZE + PARITY_HEADER_SIZE + max_shard_data_size,
+                0,
+            );
+            // A failed send of the previous packet can leave a partial parity behind
+            self.parity_buffer[SHARD_PREFIX_SIZE + PARITY_HEADER_SIZE..].fill(0);
+        }
+        let mut parity_data_size = 0;
+
         for idx in 0..shards_count {
             // this overlaps with the previous shard, this is intended behavior and allows to
             // reduce allocations
             let packet_start_position = idx * max_shard_data_size;
-            let sub_buffer = &mut buffer.inner[packet_start_position..];
+            let sub_buffer = &mut buffer[packet_start_position..];
 
             // NB: true shard length (account for last shard that is smaller)
             let packet_length = usize::min(
@@ -113,18 +247,62 @@ impl<H> StreamSender<H> {
                 actual_buffer_size - packet_start_position,
             );
 
+            // The shard data is still intact here, it gets overwritten only by the prefix of the
+            // next shard
+            if self.fec_group_size.is_some() {
+                let shard_data = &sub_buffer[SHARD_PREFIX_SIZE..packet_length];
+                for (parity, data) in self.parity_buffer[SHARD_PREFIX_SIZE + PARITY_HEADER_SIZE..]
+                    .iter_mut()
+                    .zip(shard_data)
+                {
+                    *parity ^= data;
+                }
+                parity_data_size = usize::max(parity_data_size, shard_data.len());
+            }
+
             sub_buffer[0..4].copy_from_slice(&(packet_length as u32).to_le_bytes());
             sub_buffer[4..6].copy_from_slice(&self.stream_id.to_le_bytes());
             sub_buffer[6..10].copy_from_slice(&self.next_packet_index.to_le_bytes());
             sub_buffer[10..14].copy_from_slice(&(shards_count as u32).to_le_bytes());
             sub_buffer[14..18].copy_from_slice(&(idx as u32).to_le_bytes());
 
-            self.inner.lock().send(&sub_buffer[..packet_length])?;
-        }
+            if self.reliable {
+                self.inner
+                    .lock()
+                    .send_reliable(&sub_buffer[..packet_length])?;
+            } else {
+                self.inner.lock().send(&sub_buffer[..packet_length])?;
+            }
 
-        self.next_packet_index += 1;
+            if let Some(history) = &self.retransmission_history {
+                history.lock().push(
+                    self.stream_id,
+                    self.next_packet_index,
+                    idx as u32,
+                    &sub_buffer[..packet_length],
+                );
+            }
 
-        self.used_buffers.push(buffer.inner);
+            if let Some(group_size) = self.fec_group_size
+                && (idx % group_size == group_size - 1 || idx == shards_count - 1)
+            {
+                let parity_length = SHARD_PREFIX_SIZE + PARITY_HEADER_SIZE + parity_data_size;
+                let parity_index = shards_count + idx / group_size;
+
+                let parity_buffer = &mut self.parity_buffer;
+                parity_buffer[0..4].copy_from_slice(&(parity_length as u32).to_le_bytes());
+                parity_buffer[4..6].copy_from_slice(&self.stream_id.to_le_bytes());
+                parity_buffer[6..10].copy_from_slice(&self.next_packet_index.to_le_bytes());
+                parity_buffer[10..14].copy_from_slice(&(shards_count as u32).to_le_bytes());
+                parity_buffer[14..18].copy_from_slice(&(parity_index as u32).to_le_bytes());
+                parity_buffer[18..22].copy_from_slice(&(actual_buffer_size as u32).to_le_bytes());
+
+                self.inner.lock().send(&parity_buffer[..parity_length])?;
+
+                parity_buffer[SHARD_PREFIX_SIZE + PARITY_HEADER_SIZE..].fill(0);
+                parity_data_size = 0;
+            }
+        }
 
         Ok(())
     }
@@ -152,6 +330,22 @@ impl<H: Serialize> StreamSender<H> {
     }
 }
 
+/// Network statistics of a subscribed stream, cumulative since the subscription
+#[derive(Clone, Debug, Default)]
+pub struct StreamStatistics {
+    /// Data and parity shards
+    pub shards_received: u64,
+    /// Data shards of abandoned packets, which could not be recovered
+    pub shards_lost: u64,
+    pub packets_reconstructed: u64,
+    /// Packets dropped because all the buffers of the stream were in use
+    pub packets_discarded: u64,
+    /// Shards with an invalid prefix, which have been dropped
+    pub shards_malformed: u64,
+    /// Smoothed variation of the interval between the arrivals of consecutive packets
+    pub jitter: Duration,
+}
+
 pub struct ReceiverData<H> {
     buffer: Option<Vec<u8>>,
     used_buffer_queue: mpsc::Sender<Vec<u8>>,
@@ -195,9 +389,21 @@ pub struct StreamReceiver<H> {
     packet_receiver: mpsc::Receiver<ReconstructedPacket>,
     used_buffer_queue: mpsc::Sender<Vec<u8>>,
     last_packet_index: Option<u32>,
+    statistics: Arc<Mutex<StreamStatistics>>,
+    tap: Option<PacketTap>,
     _phantom: PhantomData<H>,
 }
 
+impl<H> StreamReceiver<H> {
+    pub fn statistics(&self) -> StreamStatistics {
+        self.statistics.lock().clone()
+    }
+
+    pub fn set_tap(&mut self, tap: Option<PacketTap>) {
+        self.tap = tap;
+    }
+}
+
 fn wrapping_cmp(lhs: u32, rhs: u32) -> Ordering {
     let diff = lhs.wrapping_sub(rhs);
     if diff == 0 {
@@ -238,18 +444,28 @@ impl<H: DeserializeOwned + Serialize> StreamReceiver<H> {
         }
         self.last_packet_index = Some(packet.index);
 
-        Ok(ReceiverData {
+        let data = ReceiverData {
             buffer: Some(packet.buffer),
             used_buffer_queue: self.used_buffer_queue.clone(),
             had_packet_loss,
             _phantom: PhantomData,
-        })
+        };
+
+        if let Some(tap) = &self.tap
+            && let Ok((_, payload)) = data.get()
+        {
+            tap(payload);
+        }
+
+        Ok(data)
     }
 }
 
 pub enum StreamSocketBuilder {
     Tcp(TcpListener),
     Udp(UdpSocket),
+    // The client connects from this socket
+    Quic(UdpSocket),
 }
 
 impl StreamSocketBuilder {
@@ -275,82 +491,181 @@ impl StreamSocketBuilder {
                 send_buffer_bytes,
                 recv_buffer_bytes,
             )?),
+            SocketProtocol::Quic => StreamSocketBuilder::Quic(udp::bind(
+                port,
+                stream_tos_config,
+                send_buffer_bytes,
+                recv_buffer_bytes,
+            )?),
         })
     }
 
+    // server_certificate is needed by QUIC, see listen_for_client()
+    #[allow(clippy::too_many_arguments)]
     pub fn accept_from_server(
         self,
         server_ip: IpAddr,
         port: u16,
+        server_certificate: Option<Vec<u8>>,
         max_packet_size: usize,
+        forward_error_correction: Option<ForwardErrorCorrectionConfig>,
+        retransmission_deadline: Option<Duration>,
+        encryption_keys: Option<EncryptionKeys>,
         timeout: Duration,
     ) -> ConResult<StreamSocket> {
-        let (send_socket, receive_socket): (Box<dyn SocketWriter>, Box<dyn SocketReader>) =
-            match self {
-                StreamSocketBuilder::Udp(socket) => {
-                    let (send_socket, receive_socket) =
-                        udp::connect(&socket, server_ip, port, timeout).to_con()?;
+        let (send_socket, receive_socket, protocol, control_stream): (
+            Box<dyn SocketWriter>,
+            Box<dyn SocketReader>,
+            _,
+            Option<ControlStream>,
+        ) = match self {
+            StreamSocketBuilder::Udp(socket) => {
+                let (send_socket, receive_socket) =
+                    udp::connect(&socket, server_ip, port, timeout).to_con()?;
+
+                (
+                    Box::new(send_socket),
+                    Box::new(receive_socket),
+                    SocketProtocol::Udp,
+                    None,
+                )
+            }
+            StreamSocketBuilder::Tcp(listener) => {
+                let (send_socket, receive_socket) =
+                    tcp::accept_from_server(&listener, Some(server_ip), timeout)?;
+
+                (
+                    Box::new(send_socket),
+                    Box::new(receive_socket),
+                    SocketProtocol::Tcp,
+                    None,
+                )
+            }
+            StreamSocketBuilder::Quic(socket) => {
+                let Some(certificate) = server_certificate else {
+                    con_bail!("The server did not send its certificate");
+                };
+
+                let sockets =
+                    quic::connect_to_server(socket, server_ip, port, &certificate, timeout)?;
+
+                (
+                    Box::new(sockets.writer),
+                    Box::new(sockets.reader),
+                    SocketProtocol::Quic,
+                    Some((
+                        Box::new(sockets.control_writer),
+                        Box::new(sockets.control_reader),
+                    )),
+                )
+            }
+        };
 
-                    (Box::new(send_socket), Box::new(receive_socket))
-                }
-                StreamSocketBuilder::Tcp(listener) => {
-                    let (send_socket, receive_socket) =
-                        tcp::accept_from_server(&listener, Some(server_ip), timeout)?;
+        let mut socket = StreamSocket::new(
+            send_socket,
+            receive_socket,
+            protocol,
+            max_packet_size,
+            forward_error_correction,
+            retransmission_deadline,
+            encryption_keys,
+        );
+        socket.control_stream = control_stream;
 
-                    (Box::new(send_socket), Box::new(receive_socket))
-                }
-            };
+        Ok(socket)
+    }
 
-        Ok(StreamSocket {
-            max_packet_size,
-            send_socket: Arc::new(Mutex::new(send_socket)),
-            receive_socket,
-            shard_recv_state: None,
-            stream_recv_components: HashMap::new(),
-        })
+    // Binds the UDP socket used by the server for a client ahead of connect_to_client(). With port
+    // 0 the system picks a free port, which must then be sent to the client
+    pub fn bind_for_client(
+        port: u16,
+        dscp: Option<DscpTos>,
+        send_buffer_bytes: SocketBufferSize,
+        recv_buffer_bytes: SocketBufferSize,
+    ) -> Result<UdpSocket> {
+        udp::bind(port, dscp, send_buffer_bytes, recv_buffer_bytes)
+    }
+
+    // The QUIC endpoint of the server, created from the socket returned by bind_for_client(). It
+    // must be created before the stream config is sent, which carries its certificate
+    pub fn listen_for_client(local_socket: UdpSocket) -> Result<QuicListener> {
+        quic::bind(local_socket)
     }
 
+    // local_socket is used by UDP instead of binding the stream port. QUIC waits for the client to
+    // connect to quic_listener instead
     #[allow(clippy::too_many_arguments)]
     pub fn connect_to_client(
         timeout: Duration,
         client_ip: IpAddr,
         port: u16,
+        local_socket: Option<UdpSocket>,
         protocol: SocketProtocol,
+        quic_listener: Option<QuicListener>,
         dscp: Option<DscpTos>,
         send_buffer_bytes: SocketBufferSize,
         recv_buffer_bytes: SocketBufferSize,
         max_packet_size: usize,
+        forward_error_correction: Option<ForwardErrorCorrectionConfig>,
+        retransmission_deadline: Option<Duration>,
+        encryption_keys: Option<EncryptionKeys>,
     ) -> ConResult<StreamSocket> {
-        let (send_socket, receive_socket): (Box<dyn SocketWriter>, Box<dyn SocketReader>) =
-            match protocol {
-                SocketProtocol::Udp => {
-                    let socket =
-                        udp::bind(port, dscp, send_buffer_bytes, recv_buffer_bytes).to_con()?;
-                    let (send_socket, receive_socket) =
-                        udp::connect(&socket, client_ip, port, timeout).to_con()?;
+        let (send_socket, receive_socket, control_stream): (
+            Box<dyn SocketWriter>,
+            Box<dyn SocketReader>,
+            Option<ControlStream>,
+        ) = match protocol {
+            SocketProtocol::Udp => {
+                let socket = match local_socket {
+                    Some(socket) => socket,
+                    None => udp::bind(port, dscp, send_buffer_bytes, recv_buffer_bytes).to_con()?,
+                };
+                let (send_socket, receive_socket) =
+                    udp::connect(&socket, client_ip, port, timeout).to_con()?;
+
+                (Box::new(send_socket), Box::new(receive_socket), None)
+            }
+            SocketProtocol::Tcp => {
+                let (send_socket, receive_socket) = tcp::connect_to_client(
+                    timeout,
+                    &[client_ip],
+                    port,
+                    send_buffer_bytes,
+                    recv_buffer_bytes,
+                )?;
 
-                    (Box::new(send_socket), Box::new(receive_socket))
-                }
-                SocketProtocol::Tcp => {
-                    let (send_socket, receive_socket) = tcp::connect_to_client(
-                        timeout,
-                        &[client_ip],
-                        port,
-                        send_buffer_bytes,
-                        recv_buffer_bytes,
-                    )?;
-
-                    (Box::new(send_socket), Box::new(receive_socket))
-                }
-            };
+                (Box::new(send_socket), Box::new(receive_socket), None)
+            }
+            SocketProtocol::Quic => {
+                let Some(listener) = quic_listener else {
+                    con_bail!("The QUIC endpoint has not been created");
+                };
+
+                let sockets = quic::accept_from_client(listener, client_ip, timeout)?;
+
+                (
+                    Box::new(sockets.writer),
+                    Box::new(sockets.reader),
+                    Some((
+                        Box::new(sockets.control_writer),
+                        Box::new(sockets.control_reader),
+                    )),
+                )
+            }
+        };
 
-        Ok(StreamSocket {
-            max_packet_size,
-            send_socket: Arc::new(Mutex::new(send_socket)),
+        let mut socket = StreamSocket::new(
+            send_socket,
             receive_socket,
-            shard_recv_state: None,
-            stream_recv_components: HashMap::new(),
-        })
+            protocol,
+            max_packet_size,
+            forward_error_correction,
+            retransmission_deadline,
+            encryption_keys,
+        );
+        socket.control_stream = control_stream;
+
+        Ok(socket)
     }
 }
 
@@ -367,7 +682,27 @@ struct RecvState {
 
 struct InProgressPacket {
     buffer: Vec<u8>,
+    shards_count: usize,
     received_shard_indices: HashSet<usize>,
+    // All data shards before this index have been either received or requested again
+    nack_cursor: usize,
+    // Parity shards are stored whole (prefix included) at a fixed stride, indexed by group
+    parity_buffer: Vec<u8>,
+    received_parity_indices: HashSet<usize>,
+}
+
+impl InProgressPacket {
+    fn new(buffer: Vec<u8>, shards_count: usize) -> Self {
+        Self {
+            buffer,
+            shards_count,
+            // todo: find a way to skipping this allocation
+            received_shard_indices: HashSet::with_capacity(shards_count),
+            nack_cursor: 0,
+            parity_buffer: vec![],
+            received_parity_indices: HashSet::new(),
+        }
+    }
 }
 
 struct StreamRecvComponents {
@@ -376,19 +711,261 @@ struct StreamRecvComponents {
     packet_queue: mpsc::Sender<ReconstructedPacket>,
     in_progress_packets: HashMap<u32, InProgressPacket>,
     discarded_shards_sink: InProgressPacket,
+    last_completed_packet_index: Option<u32>,
+    last_discarded_packet_index: Option<u32>,
+    last_packet_arrival: Option<Instant>,
+    last_packet_interval: Duration,
+    statistics: Arc<Mutex<StreamStatistics>>,
+    max_shards_count: usize,
+    // Memory reserved from MAX_RECEIVE_BUFFERS_SIZE
+    reserved_size: usize,
+}
+
+impl StreamRecvComponents {
+    // Called when the first shard of a packet is received
+    fn report_packet_arrival(&mut self) {
+        let now = Instant::now();
+
+        if let Some(last_arrival) = self.last_packet_arrival {
+            let interval = now.saturating_duration_since(last_arrival);
+            let variation = interval.abs_diff(self.last_packet_interval);
+            self.last_packet_interval = interval;
+
+            let mut statistics = self.statistics.lock();
+            statistics.jitter = statistics.jitter.mul_f32(1.0 - JITTER_SMOOTHING_FACTOR)
+                + variation.mul_f32(JITTER_SMOOTHING_FACTOR);
+        }
+
+        self.last_packet_arrival = Some(now);
+    }
+}
+
+// Rebuild the only missing data shard of a group, if any, using the group parity shard.
+// Returns the index of the recovered shard.
+fn recover_shard_from_parity(
+    packet: &mut InProgressPacket,
+    group_index: usize,
+    group_size: usize,
+    shards_count: usize,
+    max_packet_size: usize,
+) -> Option<usize> {
+    if !packet.received_parity_indices.contains(&group_index) {
+        return None;
+    }
+
+    let group_range =
+        group_index * group_size..usize::min((group_index + 1) * group_size, shards_count);
+    let mut missing_indices = group_range
+        .clone()
+        .filter(|idx| !packet.received_shard_indices.contains(idx));
+    let missing_index = missing_indices.next()?;
+    if missing_indices.next().is_some() {
+        return None;
+    }
+
+    let max_shard_data_size = max_packet_size - SHARD_PREFIX_SIZE;
+    let parity_shard =
+        &packet.parity_buffer[group_index * (max_packet_size + PARITY_HEADER_SIZE)..];
+    let parity_length = u32::from_le_bytes(parity_shard[0..4].try_into().unwrap()) as usize;
+    if parity_length < SHARD_PREFIX_SIZE + PARITY_HEADER_SIZE {
+        return None;
+    }
+    let packet_buffer_size = u32::from_le_bytes(
+        parity_shard[SHARD_PREFIX_SIZE..SHARD_PREFIX_SIZE + PARITY_HEADER_SIZE]
+            .try_into()
+            .unwrap(),
+    ) as usize;
+    let parity_data = &parity_shard[SHARD_PREFIX_SIZE + PARITY_HEADER_SIZE..parity_length];
+
+    let shard_data_size = |idx: usize| {
+        usize::min(
+            max_packet_size,
+            packet_buffer_size.saturating_sub(idx * max_shard_data_size),
+        )
+        .saturating_sub(SHARD_PREFIX_SIZE)
+    };
+
+    let recovered_data_size = shard_data_size(missing_index);
+    if recovered_data_size > parity_data.len() {
+        return None;
+    }
+
+    let mut recovered_data = parity_data[..recovered_data_size].to_vec();
+    for idx in group_range.filter(|idx| *idx != missing_index) {
+        let data_start = idx * max_shard_data_size + SHARD_PREFIX_SIZE;
+        let shard_data = packet
+            .buffer
+            .get(data_start..data_start + shard_data_size(idx))?;
+        for (recovered, data) in recovered_data.iter_mut().zip(shard_data) {
+            *recovered ^= data;
+        }
+    }
+
+    let data_start = missing_index * max_shard_data_size + SHARD_PREFIX_SIZE;
+    if packet.buffer.len() < data_start + recovered_data_size {
+        packet.buffer.resize(data_start + recovered_data_size, 0);
+    }
+    packet.buffer[data_start..data_start + recovered_data_size].copy_from_slice(&recovered_data);
+
+    Some(missing_index)
+}
+
+// Ask the peer to send again the specified shards. The indices are split into multiple NACK shards
+// if they don't fit into one.
+fn send_nack(
+    socket: &Mutex<Box<dyn SocketWriter>>,
+    max_packet_size: usize,
+    stream_id: u16,
+    packet_index: u32,
+    missing_shard_indices: &[usize],
+) -> Result<()> {
+    let max_indices_per_shard =
+        (max_packet_size - SHARD_PREFIX_SIZE - NACK_HEADER_SIZE) / mem::size_of::<u32>();
+
+    let mut buffer = Vec::with_capacity(max_packet_size);
+    for shard_indices in missing_shard_indices.chunks(max_indices_per_shard) {
+        let shard_length =
+            SHARD_PREFIX_SIZE + NACK_HEADER_SIZE + shard_indices.len() * mem::size_of::<u32>();
+
+        buffer.clear();
+        buffer.extend_from_slice(&(shard_length as u32).to_le_bytes());
+        buffer.extend_from_slice(&NACK_STREAM_ID.to_le_bytes());
+        buffer.extend_from_slice(&packet_index.to_le_bytes());
+        buffer.extend_from_slice(&1_u32.to_le_bytes()); // shards count
+        buffer.extend_from_slice(&0_u32.to_le_bytes()); // shard index
+        buffer.extend_from_slice(&stream_id.to_le_bytes());
+        for idx in shard_indices {
+            buffer.extend_from_slice(&(*idx as u32).to_le_bytes());
+        }
+
+        socket.lock().send(&buffer)?;
+    }
+
+    Ok(())
 }
 
 // Note: used buffers don't *have* to be split by stream ID, but doing so improves memory usage
-// todo: impose cap on number of created buffers to avoid OOM crashes
+// Note: buffers are only created by subscribe_to_stream(), and their size is bounded by the shard
+// prefix validation, so each stream uses at most the memory it reserved
 pub struct StreamSocket {
     max_packet_size: usize,
+    fec_group_size: Option<usize>,
+    retransmission_history: Option<Arc<Mutex<RetransmissionHistory>>>,
+    nack_buffer: Vec<u8>,
     send_socket: Arc<Mutex<Box<dyn SocketWriter>>>,
     receive_socket: Box<dyn SocketReader>,
     shard_recv_state: Option<RecvState>,
     stream_recv_components: HashMap<u16, StreamRecvComponents>,
+    reserved_receive_size: usize,
+    control_stream: Option<ControlStream>,
 }
 
 impl StreamSocket {
+    pub(crate) fn new(
+        send_socket: Box<dyn SocketWriter>,
+        receive_socket: Box<dyn SocketReader>,
+        protocol: SocketProtocol,
+        max_packet_size: usize,
+        forward_error_correction: Option<ForwardErrorCorrectionConfig>,
+        retransmission_deadline: Option<Duration>,
+        encryption_keys: Option<EncryptionKeys>,
+    ) -> Self {
+        let (forward_error_correction, retransmission_deadline, encryption_keys, max_packet_size) =
+            match protocol {
+                SocketProtocol::Udp => (
+                    forward_error_correction,
+                    retransmission_deadline,
+                    encryption_keys,
+                    max_packet_size,
+                ),
+                // TCP never loses shards
+                SocketProtocol::Tcp => (None, None, encryption_keys, max_packet_size),
+                // QUIC already encrypts the shards, and the peer certificate is pinned. Shards must
+                // fit in one datagram
+                SocketProtocol::Quic => {
+                    if max_packet_size > quic::MAX_PACKET_SIZE {
+                        warn!(
+                            "Packet size {max_packet_size} is too large for QUIC, using {}",
+                            quic::MAX_PACKET_SIZE
+                        );
+                    }
+
+                    (
+                        forward_error_correction,
+                        retransmission_deadline,
+                        None,
+                        usize::min(max_packet_size, quic::MAX_PACKET_SIZE),
+                    )
+                }
+            };
+
+        let fec_group_size = forward_error_correction
+            .map(|config| usize::max(config.data_shards_per_parity_shard as usize, 1));
+
+        // Make room for the parity header, so parity shards are not bigger than data shards
+        let max_packet_size = if fec_group_size.is_some() {
+            max_packet_size - PARITY_HEADER_SIZE
+        } else {
+            max_packet_size
+        };
+
+        // Make room for the authentication tag and nonce, so sealed shards still fit in one packet
+        let (send_socket, receive_socket, max_packet_size): (
+            Box<dyn SocketWriter>,
+            Box<dyn SocketReader>,
+            _,
+        ) = if let Some(keys) = encryption_keys {
+            (
+                Box::new(EncryptedSocketWriter::new(
+                    send_socket,
+                    &keys.send,
+                    SHARD_PREFIX_SIZE,
+                )),
+                Box::new(DecryptedSocketReader::new(
+                    receive_socket,
+                    &keys.recv,
+                    SHARD_PREFIX_SIZE,
+                    // Parity shards are the longest
+                    max_packet_size - SHARD_ENCRYPTION_OVERHEAD + PARITY_HEADER_SIZE,
+                )),
+                max_packet_size - SHARD_ENCRYPTION_OVERHEAD,
+            )
+        } else {
+            (send_socket, receive_socket, max_packet_size)
+        };
+
+        Self {
+            max_packet_size,
+            fec_group_size,
+            retransmission_history: retransmission_deadline.map(|deadline| {
+                Arc::new(Mutex::new(RetransmissionHistory {
+                    deadline,
+                    shards: HashMap::new(),
+                }))
+            }),
+            nack_buffer: vec![],
+            send_socket: Arc::new(Mutex::new(send_socket)),
+            receive_socket,
+            shard_recv_state: None,
+            stream_recv_components: HashMap::new(),
+            reserved_receive_size: 0,
+            control_stream: None,
+        }
+    }
+
+    // Moves the control packets to the reliable stream of the connection, if the protocol provides
+    // one. Both peers must call this right after connecting, before sending any other control packet
+    pub fn carry_control<S, R>(
+        &mut self,
+        control_sender: &mut ControlSocketSender<S>,
+        control_receiver: &mut ControlSocketReceiver<R>,
+    ) {
+        if let Some((writer, reader)) = self.control_stream.take() {
+            control_sender.replace_socket(writer);
+            control_receiver.replace_socket(reader);
+        }
+    }
+
     pub fn request_stream<T>(&self, stream_id: u16) -> StreamSender<T> {
         StreamSender {
             inner: Arc::clone(&self.send_socket),
@@ -396,20 +973,54 @@ impl StreamSocket {
             max_packet_size: self.max_packet_size,
             next_packet_index: 0,
             used_buffers: vec![],
+            reliable: false,
+            fec_group_size: self.fec_group_size,
+            parity_buffer: vec![],
+            retransmission_history: self.retransmission_history.clone(),
+            tap: None,
             _phantom: PhantomData,
         }
     }
 
+    // Packets of this stream are never lost with the backends that support reliable delivery
+    // (QUIC), at the cost of head-of-line blocking. Other backends behave as request_stream().
+    pub fn request_reliable_stream<T>(&self, stream_id: u16) -> StreamSender<T> {
+        StreamSender {
+            reliable: true,
+            ..self.request_stream(stream_id)
+        }
+    }
+
     // max_concurrent_buffers: number of buffers allocated by this call which will be reused to
     // receive packets for this stream ID. If packets are not read fast enough, the shards received
     // for this particular stream will be discarded
+    // max_received_packet_size: upper bound for the size of the packets of this stream, including
+    // the header. Bigger packets are dropped. Fails if the buffers would exceed the memory budget of
+    // the socket
     pub fn subscribe_to_stream<T>(
         &mut self,
         stream_id: u16,
         max_concurrent_buffers: usize,
-    ) -> StreamReceiver<T> {
+        max_received_packet_size: usize,
+    ) -> Result<StreamReceiver<T>> {
+        let previous_reserved_size = self
+            .stream_recv_components
+            .get(&stream_id)
+            .map_or(0, |components| components.reserved_size);
+        let reserved_size = max_concurrent_buffers * max_received_packet_size;
+        let total_reserved_size =
+            self.reserved_receive_size - previous_reserved_size + reserved_size;
+        if total_reserved_size > MAX_RECEIVE_BUFFERS_SIZE {
+            bail!(
+                "Buffers of stream {stream_id} exceed the receive memory budget: \
+                {total_reserved_size} > {MAX_RECEIVE_BUFFERS_SIZE} bytes"
+            );
+        }
+        self.reserved_receive_size = total_reserved_size;
+
         let (packet_sender, packet_receiver) = mpsc::channel();
         let (used_buffer_sender, used_buffer_receiver) = mpsc::channel();
+        let statistics = Arc::new(Mutex::new(StreamStatistics::default()));
 
         for _ in 0..max_concurrent_buffers {
             used_buffer_sender.send(vec![]).ok();
@@ -422,19 +1033,26 @@ impl StreamSocket {
                 used_buffer_receiver,
                 packet_queue: packet_sender,
                 in_progress_packets: HashMap::new(),
-                discarded_shards_sink: InProgressPacket {
-                    buffer: vec![],
-                    received_shard_indices: HashSet::new(),
-                },
+                discarded_shards_sink: InProgressPacket::new(vec![], 0),
+                last_completed_packet_index: None,
+                last_discarded_packet_index: None,
+                last_packet_arrival: None,
+                last_packet_interval: Duration::ZERO,
+                statistics: Arc::clone(&statistics),
+                max_shards_count: max_received_packet_size
+                    .div_ceil(self.max_packet_size - SHARD_PREFIX_SIZE),
+                reserved_size,
             },
         );
 
-        StreamReceiver {
+        Ok(StreamReceiver {
             packet_receiver,
             used_buffer_queue: used_buffer_sender,
             _phantom: PhantomData,
             last_packet_index: None,
-        }
+            statistics,
+            tap: None,
+        })
     }
 
     pub fn recv(&mut self) -> ConResult {
@@ -453,6 +1071,44 @@ impl StreamSocket {
             let shards_count = u32::from_le_bytes(bytes[10..14].try_into().unwrap()) as usize;
             let shard_index = u32::from_le_bytes(bytes[14..18].try_into().unwrap()) as usize;
 
+            // Never trust the prefix: these values are used to allocate and index the buffers. The
+            // shard of a stream without subscription is left in the socket, it is validated against
+            // the limits of the stream once subscribed
+            let max_shards_count = if stream_id == NACK_STREAM_ID {
+                1
+            } else if let Some(components) = self.stream_recv_components.get(&stream_id) {
+                components.max_shards_count
+            } else {
+                debug!("Received packet from stream {stream_id} before subscribing!");
+
+                return alvr_common::try_again();
+            };
+            let parity_shards_count = self
+                .fec_group_size
+                .map(|group_size| shards_count.div_ceil(group_size))
+                .unwrap_or(0);
+            let max_shard_length = if shard_index < shards_count {
+                self.max_packet_size
+            } else {
+                self.max_packet_size + PARITY_HEADER_SIZE
+            };
+            if !(SHARD_PREFIX_SIZE..=max_shard_length).contains(&shard_length)
+                || !(1..=max_shards_count).contains(&shards_count)
+                || shard_index >= shards_count + parity_shards_count
+            {
+                debug!(
+                    "Dropping malformed shard: stream {stream_id}, length {shard_length}, index \
+                    {shard_index}/{shards_count}"
+                );
+                if let Some(components) = self.stream_recv_components.get(&stream_id) {
+                    components.statistics.lock().shards_malformed += 1;
+                }
+
+                self.receive_socket.discard_shard()?;
+
+                return alvr_common::try_again();
+            }
+
             self.shard_recv_state.insert(RecvState {
                 shard_length,
                 stream_id,
@@ -465,17 +1121,56 @@ impl StreamSocket {
             })
         };
 
+        if shard_recv_state_mut.stream_id == NACK_STREAM_ID {
+            let shard_length = shard_recv_state_mut.shard_length;
+            let packet_index = shard_recv_state_mut.packet_index;
+
+            self.nack_buffer.resize(shard_length, 0);
+            while shard_recv_state_mut.packet_cursor < shard_length {
+                shard_recv_state_mut.packet_cursor += self.receive_socket.recv(
+                    &mut self.nack_buffer[shard_recv_state_mut.packet_cursor..shard_length],
+                )?;
+            }
+            self.shard_recv_state = None;
+
+            if let Some(history) = &self.retransmission_history
+                && shard_length >= SHARD_PREFIX_SIZE + NACK_HEADER_SIZE
+            {
+                let payload = &self.nack_buffer[SHARD_PREFIX_SIZE..];
+                let stream_id = u16::from_le_bytes(payload[0..2].try_into().unwrap());
+
+                let shard_indices_bytes =
+                    payload[NACK_HEADER_SIZE..].chunks_exact(mem::size_of::<u32>());
+
+                let history = history.lock();
+                for index_bytes in shard_indices_bytes {
+                    let shard_index = u32::from_le_bytes(index_bytes.try_into().unwrap());
+
+                    // Shards past the deadline are not found. Retransmitting them would be useless
+                    if let Some(shard) = history.get(stream_id, packet_index, shard_index) {
+                        self.send_socket.lock().send(shard).ok();
+                    }
+                }
+            }
+
+            return Ok(());
+        }
+
+        // Checked when the prefix was read
         let Some(components) = self
             .stream_recv_components
             .get_mut(&shard_recv_state_mut.stream_id)
         else {
-            debug!(
-                "Received packet from stream {} before subscribing!",
-                shard_recv_state_mut.stream_id
-            );
             return alvr_common::try_again();
         };
 
+        // Shards of packets that have already been delivered are not needed anymore. This happens
+        // with parity shards of packets that did not lose any data shard.
+        let is_stale_packet = components.last_completed_packet_index.is_some_and(|idx| {
+            wrapping_cmp(shard_recv_state_mut.packet_index, idx) != Ordering::Greater
+        });
+
+        let mut is_new_packet = false;
         let in_progress_packet = if shard_recv_state_mut.should_discard {
             &mut components.discarded_shards_sink
         } else if let Some(packet) = components
@@ -483,27 +1178,28 @@ impl StreamSocket {
             .get_mut(&shard_recv_state_mut.packet_index)
         {
             packet
-        } else if let Some(mut buffer) =
-            components.used_buffer_receiver.try_recv().ok().or_else(|| {
-                // By default, try to dequeue a used buffer. In case none were found, recycle one of the
-                // in progress packets, chances are these buffers are "dead" because one of their shards
-                // has been dropped by the network.
-                let idx = *components.in_progress_packets.iter().next()?.0;
-                Some(components.in_progress_packets.remove(&idx).unwrap().buffer)
+        } else if let Some(mut buffer) = (!is_stale_packet)
+            .then(|| {
+                components.used_buffer_receiver.try_recv().ok().or_else(|| {
+                    // By default, try to dequeue a used buffer. In case none were found, recycle
+                    // one of the in progress packets, chances are these buffers are "dead" because
+                    // one of their shards has been dropped by the network.
+                    let idx = *components.in_progress_packets.iter().next()?.0;
+                    components.statistics.lock().packets_discarded += 1;
+
+                    Some(components.in_progress_packets.remove(&idx).unwrap().buffer)
+                })
             })
+            .flatten()
         {
             buffer.clear();
+            is_new_packet = true;
+            components.report_packet_arrival();
 
             // NB: Can't use entry pattern because we want to allow bailing out on the line above
             components.in_progress_packets.insert(
                 shard_recv_state_mut.packet_index,
-                InProgressPacket {
-                    buffer,
-                    // todo: find a way to skipping this allocation
-                    received_shard_indices: HashSet::with_capacity(
-                        shard_recv_state_mut.shards_count,
-                    ),
-                },
+                InProgressPacket::new(buffer, shard_recv_state_mut.shards_count),
             );
             components
                 .in_progress_packets
@@ -511,6 +1207,13 @@ impl StreamSocket {
                 .unwrap()
         } else {
             // This branch may be hit in case the thread related to the stream hangs for some reason
+            if !is_stale_packet
+                && components.last_discarded_packet_index != Some(shard_recv_state_mut.packet_index)
+            {
+                components.last_discarded_packet_index = Some(shard_recv_state_mut.packet_index);
+                components.statistics.lock().packets_discarded += 1;
+            }
+
             shard_recv_state_mut.should_discard = true;
             shard_recv_state_mut.packet_cursor = 0; // reset cursor from old shards
             // always write at the start of the packet so the buffer doesn't grow much
@@ -520,25 +1223,44 @@ impl StreamSocket {
         };
 
         let max_shard_data_size = self.max_packet_size - SHARD_PREFIX_SIZE;
-        // Note: there is no prefix offset, since we want to write the prefix too.
-        let packet_start_index = shard_recv_state_mut.shard_index * max_shard_data_size;
 
-        // Prepare buffer to accomodate receiving shard
-        {
-            // Note: this contains the prefix offset
-            let size = packet_start_index + shard_recv_state_mut.shard_length;
+        let parity_index = shard_recv_state_mut
+            .shard_index
+            .checked_sub(shard_recv_state_mut.shards_count)
+            .filter(|_| self.fec_group_size.is_some());
 
-            if in_progress_packet.buffer.len() < size {
-                in_progress_packet.buffer.resize(size, 0);
+        let sub_buffer = if let Some(parity_index) = parity_index {
+            // Parity shards are not contiguous with the data, they are stored whole
+            let parity_start_index = parity_index * (self.max_packet_size + PARITY_HEADER_SIZE);
+            let size = parity_start_index + shard_recv_state_mut.shard_length;
+
+            if in_progress_packet.parity_buffer.len() < size {
+                in_progress_packet.parity_buffer.resize(size, 0);
             }
-        }
 
-        let sub_buffer = &mut in_progress_packet.buffer[packet_start_index..];
+            &mut in_progress_packet.parity_buffer[parity_start_index..]
+        } else {
+            // Note: there is no prefix offset, since we want to write the prefix too.
+            let packet_start_index = shard_recv_state_mut.shard_index * max_shard_data_size;
+
+            // Prepare buffer to accomodate receiving shard
+            {
+                // Note: this contains the prefix offset
+                let size = packet_start_index + shard_recv_state_mut.shard_length;
+
+                if in_progress_packet.buffer.len() < size {
+                    in_progress_packet.buffer.resize(size, 0);
+                }
+            }
+
+            &mut in_progress_packet.buffer[packet_start_index..]
+        };
 
         // Read shard into the single contiguous buffer
         {
             // Backup the small section of bytes that will be overwritten by reading from socket.
-            if shard_recv_state_mut.overwritten_data_backup.is_none() {
+            // Parity shards don't overlap with other shards, and their prefix is needed later.
+            if parity_index.is_none() && shard_recv_state_mut.overwritten_data_backup.is_none() {
                 shard_recv_state_mut.overwritten_data_backup =
                     Some(sub_buffer[..SHARD_PREFIX_SIZE].try_into().unwrap())
             }
@@ -554,19 +1276,72 @@ impl StreamSocket {
             }
 
             // Restore backed up bytes
-            // Safety: overwritten_data_backup is always set just before receiving the packet
-            sub_buffer[..SHARD_PREFIX_SIZE]
-                .copy_from_slice(&shard_recv_state_mut.overwritten_data_backup.take().unwrap());
+            if let Some(backup) = shard_recv_state_mut.overwritten_data_backup.take() {
+                sub_buffer[..SHARD_PREFIX_SIZE].copy_from_slice(&backup);
+            }
         }
 
         if !shard_recv_state_mut.should_discard {
-            in_progress_packet
-                .received_shard_indices
-                .insert(shard_recv_state_mut.shard_index);
+            components.statistics.lock().shards_received += 1;
+
+            let group_index = if let Some(parity_index) = parity_index {
+                in_progress_packet
+                    .received_parity_indices
+                    .insert(parity_index);
+
+                parity_index
+            } else {
+                in_progress_packet
+                    .received_shard_indices
+                    .insert(shard_recv_state_mut.shard_index);
+
+                // Shards are sent in order, a gap means that some shards have been lost (or
+                // reordered by the network)
+                if self.retransmission_history.is_some()
+                    && shard_recv_state_mut.shard_index >= in_progress_packet.nack_cursor
+                {
+                    let missing_shard_indices = (in_progress_packet.nack_cursor
+                        ..shard_recv_state_mut.shard_index)
+                        .filter(|idx| !in_progress_packet.received_shard_indices.contains(idx))
+                        .collect::<Vec<_>>();
+                    in_progress_packet.nack_cursor = shard_recv_state_mut.shard_index + 1;
+
+                    if !missing_shard_indices.is_empty() {
+                        send_nack(
+                            &self.send_socket,
+                            self.max_packet_size,
+                            shard_recv_state_mut.stream_id,
+                            shard_recv_state_mut.packet_index,
+                            &missing_shard_indices,
+                        )
+                        .ok();
+                    }
+                }
+
+                shard_recv_state_mut.shard_index / self.fec_group_size.unwrap_or(1)
+            };
+
+            if let Some(group_size) = self.fec_group_size
+                && in_progress_packet.received_shard_indices.len()
+                    < shard_recv_state_mut.shards_count
+                && let Some(recovered_index) = recover_shard_from_parity(
+                    in_progress_packet,
+                    group_index,
+                    group_size,
+                    shard_recv_state_mut.shards_count,
+                    self.max_packet_size,
+                )
+            {
+                in_progress_packet
+                    .received_shard_indices
+                    .insert(recovered_index);
+            }
         }
 
         // Check if packet is complete and send
-        if in_progress_packet.received_shard_indices.len() == shard_recv_state_mut.shards_count {
+        if !shard_recv_state_mut.should_discard
+            && in_progress_packet.received_shard_indices.len() == shard_recv_state_mut.shards_count
+        {
             components
                 .packet_queue
                 .send(ReconstructedPacket {
@@ -579,6 +1354,9 @@ impl StreamSocket {
                 })
                 .ok();
 
+            components.last_completed_packet_index = Some(shard_recv_state_mut.packet_index);
+            components.statistics.lock().packets_reconstructed += 1;
+
             // Keep only shards with later packet index (using wrapping logic)
             while let Some((idx, _)) = components.in_progress_packets.iter().find(|(idx, _)| {
                 wrapping_cmp(**idx, shard_recv_state_mut.packet_index) == Ordering::Less
@@ -586,14 +1364,630 @@ impl StreamSocket {
                 let idx = *idx; // fix borrow rule
                 let packet = components.in_progress_packets.remove(&idx).unwrap();
 
+                components.statistics.lock().shards_lost += packet
+                    .shards_count
+                    .saturating_sub(packet.received_shard_indices.len())
+                    as u64;
+
                 // Recycle buffer
                 components.used_buffer_sender.send(packet.buffer).ok();
             }
         }
 
+        // A shard of a new packet means that the previous packets of the same stream have been sent
+        // whole. Request their trailing shards which never arrived.
+        if is_new_packet && self.retransmission_history.is_some() {
+            for (idx, packet) in components.in_progress_packets.iter_mut() {
+                if wrapping_cmp(*idx, shard_recv_state_mut.packet_index) == Ordering::Less
+                    && packet.nack_cursor < packet.shards_count
+                {
+                    let missing_shard_indices = (packet.nack_cursor..packet.shards_count)
+                        .filter(|shard_idx| !packet.received_shard_indices.contains(shard_idx))
+                        .collect::<Vec<_>>();
+                    packet.nack_cursor = packet.shards_count;
+
+                    if !missing_shard_indices.is_empty() {
+                        send_nack(
+                            &self.send_socket,
+                            self.max_packet_size,
+                            shard_recv_state_mut.stream_id,
+                            *idx,
+                            &missing_shard_indices,
+                        )
+                        .ok();
+                    }
+                }
+            }
+        }
+
         // Mark current shard as read and allow for a new shard to be read
         self.shard_recv_state = None;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::memory::{Framing, LinkEvent, MemoryLink};
+    use proptest::{prelude::*, sample::Index};
+
+    const STREAM_ID: u16 = 0;
+    const MAX_RECEIVED_PACKET_SIZE: usize = 64 * 1024;
+    // Upper bound for the recv() calls needed to process the shards in flight
+    const MAX_PUMP_ITERATIONS: usize = 100_000;
+
+    // The header is the index of the packet in the test sequence, independent of the packet index
+    // in the shard prefix
+    struct Peers {
+        forward_link: MemoryLink,
+        backward_link: MemoryLink,
+        sender_socket: StreamSocket,
+        receiver_socket: StreamSocket,
+        sender: StreamSender<u32>,
+        receiver: StreamReceiver<u32>,
+    }
+
+    impl Peers {
+        fn new(
+            max_packet_size: usize,
+            fec_group_size: Option<u32>,
+            retransmission: bool,
+            max_concurrent_buffers: usize,
+        ) -> Self {
+            let forward_link = MemoryLink::new(Framing::Datagram);
+            let backward_link = MemoryLink::new(Framing::Datagram);
+
+            let socket = |writer_link: &MemoryLink, reader_link: &MemoryLink| {
+                StreamSocket::new(
+                    Box::new(writer_link.writer()),
+                    Box::new(reader_link.reader()),
+                    SocketProtocol::Udp,
+                    max_packet_size,
+                    fec_group_size.map(|group_size| ForwardErrorCorrectionConfig {
+                        data_shards_per_parity_shard: group_size,
+                    }),
+                    retransmission.then_some(Duration::from_secs(60)),
+                    None,
+                )
+            };
+
+            let sender_socket = socket(&forward_link, &backward_link);
+            let mut receiver_socket = socket(&backward_link, &forward_link);
+
+            let sender = sender_socket.request_stream(STREAM_ID);
+            let receiver = receiver_socket
+                .subscribe_to_stream(STREAM_ID, max_concurrent_buffers, MAX_RECEIVED_PACKET_SIZE)
+                .unwrap();
+
+            Self {
+                forward_link,
+                backward_link,
+                sender_socket,
+                receiver_socket,
+                sender,
+                receiver,
+            }
+        }
+
+        fn send(&mut self, sequence_index: u32, payload: &[u8]) {
+            let mut buffer = self.sender.get_buffer(&sequence_index).unwrap();
+            buffer
+                .get_range_mut(0, payload.len())
+                .copy_from_slice(payload);
+            self.sender.send(buffer).unwrap();
+        }
+
+        // Process the shards in flight in both directions, including NACKs and retransmissions
+        fn pump(&mut self) {
+            for _ in 0..MAX_PUMP_ITERATIONS {
+                if self.forward_link.pending_count() > 0 {
+                    self.receiver_socket.recv().ok();
+                } else if self.backward_link.pending_count() > 0 {
+                    self.sender_socket.recv().ok();
+                } else {
+                    return;
+                }
+            }
+
+            panic!("Shards are not consumed");
+        }
+
+        // Returns: sequence index, payload, had packet loss
+        fn received(&mut self) -> Vec<(u32, Vec<u8>, bool)> {
+            let mut packets = vec![];
+            while let Ok(data) = self.receiver.recv(Duration::ZERO) {
+                let (sequence_index, payload) = data.get().unwrap();
+                packets.push((sequence_index, payload.to_vec(), data.had_packet_loss()));
+            }
+
+            packets
+        }
+    }
+
+    fn packets_strategy() -> impl Strategy<Value = Vec<Vec<u8>>> {
+        prop::collection::vec(prop::collection::vec(any::<u8>(), 0..3000), 1..16)
+    }
+
+    fn fec_strategy() -> impl Strategy<Value = Option<u32>> {
+        prop::option::of(1_u32..8)
+    }
+
+    fn link_event_strategy() -> impl Strategy<Value = LinkEvent> {
+        prop_oneof![
+            6 => Just(LinkEvent::Deliver),
+            1 => Just(LinkEvent::Drop),
+            1 => Just(LinkEvent::Duplicate),
+            1 => (1_usize..8).prop_map(LinkEvent::Delay),
+        ]
+    }
+
+    // Delivered packets must be intact, in order, and flagged when preceded by lost packets
+    fn check_delivery(sent: &[Vec<u8>], received: &[(u32, Vec<u8>, bool)]) {
+        let mut last_sequence_index = None;
+        for (sequence_index, payload, had_packet_loss) in received {
+            assert_eq!(payload, &sent[*sequence_index as usize]);
+
+            if let Some(last_idx) = last_sequence_index {
+                assert!(*sequence_index > last_idx);
+                assert_eq!(*had_packet_loss, *sequence_index != last_idx + 1);
+            } else {
+                assert!(!had_packet_loss);
+            }
+            last_sequence_index = Some(*sequence_index);
+        }
+    }
+
+    #[test]
+    fn test_wrapping_cmp() {
+        assert_eq!(wrapping_cmp(5, 5), Ordering::Equal);
+        assert_eq!(wrapping_cmp(0, u32::MAX), Ordering::Greater);
+        assert_eq!(wrapping_cmp(u32::MAX, 0), Ordering::Less);
+    }
+
+    #[test]
+    fn test_malformed_shards_are_dropped() {
+        let mut peers = Peers::new(1400, None, false, 2);
+
+        let prefix = |length: u32, shards_count: u32, shard_index: u32| {
+            let mut shard = vec![0; SHARD_PREFIX_SIZE];
+            shard[0..4].copy_from_slice(&length.to_le_bytes());
+            shard[4..6].copy_from_slice(&STREAM_ID.to_le_bytes());
+            shard[10..14].copy_from_slice(&shards_count.to_le_bytes());
+            shard[14..18].copy_from_slice(&shard_index.to_le_bytes());
+            shard
+        };
+        peers.forward_link.inject(&prefix(u32::MAX, 1, 0));
+        peers
+            .forward_link
+            .inject(&prefix(SHARD_PREFIX_SIZE as u32, u32::MAX, 0));
+        peers
+            .forward_link
+            .inject(&prefix(SHARD_PREFIX_SIZE as u32, 1, 1));
+        peers.forward_link.inject(&[0; SHARD_PREFIX_SIZE - 1]);
+        peers.pump();
+
+        peers.send(0, &[1, 2, 3]);
+        peers.pump();
+
+        assert_eq!(peers.receiver.statistics().shards_malformed, 3);
+        assert_eq!(peers.received(), [(0, vec![1, 2, 3], false)]);
+    }
+
+    #[test]
+    fn test_packets_over_stream_limit_are_dropped() {
+        let mut peers = Peers::new(64, None, false, 2);
+        peers.receiver = peers
+            .receiver_socket
+            .subscribe_to_stream(STREAM_ID, 2, 200)
+            .unwrap();
+
+        peers.send(0, &[1, 2, 3]);
+        peers.send(1, &[0; 1000]);
+        peers.send(2, &[4, 5, 6]);
+        peers.pump();
+
+        assert!(peers.receiver.statistics().shards_malformed > 0);
+        assert_eq!(
+            peers.received(),
+            [(0, vec![1, 2, 3], false), (2, vec![4, 5, 6], true)]
+        );
+    }
+
+    #[test]
+    fn test_receive_memory_budget() {
+        let mut peers = Peers::new(1400, None, false, 2);
+
+        assert!(
+            peers
+                .receiver_socket
+                .subscribe_to_stream::<u32>(1, 2, MAX_RECEIVE_BUFFERS_SIZE)
+                .is_err()
+        );
+        peers
+            .receiver_socket
+            .subscribe_to_stream::<u32>(1, 1, MAX_RECEIVE_BUFFERS_SIZE / 2)
+            .unwrap();
+
+        // The reservation of a stream is replaced when subscribing again
+        peers
+            .receiver_socket
+            .subscribe_to_stream::<u32>(1, 1, MAX_RECEIVE_BUFFERS_SIZE / 2)
+            .unwrap();
+        assert!(
+            peers
+                .receiver_socket
+                .subscribe_to_stream::<u32>(2, 1, MAX_RECEIVE_BUFFERS_SIZE / 2)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_taps_see_payloads() {
+        let mut peers = Peers::new(64, None, false, 4);
+
+        let tap = |packets: &Arc<Mutex<Vec<Vec<u8>>>>| -> PacketTap {
+            let packets = Arc::clone(packets);
+            Arc::new(move |payload: &[u8]| packets.lock().push(payload.to_vec()))
+        };
+        let sent = Arc::new(Mutex::new(vec![]));
+        let received = Arc::new(Mutex::new(vec![]));
+        peers.sender.set_tap(Some(tap(&sent)));
+        peers.receiver.set_tap(Some(tap(&received)));
+
+        let payloads: [Vec<u8>; 2] = [vec![1, 2, 3], (0..200).collect()];
+        for (idx, payload) in payloads.iter().enumerate() {
+            peers.send(idx as u32, payload);
+        }
+        peers.pump();
+        peers.received();
+
+        assert_eq!(*sent.lock(), payloads);
+        assert_eq!(*received.lock(), payloads);
+    }
+
+    #[test]
+    fn test_failed_send_does_not_corrupt_next_packet() {
+        const MAX_PACKET_SIZE: usize = 64;
+        let mut peers = Peers::new(MAX_PACKET_SIZE, Some(4), false, 4);
+
+        // The second data shard of the first packet fails to be sent
+        peers
+            .forward_link
+            .push_events([LinkEvent::Deliver, LinkEvent::Fail]);
+        let mut buffer = peers.sender.get_buffer(&0).unwrap();
+        buffer.get_range_mut(0, 200).fill(0xAA);
+        assert!(peers.sender.send(buffer).is_err());
+        peers.forward_link.flush();
+        peers.pump();
+        peers.received();
+
+        // The first shard of the second packet is lost and must be recovered from a clean parity
+        peers.forward_link.push_events([LinkEvent::Drop]);
+        let payload = (0..200).map(|i| i as u8).collect::<Vec<_>>();
+        peers.send(1, &payload);
+        peers.pump();
+
+        assert_eq!(peers.received(), [(1, payload, false)]);
+    }
+
+    // The server connects to a client and a spectator at the same time, with a UDP socket each
+    #[test]
+    fn test_two_udp_clients_at_once() {
+        const MAX_PACKET_SIZE: usize = 1400;
+        let loopback = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+        let timeout = Duration::from_millis(100);
+
+        let mut peers = vec![];
+        for _ in 0..2 {
+            let client_builder = StreamSocketBuilder::listen_for_server(
+                timeout,
+                0,
+                SocketProtocol::Udp,
+                None,
+                SocketBufferSize::Default,
+                SocketBufferSize::Default,
+            )
+            .unwrap();
+            let StreamSocketBuilder::Udp(client_socket) = &client_builder else {
+                unreachable!()
+            };
+            let client_port = client_socket.local_addr().unwrap().port();
+
+            let server_local_socket = StreamSocketBuilder::bind_for_client(
+                0,
+                None,
+                SocketBufferSize::Default,
+                SocketBufferSize::Default,
+            )
+            .unwrap();
+            let server_port = server_local_socket.local_addr().unwrap().port();
+
+            let server_socket = StreamSocketBuilder::connect_to_client(
+                timeout,
+                loopback,
+                client_port,
+                Some(server_local_socket),
+                SocketProtocol::Udp,
+                None,
+                None,
+                SocketBufferSize::Default,
+                SocketBufferSize::Default,
+                MAX_PACKET_SIZE,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+            let mut client_socket = client_builder
+                .accept_from_server(
+                    loopback,
+                    server_port,
+                    None,
+                    MAX_PACKET_SIZE,
+                    None,
+                    None,
+                    None,
+                    timeout,
+                )
+                .unwrap();
+
+            let sender = server_socket.request_stream::<u32>(STREAM_ID);
+            let receiver = client_socket
+                .subscribe_to_stream::<u32>(STREAM_ID, 4, MAX_RECEIVED_PACKET_SIZE)
+                .unwrap();
+
+            peers.push((server_socket, client_socket, sender, receiver));
+        }
+
+        for (idx, (_, _, sender, _)) in peers.iter_mut().enumerate() {
+            let buffer = sender.get_buffer(&(idx as u32)).unwrap();
+            sender.send(buffer).unwrap();
+        }
+
+        // Each client receives only the packet sent to it
+        for (idx, (_, client_socket, _, receiver)) in peers.iter_mut().enumerate() {
+            let data = (0..10)
+                .find_map(|_| {
+                    client_socket.recv().ok();
+                    receiver.recv(Duration::ZERO).ok()
+                })
+                .unwrap();
+            assert_eq!(data.get().unwrap().0, idx as u32);
+        }
+    }
+
+    // The client connects to the QUIC endpoint of the server, pinning its certificate
+    #[test]
+    fn test_quic_client_connects_to_server() {
+        const MAX_PACKET_SIZE: usize = 1400;
+        let loopback = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+        let timeout = Duration::from_secs(5);
+
+        let server_local_socket = StreamSocketBuilder::bind_for_client(
+            0,
+            None,
+            SocketBufferSize::Default,
+            SocketBufferSize::Default,
+        )
+        .unwrap();
+        let server_port = server_local_socket.local_addr().unwrap().port();
+        let listener = StreamSocketBuilder::listen_for_client(server_local_socket).unwrap();
+        let certificate = listener.certificate().to_vec();
+
+        let client_thread = std::thread::spawn(move || {
+            StreamSocketBuilder::listen_for_server(
+                timeout,
+                0,
+                SocketProtocol::Quic,
+                None,
+                SocketBufferSize::Default,
+                SocketBufferSize::Default,
+            )
+            .unwrap()
+            .accept_from_server(
+                loopback,
+                server_port,
+                Some(certificate),
+                MAX_PACKET_SIZE,
+                None,
+                None,
+                None,
+                timeout,
+            )
+            .unwrap()
+        });
+        let server_socket = StreamSocketBuilder::connect_to_client(
+            timeout,
+            loopback,
+            0,
+            None,
+            SocketProtocol::Quic,
+            Some(listener),
+            None,
+            SocketBufferSize::Default,
+            SocketBufferSize::Default,
+            MAX_PACKET_SIZE,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let mut client_socket = client_thread.join().unwrap();
+
+        // Shards are shrunk to fit in one datagram
+        assert_eq!(server_socket.max_packet_size, quic::MAX_PACKET_SIZE);
+        assert!(server_socket.control_stream.is_some());
+        assert!(client_socket.control_stream.is_some());
+
+        let mut sender = server_socket.request_reliable_stream::<u32>(STREAM_ID);
+        let mut receiver = client_socket
+            .subscribe_to_stream::<u32>(STREAM_ID, 4, MAX_RECEIVED_PACKET_SIZE)
+            .unwrap();
+        let buffer = sender.get_buffer(&42).unwrap();
+        sender.send(buffer).unwrap();
+
+        let data = (0..10)
+            .find_map(|_| {
+                client_socket.recv().ok();
+                receiver.recv(Duration::ZERO).ok()
+            })
+            .unwrap();
+        assert_eq!(data.get().unwrap().0, 42);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_wrapping_cmp_antisymmetric(index: u32, distance in 1..u32::MAX / 2) {
+            prop_assert_eq!(wrapping_cmp(index.wrapping_add(distance), index), Ordering::Greater);
+            prop_assert_eq!(wrapping_cmp(index, index.wrapping_add(distance)), Ordering::Less);
+        }
+
+        #[test]
+        fn prop_lossless_link(
+            packets in packets_strategy(),
+            max_packet_size in 64_usize..1500,
+            fec_group_size in fec_strategy(),
+            retransmission: bool,
+            duplicated_shards in prop::collection::vec(any::<bool>(), 0..200),
+        ) {
+            let mut peers =
+                Peers::new(max_packet_size, fec_group_size, retransmission, packets.len() + 1);
+            peers.forward_link.push_events(duplicated_shards.into_iter().map(|duplicate| {
+                if duplicate {
+                    LinkEvent::Duplicate
+                } else {
+                    LinkEvent::Deliver
+                }
+            }));
+
+            for (idx, packet) in packets.iter().enumerate() {
+                peers.send(idx as u32, packet);
+            }
+            peers.pump();
+
+            let received = peers.received();
+            check_delivery(&packets, &received);
+            prop_assert_eq!(received.len(), packets.len());
+        }
+
+        #[test]
+        fn prop_impaired_link(
+            packets in packets_strategy(),
+            max_packet_size in 64_usize..1500,
+            fec_group_size in fec_strategy(),
+            retransmission: bool,
+            events in prop::collection::vec(link_event_strategy(), 0..500),
+        ) {
+            let mut peers =
+                Peers::new(max_packet_size, fec_group_size, retransmission, packets.len() + 1);
+            peers.forward_link.push_events(events);
+
+            for (idx, packet) in packets.iter().enumerate() {
+                peers.send(idx as u32, packet);
+                peers.pump();
+            }
+            peers.forward_link.flush();
+            peers.pump();
+
+            check_delivery(&packets, &peers.received());
+        }
+
+        // Truncated shards can be reassembled into corrupted packets, but they must not crash the
+        // receiver
+        #[test]
+        fn prop_truncated_shards(
+            packets in packets_strategy(),
+            max_packet_size in 64_usize..1500,
+            fec_group_size in fec_strategy(),
+            truncated_sizes in prop::collection::vec(prop::option::of(0_usize..1500), 0..500),
+        ) {
+            let mut peers = Peers::new(max_packet_size, fec_group_size, true, packets.len() + 1);
+            peers.forward_link.push_events(truncated_sizes.into_iter().map(|size| {
+                size.map(LinkEvent::Truncate).unwrap_or(LinkEvent::Deliver)
+            }));
+
+            for (idx, packet) in packets.iter().enumerate() {
+                peers.send(idx as u32, packet);
+                peers.pump();
+            }
+
+            let mut received_count = 0;
+            while let Ok(data) = peers.receiver.recv(Duration::ZERO) {
+                data.get().ok();
+                received_count += 1;
+            }
+            prop_assert!(received_count <= packets.len());
+        }
+
+        #[test]
+        fn prop_fec_recovers_one_shard_per_packet(
+            packets in packets_strategy(),
+            max_packet_size in 64_usize..1500,
+            fec_group_size in 1_u32..8,
+            lost_shards in prop::collection::vec(any::<Index>(), 16),
+        ) {
+            let mut peers = Peers::new(max_packet_size, Some(fec_group_size), false, 2);
+
+            for (idx, packet) in packets.iter().enumerate() {
+                peers.send(idx as u32, packet);
+                // Either a data shard or the parity shard of a group
+                let lost_shard = lost_shards[idx].index(peers.forward_link.pending_count());
+                peers.forward_link.remove_pending(lost_shard);
+                peers.pump();
+
+                let received = peers.received();
+                prop_assert_eq!(received.len(), 1);
+                prop_assert_eq!(&received[0].1, packet);
+                prop_assert!(!received[0].2);
+            }
+        }
+
+        #[test]
+        fn prop_retransmission_recovers_lost_shards(
+            packets in packets_strategy(),
+            max_packet_size in 64_usize..1500,
+            lost_shards in prop::collection::vec(any::<Index>(), 16),
+        ) {
+            let mut peers = Peers::new(max_packet_size, None, true, 2);
+
+            for (idx, packet) in packets.iter().enumerate() {
+                peers.send(idx as u32, packet);
+                // The loss of a shard is detected only when a later shard of the same packet is
+                // received, so the last shard is never lost
+                let shards_count = peers.forward_link.pending_count();
+                if shards_count > 1 {
+                    peers
+                        .forward_link
+                        .remove_pending(lost_shards[idx].index(shards_count - 1));
+                }
+                peers.pump();
+
+                let received = peers.received();
+                prop_assert_eq!(received.len(), 1);
+                prop_assert_eq!(&received[0].1, packet);
+                prop_assert!(!received[0].2);
+            }
+        }
+
+        #[test]
+        fn prop_packet_index_rollover(
+            packets in packets_strategy(),
+            max_packet_size in 64_usize..1500,
+            first_packet_index in u32::MAX - 8..=u32::MAX,
+        ) {
+            let mut peers = Peers::new(max_packet_size, None, false, packets.len() + 1);
+            peers.sender.next_packet_index = first_packet_index;
+
+            for (idx, packet) in packets.iter().enumerate() {
+                peers.send(idx as u32, packet);
+            }
+            peers.pump();
+
+            let received = peers.received();
+            check_delivery(&packets, &received);
+            prop_assert_eq!(received.len(), packets.len());
+        }
+    }
+}
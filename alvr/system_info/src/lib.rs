@@ -188,7 +188,9 @@ pub fn platform() -> Platform {
 pub fn local_ip() -> std::net::IpAddr {
     use std::net::{IpAddr, Ipv4Addr};
 
-    local_ip_address::local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    local_ip_address::local_ip()
+        .or_else(|_| local_ip_address::local_ipv6())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
 }
 
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]